@@ -0,0 +1,24 @@
+extern crate cbindgen;
+
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=NOSLEEP_CAPI_UPDATE_HEADER");
+    let crate_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let bindings = match cbindgen::generate(&crate_dir) {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            // The committed header is still there, the build goes on
+            println!("cargo:warning=Unable to generate nosleep.h: {}", e);
+            return;
+        }
+    };
+    bindings.write_to_file(out_dir.join("nosleep.h"));
+    // The source directory may be read-only, e.g. in the registry cache
+    if std::env::var_os("NOSLEEP_CAPI_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(crate_dir.join("include/nosleep.h"));
+    }
+}
@@ -0,0 +1,93 @@
+#ifndef NOSLEEP_H
+#define NOSLEEP_H
+
+/* Generated by cbindgen, do not edit manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result codes returned by the C API.
+ */
+typedef enum NoSleepResult {
+  NoSleepResult_Ok = 0,
+  NoSleepResult_Init = 1,
+  NoSleepResult_PreventSleep = 2,
+  /**
+   * Only returned on Linux
+   */
+  NoSleepResult_DBus = 3,
+  NoSleepResult_StopLock = 4,
+  NoSleepResult_Unsupported = 5,
+  /**
+   * An error added to the Rust API after this version
+   */
+  NoSleepResult_Other = 6,
+  NoSleepResult_PolicyDenied = 7,
+  NoSleepResult_NullPointer = -1,
+} NoSleepResult;
+
+/**
+ * Opaque handle to a `NoSleep` instance.
+ */
+typedef struct NoSleepInstance NoSleepInstance;
+
+/**
+ * Creates a new instance and stores it in `out`.
+ *
+ * # Safety
+ *
+ * `out` must be a valid pointer to write the instance to.
+ * The instance must be released with `nosleep_free`.
+ */
+enum NoSleepResult nosleep_new(struct NoSleepInstance **out);
+
+/**
+ * Prevents the display from dimming automatically.
+ *
+ * # Safety
+ *
+ * `nosleep` must be an instance created by `nosleep_new`.
+ */
+enum NoSleepResult nosleep_prevent_display(struct NoSleepInstance *nosleep);
+
+/**
+ * Prevents the system from sleeping automatically.
+ *
+ * # Safety
+ *
+ * `nosleep` must be an instance created by `nosleep_new`.
+ */
+enum NoSleepResult nosleep_prevent_system(struct NoSleepInstance *nosleep);
+
+/**
+ * Releases any block held by the instance.
+ *
+ * # Safety
+ *
+ * `nosleep` must be an instance created by `nosleep_new`.
+ */
+enum NoSleepResult nosleep_stop(struct NoSleepInstance *nosleep);
+
+/**
+ * Returns `true` if the instance currently holds a block.
+ *
+ * # Safety
+ *
+ * `nosleep` must be an instance created by `nosleep_new` or null.
+ */
+bool nosleep_is_blocked(const struct NoSleepInstance *nosleep);
+
+/**
+ * Releases the instance, including any block it holds.
+ *
+ * # Safety
+ *
+ * `nosleep` must be an instance created by `nosleep_new` or null,
+ * and must not be used afterwards.
+ */
+void nosleep_free(struct NoSleepInstance *nosleep);
+
+#endif  /* NOSLEEP_H */
@@ -0,0 +1,206 @@
+//! C API to block the power save functionality in the OS.
+//!
+//! Instances are handed out as opaque pointers created by [`nosleep_new`]
+//! and released by [`nosleep_free`]. All fallible functions return a
+//! [`NoSleepResult`] mirroring the [`NoSleepError`] variants.
+
+use nosleep::{NoSleep, NoSleepError, NoSleepTrait};
+
+/// Opaque handle to a `NoSleep` instance.
+pub struct NoSleepInstance {
+    inner: NoSleep,
+}
+
+/// Result codes returned by the C API.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NoSleepResult {
+    Ok = 0,
+    Init = 1,
+    PreventSleep = 2,
+    /// Only returned on Linux
+    DBus = 3,
+    StopLock = 4,
+    Unsupported = 5,
+    /// An error added to the Rust API after this version
+    Other = 6,
+    PolicyDenied = 7,
+    NullPointer = -1,
+}
+
+impl From<&NoSleepError> for NoSleepResult {
+    fn from(error: &NoSleepError) -> NoSleepResult {
+        match error {
+            NoSleepError::Init { .. } => NoSleepResult::Init,
+            NoSleepError::PreventSleep { .. } => NoSleepResult::PreventSleep,
+            #[cfg(target_os = "linux")]
+            NoSleepError::DBus { .. } => NoSleepResult::DBus,
+            NoSleepError::StopLock { .. } => NoSleepResult::StopLock,
+            NoSleepError::Unsupported { .. } => NoSleepResult::Unsupported,
+            NoSleepError::PolicyDenied { .. } => NoSleepResult::PolicyDenied,
+            _ => NoSleepResult::Other,
+        }
+    }
+}
+
+fn to_result(result: Result<(), NoSleepError>) -> NoSleepResult {
+    match result {
+        Ok(()) => NoSleepResult::Ok,
+        Err(e) => NoSleepResult::from(&e),
+    }
+}
+
+/// Creates a new instance and stores it in `out`.
+///
+/// # Safety
+///
+/// `out` must be a valid pointer to write the instance to.
+/// The instance must be released with `nosleep_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nosleep_new(out: *mut *mut NoSleepInstance) -> NoSleepResult {
+    if out.is_null() {
+        return NoSleepResult::NullPointer;
+    }
+    match NoSleep::new() {
+        Ok(inner) => {
+            *out = Box::into_raw(Box::new(NoSleepInstance { inner }));
+            NoSleepResult::Ok
+        }
+        Err(e) => NoSleepResult::from(&e),
+    }
+}
+
+/// Prevents the display from dimming automatically.
+///
+/// # Safety
+///
+/// `nosleep` must be an instance created by `nosleep_new`.
+#[no_mangle]
+pub unsafe extern "C" fn nosleep_prevent_display(nosleep: *mut NoSleepInstance) -> NoSleepResult {
+    match nosleep.as_mut() {
+        Some(nosleep) => to_result(nosleep.inner.prevent_display_sleep()),
+        None => NoSleepResult::NullPointer,
+    }
+}
+
+/// Prevents the system from sleeping automatically.
+///
+/// # Safety
+///
+/// `nosleep` must be an instance created by `nosleep_new`.
+#[no_mangle]
+pub unsafe extern "C" fn nosleep_prevent_system(nosleep: *mut NoSleepInstance) -> NoSleepResult {
+    match nosleep.as_mut() {
+        Some(nosleep) => to_result(nosleep.inner.prevent_system_sleep()),
+        None => NoSleepResult::NullPointer,
+    }
+}
+
+/// Releases any block held by the instance.
+///
+/// # Safety
+///
+/// `nosleep` must be an instance created by `nosleep_new`.
+#[no_mangle]
+pub unsafe extern "C" fn nosleep_stop(nosleep: *mut NoSleepInstance) -> NoSleepResult {
+    match nosleep.as_mut() {
+        Some(nosleep) => to_result(nosleep.inner.stop()),
+        None => NoSleepResult::NullPointer,
+    }
+}
+
+/// Returns `true` if the instance currently holds a block.
+///
+/// # Safety
+///
+/// `nosleep` must be an instance created by `nosleep_new` or null.
+#[no_mangle]
+pub unsafe extern "C" fn nosleep_is_blocked(nosleep: *const NoSleepInstance) -> bool {
+    match nosleep.as_ref() {
+        Some(nosleep) => nosleep.inner.is_blocked(),
+        None => false,
+    }
+}
+
+/// Releases the instance, including any block it holds.
+///
+/// # Safety
+///
+/// `nosleep` must be an instance created by `nosleep_new` or null,
+/// and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn nosleep_free(nosleep: *mut NoSleepInstance) {
+    if !nosleep.is_null() {
+        let mut nosleep = Box::from_raw(nosleep);
+        let _ = nosleep.inner.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[cfg_attr(target_os = "linux", ignore)]
+    #[test]
+    fn test_round_trip() {
+        unsafe {
+            let mut nosleep: *mut NoSleepInstance = std::ptr::null_mut();
+            assert_eq!(NoSleepResult::Ok, nosleep_new(&mut nosleep));
+            assert!(!nosleep.is_null());
+            assert!(!nosleep_is_blocked(nosleep));
+            assert_eq!(NoSleepResult::Ok, nosleep_stop(nosleep));
+            nosleep_free(nosleep);
+        }
+    }
+
+    #[test]
+    fn test_null_pointer() {
+        unsafe {
+            assert_eq!(
+                NoSleepResult::NullPointer,
+                nosleep_new(std::ptr::null_mut())
+            );
+            assert_eq!(
+                NoSleepResult::NullPointer,
+                nosleep_prevent_display(std::ptr::null_mut())
+            );
+            assert_eq!(
+                NoSleepResult::NullPointer,
+                nosleep_prevent_system(std::ptr::null_mut())
+            );
+            assert_eq!(
+                NoSleepResult::NullPointer,
+                nosleep_stop(std::ptr::null_mut())
+            );
+            assert!(!nosleep_is_blocked(std::ptr::null()));
+            nosleep_free(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let error = NoSleepError::PreventSleep {
+            reason: "test".to_string(),
+            code: None,
+        };
+        assert_eq!(NoSleepResult::PreventSleep, NoSleepResult::from(&error));
+        assert_eq!(NoSleepResult::StopLock as i32, 4);
+        assert_eq!(NoSleepResult::NullPointer as i32, -1);
+    }
+
+    // Requires a platform where blocking is possible (e.g. an active Gnome session)
+    #[test]
+    #[ignore]
+    fn test_prevent_display() {
+        unsafe {
+            let mut nosleep: *mut NoSleepInstance = std::ptr::null_mut();
+            assert_eq!(NoSleepResult::Ok, nosleep_new(&mut nosleep));
+            assert_eq!(NoSleepResult::Ok, nosleep_prevent_display(nosleep));
+            assert!(nosleep_is_blocked(nosleep));
+            assert_eq!(NoSleepResult::Ok, nosleep_stop(nosleep));
+            assert!(!nosleep_is_blocked(nosleep));
+            nosleep_free(nosleep);
+        }
+    }
+}
@@ -1,91 +1,1061 @@
 //! Block the power save functionality on macOS
 
-#![allow(improper_ctypes)]
+use std::borrow::Cow;
+use std::sync::mpsc::Receiver;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
-use std::ops::Deref;
+use nosleep_types::{
+    ActivityPoke, BackendProbe, BlockStats, BlockTracker, Capabilities, EnvConfig, HandleInfo,
+    HandleRegistry, HealthReport, NoSleepError, NoSleepObserver, NoSleepTrait, Registered,
+    StateEvent, StateEvents,
+};
+pub use nosleep_types::{BlockLevel, NoSleepType, SwitchPolicy};
 
-use nosleep_types::{NoSleepError, NoSleepTrait};
-use objc_foundation::{INSString, NSString};
+mod sys;
 
-mod sys {
-    use objc_foundation::NSString;
+/// The types held as asked on macOS, for static checks where
+/// [`NoSleepTrait::is_supported_type`] needs an instance. There is no
+/// separate screensaver assertion, so `PreventScreenSaver` is left out:
+/// it is held as `PreventUserIdleDisplaySleep`, or fails in strict mode.
+pub const SUPPORTED_TYPES: &[NoSleepType] = &[
+    NoSleepType::PreventUserIdleDisplaySleep,
+    NoSleepType::PreventUserIdleSystemSleep,
+    NoSleepType::PreventSystemSleep,
+];
 
-    #[link(name = "nosleep")]
-    extern "C" {
-        pub fn start(
-            noSleepType: *const NSString,
-            handle: *mut std::os::raw::c_uint,
-        ) -> std::os::raw::c_int;
-        pub fn stop(handle: std::os::raw::c_uint);
+/// Maps the `IOReturn` code returned by the IOKit assertion calls
+/// to a human readable reason, keeping the numeric code.
+fn io_return_reason(ret: std::os::raw::c_int) -> String {
+    let code = ret as u32;
+    let name = match code {
+        0xe00002bc => "kIOReturnError: general error",
+        0xe00002bd => "kIOReturnNoMemory: can't allocate memory",
+        0xe00002be => "kIOReturnNoResources: resource shortage",
+        0xe00002c1 => "kIOReturnNotPrivileged: privilege violation",
+        0xe00002c2 => "kIOReturnBadArgument: invalid argument",
+        0xe00002c7 => "kIOReturnUnsupported: unsupported function",
+        0xe00002d5 => "kIOReturnBusy: device busy",
+        0xe00002d6 => "kIOReturnTimeout: I/O timeout",
+        0xe00002d8 => "kIOReturnNotReady: not ready",
+        0xe00002e2 => "kIOReturnNotPermitted: not permitted",
+        _ => "unknown IOReturn",
+    };
+    format!("{} ({:#010x})", name, code)
+}
+
+// kIOReturnNotPrivileged and kIOReturnNotPermitted, the assertion was refused on purpose
+const POLICY_DENIED: [u32; 2] = [0xe00002c1, 0xe00002e2];
+
+// Classifies a failed assertion, policy denials get their own variant
+fn prevent_error(ret: std::os::raw::c_int) -> NoSleepError {
+    if POLICY_DENIED.contains(&(ret as u32)) {
+        NoSleepError::PolicyDenied {
+            reason: io_return_reason(ret),
+            code: io_return_code(ret),
+        }
+    } else {
+        NoSleepError::PreventSleep {
+            reason: io_return_reason(ret),
+            code: io_return_code(ret),
+        }
     }
 }
 
+fn release_assertion(handle: u32) -> Result<(), NoSleepError> {
+    sys::stop(handle).map_err(|ret| NoSleepError::StopLock {
+        reason: io_return_reason(ret),
+        code: io_return_code(ret),
+    })
+}
+
+// The `IOReturn` as unsigned, as IOKit documents it
+fn io_return_code(ret: std::os::raw::c_int) -> Option<i64> {
+    Some(ret as u32 as i64)
+}
+
+// The assertions of every live instance
+static REGISTRY: HandleRegistry<Vec<(NoSleepType, u32)>> = HandleRegistry::new();
+
 pub struct NoSleep {
-    // The unblock handle
-    no_sleep_handle: Option<u32>,
+    // The unblock handles, with the type of block each was created for,
+    // shared with `NoSleep::stop_all`
+    no_sleep_handles: Registered<Vec<(NoSleepType, u32)>>,
+
+    // The types of the blocks released by `suspend`
+    suspended: Vec<NoSleepType>,
+
+    // How long blocks were held
+    stats: BlockTracker,
+
+    // The subscribers of `subscribe`
+    events: StateEvents,
+
+    // When the OS releases the block by itself
+    deadline: Option<Instant>,
+
+    // Name of the assertion, shown by `pmset -g assertions`
+    reason: Cow<'static, str>,
+
+    // What `prevent_sleep` does when a block is already held
+    switch_policy: SwitchPolicy,
+
+    // The level of the block held, see `prevent_sleep_with_level`
+    level: BlockLevel,
+
+    // Who the block held is attributed to, see `prevent_sleep_as`
+    who: Option<String>,
+
+    // Calls back when the system wakes up, until `stop`
+    wake_observer: Option<NotificationObserver>,
+
+    // Creates the assertions again on wake, see `set_reacquire_on_resume`
+    reacquire_on_resume: bool,
+
+    // Observes the wakes for the block held, see `set_reacquire_on_resume`
+    reacquire_observer: Option<NotificationObserver>,
+
+    // Holds the block while this application is frontmost,
+    // see `prevent_display_sleep_while_frontmost`
+    frontmost_observer: Option<NotificationObserver>,
+
+    // How often display blocks declare user activity, see `set_activity_poke`
+    activity_poke: Option<Duration>,
+
+    // Declares user activity while a display block is held
+    poke: Option<ActivityPoke>,
+
+    // The assertions released last, see `confirm_released`
+    released: Vec<u32>,
+
+    // Fails `PreventScreenSaver` instead of aliasing it, see `set_strict`
+    strict: bool,
 }
 
-impl NoSleepTrait for NoSleep {
-    fn new() -> Result<NoSleep, NoSleepError> {
-        Ok(NoSleep {
-            no_sleep_handle: None,
-        })
+// The assertion of `IOPMAssertionDeclareUserActivity`, renewed on every
+// poke and released once the pokes end
+struct UserActivity {
+    reason: String,
+    handle: u32,
+}
+
+impl UserActivity {
+    fn poke(&mut self) {
+        // Best effort, the display block itself is still held
+        if let Ok(handle) = sys::declare_user_activity(&self.reason, self.handle) {
+            self.handle = handle;
+        }
     }
+}
 
-    fn prevent_display_sleep(&mut self) -> Result<(), NoSleepError> {
-        self.stop()?;
+impl Drop for UserActivity {
+    fn drop(&mut self) {
+        if self.handle != 0 {
+            let _ = sys::stop(self.handle);
+        }
+    }
+}
 
-        let mut handle = 0u32;
-        let ret = unsafe {
-            sys::start(
-                NSString::from_str("PreventUserIdleDisplaySleep").deref(),
-                &mut handle,
-            )
+type ResumeCallback = Box<dyn Fn() + Send + Sync>;
+
+// Called with `true` when this application is activated, `false` for others
+type ActivationCallback = Box<dyn Fn(bool) + Send + Sync>;
+
+// Calls back on every notification of NSWorkspace it observes until dropped
+struct NotificationObserver {
+    observer: sys::Observer,
+}
+
+// The notification center can be used from any thread
+unsafe impl Send for NotificationObserver {}
+unsafe impl Sync for NotificationObserver {}
+
+impl NotificationObserver {
+    // On every NSWorkspaceDidWakeNotification
+    fn wake(callback: ResumeCallback) -> NotificationObserver {
+        NotificationObserver {
+            observer: sys::add_wake_observer(callback),
+        }
+    }
+
+    // On every NSWorkspaceDidActivateApplicationNotification
+    fn activation(callback: ActivationCallback) -> NotificationObserver {
+        NotificationObserver {
+            observer: sys::add_activation_observer(callback),
+        }
+    }
+}
+
+impl Drop for NotificationObserver {
+    fn drop(&mut self) {
+        sys::remove_observer(&self.observer);
+    }
+}
+
+// What an activation does to the block of
+// `prevent_display_sleep_while_frontmost`
+#[derive(Debug, PartialEq, Eq)]
+enum FrontmostChange {
+    Acquire,
+    Release,
+    Unchanged,
+}
+
+// Follows whether this application is frontmost, as applications get activated
+#[derive(Debug, Default)]
+struct FrontmostState {
+    frontmost: bool,
+    // The assertion held for being frontmost, the only one released on
+    // `FrontmostChange::Release`
+    handle: Option<u32>,
+}
+
+impl FrontmostState {
+    // `own` if the application activated is this one
+    fn activated(&mut self, own: bool) -> FrontmostChange {
+        let change = match (self.frontmost, own) {
+            (false, true) => FrontmostChange::Acquire,
+            (true, false) => FrontmostChange::Release,
+            _ => FrontmostChange::Unchanged,
         };
-        if ret != 0 {
-            return Err(NoSleepError::PreventSleep {
-                reason: ret.to_string(),
+        self.frontmost = own;
+        change
+    }
+}
+
+// The assertion name when no reason is set
+const DEFAULT_REASON: &str = "Power Save Blocker";
+
+// How often `confirm_released` lists the assertions again
+const CONFIRM_RELEASED_INTERVAL: Duration = Duration::from_millis(10);
+
+// The display uses kIOPMAssertionTypePreventUserIdleDisplaySleep, not the
+// deprecated kIOPMAssertionTypeNoDisplaySleep, so only idle sleep is prevented
+fn assertion_type(nosleep_type: NoSleepType) -> &'static str {
+    match nosleep_type {
+        // No separate assertion, holding the display also holds off the screensaver
+        NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver => {
+            "PreventUserIdleDisplaySleep"
+        }
+        NoSleepType::PreventUserIdleSystemSleep => "PreventUserIdleSystemSleep",
+        NoSleepType::PreventSystemSleep => "PreventSystemSleep",
+    }
+}
+
+// A system sleep timer of 0 minutes is "Never"
+fn sleep_timer(minutes: i64) -> Option<Duration> {
+    (minutes > 0).then(|| Duration::from_secs(minutes as u64 * 60))
+}
+
+fn create_assertion(
+    nosleep_type: NoSleepType,
+    reason: &str,
+    level: BlockLevel,
+) -> Result<u32, NoSleepError> {
+    let handle = sys::start(assertion_type(nosleep_type), reason).map_err(prevent_error)?;
+    if level == BlockLevel::High && nosleep_type == NoSleepType::PreventSystemSleep {
+        if let Err(ret) = sys::set_applies_to_limited_power(handle) {
+            // Best effort, the original error is more useful
+            let _ = sys::stop(handle);
+            return Err(prevent_error(ret));
+        }
+    }
+    Ok(handle)
+}
+
+// Replaces the assertions of a block with new ones. Holds the lock
+// throughout, so `stop` releases either the old or the new ones.
+fn recreate_assertions(
+    no_sleep_handles: &Registered<Vec<(NoSleepType, u32)>>,
+    reason: &str,
+    level: BlockLevel,
+) -> Result<(), NoSleepError> {
+    for (nosleep_type, handle) in no_sleep_handles.lock().iter_mut() {
+        let recreated = create_assertion(*nosleep_type, reason, level)?;
+        // Best effort, the new assertion holds the block
+        let _ = sys::stop(*handle);
+        *handle = recreated;
+    }
+    Ok(())
+}
+
+impl NoSleep {
+    /// Creates a new, unblocked NoSleep type carrying the same
+    /// configuration as `self`.
+    pub fn clone_config(&self) -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.reason = self.reason.clone();
+        nosleep.switch_policy = self.switch_policy;
+        nosleep.activity_poke = self.activity_poke;
+        nosleep.strict = self.strict;
+        Ok(nosleep)
+    }
+
+    /// Creates a new NoSleep type in strict mode, see [`NoSleep::set_strict`].
+    pub fn strict() -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.set_strict(true);
+        Ok(nosleep)
+    }
+
+    /// Fails `PreventScreenSaver` with [`NoSleepError::Unsupported`] instead
+    /// of holding it as `PreventUserIdleDisplaySleep`, as there is no
+    /// separate screensaver assertion. Defaults to `false`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Creates a new NoSleep type configured by environment variables,
+    /// for deployments that cannot change code. Unset variables keep the
+    /// defaults, invalid values are an `Init` error.
+    ///
+    /// - `NOSLEEP_BACKEND`: only `IOPMAssertion` exists on macOS
+    /// - `NOSLEEP_REASON`: see [`NoSleepTrait::set_reason`]
+    ///
+    /// `NOSLEEP_APP_ID` and `NOSLEEP_DBUS_TIMEOUT_MS` only apply to Linux
+    /// and are ignored.
+    pub fn from_env() -> Result<NoSleep, NoSleepError> {
+        let config = EnvConfig::read()?;
+        if let Some(backend) = config
+            .backends
+            .iter()
+            .flatten()
+            .find(|backend| !backend.eq_ignore_ascii_case("IOPMAssertion"))
+        {
+            return Err(NoSleepError::Init {
+                reason: format!("Unknown NOSLEEP_BACKEND: {}", backend),
+                code: None,
             });
         }
-        self.no_sleep_handle = Some(handle);
+        let mut nosleep = NoSleep::new()?;
+        if let Some(reason) = config.reason {
+            if reason.contains('\0') {
+                return Err(NoSleepError::Init {
+                    reason: format!(
+                        "Invalid NOSLEEP_REASON, contains a NUL character: {:?}",
+                        reason
+                    ),
+                    code: None,
+                });
+            }
+            nosleep.reason = reason.into();
+        }
+        Ok(nosleep)
+    }
+
+    /// Lists the power management assertions of the system, without
+    /// creating one. Meant for bug reports, see [`HealthReport`].
+    pub fn health_check(&self) -> HealthReport {
+        let error = sys::probe_assertions().err().map(io_return_reason);
+        let available = error.is_none();
+        HealthReport {
+            platform: format!("macos (nosleep-mac-sys {})", env!("CARGO_PKG_VERSION")),
+            backends: vec![BackendProbe {
+                name: "IOPMAssertion".to_string(),
+                available,
+                error,
+            }],
+            selected: available.then(|| "IOPMAssertion".to_string()),
+            reason: self.reason.to_string(),
+            app_id: None,
+            timeout: None,
+        }
+    }
+
+    /// Releases the assertions of every live NoSleep in this process, then
+    /// any other assertion `IOPMCopyAssertionsByProcess` lists for this
+    /// process, for example ones leaked by other code. Best effort, continues
+    /// past failures and returns the first error.
+    pub fn stop_all() -> Result<(), NoSleepError> {
+        let result = NoSleep::release_all(REGISTRY.live());
+        result.and(sys::stop_all().map_err(|ret| NoSleepError::StopLock {
+            reason: io_return_reason(ret),
+            code: io_return_code(ret),
+        }))
+    }
+
+    /// Waits until the assertions released by the last `stop`, `suspend`
+    /// or `stop_type` are no longer listed for this process, as macOS can
+    /// drop them asynchronously. For example before measuring power.
+    /// Returns `false` if one is still listed after `timeout`, or if the
+    /// assertions cannot be listed.
+    pub fn confirm_released(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let released = self
+                .released
+                .iter()
+                .all(|handle| matches!(sys::is_started(*handle), Ok(false)));
+            if released {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(CONFIRM_RELEASED_INTERVAL);
+        }
+    }
+
+    fn release_all(live: Vec<Registered<Vec<(NoSleepType, u32)>>>) -> Result<(), NoSleepError> {
+        let mut result = Ok(());
+        for no_sleep_handles in live {
+            let handles = std::mem::take(&mut *no_sleep_handles.lock());
+            for (_, handle) in handles {
+                result = result.and(release_assertion(handle));
+            }
+        }
+        result
+    }
+
+    fn create_assertion(&self, nosleep_type: NoSleepType) -> Result<u32, NoSleepError> {
+        create_assertion(nosleep_type, &self.attributed_reason(), self.level)
+    }
+
+    // The reason of new assertions, prefixed with who they are attributed to
+    fn attributed_reason(&self) -> Cow<'_, str> {
+        match &self.who {
+            Some(who) => Cow::Owned(format!("{}: {}", who, self.reason)),
+            None => Cow::Borrowed(&self.reason),
+        }
+    }
+
+    // The types of the blocks currently held
+    fn nosleep_types(&self) -> Vec<NoSleepType> {
+        self.no_sleep_handles
+            .lock()
+            .iter()
+            .map(|(nosleep_type, _)| *nosleep_type)
+            .collect()
+    }
+
+    /// Keeps the display from dimming and sleeping on idle, while the user
+    /// can still put it to sleep, for example with Ctrl+Shift+Eject,
+    /// a hot corner or `pmset displaysleepnow`.
+    ///
+    /// The same as [`NoSleepTrait::prevent_display_sleep`], which holds a
+    /// `kIOPMAssertionTypePreventUserIdleDisplaySleep` assertion that only
+    /// affects idle sleep.
+    pub fn prevent_idle_display_sleep(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventUserIdleDisplaySleep)
+    }
+
+    /// The IDs of the active displays (`CGDirectDisplayID`), the main
+    /// display first, see [`NoSleep::prevent_display_sleep_on`].
+    pub fn displays() -> Vec<u32> {
+        sys::active_displays()
+    }
+
+    /// Keeps only the display `display` awake, one of [`NoSleep::displays`].
+    ///
+    /// macOS has no per-display power assertions: a display assertion, like
+    /// `IOPMAssertionDeclareUserActivity`, keeps every display awake. So this
+    /// only succeeds if `display` is the only active display, holding the
+    /// same block as [`NoSleepTrait::prevent_display_sleep`]. Otherwise the
+    /// error is [`NoSleepError::Unsupported`].
+    pub fn prevent_display_sleep_on(&mut self, display: u32) -> Result<(), NoSleepError> {
+        match NoSleep::displays()[..] {
+            [only] if only == display => self.prevent_display_sleep(),
+            _ => Err(NoSleepError::Unsupported {
+                operation: format!("keeping only display {} awake", display),
+            }),
+        }
+    }
+
+    /// Keeps the system awake while audio plays, the display can still
+    /// sleep. Holds a `kIOPMAssertionTypePreventUserIdleSystemSleep`
+    /// assertion marked as audio output, like the ones of `coreaudiod`,
+    /// so `pmset -g assertions` attributes it to audio.
+    ///
+    /// Replaces any block that is held, whatever the switch policy.
+    /// The block reports itself as [`NoSleepType::PreventUserIdleSystemSleep`]
+    /// and `resume` after `suspend` restores it without the audio marking.
+    pub fn prevent_sleep_for_audio(&mut self) -> Result<(), NoSleepError> {
+        self.who = None;
+        self.reported(NoSleep::hold_for_audio)
+    }
+
+    /// Keeps the system awake while the screen is locked, for example
+    /// during a backup. Holds a `kIOPMAssertionTypePreventUserIdleSystemSleep`
+    /// assertion.
+    ///
+    /// Locking the screen does not release power assertions, but it lets
+    /// the display sleep after a short while, or right away when locked
+    /// through a hot corner. A display block only keeps the system awake
+    /// as long as the display is on, so it can leave a gap. Idle system
+    /// sleep honours the system assertion whatever the display does.
+    /// Closing the lid or choosing Sleep still puts the system to sleep.
+    ///
+    /// Replaces any block that is held, whatever the switch policy, as
+    /// a display block would otherwise be kept under `KeepStrongest`.
+    pub fn prevent_system_sleep_through_lock(&mut self) -> Result<(), NoSleepError> {
+        self.level = BlockLevel::Normal;
+        self.who = None;
+        self.reported(|nosleep| {
+            nosleep.release()?;
+            nosleep.hold(&[NoSleepType::PreventUserIdleSystemSleep])
+        })
+    }
+
+    // Acquires the block of `prevent_sleep_for_audio`
+    fn hold_for_audio(&mut self) -> Result<(), NoSleepError> {
+        self.release()?;
+        let handle = sys::start_audio(&self.attributed_reason()).map_err(prevent_error)?;
+        let nosleep_type = NoSleepType::PreventUserIdleSystemSleep;
+        *self.no_sleep_handles.lock() = vec![(nosleep_type, handle)];
+        self.stats.start(nosleep_type, None);
         Ok(())
     }
 
-    fn prevent_system_sleep(&mut self) -> Result<(), NoSleepError> {
-        self.stop()?;
+    /// Sets what `prevent_sleep` does when a block of a different type
+    /// is already held. Defaults to [`SwitchPolicy::Replace`].
+    pub fn set_switch_policy(&mut self, policy: SwitchPolicy) {
+        self.switch_policy = policy;
+    }
 
-        let mut handle = 0u32;
-        let ret = unsafe {
-            sys::start(
-                NSString::from_str("PreventUserIdleSystemSleep").deref(),
-                &mut handle,
-            )
-        };
-        if ret != 0 {
-            return Err(NoSleepError::PreventSleep {
-                reason: ret.to_string(),
+    /// Declares user activity every `interval` while a display block is
+    /// held, for environments that only respect user activity and not
+    /// assertions. Uses `IOPMAssertionDeclareUserActivity`, which also
+    /// turns a sleeping display back on. `None`, the default, turns it off.
+    ///
+    /// Applies to blocks acquired afterwards, except for
+    /// `prevent_display_sleep_until`. `stop` and `suspend` end the pokes.
+    pub fn set_activity_poke(&mut self, interval: Option<Duration>) {
+        self.activity_poke = interval;
+    }
+
+    /// Creates the assertions of the block again every time the system
+    /// wakes up, in case they were dropped across the sleep. Like
+    /// `on_resume`, this runs on the main thread on
+    /// `NSWorkspaceDidWakeNotification`, which is only delivered while the
+    /// main run loop runs. A block released while the system sleeps is not
+    /// acquired again. Timed blocks are not acquired again either.
+    /// Defaults to `false`.
+    pub fn set_reacquire_on_resume(&mut self, enabled: bool) -> Result<(), NoSleepError> {
+        self.reacquire_on_resume = enabled;
+        self.observe_reacquire();
+        Ok(())
+    }
+
+    /// Holds a display block only while this application is frontmost,
+    /// for example for a menu bar utility. It is acquired right away if the
+    /// application is already frontmost, released when another application
+    /// is activated and acquired again when this one is, until `stop` or
+    /// another block replaces it.
+    ///
+    /// Activations are observed with
+    /// `NSWorkspaceDidActivateApplicationNotification`, which is only
+    /// delivered while the main run loop runs, as in an `NSApplication`.
+    /// The blocks acquired and released by activations are not reported
+    /// to `subscribe` nor counted by `stats`.
+    pub fn prevent_display_sleep_while_frontmost(&mut self) -> Result<(), NoSleepError> {
+        let frontmost = sys::is_frontmost();
+        let mut handle = None;
+        if frontmost {
+            self.prevent_display_sleep()?;
+            handle = self
+                .no_sleep_handles
+                .lock()
+                .first()
+                .map(|(_, handle)| *handle);
+        } else {
+            self.reported(NoSleep::release)?;
+        }
+        let state = Mutex::new(FrontmostState { frontmost, handle });
+        let no_sleep_handles = self.no_sleep_handles.clone();
+        let reason = self.attributed_reason().into_owned();
+        self.frontmost_observer = Some(NotificationObserver::activation(Box::new(move |own| {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            // Best effort, there is no one to report the error to
+            match state.activated(own) {
+                FrontmostChange::Acquire => {
+                    let nosleep_type = NoSleepType::PreventUserIdleDisplaySleep;
+                    if let Ok(handle) = create_assertion(nosleep_type, &reason, BlockLevel::Normal)
+                    {
+                        no_sleep_handles.lock().push((nosleep_type, handle));
+                        state.handle = Some(handle);
+                    }
+                }
+                FrontmostChange::Release => {
+                    if let Some(handle) = state.handle.take() {
+                        let mut handles = no_sleep_handles.lock();
+                        // Not held anymore if it was released in between
+                        if let Some(index) = handles.iter().position(|(_, held)| *held == handle) {
+                            handles.remove(index);
+                            let _ = release_assertion(handle);
+                        }
+                    }
+                }
+                FrontmostChange::Unchanged => {}
+            }
+        })));
+        Ok(())
+    }
+
+    // Observes the wakes for the block held, with its current reason
+    fn observe_reacquire(&mut self) {
+        self.reacquire_observer = None;
+        if !self.reacquire_on_resume
+            || self.deadline.is_some()
+            || self.no_sleep_handles.lock().is_empty()
+        {
+            return;
+        }
+        let no_sleep_handles = self.no_sleep_handles.clone();
+        let reason = self.attributed_reason().into_owned();
+        let level = self.level;
+        self.reacquire_observer = Some(NotificationObserver::wake(Box::new(move || {
+            // Best effort, there is no one to report the error to
+            let _ = recreate_assertions(&no_sleep_handles, &reason, level);
+        })));
+    }
+
+    // Acquires the block of `prevent`
+    fn hold(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        if let Some(nosleep_type) = nosleep_types
+            .iter()
+            .find(|nosleep_type| !self.is_supported_type(**nosleep_type))
+        {
+            return Err(NoSleepError::Unsupported {
+                operation: format!("{nosleep_type} in strict mode"),
             });
         }
-        self.no_sleep_handle = Some(handle);
+        let current = if self.is_blocked() {
+            self.nosleep_types()
+        } else {
+            vec![]
+        };
+        if self.switch_policy.keep_current(&current, nosleep_types)? {
+            return Ok(());
+        }
+
+        self.release()?;
+
+        let Some(strongest) = NoSleepType::strongest(nosleep_types) else {
+            return Ok(());
+        };
+        let mut handles = vec![];
+        for nosleep_type in nosleep_types {
+            match self.create_assertion(*nosleep_type) {
+                Ok(handle) => handles.push((*nosleep_type, handle)),
+                Err(e) => {
+                    for (_, handle) in handles {
+                        // Best effort, the original error is more useful
+                        let _ = sys::stop(handle);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        *self.no_sleep_handles.lock() = handles;
+        self.stats.start(strongest, None);
+        self.observe_reacquire();
+        let display = nosleep_types.iter().any(|nosleep_type| {
+            matches!(
+                nosleep_type,
+                NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver
+            )
+        });
+        if let (Some(interval), true) = (self.activity_poke, display) {
+            let mut activity = UserActivity {
+                reason: self.attributed_reason().into_owned(),
+                handle: 0,
+            };
+            self.poke = Some(ActivityPoke::start(interval, move || activity.poke()));
+        }
+        Ok(())
+    }
+
+    // Acquires the block of `prevent_display_sleep_until`
+    fn hold_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.release()?;
+        let now = Instant::now();
+        if deadline <= now {
+            return Ok(());
+        }
+
+        let nosleep_type = NoSleepType::PreventUserIdleDisplaySleep;
+        let handle = sys::start_with_timeout(
+            assertion_type(nosleep_type),
+            &self.attributed_reason(),
+            (deadline - now).as_secs_f64(),
+        )
+        .map_err(prevent_error)?;
+        *self.no_sleep_handles.lock() = vec![(nosleep_type, handle)];
+        self.stats.start(nosleep_type, Some(deadline));
+        self.deadline = Some(deadline);
         Ok(())
     }
 
+    // Runs `change` and reports the block held afterwards to the subscribers
+    fn reported<R>(&mut self, change: impl FnOnce(&mut NoSleep) -> R) -> R {
+        let result = change(self);
+        let held = if self.is_blocked() {
+            NoSleepType::strongest(&self.nosleep_types())
+        } else {
+            None
+        };
+        self.events.update(held);
+        result
+    }
+
+    // Releases the assertions of `stop_type`, the others stay held
+    fn release_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        let held = self.nosleep_types();
+        if !held.contains(&nosleep_type) {
+            return Ok(());
+        }
+        let others = held
+            .into_iter()
+            .filter(|held| *held != nosleep_type)
+            .collect::<Vec<_>>();
+        let Some(strongest) = NoSleepType::strongest(&others) else {
+            return self.release();
+        };
+        let released = {
+            let mut handles = self.no_sleep_handles.lock();
+            let (released, kept) = std::mem::take(&mut *handles)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(held, _)| *held == nosleep_type);
+            *handles = kept;
+            released
+        };
+        let mut result = Ok(());
+        self.released = released.iter().map(|(_, handle)| *handle).collect();
+        for (_, handle) in released {
+            result = result.and(release_assertion(handle));
+        }
+        let display = others.iter().any(|other| {
+            matches!(
+                other,
+                NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver
+            )
+        });
+        if !display {
+            self.poke = None;
+        }
+        self.stats.start(strongest, self.deadline);
+        result
+    }
+
+    /// Releases the assertions. The handles are forgotten even if a release
+    /// fails, as retrying it would fail again. The first error is returned.
+    fn release(&mut self) -> Result<(), NoSleepError> {
+        self.poke = None;
+        self.reacquire_observer = None;
+        self.frontmost_observer = None;
+        let mut result = Ok(());
+        let handles = std::mem::take(&mut *self.no_sleep_handles.lock());
+        self.released = handles.iter().map(|(_, handle)| *handle).collect();
+        for (_, handle) in handles {
+            result = result.and(release_assertion(handle));
+        }
+        self.stats.finish();
+        self.suspended.clear();
+        self.deadline = None;
+        result
+    }
+}
+
+impl NoSleepTrait for NoSleep {
+    fn new() -> Result<NoSleep, NoSleepError> {
+        Ok(NoSleep {
+            no_sleep_handles: REGISTRY.register(vec![]),
+            suspended: vec![],
+            deadline: None,
+            stats: BlockTracker::default(),
+            events: StateEvents::default(),
+            reason: Cow::Borrowed(DEFAULT_REASON),
+            switch_policy: SwitchPolicy::default(),
+            level: BlockLevel::default(),
+            who: None,
+            wake_observer: None,
+            reacquire_on_resume: false,
+            reacquire_observer: None,
+            frontmost_observer: None,
+            activity_poke: None,
+            poke: None,
+            released: vec![],
+            strict: false,
+        })
+    }
+
+    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.prevent(&[nosleep_type])
+    }
+
+    /// Creates an assertion per type.
+    fn prevent(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        self.level = BlockLevel::Normal;
+        self.who = None;
+        self.reported(|nosleep| nosleep.hold(nosleep_types))
+    }
+
+    fn prevent_sleep_with_level(
+        &mut self,
+        nosleep_type: NoSleepType,
+        level: BlockLevel,
+    ) -> Result<(), NoSleepError> {
+        self.level = level;
+        self.who = None;
+        self.reported(|nosleep| nosleep.hold(&[nosleep_type]))
+    }
+
+    /// Names the assertions `who: reason`.
+    fn prevent_sleep_as(
+        &mut self,
+        nosleep_type: NoSleepType,
+        who: &str,
+    ) -> Result<(), NoSleepError> {
+        self.level = BlockLevel::Normal;
+        self.who = Some(who.to_string());
+        self.reported(|nosleep| nosleep.hold(&[nosleep_type]))
+    }
+
+    fn prevent_display_sleep_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.who = None;
+        self.reported(|nosleep| nosleep.hold_until(deadline))
+    }
+
+    /// Releases the assertions and stops calling the callback of `on_resume`.
     fn stop(&mut self) -> Result<(), NoSleepError> {
-        if let Some(handle) = &self.no_sleep_handle {
-            unsafe {
-                sys::stop(*handle);
-            }
-            self.no_sleep_handle.take();
+        self.wake_observer = None;
+        self.reported(NoSleep::release)
+    }
+
+    /// Releases the assertions created for `nosleep_type`.
+    fn stop_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.reported(|nosleep| nosleep.release_type(nosleep_type))
+    }
+
+    /// Renames the active assertions in place.
+    fn set_reason(&mut self, reason: &str) -> Result<(), NoSleepError> {
+        self.set_reason_cow(reason.to_string())
+    }
+
+    fn set_reason_cow(&mut self, reason: impl Into<Cow<'static, str>>) -> Result<(), NoSleepError> {
+        self.reason = reason.into();
+        let reason = self.attributed_reason();
+        for (_, handle) in self.no_sleep_handles.lock().iter() {
+            sys::set_reason(*handle, &reason).map_err(|ret| NoSleepError::PreventSleep {
+                reason: io_return_reason(ret),
+                code: io_return_code(ret),
+            })?;
         }
+        // New assertions get the new reason as well
+        self.observe_reacquire();
         Ok(())
     }
+
+    fn suspend(&mut self) -> Result<(), NoSleepError> {
+        if self.no_sleep_handles.lock().is_empty() {
+            return Ok(());
+        }
+        let nosleep_types = self.nosleep_types();
+        self.reported(NoSleep::release)?;
+        self.suspended = nosleep_types;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), NoSleepError> {
+        let nosleep_types = std::mem::take(&mut self.suspended);
+        if nosleep_types.is_empty() {
+            return Ok(());
+        }
+        // Keeps the level and attribution of the suspended block
+        self.reported(|nosleep| nosleep.hold(&nosleep_types))
+    }
+
+    fn is_blocked(&self) -> bool {
+        !self.no_sleep_handles.lock().is_empty()
+            && self
+                .deadline
+                .is_none_or(|deadline| Instant::now() < deadline)
+    }
+
+    fn handles(&self) -> Vec<HandleInfo> {
+        if !self.is_blocked() {
+            return vec![];
+        }
+        self.no_sleep_handles
+            .lock()
+            .iter()
+            .map(|(kind, handle)| HandleInfo {
+                kind: *kind,
+                backend: "IOPMAssertion",
+                raw: *handle as u64,
+                who: self.who.clone(),
+            })
+            .collect()
+    }
+
+    fn stats(&self) -> BlockStats {
+        self.stats.stats()
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// The callback runs on the main thread on `NSWorkspaceDidWakeNotification`,
+    /// which is only delivered while the main run loop runs, for example
+    /// in an `NSApplication` or a winit event loop.
+    fn on_resume(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.wake_observer = None;
+        self.wake_observer = Some(NotificationObserver::wake(callback));
+        Ok(())
+    }
+
+    /// `Revoked` is never reported, assertions are only released by their
+    /// timeout.
+    fn subscribe(&self) -> Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    fn observer(&self) -> NoSleepObserver {
+        self.events.observer()
+    }
+
+    /// Declares user activity with `IOPMAssertionDeclareUserActivity`,
+    /// which also turns a sleeping display back on.
+    fn simulate_user_activity(&mut self) -> Result<(), NoSleepError> {
+        let handle =
+            sys::declare_user_activity(&self.attributed_reason(), 0).map_err(prevent_error)?;
+        // The idle timer is reset already, the assertion is not needed after
+        release_assertion(handle)
+    }
+
+    /// Checks if the main display is asleep.
+    fn display_is_on(&self) -> Option<bool> {
+        Some(!sys::display_is_asleep())
+    }
+
+    /// Reads the system sleep timer of the active power management
+    /// preferences for the power source in use, see `pmset -g`.
+    fn system_sleep_timeout(&self) -> Option<Duration> {
+        sleep_timer(sys::system_sleep_minutes()?)
+    }
+
+    /// Every type is supported, except `PreventScreenSaver` in strict
+    /// mode, see [`NoSleep::set_strict`].
+    fn is_supported_type(&self, nosleep_type: NoSleepType) -> bool {
+        !self.strict || nosleep_type != NoSleepType::PreventScreenSaver
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            display_sleep: true,
+            system_sleep: true,
+            // PreventUserIdleDisplaySleep also prevents idle system sleep
+            display_only: false,
+            screensaver: true,
+            timed_blocks: true,
+            battery_aware: false,
+        }
+    }
+}
+
+// `new` cannot fail on this platform, nothing is acquired until a block
+impl Default for NoSleep {
+    fn default() -> Self {
+        NoSleep::new().expect("NoSleep::new is infallible")
+    }
+}
+
+// Releases the assertions, then tells the observers
+impl Drop for NoSleep {
+    fn drop(&mut self) {
+        // Best effort, there is no one to report an error to
+        let _ = self.release();
+        self.events.update(None);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, Instant};
+
     use nosleep_types::NoSleepTrait;
 
-    use super::NoSleep;
+    use super::{
+        assertion_type, io_return_code, io_return_reason, prevent_error, recreate_assertions,
+        sleep_timer, sys, BlockLevel, FrontmostChange, FrontmostState, NoSleep, NoSleepError,
+        NoSleepType, StateEvent, SwitchPolicy, DEFAULT_REASON, POLICY_DENIED, REGISTRY,
+        SUPPORTED_TYPES,
+    };
+
+    #[test]
+    fn test_prevent_display_sleep_on() {
+        let mut nosleep = NoSleep::new().unwrap();
+        let displays = NoSleep::displays();
+        if let [only] = displays[..] {
+            nosleep.prevent_display_sleep_on(only).unwrap();
+            assert_eq!(
+                vec![NoSleepType::PreventUserIdleDisplaySleep],
+                nosleep.nosleep_types()
+            );
+            nosleep.stop().unwrap();
+        } else {
+            // Assertions would keep the other displays awake as well
+            for display in displays {
+                assert!(matches!(
+                    nosleep.prevent_display_sleep_on(display),
+                    Err(NoSleepError::Unsupported { .. })
+                ));
+            }
+        }
+        // Not a display
+        assert!(matches!(
+            nosleep.prevent_display_sleep_on(u32::MAX),
+            Err(NoSleepError::Unsupported { .. })
+        ));
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_attributed_reason() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.reason = "Backup".into();
+        assert_eq!("Backup", nosleep.attributed_reason());
+        nosleep.who = Some("client-1".to_string());
+        assert_eq!("client-1: Backup", nosleep.attributed_reason());
+    }
+
+    #[test]
+    fn test_prevent_sleep_as() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_sleep_as(NoSleepType::PreventUserIdleSystemSleep, "client-1")
+            .unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!(Some("client-1"), handles[0].who.as_deref());
+        let output = std::process::Command::new("pmset")
+            .args(["-g", "assertions"])
+            .output()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&output.stdout).contains("client-1: "));
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        assert_eq!(Some("client-1"), nosleep.handles()[0].who.as_deref());
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(None, nosleep.handles()[0].who);
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_sleep_with_level() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_sleep_with_level(NoSleepType::PreventSystemSleep, BlockLevel::High)
+            .unwrap();
+        assert_eq!(BlockLevel::High, nosleep.level);
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        assert_eq!(BlockLevel::High, nosleep.level);
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(BlockLevel::Normal, nosleep.level);
+        nosleep.stop().unwrap();
+    }
 
     #[test]
     fn test_prevent_display_sleep() {
@@ -99,10 +1069,677 @@ mod tests {
         nosleep.prevent_system_sleep().unwrap();
     }
 
+    #[test]
+    fn test_prevent_screensaver() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_screensaver().unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(
+            "PreventUserIdleDisplaySleep",
+            assertion_type(NoSleepType::PreventScreenSaver)
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.handles().is_empty());
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!(NoSleepType::PreventUserIdleDisplaySleep, handles[0].kind);
+        assert_eq!("IOPMAssertion", handles[0].backend);
+        nosleep.stop().unwrap();
+        assert!(nosleep.handles().is_empty());
+    }
+
+    // Only honoured on AC power, verify with `pmset -g assertions`
+    #[test]
+    #[ignore]
+    fn test_prevent_system_sleep_forced() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_sleep(NoSleepType::PreventSystemSleep)
+            .unwrap();
+        let output = std::process::Command::new("pmset")
+            .args(["-g", "assertions"])
+            .output()
+            .unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output
+                .lines()
+                .any(|line| line.contains("PreventSystemSleep")
+                    && line.contains("Power Save Blocker"))
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_io_return_reason() {
+        assert_eq!(
+            "kIOReturnNotPrivileged: privilege violation (0xe00002c1)",
+            io_return_reason(0xe00002c1u32 as i32)
+        );
+        assert_eq!(Some(0xe00002c1), io_return_code(0xe00002c1u32 as i32));
+        assert_eq!("unknown IOReturn (0x00000001)", io_return_reason(1));
+    }
+
+    #[test]
+    fn test_prevent_error_policy_denied() {
+        for code in POLICY_DENIED {
+            assert!(matches!(
+                prevent_error(code as i32),
+                NoSleepError::PolicyDenied { code: Some(c), .. } if c == code as i64
+            ));
+        }
+        // kIOReturnBadArgument
+        assert!(matches!(
+            prevent_error(0xe00002c2u32 as i32),
+            NoSleepError::PreventSleep { .. }
+        ));
+    }
+
     #[test]
     fn test_stop() {
         let mut nosleep = NoSleep::new().unwrap();
         nosleep.prevent_display_sleep().unwrap();
         nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_stop_invalid_handle() {
+        let mut nosleep = NoSleep::new().unwrap();
+        // Never handed out by IOKit
+        *nosleep.no_sleep_handles.lock() =
+            vec![(NoSleepType::PreventUserIdleDisplaySleep, u32::MAX)];
+        let error = nosleep.stop().unwrap_err();
+        assert!(matches!(error, NoSleepError::StopLock { .. }));
+        assert!(error.code().is_some_and(|code| code != 0));
+        assert!(!nosleep.is_blocked());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_health_check() {
+        let nosleep = NoSleep::new().unwrap();
+        let report = nosleep.health_check();
+        assert!(report.platform.starts_with("macos"));
+        assert_eq!(1, report.backends.len());
+        assert_eq!(Some("IOPMAssertion"), report.selected.as_deref());
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_drop() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.no_sleep_handles.clone();
+        let assertions: Vec<u32> = handles.lock().iter().map(|(_, handle)| *handle).collect();
+        let observer = nosleep.observer();
+        drop(nosleep);
+        assert!(handles.lock().is_empty());
+        for assertion in assertions {
+            assert!(!sys::is_started(assertion).unwrap());
+        }
+        assert!(!observer.is_blocked());
+    }
+
+    #[test]
+    fn test_stop_all() {
+        let mut first = NoSleep::new().unwrap();
+        let mut second = NoSleep::new().unwrap();
+        first.prevent_display_sleep().unwrap();
+        second.prevent_system_sleep().unwrap();
+        let registered = REGISTRY.live();
+        assert!(registered.contains(&first.no_sleep_handles));
+        assert!(registered.contains(&second.no_sleep_handles));
+
+        // Only these two, `stop_all` would also release the blocks of other tests
+        NoSleep::release_all(vec![
+            first.no_sleep_handles.clone(),
+            second.no_sleep_handles.clone(),
+        ])
+        .unwrap();
+        assert!(!first.is_blocked());
+        assert!(!second.is_blocked());
+        // Nothing is released twice
+        first.stop().unwrap();
+        second.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_until() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() + Duration::from_millis(200))
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(!nosleep.is_blocked());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_until_past_deadline() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() - Duration::from_millis(10))
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_suspend_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        assert!(!nosleep.is_blocked());
+        nosleep.resume().unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_stop_after_suspend() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        nosleep.stop().unwrap();
+        nosleep.resume().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut nosleep = NoSleep::new().unwrap();
+        let first = nosleep.subscribe();
+        let second = nosleep.subscribe();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        nosleep.stop().unwrap();
+
+        for events in [first, second] {
+            let received = events.try_iter().collect::<Vec<_>>();
+            assert!(matches!(
+                received[..],
+                [
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleDisplaySleep,
+                        ..
+                    },
+                    StateEvent::Switched {
+                        from: NoSleepType::PreventUserIdleDisplaySleep,
+                        to: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                ]
+            ));
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.stats().current_started_at.is_some());
+        std::thread::sleep(Duration::from_millis(100));
+        nosleep.stop().unwrap();
+        let stats = nosleep.stats();
+        assert!(stats.display_total >= Duration::from_millis(100));
+        assert_eq!(None, stats.current_started_at);
+        nosleep.reset_stats();
+        assert_eq!(Duration::ZERO, nosleep.stats().display_total);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let nosleep = NoSleep::new().unwrap();
+        let capabilities = nosleep.capabilities();
+        assert!(capabilities.display_sleep);
+        assert!(capabilities.system_sleep);
+        assert!(!capabilities.display_only);
+        assert!(capabilities.screensaver);
+        assert!(capabilities.timed_blocks);
+        assert!(!capabilities.battery_aware);
+    }
+
+    // The only test that sets NOSLEEP_* variables, they are process wide
+    #[test]
+    fn test_from_env() {
+        std::env::set_var("NOSLEEP_BACKEND", "IOPMAssertion");
+        std::env::set_var("NOSLEEP_REASON", "Nightly backup");
+        let nosleep = NoSleep::from_env().unwrap();
+        assert_eq!("Nightly backup", nosleep.reason);
+
+        std::env::set_var("NOSLEEP_BACKEND", "gnome");
+        assert!(matches!(
+            NoSleep::from_env(),
+            Err(NoSleepError::Init { .. })
+        ));
+
+        std::env::remove_var("NOSLEEP_BACKEND");
+        std::env::remove_var("NOSLEEP_REASON");
+        let nosleep = NoSleep::from_env().unwrap();
+        assert_eq!(DEFAULT_REASON, nosleep.reason);
+    }
+
+    #[test]
+    fn test_activity_poke() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_activity_poke(Some(Duration::from_secs(60)));
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.poke.is_none());
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.poke.is_some());
+        nosleep.suspend().unwrap();
+        assert!(nosleep.poke.is_none());
+        nosleep.resume().unwrap();
+        assert!(nosleep.poke.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.poke.is_none());
+    }
+
+    #[test]
+    fn test_clone_config() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        let clone = nosleep.clone_config().unwrap();
+        assert!(clone.handles().is_empty());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_supported_types() {
+        assert!(!SUPPORTED_TYPES.contains(&NoSleepType::PreventScreenSaver));
+        // Strict mode only accepts the types held as asked
+        let nosleep = NoSleep::strict().unwrap();
+        for nosleep_type in [
+            NoSleepType::PreventUserIdleDisplaySleep,
+            NoSleepType::PreventUserIdleSystemSleep,
+            NoSleepType::PreventSystemSleep,
+            NoSleepType::PreventScreenSaver,
+        ] {
+            assert_eq!(
+                SUPPORTED_TYPES.contains(&nosleep_type),
+                nosleep.is_supported_type(nosleep_type),
+                "{:?}",
+                nosleep_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut strict = NoSleep::strict().unwrap();
+        assert!(!strict.is_supported_type(NoSleepType::PreventScreenSaver));
+        assert!(matches!(
+            strict.prevent_sleep(NoSleepType::PreventScreenSaver),
+            Err(NoSleepError::Unsupported { .. })
+        ));
+        assert!(!strict.is_blocked());
+        // The other types are held as usual
+        strict.prevent_display_sleep().unwrap();
+        strict.stop().unwrap();
+        let clone = strict.clone_config().unwrap();
+        assert!(!clone.is_supported_type(NoSleepType::PreventScreenSaver));
+    }
+
+    #[test]
+    fn test_is_supported_type() {
+        let nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleDisplaySleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleSystemSleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventSystemSleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventScreenSaver));
+    }
+
+    #[test]
+    fn test_resume_without_suspend() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.resume().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_on_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.on_resume(Box::new(|| {})).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.wake_observer.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.wake_observer.is_none());
+    }
+
+    #[test]
+    fn test_reacquire_on_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_reacquire_on_resume(true).unwrap();
+        assert!(nosleep.reacquire_observer.is_none());
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.reacquire_observer.is_some());
+        let before = nosleep.no_sleep_handles.lock().clone();
+        // What the observer runs on wake
+        recreate_assertions(
+            &nosleep.no_sleep_handles,
+            DEFAULT_REASON,
+            BlockLevel::Normal,
+        )
+        .unwrap();
+        let after = nosleep.no_sleep_handles.lock().clone();
+        assert_eq!(before.len(), after.len());
+        assert_ne!(before, after);
+        nosleep.stop().unwrap();
+        assert!(nosleep.reacquire_observer.is_none());
+
+        // Stopped while asleep, not acquired again on wake
+        recreate_assertions(
+            &nosleep.no_sleep_handles,
+            DEFAULT_REASON,
+            BlockLevel::Normal,
+        )
+        .unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_set_reason() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.handles();
+        nosleep.set_reason("Playing a video").unwrap();
+        assert_eq!(handles, nosleep.handles());
+        nosleep.stop().unwrap();
+    }
+
+    // Verify the name with `pmset -g assertions`
+    #[test]
+    #[ignore]
+    fn test_set_reason_pmset() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.set_reason("Downloading").unwrap();
+        let output = std::process::Command::new("pmset")
+            .args(["-g", "assertions"])
+            .output()
+            .unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(output.contains("Downloading"));
+        nosleep.stop().unwrap();
+    }
+
+    // Turns the display off, needs a display and a user to look at it
+    #[test]
+    #[ignore]
+    fn test_prevent_idle_display_sleep_allows_manual_sleep() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_idle_display_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types()
+        );
+        std::process::Command::new("pmset")
+            .arg("displaysleepnow")
+            .status()
+            .unwrap();
+        std::thread::sleep(Duration::from_secs(2));
+        assert_eq!(Some(false), nosleep.display_is_on());
+        // Wakes the display again
+        std::process::Command::new("caffeinate")
+            .args(["-u", "-t", "1"])
+            .status()
+            .unwrap();
+        nosleep.stop().unwrap();
+    }
+
+    // Verify with `pmset -g assertions`
+    #[test]
+    #[ignore]
+    fn test_prevent_sleep_for_audio() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_reason("Playing music").unwrap();
+        nosleep.prevent_sleep_for_audio().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types()
+        );
+        let output = std::process::Command::new("pmset")
+            .args(["-g", "assertions"])
+            .output()
+            .unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        let assertion = output
+            .split("\n   pid ")
+            .find(|assertion| assertion.contains("Playing music"))
+            .unwrap();
+        assert!(assertion.contains("PreventUserIdleSystemSleep"));
+        assert!(assertion.contains("audio-out"));
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_prevent_system_sleep_through_lock_replaces_display_block() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::KeepStrongest);
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep_through_lock().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+    }
+
+    // Needs someone at the Mac, run with `--ignored --nocapture`:
+    // 1. Set "Prevent automatic sleeping when the display is off" to off
+    //    and the display to turn off after 1 minute in System Settings
+    // 2. Lock the screen with Ctrl+Cmd+Q once the test prints so
+    // 3. Unlock after 5 minutes, the test fails if the system slept
+    #[test]
+    #[ignore]
+    fn test_prevent_system_sleep_through_lock() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_reason("Backup").unwrap();
+        nosleep.prevent_system_sleep_through_lock().unwrap();
+        println!("Lock the screen now");
+        std::thread::sleep(Duration::from_secs(5 * 60));
+        // The power log lists every sleep since the assertion was created
+        let output = std::process::Command::new("pmset")
+            .args(["-g", "log"])
+            .output()
+            .unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        let slept = output
+            .lines()
+            .rev()
+            .take_while(|line| !line.contains("Backup"))
+            .any(|line| line.contains("Entering Sleep"));
+        assert!(!slept);
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_switch_policy_replace() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::Replace);
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_switch_policy_keep_strongest() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::KeepStrongest);
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_switch_policy_reject() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::Reject);
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(matches!(
+            nosleep.prevent_system_sleep(),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_frontmost_state() {
+        let mut state = FrontmostState::default();
+        // Other applications come and go while this one is in the background
+        assert_eq!(FrontmostChange::Unchanged, state.activated(false));
+        assert_eq!(FrontmostChange::Acquire, state.activated(true));
+        assert_eq!(FrontmostChange::Unchanged, state.activated(true));
+        assert_eq!(FrontmostChange::Release, state.activated(false));
+        assert_eq!(FrontmostChange::Unchanged, state.activated(false));
+        assert_eq!(FrontmostChange::Acquire, state.activated(true));
+
+        let mut state = FrontmostState {
+            frontmost: true,
+            handle: None,
+        };
+        assert_eq!(FrontmostChange::Release, state.activated(false));
+    }
+
+    // A test binary is never the frontmost application
+    #[test]
+    fn test_prevent_display_sleep_while_frontmost() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep_while_frontmost().unwrap();
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.frontmost_observer.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.frontmost_observer.is_none());
+    }
+
+    #[test]
+    fn test_default() {
+        let nosleep = NoSleep::default();
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.handles().is_empty());
+    }
+
+    #[test]
+    fn test_display_is_on() {
+        let nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.display_is_on().is_some());
+    }
+
+    #[test]
+    fn test_sleep_timer() {
+        assert_eq!(None, sleep_timer(0));
+        assert_eq!(Some(Duration::from_secs(600)), sleep_timer(10));
+    }
+
+    #[test]
+    fn test_system_sleep_timeout() {
+        let nosleep = NoSleep::new().unwrap();
+        if let Some(timeout) = nosleep.system_sleep_timeout() {
+            assert!(timeout >= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn test_prevent_multiple_types() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleDisplaySleep,
+                NoSleepType::PreventSystemSleep,
+            ])
+            .unwrap();
+        assert_eq!(2, nosleep.handles().len());
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        assert_eq!(
+            vec![
+                NoSleepType::PreventUserIdleDisplaySleep,
+                NoSleepType::PreventSystemSleep
+            ],
+            nosleep.nosleep_types()
+        );
+        nosleep.stop().unwrap();
+        assert!(nosleep.handles().is_empty());
+    }
+
+    #[test]
+    fn test_confirm_released() {
+        let mut nosleep = NoSleep::new().unwrap();
+        // Nothing released yet
+        assert!(nosleep.confirm_released(Duration::ZERO));
+        nosleep.prevent_display_sleep().unwrap();
+        let handle = nosleep.no_sleep_handles.lock()[0].1;
+        assert_eq!(Ok(true), sys::is_started(handle));
+        nosleep.stop().unwrap();
+        let started = Instant::now();
+        assert!(nosleep.confirm_released(Duration::from_secs(1)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_stop_type() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleDisplaySleep,
+                NoSleepType::PreventSystemSleep,
+            ])
+            .unwrap();
+        nosleep
+            .stop_type(NoSleepType::PreventUserIdleDisplaySleep)
+            .unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventSystemSleep],
+            nosleep.nosleep_types()
+        );
+        assert_eq!(1, nosleep.handles().len());
+
+        // Not held, nothing to release
+        nosleep
+            .stop_type(NoSleepType::PreventUserIdleDisplaySleep)
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.stop_type(NoSleepType::PreventSystemSleep).unwrap();
+        assert!(!nosleep.is_blocked());
     }
 }
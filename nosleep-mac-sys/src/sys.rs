@@ -0,0 +1,459 @@
+//! The IOKit, CoreGraphics and AppKit calls behind [`crate::NoSleep`].
+//! Every IOKit call returns its `IOReturn` as the error.
+//!
+//! References:
+//! https://developer.apple.com/documentation/iokit/1557134-iopmassertioncreatewithname
+//! https://developer.apple.com/library/archive/qa/qa1340/_index.html
+
+#![allow(non_snake_case)]
+
+use std::os::raw::{c_int, c_uint};
+use std::ptr::NonNull;
+
+use block2::RcBlock;
+use core_foundation::array::CFArray;
+use core_foundation::base::{CFType, TCFType};
+use core_foundation::boolean::CFBoolean;
+use core_foundation::dictionary::{CFDictionary, CFDictionaryRef};
+use core_foundation::number::CFNumber;
+use core_foundation::string::{CFString, CFStringRef};
+use core_foundation_sys::base::CFTypeRef;
+use core_foundation_sys::date::CFTimeInterval;
+use objc2::rc::Retained;
+use objc2::runtime::{AnyObject, ProtocolObject};
+use objc2_app_kit::{
+    NSRunningApplication, NSWorkspace, NSWorkspaceApplicationKey,
+    NSWorkspaceDidActivateApplicationNotification, NSWorkspaceDidWakeNotification,
+};
+use objc2_foundation::{NSNotification, NSObjectProtocol};
+
+pub(crate) type IOReturn = c_int;
+
+type IOPMAssertionID = c_uint;
+
+const K_IOPM_ASSERTION_LEVEL_ON: c_uint = 255;
+
+// kIOPMUserActiveLocal, activity of a user at this machine
+const K_IOPM_USER_ACTIVE_LOCAL: c_uint = 0;
+
+// The keys and values of IOPMLib.h, which are `CFSTR` macros, not symbols
+const K_IOPM_ASSERTION_TYPE_KEY: &str = "AssertType";
+const K_IOPM_ASSERTION_LEVEL_KEY: &str = "AssertLevel";
+const K_IOPM_ASSERTION_NAME_KEY: &str = "AssertName";
+const K_IOPM_ASSERTION_DETAILS_KEY: &str = "Details";
+const K_IOPM_ASSERTION_TIMEOUT_ACTION_RELEASE: &str = "TimeoutActionRelease";
+const K_IOPM_ASSERTION_APPLIES_TO_LIMITED_POWER_KEY: &str = "AppliesToLimitedPower";
+// The key `IOPMCopyAssertionsByProcess` lists the handles under
+const ASSERTION_ID_KEY: &str = "AssertionId";
+// kIOPMAssertionResourcesUsed and kIOPMAudioOutResource of IOPMLibPrivate.h
+const RESOURCES_USED_KEY: &str = "ResourcesUsed";
+const AUDIO_OUT_RESOURCE: &str = "audio-out";
+// kIOPMSystemSleepKey of IOPMLib.h and kIOPMACPowerKey of IOPSKeys.h
+const K_IOPM_SYSTEM_SLEEP_KEY: &str = "System Sleep Timer";
+const K_IOPM_AC_POWER_KEY: &str = "AC Power";
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IOPMAssertionCreateWithName(
+        AssertionType: CFStringRef,
+        AssertionLevel: c_uint,
+        AssertionName: CFStringRef,
+        AssertionID: *mut IOPMAssertionID,
+    ) -> IOReturn;
+    fn IOPMAssertionCreateWithDescription(
+        AssertionType: CFStringRef,
+        Name: CFStringRef,
+        Details: CFStringRef,
+        HumanReadableReason: CFStringRef,
+        LocalizationBundlePath: CFStringRef,
+        Timeout: CFTimeInterval,
+        TimeoutAction: CFStringRef,
+        AssertionID: *mut IOPMAssertionID,
+    ) -> IOReturn;
+    fn IOPMAssertionCreateWithProperties(
+        AssertionProperties: CFDictionaryRef,
+        AssertionID: *mut IOPMAssertionID,
+    ) -> IOReturn;
+    fn IOPMAssertionSetProperty(
+        theAssertion: IOPMAssertionID,
+        theProperty: CFStringRef,
+        theValue: CFTypeRef,
+    ) -> IOReturn;
+    fn IOPMAssertionDeclareUserActivity(
+        AssertionName: CFStringRef,
+        userType: c_uint,
+        AssertionID: *mut IOPMAssertionID,
+    ) -> IOReturn;
+    fn IOPMAssertionRelease(AssertionID: IOPMAssertionID) -> IOReturn;
+    fn IOPMCopyAssertionsByProcess(AssertionsByPID: *mut CFDictionaryRef) -> IOReturn;
+    fn IOPMCopyActivePMPreferences() -> CFDictionaryRef;
+    fn IOPSCopyPowerSourcesInfo() -> CFTypeRef;
+    fn IOPSGetProvidingPowerSourceType(snapshot: CFTypeRef) -> CFStringRef;
+}
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGMainDisplayID() -> u32;
+    fn CGDisplayIsAsleep(display: u32) -> c_int;
+    fn CGGetActiveDisplayList(
+        maxDisplays: u32,
+        activeDisplays: *mut u32,
+        displayCount: *mut u32,
+    ) -> c_int;
+}
+
+// The handle of an assertion created by `create`, or its error
+fn created(create: impl FnOnce(*mut IOPMAssertionID) -> IOReturn) -> Result<u32, IOReturn> {
+    let mut handle = 0;
+    match create(&mut handle) {
+        0 => Ok(handle),
+        ret => Err(ret),
+    }
+}
+
+fn checked(ret: IOReturn) -> Result<(), IOReturn> {
+    match ret {
+        0 => Ok(()),
+        ret => Err(ret),
+    }
+}
+
+/// Creates an assertion of `assertion_type` named `reason`.
+pub(crate) fn start(assertion_type: &str, reason: &str) -> Result<u32, IOReturn> {
+    let assertion_type = CFString::new(assertion_type);
+    let reason = CFString::new(reason);
+    created(|handle| unsafe {
+        IOPMAssertionCreateWithName(
+            assertion_type.as_concrete_TypeRef(),
+            K_IOPM_ASSERTION_LEVEL_ON,
+            reason.as_concrete_TypeRef(),
+            handle,
+        )
+    })
+}
+
+/// Same as `start`, but the assertion is released by the OS after
+/// `timeout` seconds.
+pub(crate) fn start_with_timeout(
+    assertion_type: &str,
+    reason: &str,
+    timeout: f64,
+) -> Result<u32, IOReturn> {
+    let assertion_type = CFString::new(assertion_type);
+    let reason = CFString::new(reason);
+    let timeout_action = CFString::from_static_string(K_IOPM_ASSERTION_TIMEOUT_ACTION_RELEASE);
+    created(|handle| unsafe {
+        IOPMAssertionCreateWithDescription(
+            assertion_type.as_concrete_TypeRef(),
+            reason.as_concrete_TypeRef(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+            timeout,
+            timeout_action.as_concrete_TypeRef(),
+            handle,
+        )
+    })
+}
+
+/// Same as `start` with PreventUserIdleSystemSleep, but attributed to audio
+/// playback like the assertions of coreaudiod, which `pmset -g assertions`
+/// lists with "Resources: audio-out".
+pub(crate) fn start_audio(reason: &str) -> Result<u32, IOReturn> {
+    let properties = CFDictionary::from_CFType_pairs(&[
+        (
+            CFString::from_static_string(K_IOPM_ASSERTION_TYPE_KEY),
+            CFString::from_static_string("PreventUserIdleSystemSleep").as_CFType(),
+        ),
+        (
+            CFString::from_static_string(K_IOPM_ASSERTION_LEVEL_KEY),
+            CFNumber::from(K_IOPM_ASSERTION_LEVEL_ON as i32).as_CFType(),
+        ),
+        (
+            CFString::from_static_string(K_IOPM_ASSERTION_NAME_KEY),
+            CFString::new(reason).as_CFType(),
+        ),
+        (
+            CFString::from_static_string(K_IOPM_ASSERTION_DETAILS_KEY),
+            CFString::from_static_string("Audio playback").as_CFType(),
+        ),
+        (
+            CFString::from_static_string(RESOURCES_USED_KEY),
+            CFArray::from_CFTypes(&[CFString::from_static_string(AUDIO_OUT_RESOURCE)]).as_CFType(),
+        ),
+    ]);
+    created(|handle| unsafe {
+        IOPMAssertionCreateWithProperties(properties.as_concrete_TypeRef(), handle)
+    })
+}
+
+/// Renames an active assertion, it is not released in between.
+pub(crate) fn set_reason(handle: u32, reason: &str) -> Result<(), IOReturn> {
+    let name_key = CFString::from_static_string(K_IOPM_ASSERTION_NAME_KEY);
+    let reason = CFString::new(reason);
+    checked(unsafe {
+        IOPMAssertionSetProperty(
+            handle,
+            name_key.as_concrete_TypeRef(),
+            reason.as_CFTypeRef(),
+        )
+    })
+}
+
+/// Lets an assertion apply on battery as well, PreventSystemSleep
+/// assertions are only honoured on AC power otherwise.
+pub(crate) fn set_applies_to_limited_power(handle: u32) -> Result<(), IOReturn> {
+    let key = CFString::from_static_string(K_IOPM_ASSERTION_APPLIES_TO_LIMITED_POWER_KEY);
+    checked(unsafe {
+        IOPMAssertionSetProperty(
+            handle,
+            key.as_concrete_TypeRef(),
+            CFBoolean::true_value().as_CFTypeRef(),
+        )
+    })
+}
+
+/// Declares that the user is active, which turns the display on and
+/// resets the idle timer. Renews the assertion `handle` of an earlier
+/// call, creates a new one if it is 0. Released with `stop`.
+pub(crate) fn declare_user_activity(reason: &str, handle: u32) -> Result<u32, IOReturn> {
+    let reason = CFString::new(reason);
+    let mut handle = handle;
+    checked(unsafe {
+        IOPMAssertionDeclareUserActivity(
+            reason.as_concrete_TypeRef(),
+            K_IOPM_USER_ACTIVE_LOCAL,
+            &mut handle,
+        )
+    })?;
+    Ok(handle)
+}
+
+/// Releases an assertion.
+pub(crate) fn stop(handle: u32) -> Result<(), IOReturn> {
+    checked(unsafe { IOPMAssertionRelease(handle) })
+}
+
+type AssertionsByProcess = CFDictionary<CFNumber, CFArray<CFDictionary<CFString, CFType>>>;
+
+fn copy_assertions_by_process() -> Result<AssertionsByProcess, IOReturn> {
+    let mut assertions: CFDictionaryRef = std::ptr::null();
+    checked(unsafe { IOPMCopyAssertionsByProcess(&mut assertions) })?;
+    if assertions.is_null() {
+        return Ok(CFDictionary::from_CFType_pairs(&[]));
+    }
+    Ok(unsafe { AssertionsByProcess::wrap_under_create_rule(assertions) })
+}
+
+/// The assertions of this process, also those created by other code, as
+/// `pmset -g assertions` lists them.
+pub(crate) fn started_handles() -> Result<Vec<u32>, IOReturn> {
+    let assertions = copy_assertions_by_process()?;
+    let pid = CFNumber::from(std::process::id() as i32);
+    let Some(own) = assertions.find(&pid) else {
+        return Ok(vec![]);
+    };
+    let id_key = CFString::from_static_string(ASSERTION_ID_KEY);
+    Ok(own
+        .iter()
+        .filter_map(|assertion| {
+            assertion
+                .find(&id_key)
+                .and_then(|handle| handle.downcast::<CFNumber>())
+                .and_then(|handle| handle.to_i64())
+        })
+        .map(|handle| handle as u32)
+        .collect())
+}
+
+/// Returns `true` while `handle` is listed among the assertions of this
+/// process. Released assertions can stay listed for a moment.
+pub(crate) fn is_started(handle: u32) -> Result<bool, IOReturn> {
+    Ok(started_handles()?.contains(&handle))
+}
+
+/// Releases every assertion of this process, also those created by other
+/// code. Continues past failures and returns the first error.
+pub(crate) fn stop_all() -> Result<(), IOReturn> {
+    let mut result = Ok(());
+    for handle in started_handles()? {
+        result = result.and(stop(handle));
+    }
+    result
+}
+
+/// Checks that the power management assertions can be listed.
+pub(crate) fn probe_assertions() -> Result<(), IOReturn> {
+    copy_assertions_by_process().map(|_| ())
+}
+
+// The settings of the active power preferences per power source
+type PMPreferences = CFDictionary<CFString, CFDictionary<CFString, CFType>>;
+
+// The power source in use, "AC Power", "Battery Power" or "UPS Power".
+// AC if it cannot be told, like on a desktop without a battery.
+fn providing_power_source() -> CFString {
+    let snapshot = unsafe { IOPSCopyPowerSourcesInfo() };
+    if snapshot.is_null() {
+        return CFString::from_static_string(K_IOPM_AC_POWER_KEY);
+    }
+    let snapshot = unsafe { CFType::wrap_under_create_rule(snapshot) };
+    let source = unsafe { IOPSGetProvidingPowerSourceType(snapshot.as_CFTypeRef()) };
+    if source.is_null() {
+        return CFString::from_static_string(K_IOPM_AC_POWER_KEY);
+    }
+    unsafe { CFString::wrap_under_get_rule(source) }
+}
+
+/// The minutes of inactivity before the system sleeps on the power
+/// source in use, 0 if it never does, like `pmset -g`.
+/// `None` if the preferences cannot be read.
+pub(crate) fn system_sleep_minutes() -> Option<i64> {
+    let preferences = unsafe { IOPMCopyActivePMPreferences() };
+    if preferences.is_null() {
+        return None;
+    }
+    let preferences = unsafe { PMPreferences::wrap_under_create_rule(preferences) };
+    let settings = preferences.find(providing_power_source())?;
+    settings
+        .find(CFString::from_static_string(K_IOPM_SYSTEM_SLEEP_KEY))?
+        .downcast::<CFNumber>()?
+        .to_i64()
+}
+
+/// Detects if the main display is asleep.
+pub(crate) fn display_is_asleep() -> bool {
+    unsafe { CGDisplayIsAsleep(CGMainDisplayID()) != 0 }
+}
+
+/// The IDs of the active displays, the main display first.
+/// Empty if they cannot be listed.
+pub(crate) fn active_displays() -> Vec<u32> {
+    let mut count = 0;
+    if unsafe { CGGetActiveDisplayList(0, std::ptr::null_mut(), &mut count) } != 0 {
+        return vec![];
+    }
+    let mut displays = vec![0; count as usize];
+    if unsafe { CGGetActiveDisplayList(count, displays.as_mut_ptr(), &mut count) } != 0 {
+        return vec![];
+    }
+    displays.truncate(count as usize);
+    displays
+}
+
+pub(crate) type Observer = Retained<ProtocolObject<dyn NSObjectProtocol>>;
+
+/// Calls `callback` on every NSWorkspaceDidWakeNotification. The
+/// notification is posted on the main thread, which needs a running run
+/// loop. Returns the observer to pass to `remove_wake_observer`.
+pub(crate) fn add_wake_observer(callback: impl Fn() + 'static) -> Observer {
+    let block = RcBlock::new(move |_: NonNull<NSNotification>| callback());
+    unsafe {
+        NSWorkspace::sharedWorkspace()
+            .notificationCenter()
+            .addObserverForName_object_queue_usingBlock(
+                Some(NSWorkspaceDidWakeNotification),
+                None,
+                None,
+                &block,
+            )
+    }
+}
+
+/// Calls `callback` on every NSWorkspaceDidActivateApplicationNotification,
+/// with `true` if the application activated is this one. Posted on the
+/// main thread like NSWorkspaceDidWakeNotification.
+pub(crate) fn add_activation_observer(callback: impl Fn(bool) + 'static) -> Observer {
+    let block = RcBlock::new(move |notification: NonNull<NSNotification>| {
+        callback(activates_current(unsafe { notification.as_ref() }))
+    });
+    unsafe {
+        NSWorkspace::sharedWorkspace()
+            .notificationCenter()
+            .addObserverForName_object_queue_usingBlock(
+                Some(NSWorkspaceDidActivateApplicationNotification),
+                None,
+                None,
+                &block,
+            )
+    }
+}
+
+// The application activated is under NSWorkspaceApplicationKey
+fn activates_current(notification: &NSNotification) -> bool {
+    let key: &AnyObject = unsafe { NSWorkspaceApplicationKey };
+    let Some(application) = notification
+        .userInfo()
+        .and_then(|user_info| user_info.objectForKey(key))
+    else {
+        return false;
+    };
+    NSRunningApplication::currentApplication().isEqual(Some(&application))
+}
+
+/// Detects if this application is the frontmost one.
+pub(crate) fn is_frontmost() -> bool {
+    NSRunningApplication::currentApplication().isActive()
+}
+
+/// Stops the callbacks of `add_wake_observer` or `add_activation_observer`.
+pub(crate) fn remove_observer(observer: &Observer) {
+    unsafe {
+        NSWorkspace::sharedWorkspace()
+            .notificationCenter()
+            .removeObserver(observer.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start() {
+        let handle = start("PreventUserIdleSystemSleep", "Power Save Blocker").unwrap();
+        set_reason(handle, "Renamed").unwrap();
+        stop(handle).unwrap();
+    }
+
+    #[test]
+    fn test_stop() {
+        let handle = start("PreventUserIdleDisplaySleep", "Power Save Blocker").unwrap();
+        stop(handle).unwrap();
+        // Already released
+        assert!(stop(handle).is_err());
+    }
+
+    #[test]
+    fn test_set_applies_to_limited_power() {
+        let handle = start("PreventSystemSleep", "Power Save Blocker").unwrap();
+        set_applies_to_limited_power(handle).unwrap();
+        stop(handle).unwrap();
+    }
+
+    #[test]
+    fn test_start_with_timeout() {
+        let handle = start_with_timeout("PreventUserIdleDisplaySleep", "Timed", 60.0).unwrap();
+        stop(handle).unwrap();
+    }
+
+    #[test]
+    fn test_active_displays() {
+        let displays = active_displays();
+        if let Some(main) = displays.first() {
+            assert_eq!(unsafe { CGMainDisplayID() }, *main);
+        }
+    }
+
+    #[test]
+    fn test_probe_assertions() {
+        assert_eq!(Ok(()), probe_assertions());
+    }
+
+    #[test]
+    fn test_system_sleep_minutes() {
+        // Every Mac has power management preferences
+        let minutes = system_sleep_minutes().unwrap();
+        assert!(minutes >= 0, "{}", minutes);
+    }
+}
@@ -0,0 +1,96 @@
+use dbus::arg::messageitem::MessageItem;
+use dbus::strings::{BusName, Interface, Member, Path};
+use dbus::Message;
+
+/// A D-Bus API that is not built in, see [`crate::NoSleep::with_custom_inhibit`].
+///
+/// The inhibit method is called with `inhibit_args` and must return a
+/// `u32` cookie. The uninhibit method is called with that cookie as its
+/// only argument, like the FreeDesktop APIs do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomInhibitSpec {
+    /// The service name, e.g. `org.mate.SessionManager`
+    pub destination: String,
+    /// The object path, e.g. `/org/mate/SessionManager`
+    pub path: String,
+    /// The interface of both methods
+    pub interface: String,
+    /// The method that acquires the lock
+    pub inhibit_method: String,
+    /// The method that releases the lock
+    pub uninhibit_method: String,
+    /// The arguments of the inhibit method, in order
+    pub inhibit_args: Vec<MessageItem>,
+}
+
+impl CustomInhibitSpec {
+    pub(crate) fn inhibit_msg(&self) -> Result<Message, String> {
+        let mut msg = self.method_call(&self.inhibit_method)?;
+        msg.append_items(&self.inhibit_args);
+        Ok(msg)
+    }
+
+    pub(crate) fn uninhibit_msg(&self, cookie: u32) -> Result<Message, String> {
+        Ok(self.method_call(&self.uninhibit_method)?.append1(cookie))
+    }
+
+    // The `&str` conversions of dbus panic on invalid names, these do not
+    fn method_call(&self, method: &str) -> Result<Message, String> {
+        Message::new_method_call(
+            BusName::new(self.destination.as_str())?,
+            Path::new(self.path.as_str())?,
+            Interface::new(self.interface.as_str())?,
+            Member::new(method)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mate() -> CustomInhibitSpec {
+        CustomInhibitSpec {
+            destination: "org.mate.SessionManager".to_string(),
+            path: "/org/mate/SessionManager".to_string(),
+            interface: "org.mate.SessionManager".to_string(),
+            inhibit_method: "Inhibit".to_string(),
+            uninhibit_method: "Uninhibit".to_string(),
+            inhibit_args: vec![
+                MessageItem::Str("org.powersaveblocker.app".to_string()),
+                MessageItem::UInt32(0),
+                MessageItem::Str("Power Save Blocker".to_string()),
+                MessageItem::UInt32(8),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_custom_inhibit_msg() {
+        let spec = mate();
+        let msg = spec.inhibit_msg().unwrap();
+        assert_eq!("org.mate.SessionManager", &*msg.destination().unwrap());
+        assert_eq!("/org/mate/SessionManager", &*msg.path().unwrap());
+        assert_eq!("org.mate.SessionManager", &*msg.interface().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!(spec.inhibit_args, msg.get_items());
+    }
+
+    #[test]
+    fn test_custom_uninhibit_msg() {
+        let msg = mate().uninhibit_msg(7).unwrap();
+        assert_eq!("org.mate.SessionManager", &*msg.destination().unwrap());
+        assert_eq!("Uninhibit", &*msg.member().unwrap());
+        assert_eq!(vec![MessageItem::UInt32(7)], msg.get_items());
+    }
+
+    #[test]
+    fn test_custom_inhibit_msg_invalid() {
+        let spec = CustomInhibitSpec {
+            path: "not a path".to_string(),
+            ..mate()
+        };
+        assert!(spec.inhibit_msg().is_err());
+        assert!(spec.uninhibit_msg(7).is_err());
+    }
+}
@@ -1,297 +1,4492 @@
 //! Wrapper utility to block and unblock the Linux power save mode.
 //! It uses either the org.gnome.SessionManager D-Bus or the
 //! org.freedesktop.PowerManagement API under the hood.
+//! Inside a Flatpak or Snap sandbox the org.freedesktop.portal.Inhibit
+//! portal is tried first. Inhibitors of systemd-logind (org.freedesktop.login1)
+//! on the system bus can be enabled with [`NoSleep::set_backend_order`].
+//! With the `x11` feature, display blocks fall back to suspending the
+//! X11 screensaver and DPMS when no D-Bus backend is available.
+//! With the `xdg-screensaver` feature, they fall back to running the
+//! `xdg-screensaver` script of xdg-utils on the window set with
+//! [`NoSleep::set_window_xid`], which has to be installed.
+//!
+//! Display blocks come in two strengths:
+//! - [`NoSleep::prevent_blanking`] keeps the screen from blanking and
+//!   locking through org.freedesktop.ScreenSaver. This works on X11 and
+//!   Wayland with any desktop that implements the API (GNOME, KDE, Xfce).
+//!   The desktop may still dim the screen before it would blank.
+//! - [`NoSleep::prevent_dimming`] additionally disables DPMS on the X
+//!   server, so the display stays at full brightness. This needs the `x11`
+//!   feature and an X11 session, it fails on Wayland.
+//!
+//! Applications with their own event loop, like GLib or calloop, can run
+//! the signal handling of [`NoSleep::on_revoked`] on it instead of on a
+//! background thread, see [`NoSleep::set_event_loop`]. The other D-Bus
+//! calls are made on the thread that calls NoSleep and wait for the reply.
 //!
 //! Heavily inspired on the Chromium source code:
 //! https://chromium.googlesource.com/chromium/src.git/+/refs/heads/main/services/device/wake_lock/power_save_blocker/power_save_blocker_linux.cc
 
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell};
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dbus::arg::{ArgType, OwnedFd, PropMap, TypeMismatchError, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
 use dbus::blocking::{BlockingSender, Connection};
-use nosleep_types::{NoSleepError, NoSleepTrait};
+use dbus::channel::BusType;
+use logind::{inhibitors_of, list_inhibitors_msg, logind_inhibit_msg};
+#[cfg(feature = "x11")]
+use nosleep_types::ActivityPoke;
+use nosleep_types::{
+    BackendProbe, BlockOutcome, BlockStats, BlockTracker, Capabilities, DisplayFallback, EnvConfig,
+    HandleInfo, HandleRegistry, HealthReport, NoSleepError, NoSleepObserver, NoSleepTrait,
+    Registered, StateEvent, StateEvents,
+};
+pub use nosleep_types::{BlockLevel, NoSleepType, SwitchPolicy};
+use retry::with_retry;
+use revoke_watch::{LoopRevokeWatch, RevokeWatch};
+use sleep_watch::{SleepCallback, SleepWatch};
+use timer::DeadlineBlock;
+
+mod custom;
+mod logind;
+mod pool;
+mod retry;
+mod revoke_watch;
+mod sleep_timeout;
+mod sleep_watch;
+mod threaded;
+mod timer;
+#[cfg(feature = "x11")]
+mod x11;
+#[cfg(feature = "xdg-screensaver")]
+mod xdg_screensaver;
 
+pub use custom::CustomInhibitSpec;
+pub use logind::{InhibitorInfo, LogindMode, LogindWhat};
+pub use retry::RetryPolicy;
+pub use threaded::ThreadedNoSleep;
+
+/// The types held as asked with the default backends, for static checks
+/// where [`NoSleepTrait::is_supported_type`] needs an instance.
+/// `PreventSystemSleep` is left out, it is held as
+/// `PreventUserIdleSystemSleep` and a sleep requested by the user still
+/// suspends.
+pub const SUPPORTED_TYPES: &[NoSleepType] = &[
+    NoSleepType::PreventUserIdleDisplaySleep,
+    NoSleepType::PreventUserIdleSystemSleep,
+    NoSleepType::PreventScreenSaver,
+];
+
+/// The D-Bus APIs that can hold a block, see [`NoSleep::set_backend_order`].
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum NoSleepType {
-    PreventUserIdleDisplaySleep,
-    PreventUserIdleSystemSleep,
+pub enum DBusAPI {
+    GnomeApi,                  // org.gnome.SessionManager
+    FreeDesktopPowerApi,       // org.freedesktop.PowerManagement
+    FreeDesktopScreenSaverAPI, // org.freedesktop.ScreenSaver
+    PortalApi,                 // org.freedesktop.portal.Inhibit
+    LoginApi,                  // org.freedesktop.login1
+    Custom,                    // see NoSleep::with_custom_inhibit
 }
 
-#[derive(Debug, Copy, Clone)]
-enum DBusAPI {
-    GnomeApi,                  // org.gnome.Sessionmanager
-    FreeDesktopPowerApi,       // org.freedesktop.PowerMansagement
-    FreeDesktopScreenSaverAPI, // org.freedesktop.ScreenSaver
+impl DBusAPI {
+    // The APIs called when `self` is tried as a backend
+    fn apis(&self, nosleep_type: NoSleepType) -> Vec<DBusAPI> {
+        match self {
+            // Needs the screensaver as well to keep the display on
+            DBusAPI::FreeDesktopPowerApi
+                if nosleep_type == NoSleepType::PreventUserIdleDisplaySleep =>
+            {
+                vec![
+                    DBusAPI::FreeDesktopScreenSaverAPI,
+                    DBusAPI::FreeDesktopPowerApi,
+                ]
+            }
+            api => vec![*api],
+        }
+    }
+
+    // The D-Bus service name
+    fn name(&self) -> &'static str {
+        match self {
+            DBusAPI::GnomeApi => "org.gnome.SessionManager",
+            DBusAPI::FreeDesktopPowerApi => "org.freedesktop.PowerManagement",
+            DBusAPI::FreeDesktopScreenSaverAPI => "org.freedesktop.ScreenSaver",
+            DBusAPI::PortalApi => "org.freedesktop.portal.Desktop",
+            DBusAPI::LoginApi => "org.freedesktop.login1",
+            DBusAPI::Custom => "custom",
+        }
+    }
 }
 
 // Inhibit flags defined in the org.gnome.SessionManager interface.
+#[allow(clippy::enum_variant_names)]
 enum GnomeAPIInhibitFlags {
+    InhibitLogout = 1,
     InhibitSuspendSession = 4,
     InhibitMarkSessionIdle = 8,
 }
 
+// Inhibit flags defined in the org.freedesktop.portal.Inhibit interface.
+enum PortalInhibitFlags {
+    Logout = 1,
+    Suspend = 4,
+    Idle = 8,
+}
+
+// Handle returned by the inhibit method
+#[derive(Debug, Clone, PartialEq)]
+enum InhibitHandle {
+    // Cookie to pass to the uninhibit method
+    Cookie(u32),
+    // Request object to close (org.freedesktop.portal.Inhibit)
+    Request(dbus::Path<'static>),
+    // File descriptor that holds the lock until closed (org.freedesktop.login1)
+    Fd(OwnedFd),
+    // The reply had no handle, the lock is held until the connection closes
+    Connection,
+}
+
 struct NoSleepHandle {
     // Handle to a locks being held
-    handle: u32,
+    handle: InhibitHandle,
     // The API used to acquire the lock
     api: DBusAPI,
+    // The type passed to the API
+    nosleep_type: NoSleepType,
+    // The application id passed instead of the instance's, see `prevent_sleep_as`
+    who: Option<String>,
 }
 
-pub struct NoSleep {
-    // Connection to the D-Bus
-    d_bus: Connection,
-
+// The state of an instance that `NoSleep::stop_all` needs
+#[derive(Default)]
+struct Tracked {
     // The handles to all the locks
-    no_sleep_handles: Vec<NoSleepHandle>,
+    handles: Vec<NoSleepHandle>,
+    // The calls made by `DBusAPI::Custom`
+    custom_inhibit: Option<CustomInhibitSpec>,
 }
 
-impl NoSleep {
-    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
-        // Clear any previous handles held
-        self.stop()?;
+// The configuration `clone_config` carries over to a new instance
+#[derive(Clone)]
+struct Config {
+    retry_policy: RetryPolicy,
+    backend_order: Vec<DBusAPI>,
+    logind_what: Option<LogindWhat>,
+    logind_mode: LogindMode,
+    window_xid: Option<u32>,
+    allow_suspend: bool,
+    custom_inhibit: Option<CustomInhibitSpec>,
+    honor_existing_policy: bool,
+    strict: bool,
+    reason: Cow<'static, str>,
+    app_id: String,
+    call_timeout: Duration,
+    switch_policy: SwitchPolicy,
+    event_loop: bool,
+    activity_poke: Option<Duration>,
+    display_fallback_threshold: Option<u32>,
+}
 
-        let response = self.inhibit(&DBusAPI::GnomeApi, &nosleep_type);
-        if let Ok(handle) = response {
-            self.no_sleep_handles = vec![handle];
-            return Ok(());
-        }
+impl Config {
+    fn connect(self) -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.retry_policy = self.retry_policy;
+        nosleep.backend_order = self.backend_order;
+        nosleep.logind_what = self.logind_what;
+        nosleep.logind_mode = self.logind_mode;
+        nosleep.window_xid = self.window_xid;
+        nosleep.allow_suspend = self.allow_suspend;
+        nosleep.tracked.lock().custom_inhibit = self.custom_inhibit;
+        nosleep.honor_existing_policy = self.honor_existing_policy;
+        nosleep.strict = self.strict;
+        nosleep.reason = self.reason;
+        nosleep.app_id = self.app_id;
+        nosleep.call_timeout = self.call_timeout;
+        nosleep.switch_policy = self.switch_policy;
+        nosleep.event_loop = self.event_loop;
+        nosleep.activity_poke = self.activity_poke;
+        nosleep.display_fallback = DisplayFallback::new(self.display_fallback_threshold);
+        Ok(nosleep)
+    }
+}
+
+// How long a D-Bus call to acquire or release a lock may take, by default
+const CALL_TIMEOUT: Duration = Duration::from_millis(5000);
+
+// How long `new` waits for the bus to answer
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+// How long `available` waits for the session bus to answer
+const AVAILABLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How often `wait_for_backend` looks up the bus names again
+const WAIT_FOR_BACKEND_INTERVAL: Duration = Duration::from_millis(100);
+
+// The locks of every live instance
+static REGISTRY: HandleRegistry<Tracked> = HandleRegistry::new();
+
+/// Unlike on macOS and Windows, `NoSleep` does not implement `Default`:
+/// [`NoSleep::new`] connects to the session bus, which can fail.
+pub struct NoSleep {
+    // Connection to the D-Bus, only taken on drop
+    d_bus: Option<Connection>,
+
+    // `d_bus` goes back to the connection pool of the thread on drop,
+    // if it holds no lock
+    pooled: Cell<bool>,
+
+    // `d_bus` tracks its file descriptor, see `connection_fd`
+    watched: bool,
+
+    // No session bus, `d_bus` is connected to the system bus instead
+    headless: bool,
+
+    // Connection to the system bus, only opened for logind
+    system_bus: OnceCell<Connection>,
+
+    // `d_bus` holds locks without a handle, which only closing it
+    // releases, see `reconnect`
+    stray_locks: Cell<bool>,
+
+    // Same as `stray_locks`, for `system_bus`
+    stray_system_locks: Cell<bool>,
+
+    // The handles to all the locks and how to release them
+    tracked: Registered<Tracked>,
+
+    // The types of the blocks currently held
+    nosleep_types: Vec<NoSleepType>,
+
+    // The types of the blocks released by `suspend`
+    suspended: Vec<NoSleepType>,
+
+    // How long blocks were held
+    stats: BlockTracker,
+
+    // The subscribers of `subscribe`
+    events: StateEvents,
+
+    // Block held until a deadline
+    timed_block: Option<DeadlineBlock>,
+
+    // Retries of the D-Bus calls on transient errors
+    retry_policy: RetryPolicy,
+
+    // The backends tried by `prevent_sleep`, in order
+    backend_order: Vec<DBusAPI>,
+
+    // The backend of `probe_and_select`, tried alone until it fails
+    selected_backend: Cell<Option<DBusAPI>>,
+
+    // Overrides what logind inhibits, derived from the type by default
+    logind_what: Option<LogindWhat>,
+
+    // Whether logind blocks or delays the inhibited operations
+    logind_mode: LogindMode,
+
+    // The level of the block held, see `prevent_sleep_with_level`
+    level: BlockLevel,
+
+    // Who the block held is attributed to, see `prevent_sleep_as`
+    who: Option<String>,
+
+    // X11 window the blocks are scoped to
+    window_xid: Option<u32>,
+
+    // Display blocks on Gnome leave explicit suspend allowed
+    allow_suspend: bool,
+
+    // A policy denial stops trying the other backends
+    honor_existing_policy: bool,
+
+    // Blocks that no backend can hold are unsupported instead of aliased
+    strict: bool,
+
+    // Human readable reason passed to the session manager
+    reason: Cow<'static, str>,
+
+    // Application identifier passed to the session manager
+    app_id: String,
+
+    // How long a D-Bus call to acquire or release a lock may take
+    call_timeout: Duration,
+
+    // What `prevent_sleep` does when a block is already held
+    switch_policy: SwitchPolicy,
+
+    // The display blocks that failed in a row, see `prevent_display_sleep_or_degrade`
+    display_fallback: DisplayFallback,
+
+    // Display block held through the X server, if all D-Bus backends failed
+    #[cfg(feature = "x11")]
+    x11_block: Option<x11::X11Block>,
+
+    // How often display blocks reset the X screensaver, see `set_activity_poke`
+    activity_poke: Option<Duration>,
 
-        // Try again using the FreeDesktopPowerApi for which we need two calls
-        let mut handles: Vec<NoSleepHandle> = vec![];
-        if nosleep_type == NoSleepType::PreventUserIdleDisplaySleep {
-            let handle = self.inhibit(
-                &DBusAPI::FreeDesktopScreenSaverAPI,
-                &NoSleepType::PreventUserIdleDisplaySleep,
-            )?;
-            handles.push(handle);
+    // Resets the X screensaver while a display block is held
+    #[cfg(feature = "x11")]
+    poke: Option<ActivityPoke>,
+
+    // Display block held through `xdg-screensaver`, if X11 failed as well
+    #[cfg(feature = "xdg-screensaver")]
+    xdg_block: Option<xdg_screensaver::XdgScreensaverBlock>,
+
+    // Runs a callback before the system sleeps, independent of the block
+    sleep_delay: Option<SleepWatch>,
+
+    // The logind inhibitor of `prevent_lid_close_suspend`, apart from the blocks
+    lid_switch: Option<OwnedFd>,
+
+    // Calls back when the system resumes, until `stop`
+    resume_watch: Option<SleepWatch>,
+
+    // Set by `reacquire_watch` on resume, until `dispatch` checks the locks
+    resumed: Arc<AtomicBool>,
+
+    // Notes resumes for `set_reacquire_on_resume`
+    reacquire_watch: Option<SleepWatch>,
+
+    // Calls back when a service drops the locks, until `stop`
+    revoke_watch: Option<RevokeWatch>,
+
+    // Same as `revoke_watch`, on `d_bus` through `dispatch`
+    loop_revoke_watch: Option<LoopRevokeWatch>,
+
+    // The application calls `dispatch` from its own event loop
+    event_loop: bool,
+}
+
+impl Drop for NoSleep {
+    fn drop(&mut self) {
+        // Closing the connection releases the locks without a blocking call,
+        // only one that holds none and has no match rules is reused
+        let idle = self.pooled.get()
+            && !self.stray_locks.get()
+            && self.loop_revoke_watch.is_none()
+            && self.tracked.lock().handles.is_empty();
+        if let Some(d_bus) = self.d_bus.take().filter(|_| idle) {
+            pool::recycle(d_bus);
         }
-        // Prevent suspension
-        let handle = self.inhibit(&DBusAPI::FreeDesktopPowerApi, &nosleep_type)?;
-        handles.push(handle);
-        self.no_sleep_handles = handles;
-        Ok(())
+        self.events.update(None);
     }
+}
 
-    fn inhibit(
-        &self,
-        api: &DBusAPI,
-        nosleep_type: &NoSleepType,
-    ) -> Result<NoSleepHandle, NoSleepError> {
-        let msg = inhibit_msg(api, nosleep_type);
-        let response = self
-            .d_bus
-            .send_with_reply_and_block(msg, std::time::Duration::from_millis(5000))
-            .map_err(|e| NoSleepError::PreventSleep {
-                reason: e.to_string(),
-            })?;
-        match response.get1::<u32>() {
-            Some(handle) => Ok(NoSleepHandle { handle, api: *api }),
-            None => Err(NoSleepError::PreventSleep {
-                reason: "Invalid message or type".to_string(),
-            }),
-        }
+// The D-Bus name of the service behind `api`
+fn service_name<'a>(api: &DBusAPI, spec: Option<&'a CustomInhibitSpec>) -> &'a str {
+    match (api, spec) {
+        (DBusAPI::Custom, Some(spec)) => &spec.destination,
+        (api, _) => api.name(),
     }
 }
 
-impl NoSleepTrait for NoSleep {
-    /// Creates a new NoSleep type and connects to the D-Bus.
-    /// The session is automatically closed when the instance is dropped.
-    fn new() -> Result<NoSleep, NoSleepError> {
-        Ok(NoSleep {
-            d_bus: Connection::new_session().map_err(|e| NoSleepError::Init {
-                reason: e.to_string(),
-            })?,
-            no_sleep_handles: vec![],
-        })
+// Gnome first, then the FreeDesktop APIs.
+// Inside a sandbox the other APIs are usually filtered by the bus proxy,
+// so the portal is tried first.
+fn default_backend_order() -> Vec<DBusAPI> {
+    let mut order = vec![DBusAPI::GnomeApi, DBusAPI::FreeDesktopPowerApi];
+    if is_sandboxed() {
+        order.insert(0, DBusAPI::PortalApi);
     }
+    order
+}
 
-    fn prevent_display_sleep(&mut self) -> Result<(), NoSleepError> {
-        self.prevent_sleep(NoSleepType::PreventUserIdleDisplaySleep)
+impl NoSleep {
+    /// Creates a new NoSleep type reusing an existing session bus connection,
+    /// instead of opening a new one.
+    ///
+    /// The connection is owned by NoSleep because the session manager
+    /// releases all locks when the connection that acquired them closes.
+    /// It stays available through [`NoSleep::connection`] for other calls,
+    /// but is closed once NoSleep is dropped.
+    pub fn new_with_connection(d_bus: Connection) -> NoSleep {
+        NoSleep {
+            d_bus: Some(d_bus),
+            pooled: Cell::new(false),
+            watched: false,
+            headless: false,
+            system_bus: OnceCell::new(),
+            stray_locks: Cell::new(false),
+            stray_system_locks: Cell::new(false),
+            tracked: REGISTRY.register(Tracked::default()),
+            nosleep_types: vec![],
+            suspended: vec![],
+            timed_block: None,
+            stats: BlockTracker::default(),
+            events: StateEvents::default(),
+            retry_policy: RetryPolicy::default(),
+            backend_order: default_backend_order(),
+            selected_backend: Cell::new(None),
+            logind_what: None,
+            logind_mode: LogindMode::default(),
+            level: BlockLevel::default(),
+            who: None,
+            window_xid: None,
+            allow_suspend: false,
+            honor_existing_policy: false,
+            strict: false,
+            reason: Cow::Borrowed(DEFAULT_REASON),
+            app_id: DEFAULT_APP_ID.to_string(),
+            call_timeout: CALL_TIMEOUT,
+            switch_policy: SwitchPolicy::default(),
+            display_fallback: DisplayFallback::default(),
+            #[cfg(feature = "x11")]
+            x11_block: None,
+            activity_poke: None,
+            #[cfg(feature = "x11")]
+            poke: None,
+            #[cfg(feature = "xdg-screensaver")]
+            xdg_block: None,
+            sleep_delay: None,
+            lid_switch: None,
+            resume_watch: None,
+            resumed: Arc::default(),
+            reacquire_watch: None,
+            revoke_watch: None,
+            loop_revoke_watch: None,
+            event_loop: false,
+        }
     }
 
-    fn prevent_system_sleep(&mut self) -> Result<(), NoSleepError> {
-        self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)
+    /// Creates a new NoSleep type like [`NoSleepTrait::new`], which fails
+    /// with [`NoSleepError::Init`] instead of hanging if the bus does not
+    /// answer within `timeout`, for example when its daemon is stuck.
+    /// Without a session bus, the system bus gets `timeout` as well.
+    ///
+    /// The handshake runs on a helper thread that is left behind when the
+    /// bus does not answer, until the bus answers or closes. While four
+    /// of them are left, connecting fails right away.
+    pub fn with_connect_timeout(timeout: Duration) -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::connect(pool::session_bus(timeout), || {
+            pool::open(BusType::System, timeout)
+        })?;
+        nosleep.pooled.set(!nosleep.headless);
+        nosleep.watched = true;
+        Ok(nosleep)
     }
 
-    fn stop(&mut self) -> Result<(), NoSleepError> {
-        for handle in &self.no_sleep_handles {
-            let msg = uninhibit_msg(&handle.api, handle.handle);
-            self.d_bus
-                .send_with_reply_and_block(msg, std::time::Duration::from_millis(5000))
-                .map_err(|e| NoSleepError::StopLock {
-                    reason: e.to_string(),
-                })?;
+    /// Creates a new NoSleep type that tries the backends in `order`,
+    /// see [`NoSleep::set_backend_order`].
+    pub fn with_backend_order(order: &[DBusAPI]) -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.set_backend_order(order);
+        Ok(nosleep)
+    }
+
+    /// Creates a new NoSleep type that only holds blocks through the D-Bus
+    /// API described by `spec`, for desktops without a built-in backend.
+    /// The same call is made for every type of block. [`DBusAPI::Custom`]
+    /// can be combined with the other backends with [`NoSleep::set_backend_order`].
+    pub fn with_custom_inhibit(spec: CustomInhibitSpec) -> Result<NoSleep, NoSleepError> {
+        // Report invalid names now instead of on every call
+        spec.inhibit_msg()
+            .and(spec.uninhibit_msg(0))
+            .map_err(|reason| NoSleepError::Init { reason, code: None })?;
+        let mut nosleep = NoSleep::new()?;
+        nosleep.tracked.lock().custom_inhibit = Some(spec);
+        nosleep.backend_order = vec![DBusAPI::Custom];
+        Ok(nosleep)
+    }
+
+    /// Creates a new NoSleep type in strict mode, see [`NoSleep::set_strict`].
+    pub fn strict() -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.set_strict(true);
+        Ok(nosleep)
+    }
+
+    /// Creates a new, unblocked NoSleep type with its own connection,
+    /// carrying the same configuration as `self`.
+    pub fn clone_config(&self) -> Result<NoSleep, NoSleepError> {
+        self.config().connect()
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            retry_policy: self.retry_policy,
+            backend_order: self.backend_order.clone(),
+            logind_what: self.logind_what,
+            logind_mode: self.logind_mode,
+            window_xid: self.window_xid,
+            allow_suspend: self.allow_suspend,
+            custom_inhibit: self.tracked.lock().custom_inhibit.clone(),
+            honor_existing_policy: self.honor_existing_policy,
+            strict: self.strict,
+            reason: self.reason.clone(),
+            app_id: self.app_id.clone(),
+            call_timeout: self.call_timeout,
+            switch_policy: self.switch_policy,
+            event_loop: self.event_loop,
+            activity_poke: self.activity_poke,
+            display_fallback_threshold: self.display_fallback.threshold(),
+        }
+    }
+
+    /// Creates a new NoSleep type configured by environment variables,
+    /// for deployments that cannot change code. Unset variables keep the
+    /// defaults, invalid values are an `Init` error.
+    ///
+    /// - `NOSLEEP_BACKEND`: the backends to try in order, comma separated,
+    ///   out of `gnome`, `freedesktop`, `screensaver`, `portal` and `logind`,
+    ///   or their D-Bus service names, see [`NoSleep::set_backend_order`].
+    ///   Without a session bus only `logind` is used, so an order without
+    ///   it is an `Init` error
+    /// - `NOSLEEP_REASON`: see [`NoSleepTrait::set_reason`]
+    /// - `NOSLEEP_APP_ID`: see [`NoSleep::set_app_id`]
+    /// - `NOSLEEP_DBUS_TIMEOUT_MS`: see [`NoSleep::set_call_timeout`]
+    pub fn from_env() -> Result<NoSleep, NoSleepError> {
+        let config = EnvConfig::read()?;
+        let order = match &config.backends {
+            Some(backends) => Some(
+                backends
+                    .iter()
+                    .map(|name| {
+                        backend_from_name(name).ok_or_else(|| NoSleepError::Init {
+                            reason: format!("Unknown NOSLEEP_BACKEND: {}", name),
+                            code: None,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        let mut nosleep = NoSleep::new()?;
+        if let Some(order) = order {
+            nosleep.set_env_backend_order(&order)?;
+        }
+        if let Some(reason) = config.reason {
+            nosleep.reason = reason.into();
+        }
+        if let Some(app_id) = config.app_id {
+            nosleep.set_app_id(&app_id);
+        }
+        if let Some(timeout) = config.dbus_timeout {
+            nosleep.set_call_timeout(timeout);
+        }
+        Ok(nosleep)
+    }
+
+    // Sets the order of `NOSLEEP_BACKEND`. Without a session bus only logind
+    // can be used, which stays the only backend if the order has it.
+    fn set_env_backend_order(&mut self, order: &[DBusAPI]) -> Result<(), NoSleepError> {
+        if !self.headless {
+            self.set_backend_order(order);
+            return Ok(());
+        }
+        if !order.contains(&DBusAPI::LoginApi) {
+            return Err(NoSleepError::Init {
+                reason: "NOSLEEP_BACKEND has no backend usable without a session bus, \
+                         only logind is"
+                    .to_string(),
+                code: None,
+            });
         }
         Ok(())
     }
-}
 
-fn inhibit_msg(api: &DBusAPI, nosleep_type: &NoSleepType) -> dbus::Message {
-    match api {
-        DBusAPI::GnomeApi => {
-            // Arguments are
-            // app_id:       application identifier
-            // toplevel_xid: toplevel x window identifier
-            // reason:       human readable reason
-            // flags:        flags that specify what should be inhibited
-            let flags = match nosleep_type {
-                NoSleepType::PreventUserIdleDisplaySleep => {
-                    GnomeAPIInhibitFlags::InhibitMarkSessionIdle as u32
-                        | GnomeAPIInhibitFlags::InhibitSuspendSession as u32
+    /// Releases the locks of every live NoSleep in this process, best effort.
+    /// The release calls are made on a connection of this thread, so services
+    /// that only accept them from the connection that acquired the lock keep
+    /// it until that instance is dropped. Timed blocks and X11 blocks are not
+    /// released. Continues past failures and returns the first error.
+    pub fn stop_all() -> Result<(), NoSleepError> {
+        NoSleep::release_all(REGISTRY.live())
+    }
+
+    fn release_all(live: Vec<Registered<Tracked>>) -> Result<(), NoSleepError> {
+        let mut d_bus = None;
+        let mut result = Ok(());
+        for tracked in live {
+            let (handles, spec) = {
+                let mut tracked = tracked.lock();
+                let handles = std::mem::take(&mut tracked.handles);
+                (handles, tracked.custom_inhibit.clone())
+            };
+            for handle in handles {
+                let released = release_msg(&handle, spec.as_ref()).and_then(|msg| {
+                    let Some(msg) = msg else {
+                        // Dropping the file descriptor releases the lock
+                        return Ok(());
+                    };
+                    if d_bus.is_none() {
+                        d_bus = Some(pool::session_bus(CONNECT_TIMEOUT)?);
+                    }
+                    d_bus
+                        .as_ref()
+                        .unwrap()
+                        .send_with_reply_and_block(msg, CALL_TIMEOUT)
+                        .map(|_| ())
+                });
+                match released {
+                    Err(e) if !is_released(&handle, &e) => {
+                        result = result.and(Err(NoSleepError::StopLock {
+                            reason: e.to_string(),
+                            code: None,
+                        }));
+                    }
+                    _ => {}
                 }
-                NoSleepType::PreventUserIdleSystemSleep => {
-                    GnomeAPIInhibitFlags::InhibitSuspendSession as u32
+            }
+        }
+        if let Some(d_bus) = d_bus {
+            pool::recycle(d_bus);
+        }
+        result
+    }
+
+    /// Looks up which D-Bus services are running or activatable, without
+    /// acquiring a block. Meant for bug reports, see [`HealthReport`].
+    /// The selected backend is the first available one of the backend order.
+    pub fn health_check(&self) -> HealthReport {
+        let session_names = if self.headless {
+            Err("no session bus".to_string())
+        } else {
+            bus_names(self.connection()).map_err(|e| e.to_string())
+        };
+        let system_names = if self.headless {
+            bus_names(self.connection())
+        } else {
+            pool::open(BusType::System, CONNECT_TIMEOUT)
+                .and_then(|system_bus| bus_names(&system_bus))
+        }
+        .map_err(|e| e.to_string());
+
+        let custom_inhibit = self.tracked.lock().custom_inhibit.clone();
+        let service = |api: &DBusAPI| service_name(api, custom_inhibit.as_ref()).to_string();
+        let mut apis = vec![
+            DBusAPI::GnomeApi,
+            DBusAPI::FreeDesktopPowerApi,
+            DBusAPI::FreeDesktopScreenSaverAPI,
+            DBusAPI::PortalApi,
+            DBusAPI::LoginApi,
+        ];
+        if custom_inhibit.is_some() {
+            apis.push(DBusAPI::Custom);
+        }
+        let probes = apis
+            .iter()
+            .map(|api| {
+                let name = service(api);
+                let names = match api {
+                    DBusAPI::LoginApi => &system_names,
+                    _ => &session_names,
+                };
+                match names {
+                    Ok(names) => BackendProbe {
+                        available: names.contains(&name),
+                        name,
+                        error: None,
+                    },
+                    Err(e) => BackendProbe {
+                        name,
+                        available: false,
+                        error: Some(e.clone()),
+                    },
                 }
-            };
-            dbus::Message::call_with_args(
-                "org.gnome.SessionManager",
-                "/org/gnome/SessionManager",
-                "org.gnome.SessionManager",
-                "Inhibit",
-                (
-                    "org.powersaveblocker.app",
-                    0u32,
-                    "Power Save Blocker",
-                    flags,
-                ),
-            )
+            })
+            .collect::<Vec<_>>();
+        let selected = self.backend_order.iter().map(service).find(|name| {
+            probes
+                .iter()
+                .any(|probe| probe.available && probe.name == *name)
+        });
+
+        HealthReport {
+            platform: format!(
+                "linux (nosleep-nix {}){}",
+                env!("CARGO_PKG_VERSION"),
+                if self.headless { ", headless" } else { "" }
+            ),
+            backends: probes,
+            selected,
+            reason: self.reason.to_string(),
+            app_id: Some(self.app_id.clone()),
+            timeout: Some(self.call_timeout),
         }
-        // The arguments of the method are:
-        //  app_id: The application identifier
-        //  reason: The reason for the inhibit
-        DBusAPI::FreeDesktopPowerApi => dbus::Message::call_with_args(
-            "org.freedesktop.PowerManagement",
-            "/org/freedesktop/PowerManagement/Inhibit",
-            "org.freedesktop.PowerManagement.Inhibit",
-            "Inhibit",
-            ("org.powersaveblocker.app", "Power Save Blocker"),
-        ),
-        DBusAPI::FreeDesktopScreenSaverAPI => dbus::Message::call_with_args(
-            "org.freedesktop.ScreenSaver",
-            "/org/freedesktop/ScreenSaver",
-            "org.freedesktop.ScreenSaver",
-            "Inhibit",
-            ("org.powersaveblocker.app", "Power Save Blocker"),
-        ),
     }
-}
 
-fn uninhibit_msg(api: &DBusAPI, handle: u32) -> dbus::Message {
-    match api {
-        DBusAPI::GnomeApi => {
-            // Arguments are
-            // handle:       lock from the inhibit method
-            dbus::Message::call_with_args(
-                "org.gnome.SessionManager",
-                "/org/gnome/SessionManager",
-                "org.gnome.SessionManager",
-                "Uninhibit",
-                (handle,),
-            )
+    /// Lists the logind inhibitors taken for the application id of this
+    /// instance, or for who it holds a block for with `prevent_sleep_as`,
+    /// for example to warn about ones left behind by a hung earlier run.
+    /// Inhibitors of this process are included, compare their `pid` with
+    /// [`std::process::id`]. Empty when logind cannot be reached.
+    pub fn find_own_inhibitors(&self) -> Vec<InhibitorInfo> {
+        let mut who = vec![self.app_id.as_str()];
+        who.extend(self.who.as_deref());
+        self.bus(&DBusAPI::LoginApi)
+            .ok()
+            .and_then(|bus| {
+                bus.send_with_reply_and_block(list_inhibitors_msg(), self.call_timeout)
+                    .ok()
+            })
+            .and_then(|reply| inhibitors_of(&reply, &who).ok())
+            .unwrap_or_default()
+    }
+
+    /// Sets the backends tried by `prevent_sleep`, until one succeeds.
+    /// [`DBusAPI::FreeDesktopPowerApi`] also inhibits the screensaver
+    /// when the display should stay on.
+    /// Defaults to Gnome followed by FreeDesktop, with the portal first
+    /// when running in a Flatpak or Snap sandbox.
+    pub fn set_backend_order(&mut self, order: &[DBusAPI]) {
+        self.backend_order = order.to_vec();
+        self.selected_backend.set(None);
+    }
+
+    /// Looks up the first available backend of the backend order, like
+    /// [`NoSleep::health_check`], and acquires blocks through it only,
+    /// without trying the backends before it every time. If it fails, the
+    /// whole backend order is tried again and the backend that succeeds
+    /// is selected instead. Setting the backend order clears the selection.
+    pub fn probe_and_select(&mut self) -> Result<DBusAPI, NoSleepError> {
+        let backend = self.available_backend().ok_or_else(|| NoSleepError::Init {
+            reason: "No backend of the backend order is available".to_string(),
+            code: None,
+        })?;
+        self.selected_backend.set(Some(backend));
+        Ok(backend)
+    }
+
+    /// Waits until a backend of the backend order is running or can be
+    /// activated, and returns the first one, like [`NoSleep::probe_and_select`]
+    /// without selecting it. For applications that start at boot or early
+    /// in the login, before the session manager registered on the bus.
+    ///
+    /// The bus names are looked up every 100 ms until `timeout` elapses,
+    /// then the error is [`NoSleepError::Init`].
+    pub fn wait_for_backend(&self, timeout: Duration) -> Result<DBusAPI, NoSleepError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(backend) = self.available_backend() {
+                return Ok(backend);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(NoSleepError::Init {
+                    reason: format!(
+                        "No backend of the backend order became available within {:?}",
+                        timeout
+                    ),
+                    code: None,
+                });
+            }
+            std::thread::sleep(WAIT_FOR_BACKEND_INTERVAL.min(deadline - now));
         }
-        DBusAPI::FreeDesktopPowerApi => dbus::Message::call_with_args(
-            "org.freedesktop.PowerManagement",
-            "/org/freedesktop/PowerManagement/Inhibit",
-            "org.freedesktop.PowerManagement.Inhibit",
-            "UnInhibit",
-            (handle,),
-        ),
-        DBusAPI::FreeDesktopScreenSaverAPI => dbus::Message::call_with_args(
-            "org.freedesktop.ScreenSaver",
-            "/org/freedesktop/ScreenSaver",
-            "org.freedesktop.ScreenSaver",
-            "UnInhibit",
-            (handle,),
-        ),
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::*;
+    // The first backend of the backend order that `health_check` found
+    fn available_backend(&self) -> Option<DBusAPI> {
+        let selected = self.health_check().selected;
+        let spec = self.tracked.lock().custom_inhibit.clone();
+        self.backend_order
+            .iter()
+            .copied()
+            .find(|api| selected.as_deref() == Some(service_name(api, spec.as_ref())))
+    }
 
-    #[test]
-    fn test_inhibit_gnome_api_message_prevent_display_sleep() {
-        let msg = inhibit_msg(
-            &DBusAPI::GnomeApi,
-            &NoSleepType::PreventUserIdleDisplaySleep,
-        );
-        assert_eq!("/org/gnome/SessionManager", &*msg.path().unwrap());
-        assert_eq!("org.gnome.SessionManager", &*msg.interface().unwrap());
-        assert_eq!("Inhibit", &*msg.member().unwrap());
-        assert_eq!("org.gnome.SessionManager", &*msg.destination().unwrap());
-        assert_eq!(12, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    /// The backend selected by [`NoSleep::probe_and_select`], if any.
+    pub fn active_backend(&self) -> Option<DBusAPI> {
+        self.selected_backend.get()
     }
 
-    #[test]
-    fn test_inhibit_gnome_api_message_prevent_system_sleep() {
-        let msg = inhibit_msg(&DBusAPI::GnomeApi, &NoSleepType::PreventUserIdleSystemSleep);
-        assert_eq!("/org/gnome/SessionManager", &*msg.path().unwrap());
-        assert_eq!("org.gnome.SessionManager", &*msg.interface().unwrap());
-        assert_eq!("Inhibit", &*msg.member().unwrap());
-        assert_eq!("org.gnome.SessionManager", &*msg.destination().unwrap());
-        assert_eq!(4, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    /// Sets what the logind backend inhibits, instead of deriving it
+    /// from the type of block. For example, add [`LogindWhat::HANDLE_LID_SWITCH`]
+    /// to keep running when the laptop lid is closed.
+    pub fn set_logind_what(&mut self, what: LogindWhat) {
+        self.logind_what = Some(what);
     }
 
-    #[test]
-    fn test_uninhibit_gnome_api() {
-        let msg = uninhibit_msg(&DBusAPI::GnomeApi, 0);
-        assert_eq!("/org/gnome/SessionManager", &*msg.path().unwrap());
-        assert_eq!("org.gnome.SessionManager", &*msg.interface().unwrap());
-        assert_eq!("Uninhibit", &*msg.member().unwrap());
-        assert_eq!("org.gnome.SessionManager", &*msg.destination().unwrap());
-        assert_eq!(0, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    /// Sets whether the logind backend blocks or only delays the inhibited
+    /// operations, see [`LogindMode`]. Defaults to [`LogindMode::Block`].
+    pub fn set_logind_mode(&mut self, mode: LogindMode) {
+        self.logind_mode = mode;
     }
 
-    #[test]
-    fn test_inhibit_freedesktop_screen_saver_api() {
-        let msg = inhibit_msg(
-            &DBusAPI::FreeDesktopScreenSaverAPI,
-            &NoSleepType::PreventUserIdleDisplaySleep,
-        );
-        assert_eq!("/org/freedesktop/ScreenSaver", &*msg.path().unwrap());
-        assert_eq!("org.freedesktop.ScreenSaver", &*msg.interface().unwrap());
-        assert_eq!("Inhibit", &*msg.member().unwrap());
-        assert_eq!("org.freedesktop.ScreenSaver", &*msg.destination().unwrap());
+    /// Scopes the blocks acquired from now on to an X11 window, so the
+    /// session manager can attribute them to it. Only Gnome and the portal
+    /// support this, the other APIs ignore it. The `xdg-screensaver`
+    /// fallback needs it.
+    pub fn set_window_xid(&mut self, xid: Option<u32>) {
+        self.window_xid = xid;
     }
 
-    #[test]
-    fn test_uninhibit_freedesktop_screen_saver_api() {
-        let msg = uninhibit_msg(&DBusAPI::FreeDesktopScreenSaverAPI, 0);
-        assert_eq!("/org/freedesktop/ScreenSaver", &*msg.path().unwrap());
-        assert_eq!("org.freedesktop.ScreenSaver", &*msg.interface().unwrap());
-        assert_eq!("UnInhibit", &*msg.member().unwrap());
-        assert_eq!("org.freedesktop.ScreenSaver", &*msg.destination().unwrap());
-        assert_eq!(0, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    /// Lets display blocks acquired from now on through Gnome only keep
+    /// the session from idling, while still allowing explicit suspend.
+    /// For example, a presentation that should not stop the user from
+    /// suspending the laptop. Defaults to `false`, which inhibits both.
+    pub fn set_allow_suspend(&mut self, allow: bool) {
+        self.allow_suspend = allow;
     }
 
-    #[test]
-    fn test_freedesktop_power_api() {
-        let msg = inhibit_msg(
-            &DBusAPI::FreeDesktopPowerApi,
-            &NoSleepType::PreventUserIdleSystemSleep,
-        );
-        assert_eq!(
-            "/org/freedesktop/PowerManagement/Inhibit",
-            &*msg.path().unwrap()
+    /// Stops at the first backend that refuses a block on purpose, for
+    /// example through a polkit rule, instead of trying the next backends
+    /// and the X11 fallback. The error is then [`NoSleepError::PolicyDenied`].
+    /// Defaults to `false`, but a denial is still reported as
+    /// `PolicyDenied` if no other backend succeeds.
+    pub fn set_honor_existing_policy(&mut self, honor: bool) {
+        self.honor_existing_policy = honor;
+    }
+
+    /// Fails system blocks with [`NoSleepError::Unsupported`] instead of
+    /// holding them through the screensaver API, which only keeps the
+    /// display on. Defaults to `false`, which aliases them to a display block.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    // Whether `backend` would hold a block of `nosleep_type` as another type
+    fn aliases(&self, backend: &DBusAPI, nosleep_type: &NoSleepType) -> bool {
+        self.strict
+            && *backend == DBusAPI::FreeDesktopScreenSaverAPI
+            && matches!(
+                nosleep_type,
+                NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep
+            )
+    }
+
+    // Whether `e` ends the fallback to other backends
+    fn honors_denial(&self, e: &NoSleepError) -> bool {
+        self.honor_existing_policy && matches!(e, NoSleepError::PolicyDenied { .. })
+    }
+
+    /// Sets what `prevent_sleep` does when a block of a different type
+    /// is already held. Defaults to [`SwitchPolicy::Replace`].
+    pub fn set_switch_policy(&mut self, policy: SwitchPolicy) {
+        self.switch_policy = policy;
+    }
+
+    /// Sets how many calls in a row of `prevent_display_sleep_or_degrade`
+    /// may fail to hold the display block before they hold a system block
+    /// instead. `None`, the default, never degrades.
+    pub fn set_display_fallback_threshold(&mut self, threshold: Option<u32>) {
+        self.display_fallback.set_threshold(threshold);
+    }
+
+    /// Sets how often the D-Bus calls are retried on transient errors.
+    /// By default every call is made only once.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Sets the application identifier passed along with the locks, which
+    /// some session managers show next to the reason. Defaults to
+    /// `org.powersaveblocker.app`. Applies to locks acquired afterwards.
+    pub fn set_app_id(&mut self, app_id: &str) {
+        self.app_id = app_id.to_string();
+    }
+
+    /// Sets how long a D-Bus call to acquire or release a lock may take
+    /// before it fails. Defaults to 5 seconds.
+    pub fn set_call_timeout(&mut self, timeout: Duration) {
+        self.call_timeout = timeout;
+    }
+
+    /// Resets the screensaver timer of the X server every `interval` while
+    /// a display block is held, like `XResetScreenSaver`, for environments
+    /// that only respect user activity and not inhibitors. The pokes are
+    /// best effort and do nothing without an X server, for example on
+    /// Wayland. `None`, the default, turns it off.
+    ///
+    /// Applies to blocks acquired afterwards, except for
+    /// `prevent_display_sleep_until`. `stop` and `suspend` end the pokes.
+    /// Without the `x11` feature the error is [`NoSleepError::Unsupported`].
+    pub fn set_activity_poke(&mut self, interval: Option<Duration>) -> Result<(), NoSleepError> {
+        if cfg!(not(feature = "x11")) && interval.is_some() {
+            return Err(NoSleepError::Unsupported {
+                operation: "set_activity_poke without the x11 feature".to_string(),
+            });
+        }
+        self.activity_poke = interval;
+        Ok(())
+    }
+
+    /// Acquires the locks of the block again after the system resumes, for
+    /// services that drop their inhibitors across a suspend. Resumes are
+    /// watched like in `on_resume`, on a background thread.
+    ///
+    /// The locks are bound to the connection of this instance, which only
+    /// the thread owning NoSleep uses, so the watch merely notes the
+    /// resume: the next call to [`NoSleep::dispatch`] asks the services
+    /// whether the locks are still held, and acquires the ones that are
+    /// gone again on this connection. logind lists the inhibitors of the
+    /// process, Gnome and org.freedesktop.PowerManagement only tell if
+    /// anything inhibits, the locks of other services are assumed to
+    /// survive. A block released while the system sleeps is not acquired
+    /// again, neither are timed blocks. Defaults to `false`.
+    pub fn set_reacquire_on_resume(&mut self, enabled: bool) -> Result<(), NoSleepError> {
+        self.reacquire_watch = None;
+        self.resumed.store(false, Ordering::Relaxed);
+        if enabled {
+            self.reacquire_watch = Some(SleepWatch::on_resume(self.reacquire_callback())?);
+        }
+        Ok(())
+    }
+
+    // Notes every resume for `reacquire_lost`
+    fn reacquire_callback(&self) -> SleepCallback {
+        let resumed = self.resumed.clone();
+        Arc::new(move || resumed.store(true, Ordering::Relaxed))
+    }
+
+    // Acquires the locks again that the services dropped since the last
+    // resume, see `set_reacquire_on_resume`
+    fn reacquire_lost(&mut self) -> Result<(), NoSleepError> {
+        if !self.resumed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let handles = std::mem::take(&mut self.tracked.lock().handles);
+        let mut result = Ok(());
+        let mut held = Vec::with_capacity(handles.len());
+        for handle in handles {
+            if self.lock_exists(&handle) {
+                held.push(handle);
+                continue;
+            }
+            match self.inhibit(&handle.api, &handle.nosleep_type, handle.who.as_deref()) {
+                Ok(reacquired) => held.push(reacquired),
+                Err(e) => {
+                    // Kept, so the block is still reported as held
+                    held.push(handle);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        self.tracked.lock().handles = held;
+        result
+    }
+
+    // Asks the service of `handle` whether its lock is still held.
+    // A service that does not answer lost it, for example as it restarted.
+    fn lock_exists(&self, handle: &NoSleepHandle) -> bool {
+        let Some(msg) = lock_query_msg(handle) else {
+            return true;
+        };
+        let who = handle.who.as_deref().unwrap_or(&self.app_id);
+        self.bus(&handle.api)
+            .ok()
+            .and_then(|bus| bus.send_with_reply_and_block(msg, self.call_timeout).ok())
+            .and_then(|reply| lock_held(handle, &reply, who).ok())
+            .unwrap_or(false)
+    }
+
+    /// Handles the signals of [`NoSleepTrait::on_revoked`] on the connection
+    /// of this instance when [`NoSleep::dispatch`] is called, instead of on
+    /// a background thread with a connection of its own. The callback then
+    /// runs on the thread that calls `dispatch`. Applies to callbacks
+    /// registered afterwards. Defaults to `false`.
+    ///
+    /// To integrate with an event loop, watch [`NoSleep::connection_fd`]
+    /// for readability, for example with `glib::source::unix_fd_add_local`
+    /// or a `calloop::generic::Generic` source, and call `dispatch` when it
+    /// becomes readable. Call `dispatch` after the other methods of NoSleep
+    /// as well, as they may read signals from the connection while waiting
+    /// for a reply. `on_resume` keeps its own thread, as logind lives on
+    /// the system bus.
+    pub fn set_event_loop(&mut self, enabled: bool) {
+        self.event_loop = enabled;
+    }
+
+    /// The file descriptor of the D-Bus connection of this instance, to
+    /// watch for readability in an event loop, see [`NoSleep::set_event_loop`].
+    /// It stays owned by NoSleep and is valid until NoSleep is dropped.
+    /// `None` for a connection passed to [`NoSleep::new_with_connection`],
+    /// which does not track its file descriptor.
+    pub fn connection_fd(&self) -> Option<RawFd> {
+        self.watched.then(|| self.connection().channel().watch().fd)
+    }
+
+    /// Handles the messages the connection received so far without
+    /// waiting, calling the callback of `on_revoked` for the services that
+    /// dropped their locks. After a resume, acquires the locks again that
+    /// were dropped, see [`NoSleep::set_reacquire_on_resume`].
+    pub fn dispatch(&mut self) -> Result<(), NoSleepError> {
+        while self
+            .connection()
+            .process(Duration::ZERO)
+            .map_err(|e| NoSleepError::DBus {
+                reason: e.to_string(),
+            })?
+        {}
+        if let Some(loop_revoke_watch) = &self.loop_revoke_watch {
+            loop_revoke_watch.handle_lost();
+        }
+        self.reacquire_lost()
+    }
+
+    // Stops the watch of `on_revoked`, whichever way it runs
+    fn stop_revoke_watch(&mut self) {
+        self.revoke_watch = None;
+        if let Some(loop_revoke_watch) = self.loop_revoke_watch.take() {
+            // Best effort, only matters if the connection is reused
+            let _ = loop_revoke_watch.stop(self.connection());
+        }
+    }
+
+    /// Runs `callback` right before the system sleeps, for example to save
+    /// state or close network connections. Sleep cannot be prevented this
+    /// way, only postponed: a logind delay inhibitor is held on the system
+    /// bus and released once `callback` returns or `timeout` passes,
+    /// whichever comes first. logind caps the delay at `InhibitDelayMaxSec`,
+    /// 5 seconds by default.
+    ///
+    /// The callback runs on a background thread, on every sleep until
+    /// [`NoSleep::stop_callback_on_sleep`] is called or NoSleep is dropped.
+    /// It is independent of the blocks acquired with `prevent_sleep`.
+    /// Calling this again replaces the callback. A callback that is still
+    /// running past `timeout` is skipped on the next sleeps until it returns.
+    pub fn prevent_with_callback_on_sleep(
+        &mut self,
+        timeout: Duration,
+        callback: impl Fn() + Send + Sync + 'static,
+    ) -> Result<(), NoSleepError> {
+        self.sleep_delay = None;
+        self.sleep_delay = Some(SleepWatch::delay_sleep(
+            self.app_id.clone(),
+            self.reason.to_string(),
+            timeout,
+            Arc::new(callback),
+        )?);
+        Ok(())
+    }
+
+    /// Stops running the callback of [`NoSleep::prevent_with_callback_on_sleep`]
+    /// and releases its delay inhibitor.
+    pub fn stop_callback_on_sleep(&mut self) {
+        self.sleep_delay = None;
+    }
+
+    /// Keeps the screen from blanking and locking by inhibiting the
+    /// screensaver through org.freedesktop.ScreenSaver, on X11 and Wayland.
+    /// The screen may still dim, see [`NoSleep::prevent_dimming`].
+    /// Same as [`NoSleepTrait::prevent_screensaver`].
+    pub fn prevent_blanking(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventScreenSaver)
+    }
+
+    /// Keeps the display at full brightness: prevents blanking like
+    /// [`NoSleep::prevent_blanking`] and disables DPMS on the X server in
+    /// `DISPLAY`. Replaces any block held, regardless of the switch policy.
+    ///
+    /// Fails without an X server, for example on Wayland, and keeps the
+    /// current block then. Without the `x11` feature the error is
+    /// [`NoSleepError::Unsupported`].
+    pub fn prevent_dimming(&mut self) -> Result<(), NoSleepError> {
+        self.reported(NoSleep::hold_dimming)
+    }
+
+    /// Keeps the machine running when the laptop lid is closed, for example
+    /// in a docking station. Only inhibits `handle-lid-switch` with logind,
+    /// idle sleep and explicit suspend are left alone.
+    ///
+    /// The inhibitor is held apart from the blocks of `prevent_sleep` and
+    /// friends, which neither replace nor report it, until
+    /// [`NoSleep::stop_lid_close_suspend`] or `stop` is called. It always
+    /// blocks, whatever [`NoSleep::set_logind_mode`] says.
+    ///
+    /// Requires logind, whatever the backend order: fails if it is not
+    /// running, for example in a container.
+    pub fn prevent_lid_close_suspend(&mut self) -> Result<(), NoSleepError> {
+        if self.lid_switch.is_some() {
+            return Ok(());
+        }
+        let bus = self.bus(&DBusAPI::LoginApi)?;
+        let response = with_retry(&self.retry_policy, || {
+            let msg = lid_switch_msg(&self.app_id, &self.reason);
+            let reply = bus.send_with_reply_and_block(msg, self.call_timeout)?;
+            reply
+                .read1::<OwnedFd>()
+                .map_err(|e| decode_error(&reply, e))
+        })
+        .map_err(inhibit_error)?;
+        self.lid_switch = Some(response);
+        Ok(())
+    }
+
+    /// Releases the inhibitor of [`NoSleep::prevent_lid_close_suspend`],
+    /// the blocks stay held.
+    pub fn stop_lid_close_suspend(&mut self) {
+        self.lid_switch = None;
+    }
+
+    /// Like `prevent_display_sleep`, but once display blocks failed as
+    /// many times in a row as set with
+    /// [`NoSleep::set_display_fallback_threshold`], a failure holds a
+    /// block of `PreventUserIdleSystemSleep` instead and returns
+    /// [`BlockOutcome::SystemOnly`]. For example when the screensaver
+    /// service is missing but the system can still be kept awake.
+    /// A display block that succeeds starts the count over. Only failures
+    /// to hold the display block count, errors of the bus or a policy are
+    /// returned as is.
+    pub fn prevent_display_sleep_or_degrade(&mut self) -> Result<BlockOutcome, NoSleepError> {
+        let result = match self.prevent_display_sleep() {
+            // The system block would fail the same way
+            Err(e) if !matches!(e, NoSleepError::PreventSleep { .. }) => return Err(e),
+            result => result,
+        };
+        let outcome = self.display_fallback.outcome(result)?;
+        if outcome == BlockOutcome::SystemOnly {
+            self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Like `prevent_system_sleep`, but returns [`BlockOutcome::DisplayAlias`]
+    /// if the block is only held through the screensaver API, which keeps
+    /// the display on rather than the system awake. To fail instead, see
+    /// [`NoSleep::set_strict`].
+    pub fn prevent_system_sleep_or_alias(&mut self) -> Result<BlockOutcome, NoSleepError> {
+        self.prevent_system_sleep()?;
+        let aliased = self
+            .tracked
+            .lock()
+            .handles
+            .iter()
+            .all(|handle| handle.api == DBusAPI::FreeDesktopScreenSaverAPI);
+        Ok(if aliased {
+            BlockOutcome::DisplayAlias
+        } else {
+            BlockOutcome::Full
+        })
+    }
+
+    // Acquires the block of `prevent_dimming`
+    fn hold_dimming(&mut self) -> Result<(), NoSleepError> {
+        #[cfg(feature = "x11")]
+        {
+            self.level = BlockLevel::Normal;
+            self.who = None;
+            let x11_block = match self.x11_block.take() {
+                Some(x11_block) => x11_block,
+                None => x11::X11Block::acquire()?,
+            };
+            self.release()?;
+            match self.acquire(&NoSleepType::PreventScreenSaver, None) {
+                Ok(handles) => self.tracked.lock().handles = handles,
+                Err(e) if self.honors_denial(&e) => return Err(e),
+                // The X server suspends its screensaver as well
+                Err(_) => {}
+            }
+            self.x11_block = Some(x11_block);
+            self.nosleep_types = vec![NoSleepType::PreventScreenSaver];
+            self.stats.start(NoSleepType::PreventScreenSaver, None);
+            Ok(())
+        }
+        #[cfg(not(feature = "x11"))]
+        Err(NoSleepError::Unsupported {
+            operation: "prevent_dimming without the x11 feature".to_string(),
+        })
+    }
+
+    // Acquires the block of `prevent`, attributed to `who`
+    fn hold(
+        &mut self,
+        nosleep_types: &[NoSleepType],
+        who: Option<String>,
+    ) -> Result<(), NoSleepError> {
+        let current: &[NoSleepType] = if self.is_blocked() {
+            &self.nosleep_types
+        } else {
+            &[]
+        };
+        // A block held for someone else is acquired again for `who`
+        if self.switch_policy.keep_current(current, nosleep_types)? && self.who == who {
+            return Ok(());
+        }
+
+        // Clear any previous handles held
+        self.release()?;
+        self.who = who;
+
+        let Some(strongest) = NoSleepType::strongest(nosleep_types) else {
+            return Ok(());
+        };
+        let acquired = self.acquire_all(nosleep_types, self.who.as_deref());
+        if acquired.is_err() {
+            // Best effort, releases the locks of replies without a usable handle
+            let _ = self.reconnect();
+        }
+        match acquired {
+            Ok(handles) => self.tracked.lock().handles = handles,
+            Err(e)
+                if !self.honors_denial(&e)
+                    && nosleep_types.iter().all(|nosleep_type| {
+                        matches!(
+                            nosleep_type,
+                            NoSleepType::PreventUserIdleDisplaySleep
+                                | NoSleepType::PreventScreenSaver
+                        )
+                    }) =>
+            {
+                self.acquire_fallback(e)?;
+            }
+            Err(e) => return Err(e),
+        }
+        self.nosleep_types = nosleep_types.to_vec();
+        self.stats.start(strongest, None);
+        #[cfg(feature = "x11")]
+        if let Some(interval) = self.activity_poke.filter(|_| {
+            nosleep_types.iter().any(|nosleep_type| {
+                matches!(
+                    nosleep_type,
+                    NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver
+                )
+            })
+        }) {
+            self.poke = Some(ActivityPoke::start(interval, x11::reset_screensaver()));
+        }
+        Ok(())
+    }
+
+    // Acquires the block of `prevent_display_sleep_until`
+    fn hold_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.release()?;
+        if deadline <= Instant::now() {
+            return Ok(());
+        }
+        let mut config = self.config();
+        // Pokes are left to blocks without a deadline
+        config.activity_poke = None;
+        let mut nosleep = config.connect()?;
+        nosleep.prevent_sleep(NoSleepType::PreventUserIdleDisplaySleep)?;
+        self.timed_block = Some(DeadlineBlock::start(nosleep, deadline)?);
+        self.stats
+            .start(NoSleepType::PreventUserIdleDisplaySleep, Some(deadline));
+        Ok(())
+    }
+
+    // Runs `change` and reports the block held afterwards to the subscribers
+    fn reported<R>(&mut self, change: impl FnOnce(&mut NoSleep) -> R) -> R {
+        let result = change(self);
+        self.events.update(self.held_type());
+        result
+    }
+
+    // The type of the block held as reported to `subscribe`
+    fn held_type(&self) -> Option<NoSleepType> {
+        if !self.is_blocked() {
+            return None;
+        }
+        // Timed blocks are display blocks without `nosleep_types`
+        NoSleepType::strongest(&self.nosleep_types)
+            .or(Some(NoSleepType::PreventUserIdleDisplaySleep))
+    }
+
+    /// Releases all handles, also when releasing one of them fails.
+    /// The handles are forgotten either way, so a failed release is not
+    /// retried over stale entries. The first error is returned.
+    fn release(&mut self) -> Result<(), NoSleepError> {
+        #[cfg(feature = "x11")]
+        {
+            self.poke = None;
+        }
+        let mut result = Ok(());
+        let handles = std::mem::take(&mut self.tracked.lock().handles);
+        for handle in handles {
+            if let Err(e) = self.uninhibit(&handle) {
+                result = result.and(Err(e));
+            }
+        }
+        result = result.and(self.reconnect());
+        #[cfg(feature = "x11")]
+        if let Some(x11_block) = self.x11_block.take() {
+            if let Err(e) = x11_block.release() {
+                result = result.and(Err(e));
+            }
+        }
+        #[cfg(feature = "xdg-screensaver")]
+        if let Some(xdg_block) = self.xdg_block.take() {
+            if let Err(e) = xdg_block.release() {
+                result = result.and(Err(e));
+            }
+        }
+        if let Some(mut timed_block) = self.timed_block.take() {
+            timed_block.stop();
+        }
+        self.stats.finish();
+        self.nosleep_types.clear();
+        self.suspended.clear();
+        result
+    }
+
+    // Releases the locks of `stop_type`, the others stay held. The X11 and
+    // `xdg-screensaver` fallbacks hold all display types at once, they are
+    // only released along with the last type.
+    fn release_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        // Timed blocks are display blocks without `nosleep_types`
+        if self.timed_block.is_some() && nosleep_type == NoSleepType::PreventUserIdleDisplaySleep {
+            return self.release();
+        }
+        if !self.nosleep_types.contains(&nosleep_type) {
+            return Ok(());
+        }
+        let others = self
+            .nosleep_types
+            .iter()
+            .copied()
+            .filter(|held| *held != nosleep_type)
+            .collect::<Vec<_>>();
+        let Some(strongest) = NoSleepType::strongest(&others) else {
+            return self.release();
+        };
+        if self.tracked.lock().handles.iter().any(|handle| {
+            handle.nosleep_type == nosleep_type && handle.handle == InhibitHandle::Connection
+        }) {
+            return Err(NoSleepError::StopLock {
+                reason: format!(
+                    "The lock of {:?} has no handle, only stop releases it",
+                    nosleep_type
+                ),
+                code: None,
+            });
+        }
+        let released = {
+            let mut tracked = self.tracked.lock();
+            let (released, kept) = std::mem::take(&mut tracked.handles)
+                .into_iter()
+                .partition::<Vec<_>, _>(|handle| handle.nosleep_type == nosleep_type);
+            tracked.handles = kept;
+            released
+        };
+        let mut result = Ok(());
+        for handle in released {
+            if let Err(e) = self.uninhibit(&handle) {
+                result = result.and(Err(e));
+            }
+        }
+        #[cfg(feature = "x11")]
+        if !others.iter().any(|other| {
+            matches!(
+                other,
+                NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver
+            )
+        }) {
+            self.poke = None;
+        }
+        self.nosleep_types = others;
+        self.stats.start(strongest, None);
+        result
+    }
+
+    /// The D-Bus connection used to acquire the locks. Releasing a lock
+    /// that a service handed out no handle for replaces it with a new
+    /// connection, as only closing it releases the lock.
+    pub fn connection(&self) -> &Connection {
+        self.d_bus.as_ref().expect("only taken on drop")
+    }
+
+    // Whether the connection that `api` is called on holds locks without a handle
+    fn stray_locks_of(&self, api: &DBusAPI) -> &Cell<bool> {
+        if *api == DBusAPI::LoginApi && !self.headless {
+            &self.stray_system_locks
+        } else {
+            &self.stray_locks
+        }
+    }
+
+    // Closes the connections that hold locks without a handle, which
+    // releases them, and opens new ones in their place. Any other lock
+    // held on them is released as well.
+    fn reconnect(&mut self) -> Result<(), NoSleepError> {
+        if self.stray_system_locks.take() {
+            // Opened again on first use
+            self.system_bus.take();
+        }
+        if !self.stray_locks.get() {
+            return Ok(());
+        }
+        let bus = if self.headless {
+            BusType::System
+        } else {
+            BusType::Session
+        };
+        let d_bus = pool::open(bus, CONNECT_TIMEOUT).map_err(|e| NoSleepError::StopLock {
+            reason: e.to_string(),
+            code: None,
+        })?;
+        if let Some(loop_revoke_watch) = &mut self.loop_revoke_watch {
+            loop_revoke_watch.resubscribe(&d_bus)?;
+        }
+        self.d_bus = Some(d_bus);
+        self.stray_locks.set(false);
+        Ok(())
+    }
+
+    fn connect(
+        session_bus: Result<Connection, dbus::Error>,
+        system_bus: impl FnOnce() -> Result<Connection, dbus::Error>,
+    ) -> Result<NoSleep, NoSleepError> {
+        match session_bus {
+            Ok(d_bus) => Ok(NoSleep::new_with_connection(d_bus)),
+            Err(e) => {
+                // Report the session bus error, that is the one users expect
+                let d_bus = system_bus().map_err(|_| NoSleepError::Init {
+                    reason: e.to_string(),
+                    code: None,
+                })?;
+                let mut nosleep = NoSleep::new_with_connection(d_bus);
+                nosleep.headless = true;
+                nosleep.backend_order = vec![DBusAPI::LoginApi];
+                Ok(nosleep)
+            }
+        }
+    }
+
+    // logind lives on the system bus, which is connected on first use
+    fn bus(&self, api: &DBusAPI) -> Result<&Connection, NoSleepError> {
+        if *api != DBusAPI::LoginApi || self.headless {
+            return Ok(self.connection());
+        }
+        if let Some(system_bus) = self.system_bus.get() {
+            return Ok(system_bus);
+        }
+        let system_bus = Connection::new_system().map_err(|e| NoSleepError::Init {
+            reason: e.to_string(),
+            code: None,
+        })?;
+        Ok(self.system_bus.get_or_init(|| system_bus))
+    }
+
+    /// A lock that is gone already, for example because the service
+    /// restarted, counts as released.
+    fn uninhibit(&self, handle: &NoSleepHandle) -> Result<(), NoSleepError> {
+        if handle.handle == InhibitHandle::Connection {
+            self.stray_locks_of(&handle.api).set(true);
+        }
+        let released = with_retry(&self.retry_policy, || match self.release_msg(handle)? {
+            Some(msg) => self
+                .connection()
+                .send_with_reply_and_block(msg, self.call_timeout)
+                .map(|_| ()),
+            // The lock is released once the handle is dropped
+            None => Ok(()),
+        });
+        match released {
+            Err(e) if !is_released(handle, &e) => Err(NoSleepError::StopLock {
+                reason: e.to_string(),
+                code: None,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Acquires all the handles needed by `backend`, or none at all
+    fn inhibit_backend(
+        &self,
+        backend: &DBusAPI,
+        nosleep_type: &NoSleepType,
+        who: Option<&str>,
+    ) -> Result<Vec<NoSleepHandle>, NoSleepError> {
+        let mut handles = vec![];
+        for api in backend.apis(*nosleep_type) {
+            match self.inhibit(&api, nosleep_type, who) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    for handle in &handles {
+                        // Best effort, the original error is more useful
+                        let _ = self.uninhibit(handle);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    // Acquires the handles of a block, trying the backends in order
+    fn acquire(
+        &self,
+        nosleep_type: &NoSleepType,
+        who: Option<&str>,
+    ) -> Result<Vec<NoSleepHandle>, NoSleepError> {
+        // The screensaver is only inhibited through its own API
+        if *nosleep_type == NoSleepType::PreventScreenSaver {
+            let handle = self.inhibit(&DBusAPI::FreeDesktopScreenSaverAPI, nosleep_type, who)?;
+            return Ok(vec![handle]);
+        }
+        if self.strict && !self.is_supported_type(*nosleep_type) {
+            return Err(NoSleepError::Unsupported {
+                operation: format!("{nosleep_type} without a backend that can hold it"),
+            });
+        }
+
+        let reselect = match self.selected_backend.take() {
+            Some(selected) if self.aliases(&selected, nosleep_type) => {
+                self.selected_backend.set(Some(selected));
+                false
+            }
+            Some(selected) => match self.inhibit_backend(&selected, nosleep_type, who) {
+                Ok(handles) => {
+                    self.selected_backend.set(Some(selected));
+                    return Ok(handles);
+                }
+                Err(e) if self.honors_denial(&e) => {
+                    self.selected_backend.set(Some(selected));
+                    return Err(e);
+                }
+                // Probed again through the backend order
+                Err(_) => true,
+            },
+            None => false,
+        };
+
+        let mut error = NoSleepError::PreventSleep {
+            reason: "No backend configured".to_string(),
+            code: None,
+        };
+        for backend in &self.backend_order {
+            if self.aliases(backend, nosleep_type) {
+                continue;
+            }
+            match self.inhibit_backend(backend, nosleep_type, who) {
+                Ok(handles) => {
+                    if reselect {
+                        self.selected_backend.set(Some(*backend));
+                    }
+                    return Ok(handles);
+                }
+                Err(e) if self.honors_denial(&e) => return Err(e),
+                // A denial tells more than a missing service
+                Err(_) if matches!(error, NoSleepError::PolicyDenied { .. }) => {}
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    // Acquires the handles of every type, releasing them all if one fails
+    fn acquire_all(
+        &self,
+        nosleep_types: &[NoSleepType],
+        who: Option<&str>,
+    ) -> Result<Vec<NoSleepHandle>, NoSleepError> {
+        let mut handles = vec![];
+        for nosleep_type in nosleep_types {
+            match self.acquire(nosleep_type, who) {
+                Ok(acquired) => handles.extend(acquired),
+                Err(e) => {
+                    for handle in &handles {
+                        // Best effort, the original error is more useful
+                        let _ = self.uninhibit(handle);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    // Releases the locks and acquires them again, for locks without a handle
+    fn reacquire_all(&mut self) -> Result<(), NoSleepError> {
+        let handles = std::mem::take(&mut self.tracked.lock().handles);
+        let mut result = Ok(());
+        for handle in handles {
+            if let Err(e) = self.uninhibit(&handle) {
+                result = result.and(Err(e));
+            }
+        }
+        let acquired = result
+            .and(self.reconnect())
+            .and_then(|()| self.acquire_all(&self.nosleep_types, self.who.as_deref()));
+        match acquired {
+            Ok(handles) => {
+                self.tracked.lock().handles = handles;
+                Ok(())
+            }
+            Err(e) => {
+                // Best effort, the original error is more useful
+                let _ = self.reported(NoSleep::release);
+                Err(e)
+            }
+        }
+    }
+
+    // Last resort for display blocks, without D-Bus. Reports `error`,
+    // the D-Bus error that users expect, if the fallbacks fail as well.
+    fn acquire_fallback(&mut self, error: NoSleepError) -> Result<(), NoSleepError> {
+        #[cfg(feature = "x11")]
+        if let Ok(x11_block) = x11::X11Block::acquire() {
+            self.x11_block = Some(x11_block);
+            return Ok(());
+        }
+        #[cfg(feature = "xdg-screensaver")]
+        if let Some(xid) = self.window_xid {
+            if let Ok(xdg_block) = xdg_screensaver::XdgScreensaverBlock::acquire(xid) {
+                self.xdg_block = Some(xdg_block);
+                return Ok(());
+            }
+        }
+        Err(error)
+    }
+
+    // The options of a lock acquired for `who`, or this instance
+    fn inhibit_options<'a>(&'a self, who: Option<&'a str>) -> InhibitOptions<'a> {
+        InhibitOptions {
+            xid: self.window_xid,
+            reason: &self.reason,
+            app_id: who.unwrap_or(&self.app_id),
+            allow_suspend: self.allow_suspend,
+            level: self.level,
+        }
+    }
+
+    // A high block is not merely delayed
+    fn effective_logind_mode(&self) -> LogindMode {
+        match self.level {
+            BlockLevel::High => LogindMode::Block,
+            BlockLevel::Normal => self.logind_mode,
+        }
+    }
+
+    // The message that releases `handle`, if any
+    fn release_msg(&self, handle: &NoSleepHandle) -> Result<Option<dbus::Message>, dbus::Error> {
+        release_msg(handle, self.tracked.lock().custom_inhibit.as_ref())
+    }
+
+    fn inhibit(
+        &self,
+        api: &DBusAPI,
+        nosleep_type: &NoSleepType,
+        who: Option<&str>,
+    ) -> Result<NoSleepHandle, NoSleepError> {
+        let bus = self.bus(api)?;
+        let response = with_retry(&self.retry_policy, || {
+            let msg = match api {
+                DBusAPI::LoginApi => logind_inhibit_msg(
+                    self.logind_what
+                        .unwrap_or_else(|| LogindWhat::from(*nosleep_type)),
+                    self.effective_logind_mode(),
+                    who.unwrap_or(&self.app_id),
+                    &self.reason,
+                ),
+                DBusAPI::Custom => custom_inhibit(self.tracked.lock().custom_inhibit.as_ref())?
+                    .inhibit_msg()
+                    .map_err(|e| dbus::Error::new_failed(&e))?,
+                _ => inhibit_msg(api, nosleep_type, &self.inhibit_options(who)),
+            };
+            bus.send_with_reply_and_block(msg, self.call_timeout)
+        })
+        .map_err(inhibit_error)?;
+        let handle = inhibit_handle(api, &response).map_err(|e| {
+            // The service may hold a lock that nothing but closing the connection releases
+            self.stray_locks_of(api).set(true);
+            inhibit_error(e)
+        })?;
+        Ok(NoSleepHandle {
+            handle,
+            api: *api,
+            nosleep_type: *nosleep_type,
+            who: who.map(str::to_string),
+        })
+    }
+}
+
+impl NoSleepTrait for NoSleep {
+    /// Creates a new NoSleep type and connects to the D-Bus.
+    /// The session is automatically closed when the instance is dropped.
+    ///
+    /// Without a session bus, for example over SSH, it connects to the
+    /// system bus instead and only uses logind.
+    ///
+    /// Session bus connections are reused on the same thread: dropping
+    /// NoSleep releases its locks and keeps the connection for the next
+    /// instance. This makes creating NoSleep about 5 times faster once a
+    /// connection is available (30 µs instead of 150 µs against a local
+    /// dbus-daemon).
+    ///
+    /// Fails with [`NoSleepError::Init`] if the bus does not answer within
+    /// 5 seconds, see [`NoSleep::with_connect_timeout`].
+    fn new() -> Result<NoSleep, NoSleepError> {
+        NoSleep::with_connect_timeout(CONNECT_TIMEOUT)
+    }
+
+    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.prevent(&[nosleep_type])
+    }
+
+    fn prevent(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        self.level = BlockLevel::Normal;
+        self.reported(|nosleep| nosleep.hold(nosleep_types, None))
+    }
+
+    fn prevent_sleep_with_level(
+        &mut self,
+        nosleep_type: NoSleepType,
+        level: BlockLevel,
+    ) -> Result<(), NoSleepError> {
+        self.level = level;
+        self.reported(|nosleep| nosleep.hold(&[nosleep_type], None))
+    }
+
+    /// Sends `who` as the application id, instead of [`NoSleep::set_app_id`].
+    fn prevent_sleep_as(
+        &mut self,
+        nosleep_type: NoSleepType,
+        who: &str,
+    ) -> Result<(), NoSleepError> {
+        self.level = BlockLevel::Normal;
+        self.reported(|nosleep| nosleep.hold(&[nosleep_type], Some(who.to_string())))
+    }
+
+    fn prevent_display_sleep_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.who = None;
+        self.reported(|nosleep| nosleep.hold_until(deadline))
+    }
+
+    /// Releases the block and the inhibitor of `prevent_lid_close_suspend`,
+    /// and stops calling the callbacks of `on_resume` and `on_revoked`.
+    fn stop(&mut self) -> Result<(), NoSleepError> {
+        self.lid_switch = None;
+        self.resume_watch = None;
+        self.stop_revoke_watch();
+        self.reported(NoSleep::release)
+    }
+
+    /// Releases the locks acquired for `nosleep_type` with the backends,
+    /// through the same API they were acquired with. Fails if a service
+    /// handed out no handle for one of them, as only `stop` releases it.
+    fn stop_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.reported(|nosleep| nosleep.release_type(nosleep_type))
+    }
+
+    /// Acquires the new block before releasing the old one, so the
+    /// block is held throughout. Unless a service handed out no handle for
+    /// the old one: it is released first, by reconnecting.
+    fn set_reason(&mut self, reason: &str) -> Result<(), NoSleepError> {
+        self.set_reason_cow(reason.to_string())
+    }
+
+    fn set_reason_cow(&mut self, reason: impl Into<Cow<'static, str>>) -> Result<(), NoSleepError> {
+        self.reason = reason.into();
+        if self.tracked.lock().handles.is_empty() {
+            // Timed blocks are held by their own instance and keep their reason
+            return Ok(());
+        }
+        let stray = self
+            .tracked
+            .lock()
+            .handles
+            .iter()
+            .any(|handle| handle.handle == InhibitHandle::Connection);
+        if stray {
+            // Reconnecting would release the new locks as well
+            return self.reacquire_all();
+        }
+        let handles = self.acquire_all(&self.nosleep_types, self.who.as_deref())?;
+        let mut result = Ok(());
+        let handles = std::mem::replace(&mut self.tracked.lock().handles, handles);
+        for handle in handles {
+            if let Err(e) = self.uninhibit(&handle) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    fn suspend(&mut self) -> Result<(), NoSleepError> {
+        if self.nosleep_types.is_empty() {
+            return Ok(());
+        }
+        let nosleep_types = self.nosleep_types.clone();
+        self.reported(NoSleep::release)?;
+        self.suspended = nosleep_types;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), NoSleepError> {
+        let nosleep_types = std::mem::take(&mut self.suspended);
+        if nosleep_types.is_empty() {
+            return Ok(());
+        }
+        // Keeps the level and attribution of the suspended block
+        let who = self.who.clone();
+        self.reported(|nosleep| nosleep.hold(&nosleep_types, who))
+    }
+
+    fn is_blocked(&self) -> bool {
+        #[cfg(feature = "x11")]
+        if self.x11_block.is_some() {
+            return true;
+        }
+        #[cfg(feature = "xdg-screensaver")]
+        if self.xdg_block.is_some() {
+            return true;
+        }
+        !self.tracked.lock().handles.is_empty()
+            || self
+                .timed_block
+                .as_ref()
+                .is_some_and(DeadlineBlock::is_active)
+    }
+
+    fn handles(&self) -> Vec<HandleInfo> {
+        #[allow(unused_mut)]
+        let mut handles = self
+            .tracked
+            .lock()
+            .handles
+            .iter()
+            .map(|handle| HandleInfo {
+                kind: handle.nosleep_type,
+                backend: handle.api.name(),
+                raw: match handle.handle {
+                    InhibitHandle::Cookie(cookie) => cookie as u64,
+                    // Portal requests are identified by their path only
+                    InhibitHandle::Request(_) => 0,
+                    InhibitHandle::Fd(ref fd) => fd.as_raw_fd() as u64,
+                    InhibitHandle::Connection => 0,
+                },
+                who: handle.who.clone(),
+            })
+            .collect::<Vec<_>>();
+        #[cfg(feature = "x11")]
+        if let (Some(_), Some(kind)) =
+            (&self.x11_block, NoSleepType::strongest(&self.nosleep_types))
+        {
+            handles.push(HandleInfo {
+                kind,
+                backend: "X11",
+                raw: 0,
+                who: self.who.clone(),
+            });
+        }
+        #[cfg(feature = "xdg-screensaver")]
+        if let (Some(xdg_block), Some(kind)) =
+            (&self.xdg_block, NoSleepType::strongest(&self.nosleep_types))
+        {
+            handles.push(HandleInfo {
+                kind,
+                backend: "xdg-screensaver",
+                raw: xdg_block.xid() as u64,
+                who: self.who.clone(),
+            });
+        }
+        handles
+    }
+
+    fn stats(&self) -> BlockStats {
+        self.stats.stats()
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// The callback runs on a background thread that watches the
+    /// `PrepareForSleep` signal of logind on the system bus.
+    fn on_resume(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.resume_watch = None;
+        self.resume_watch = Some(SleepWatch::on_resume(Arc::from(callback))?);
+        Ok(())
+    }
+
+    /// A block counts as revoked when the service holding it loses its
+    /// D-Bus name, for example when the session manager exits on logout
+    /// or restarts. Its handles are forgotten, so `prevent_sleep` acquires
+    /// it again. The callback runs on a background thread. Blocks held
+    /// through X11 are not watched, nor those held through logind unless
+    /// there is no session bus. With [`NoSleep::set_event_loop`], the
+    /// callback runs in `dispatch` instead.
+    fn on_revoked(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.stop_revoke_watch();
+        let headless = self.headless;
+        let events = self.events.clone();
+        let callback: Box<dyn Fn() + Send + Sync> = Box::new(move || {
+            events.revoked();
+            callback();
+        });
+        if self.event_loop {
+            // `d_bus` is connected to the system bus when headless
+            self.loop_revoke_watch = Some(LoopRevokeWatch::start(
+                self.connection(),
+                headless,
+                self.tracked.clone(),
+                callback,
+            )?);
+            return Ok(());
+        }
+        self.revoke_watch = Some(RevokeWatch::start(
+            move || {
+                if headless {
+                    Connection::new_system()
+                } else {
+                    Connection::new_session()
+                }
+            },
+            headless,
+            self.tracked.clone(),
+            callback,
+        )?);
+        Ok(())
+    }
+
+    /// Calls `SimulateUserActivity` of org.freedesktop.ScreenSaver.
+    fn simulate_user_activity(&mut self) -> Result<(), NoSleepError> {
+        self.connection()
+            .send_with_reply_and_block(simulate_user_activity_msg(), self.call_timeout)
+            .map(|_| ())
+            .map_err(|e| NoSleepError::DBus {
+                reason: e.to_string(),
+            })
+    }
+
+    /// `Revoked` is only reported while a callback of `on_revoked` is set.
+    fn subscribe(&self) -> Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    fn observer(&self) -> NoSleepObserver {
+        self.events.observer()
+    }
+
+    /// Reads the power save mode of the displays from Mutter,
+    /// so it is only known in a Gnome session.
+    fn display_is_on(&self) -> Option<bool> {
+        if self.headless {
+            return None;
+        }
+        let proxy = self.connection().with_proxy(
+            "org.gnome.Mutter.DisplayConfig",
+            "/org/gnome/Mutter/DisplayConfig",
+            std::time::Duration::from_millis(500),
+        );
+        let mode: i32 = proxy
+            .get("org.gnome.Mutter.DisplayConfig", "PowerSaveMode")
+            .ok()?;
+        power_save_mode_is_on(mode)
+    }
+
+    /// In a GNOME session, the sleep-inactive settings of GNOME for AC or
+    /// battery power as reported by UPower, read with `gsettings`.
+    /// Otherwise the `IdleAction` of logind, if it suspends or hibernates.
+    fn system_sleep_timeout(&self) -> Option<Duration> {
+        if !self.headless {
+            if let Some(timeout) = sleep_timeout::gnome_sleep_timeout() {
+                return timeout;
+            }
+        }
+        sleep_timeout::logind_sleep_timeout()
+    }
+
+    /// Depends on the backend order, the screensaver API
+    /// alone cannot keep the system awake. The screensaver API and
+    /// the idle flag of Gnome keep the display on without it.
+    fn capabilities(&self) -> Capabilities {
+        let display_sleep = !self.backend_order.is_empty();
+        let system_sleep = self
+            .backend_order
+            .iter()
+            .any(|api| *api != DBusAPI::FreeDesktopScreenSaverAPI);
+        let display_only = self
+            .backend_order
+            .iter()
+            .any(|api| matches!(api, DBusAPI::FreeDesktopScreenSaverAPI | DBusAPI::GnomeApi));
+        Capabilities {
+            display_sleep,
+            system_sleep,
+            display_only,
+            screensaver: true,
+            timed_blocks: display_sleep,
+            battery_aware: false,
+        }
+    }
+}
+
+/// Returns `true` if a service that can hold a block is running or
+/// can be activated on the session bus. Does not acquire a block, and
+/// returns `false` if the bus does not answer within half a second.
+pub fn available() -> bool {
+    pool::open(BusType::Session, AVAILABLE_TIMEOUT)
+        .and_then(|d_bus| bus_names(&d_bus))
+        .is_ok_and(|names| any_supported(&names))
+}
+
+// The built-in backend called `name` in `NOSLEEP_BACKEND`
+fn backend_from_name(name: &str) -> Option<DBusAPI> {
+    let api = match name.to_ascii_lowercase().as_str() {
+        "gnome" => DBusAPI::GnomeApi,
+        "freedesktop" => DBusAPI::FreeDesktopPowerApi,
+        "screensaver" => DBusAPI::FreeDesktopScreenSaverAPI,
+        "portal" => DBusAPI::PortalApi,
+        "logind" => DBusAPI::LoginApi,
+        _ => [
+            DBusAPI::GnomeApi,
+            DBusAPI::FreeDesktopPowerApi,
+            DBusAPI::FreeDesktopScreenSaverAPI,
+            DBusAPI::PortalApi,
+            DBusAPI::LoginApi,
+        ]
+        .into_iter()
+        .find(|api| api.name() == name)?,
+    };
+    Some(api)
+}
+
+// The names that are running or can be activated on the bus,
+// an error only if neither can be listed
+fn bus_names(d_bus: &Connection) -> Result<Vec<String>, dbus::Error> {
+    let proxy = d_bus.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(500),
+    );
+    let mut result: Result<Vec<String>, _> = Err(dbus::Error::new_failed("No bus names listed"));
+    for method in ["ListNames", "ListActivatableNames"] {
+        match proxy.method_call::<(Vec<String>,), _, _, _>("org.freedesktop.DBus", method, ()) {
+            Ok((names,)) => match &mut result {
+                Ok(all) => all.extend(names),
+                Err(_) => result = Ok(names),
+            },
+            Err(e) if result.is_err() => result = Err(e),
+            Err(_) => {}
+        }
+    }
+    result
+}
+
+// Checks a list of bus names for a supported service
+fn any_supported(names: &[String]) -> bool {
+    let supported = [
+        DBusAPI::GnomeApi,
+        DBusAPI::FreeDesktopPowerApi,
+        DBusAPI::FreeDesktopScreenSaverAPI,
+        DBusAPI::PortalApi,
+    ];
+    supported
+        .iter()
+        .any(|api| names.iter().any(|name| name == api.name()))
+}
+
+// Maps the PowerSaveMode of org.gnome.Mutter.DisplayConfig,
+// 0 is on, 1 to 3 are standby, suspend and off, -1 is unknown
+fn power_save_mode_is_on(mode: i32) -> Option<bool> {
+    match mode {
+        0 => Some(true),
+        1..=3 => Some(false),
+        _ => None,
+    }
+}
+
+// Detects a Flatpak or Snap sandbox
+fn is_sandboxed() -> bool {
+    std::env::var_os("FLATPAK_ID").is_some()
+        || std::env::var_os("SNAP").is_some()
+        || std::path::Path::new("/.flatpak-info").exists()
+}
+
+// D-Bus errors of services refusing a call on purpose, for example
+// through polkit or the permission store of the portal
+const POLICY_ERRORS: [&str; 3] = [
+    "org.freedesktop.DBus.Error.AccessDenied",
+    "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired",
+    "org.freedesktop.portal.Error.NotAllowed",
+];
+
+// Classifies a failed inhibit call, policy denials get their own variant
+fn inhibit_error(e: dbus::Error) -> NoSleepError {
+    if e.name().is_some_and(|name| POLICY_ERRORS.contains(&name)) {
+        NoSleepError::PolicyDenied {
+            reason: e.to_string(),
+            code: None,
+        }
+    } else {
+        NoSleepError::PreventSleep {
+            reason: e.to_string(),
+            code: None,
+        }
+    }
+}
+
+// Errors of a release call that mean the lock is gone already,
+// so there is nothing left to release
+const RELEASED_ERRORS: [&str; 3] = [
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.UnknownObject",
+];
+
+// Classifies a failed release call of `handle`
+fn is_released(handle: &NoSleepHandle, e: &dbus::Error) -> bool {
+    match e.name() {
+        Some(name) if RELEASED_ERRORS.contains(&name) => true,
+        // A closed portal request has no interfaces left
+        Some("org.freedesktop.DBus.Error.UnknownMethod") => {
+            matches!(handle.handle, InhibitHandle::Request(_))
+        }
+        // gnome-session forgets the cookies of a previous run
+        Some("org.gnome.SessionManager.GeneralError") => e
+            .message()
+            .is_some_and(|message| message.contains("Invalid cookie")),
+        _ => false,
+    }
+}
+
+// The reason shown by the session manager when none is set
+const DEFAULT_REASON: &str = "Power Save Blocker";
+
+// The application identifier passed when none is set
+const DEFAULT_APP_ID: &str = "org.powersaveblocker.app";
+
+// Settings passed along with every inhibit call
+struct InhibitOptions<'a> {
+    // X11 window the block is scoped to, if any
+    xid: Option<u32>,
+    // Human readable reason
+    reason: &'a str,
+    // Application identifier
+    app_id: &'a str,
+    // Gnome display blocks only inhibit idling
+    allow_suspend: bool,
+    // A high block also inhibits logging out
+    level: BlockLevel,
+}
+
+impl Default for InhibitOptions<'_> {
+    fn default() -> Self {
+        InhibitOptions {
+            xid: None,
+            reason: DEFAULT_REASON,
+            app_id: DEFAULT_APP_ID,
+            allow_suspend: false,
+            level: BlockLevel::Normal,
+        }
+    }
+}
+
+fn inhibit_msg(
+    api: &DBusAPI,
+    nosleep_type: &NoSleepType,
+    options: &InhibitOptions,
+) -> dbus::Message {
+    match api {
+        DBusAPI::GnomeApi => {
+            // Arguments are
+            // app_id:       application identifier
+            // toplevel_xid: toplevel x window identifier
+            // reason:       human readable reason
+            // flags:        flags that specify what should be inhibited
+            let flags = match nosleep_type {
+                NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep => {
+                    GnomeAPIInhibitFlags::InhibitSuspendSession as u32
+                }
+                // Display blocks. Screensaver blocks only go through
+                // org.freedesktop.ScreenSaver
+                _ if options.allow_suspend => GnomeAPIInhibitFlags::InhibitMarkSessionIdle as u32,
+                _ => {
+                    GnomeAPIInhibitFlags::InhibitMarkSessionIdle as u32
+                        | GnomeAPIInhibitFlags::InhibitSuspendSession as u32
+                }
+            };
+            let flags = match options.level {
+                BlockLevel::High => flags | GnomeAPIInhibitFlags::InhibitLogout as u32,
+                BlockLevel::Normal => flags,
+            };
+            dbus::Message::call_with_args(
+                "org.gnome.SessionManager",
+                "/org/gnome/SessionManager",
+                "org.gnome.SessionManager",
+                "Inhibit",
+                (
+                    options.app_id,
+                    options.xid.unwrap_or(0),
+                    options.reason,
+                    flags,
+                ),
+            )
+        }
+        // The arguments of the method are:
+        //  app_id: The application identifier
+        //  reason: The reason for the inhibit
+        DBusAPI::FreeDesktopPowerApi => dbus::Message::call_with_args(
+            "org.freedesktop.PowerManagement",
+            "/org/freedesktop/PowerManagement/Inhibit",
+            "org.freedesktop.PowerManagement.Inhibit",
+            "Inhibit",
+            (options.app_id, options.reason),
+        ),
+        DBusAPI::FreeDesktopScreenSaverAPI => dbus::Message::call_with_args(
+            "org.freedesktop.ScreenSaver",
+            "/org/freedesktop/ScreenSaver",
+            "org.freedesktop.ScreenSaver",
+            "Inhibit",
+            (options.app_id, options.reason),
+        ),
+        DBusAPI::PortalApi => {
+            // Arguments are
+            // window:  identifier of the application window, empty if unknown
+            // flags:   flags that specify what should be inhibited
+            // options: vardict with the human readable reason
+            let flags = match nosleep_type {
+                NoSleepType::PreventUserIdleDisplaySleep => {
+                    PortalInhibitFlags::Idle as u32 | PortalInhibitFlags::Suspend as u32
+                }
+                NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep => {
+                    PortalInhibitFlags::Suspend as u32
+                }
+                NoSleepType::PreventScreenSaver => PortalInhibitFlags::Idle as u32,
+            };
+            let flags = match options.level {
+                BlockLevel::High => flags | PortalInhibitFlags::Logout as u32,
+                BlockLevel::Normal => flags,
+            };
+            let mut vardict: PropMap = HashMap::new();
+            vardict.insert(
+                "reason".to_string(),
+                Variant(Box::new(options.reason.to_string())),
+            );
+            dbus::Message::call_with_args(
+                "org.freedesktop.portal.Desktop",
+                "/org/freedesktop/portal/desktop",
+                "org.freedesktop.portal.Inhibit",
+                "Inhibit",
+                (
+                    options
+                        .xid
+                        .map_or(String::new(), |xid| format!("x11:{:x}", xid)),
+                    flags,
+                    vardict,
+                ),
+            )
+        }
+        DBusAPI::LoginApi => logind_inhibit_msg(
+            LogindWhat::from(*nosleep_type),
+            LogindMode::default(),
+            options.app_id,
+            options.reason,
+        ),
+        DBusAPI::Custom => unreachable!("custom calls are built from the CustomInhibitSpec"),
+    }
+}
+
+// The spec of `DBusAPI::Custom`
+fn custom_inhibit(spec: Option<&CustomInhibitSpec>) -> Result<&CustomInhibitSpec, dbus::Error> {
+    spec.ok_or_else(|| dbus::Error::new_failed("No CustomInhibitSpec set"))
+}
+
+// The message that releases `handle`, if any
+fn release_msg(
+    handle: &NoSleepHandle,
+    spec: Option<&CustomInhibitSpec>,
+) -> Result<Option<dbus::Message>, dbus::Error> {
+    Ok(match (&handle.api, &handle.handle) {
+        (DBusAPI::Custom, InhibitHandle::Cookie(cookie)) => Some(
+            custom_inhibit(spec)?
+                .uninhibit_msg(*cookie)
+                .map_err(|e| dbus::Error::new_failed(&e))?,
+        ),
+        (_, InhibitHandle::Cookie(cookie)) => Some(uninhibit_msg(&handle.api, *cookie)),
+        (_, InhibitHandle::Request(path)) => Some(close_request_msg(path)),
+        // Closing the file descriptor releases the lock
+        (_, InhibitHandle::Fd(_)) => None,
+        (_, InhibitHandle::Connection) => None,
+    })
+}
+
+// The handle in the reply of a successful inhibit call of `api`. Some
+// services answer with another type than the API documents, so the other
+// shapes that can be released are tried as well. A lock taken with an
+// empty reply is held until the connection closes, any other reply
+// without a usable handle is an error.
+fn inhibit_handle(api: &DBusAPI, reply: &dbus::Message) -> Result<InhibitHandle, dbus::Error> {
+    let handle = match api {
+        DBusAPI::PortalApi => reply.get1::<dbus::Path>().map(InhibitHandle::Request),
+        DBusAPI::LoginApi => None,
+        _ => reply
+            .get1::<u32>()
+            .or_else(|| reply.get1::<i32>().map(|cookie| cookie as u32))
+            .map(InhibitHandle::Cookie),
+    };
+    if let Some(handle) = handle.or_else(|| reply.get1::<OwnedFd>().map(InhibitHandle::Fd)) {
+        return Ok(handle);
+    }
+    if reply.iter_init().arg_type() == ArgType::Invalid {
+        return Ok(InhibitHandle::Connection);
+    }
+    // Fails the same way as the handle above
+    let e = match api {
+        DBusAPI::PortalApi => reply.read1::<dbus::Path>().map(|_| ()),
+        DBusAPI::LoginApi => reply.read1::<OwnedFd>().map(|_| ()),
+        _ => reply.read1::<u32>().map(|_| ()),
+    };
+    Err(match e {
+        Err(e) => decode_error(reply, e),
+        Ok(()) => dbus::Error::new_failed("Unexpected inhibit reply"),
+    })
+}
+
+// A reply that could not be decoded, with what the service actually sent,
+// so reports from unusual environments show what they return
+pub(crate) fn decode_error(reply: &dbus::Message, e: TypeMismatchError) -> dbus::Error {
+    let items = reply.get_items();
+    let signature = items
+        .iter()
+        .map(|item| item.signature().to_string())
+        .collect::<String>();
+    dbus::Error::new_failed(&format!(
+        "{}, the reply has signature `{}`: {:?}",
+        e, signature, items
+    ))
+}
+
+// Resets the idle timer of the screensaver, without arguments
+fn simulate_user_activity_msg() -> dbus::Message {
+    dbus::Message::call_with_args(
+        "org.freedesktop.ScreenSaver",
+        "/org/freedesktop/ScreenSaver",
+        "org.freedesktop.ScreenSaver",
+        "SimulateUserActivity",
+        (),
+    )
+}
+
+// The portal releases the lock when the returned request is closed
+fn close_request_msg(path: &dbus::Path<'static>) -> dbus::Message {
+    dbus::Message::call_with_args(
+        "org.freedesktop.portal.Desktop",
+        path.clone(),
+        "org.freedesktop.portal.Request",
+        "Close",
+        (),
+    )
+}
+
+// Asks whether the lock of `handle` is still held, `None` for the services
+// that cannot tell. Only logind lists the inhibitors themselves, Gnome and
+// org.freedesktop.PowerManagement tell if anything inhibits.
+fn lock_query_msg(handle: &NoSleepHandle) -> Option<dbus::Message> {
+    match handle.api {
+        DBusAPI::LoginApi => Some(list_inhibitors_msg()),
+        DBusAPI::GnomeApi => {
+            // Arguments are
+            // flags:        flags that specify what is inhibited
+            let flags = match handle.nosleep_type {
+                NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep => {
+                    GnomeAPIInhibitFlags::InhibitSuspendSession as u32
+                }
+                NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver => {
+                    GnomeAPIInhibitFlags::InhibitMarkSessionIdle as u32
+                }
+            };
+            Some(dbus::Message::call_with_args(
+                "org.gnome.SessionManager",
+                "/org/gnome/SessionManager",
+                "org.gnome.SessionManager",
+                "IsInhibited",
+                (flags,),
+            ))
+        }
+        DBusAPI::FreeDesktopPowerApi => Some(
+            dbus::Message::new_method_call(
+                "org.freedesktop.PowerManagement",
+                "/org/freedesktop/PowerManagement/Inhibit",
+                "org.freedesktop.PowerManagement.Inhibit",
+                "HasInhibit",
+            )
+            .unwrap(),
+        ),
+        _ => None,
+    }
+}
+
+// Whether the reply to `lock_query_msg` shows the lock of `handle`, taken
+// for `who` by this process
+fn lock_held(
+    handle: &NoSleepHandle,
+    reply: &dbus::Message,
+    who: &str,
+) -> Result<bool, dbus::Error> {
+    match handle.api {
+        DBusAPI::LoginApi => Ok(inhibitors_of(reply, &[who])?
+            .iter()
+            .any(|inhibitor| inhibitor.pid == std::process::id())),
+        _ => reply.read1().map_err(|e| decode_error(reply, e)),
+    }
+}
+
+// The logind inhibitor of `prevent_lid_close_suspend`, a delay would let
+// the lid switch suspend anyway
+fn lid_switch_msg(app_id: &str, reason: &str) -> dbus::Message {
+    logind_inhibit_msg(
+        LogindWhat::HANDLE_LID_SWITCH,
+        LogindMode::Block,
+        app_id,
+        reason,
+    )
+}
+
+// The member names follow the introspection of each service:
+// Gnome uses `Uninhibit`, both FreeDesktop APIs use `UnInhibit`
+fn uninhibit_msg(api: &DBusAPI, handle: u32) -> dbus::Message {
+    match api {
+        DBusAPI::GnomeApi => {
+            // Arguments are
+            // handle:       lock from the inhibit method
+            dbus::Message::call_with_args(
+                "org.gnome.SessionManager",
+                "/org/gnome/SessionManager",
+                "org.gnome.SessionManager",
+                "Uninhibit",
+                (handle,),
+            )
+        }
+        DBusAPI::FreeDesktopPowerApi => dbus::Message::call_with_args(
+            "org.freedesktop.PowerManagement",
+            "/org/freedesktop/PowerManagement/Inhibit",
+            "org.freedesktop.PowerManagement.Inhibit",
+            "UnInhibit",
+            (handle,),
+        ),
+        DBusAPI::FreeDesktopScreenSaverAPI => dbus::Message::call_with_args(
+            "org.freedesktop.ScreenSaver",
+            "/org/freedesktop/ScreenSaver",
+            "org.freedesktop.ScreenSaver",
+            "UnInhibit",
+            (handle,),
+        ),
+        DBusAPI::PortalApi => unreachable!("portal requests are released by close_request_msg"),
+        DBusAPI::LoginApi => unreachable!("logind locks are released by closing the fd"),
+        DBusAPI::Custom => unreachable!("custom calls are built from the CustomInhibitSpec"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_inhibit_gnome_api_message_prevent_display_sleep() {
+        let msg = inhibit_msg(
+            &DBusAPI::GnomeApi,
+            &NoSleepType::PreventUserIdleDisplaySleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!("/org/gnome/SessionManager", &*msg.path().unwrap());
+        assert_eq!("org.gnome.SessionManager", &*msg.interface().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!("org.gnome.SessionManager", &*msg.destination().unwrap());
+        assert_eq!(12, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    }
+
+    #[test]
+    fn test_inhibit_gnome_api_message_allow_suspend() {
+        let msg = inhibit_msg(
+            &DBusAPI::GnomeApi,
+            &NoSleepType::PreventUserIdleDisplaySleep,
+            &InhibitOptions {
+                allow_suspend: true,
+                ..InhibitOptions::default()
+            },
+        );
+        assert_eq!(8, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+
+        // System blocks are about suspend only and keep inhibiting it
+        let msg = inhibit_msg(
+            &DBusAPI::GnomeApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &InhibitOptions {
+                allow_suspend: true,
+                ..InhibitOptions::default()
+            },
+        );
+        assert_eq!(4, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    }
+
+    #[test]
+    fn test_inhibit_gnome_api_message_prevent_system_sleep() {
+        let msg = inhibit_msg(
+            &DBusAPI::GnomeApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!("/org/gnome/SessionManager", &*msg.path().unwrap());
+        assert_eq!("org.gnome.SessionManager", &*msg.interface().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!("org.gnome.SessionManager", &*msg.destination().unwrap());
+        assert_eq!(4, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    }
+
+    #[test]
+    fn test_uninhibit_gnome_api() {
+        let msg = uninhibit_msg(&DBusAPI::GnomeApi, 0);
+        assert_eq!("/org/gnome/SessionManager", &*msg.path().unwrap());
+        assert_eq!("org.gnome.SessionManager", &*msg.interface().unwrap());
+        assert_eq!("Uninhibit", &*msg.member().unwrap());
+        assert_eq!("org.gnome.SessionManager", &*msg.destination().unwrap());
+        assert_eq!(0, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    }
+
+    #[test]
+    fn test_member_names() {
+        // (api, path, interface, inhibit, uninhibit) as introspected on the services
+        let known_good = [
+            (
+                DBusAPI::GnomeApi,
+                "/org/gnome/SessionManager",
+                "org.gnome.SessionManager",
+                "Inhibit",
+                "Uninhibit",
+            ),
+            (
+                DBusAPI::FreeDesktopPowerApi,
+                "/org/freedesktop/PowerManagement/Inhibit",
+                "org.freedesktop.PowerManagement.Inhibit",
+                "Inhibit",
+                "UnInhibit",
+            ),
+            (
+                DBusAPI::FreeDesktopScreenSaverAPI,
+                "/org/freedesktop/ScreenSaver",
+                "org.freedesktop.ScreenSaver",
+                "Inhibit",
+                "UnInhibit",
+            ),
+        ];
+        for (api, path, interface, inhibit, uninhibit) in known_good {
+            let nosleep_type = NoSleepType::PreventUserIdleSystemSleep;
+            for (msg, member) in [
+                (
+                    inhibit_msg(&api, &nosleep_type, &InhibitOptions::default()),
+                    inhibit,
+                ),
+                (uninhibit_msg(&api, 0), uninhibit),
+            ] {
+                assert_eq!(api.name(), &*msg.destination().unwrap());
+                assert_eq!(path, &*msg.path().unwrap());
+                assert_eq!(interface, &*msg.interface().unwrap());
+                assert_eq!(member, &*msg.member().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_inhibit_freedesktop_screen_saver_api() {
+        let msg = inhibit_msg(
+            &DBusAPI::FreeDesktopScreenSaverAPI,
+            &NoSleepType::PreventUserIdleDisplaySleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!("/org/freedesktop/ScreenSaver", &*msg.path().unwrap());
+        assert_eq!("org.freedesktop.ScreenSaver", &*msg.interface().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!("org.freedesktop.ScreenSaver", &*msg.destination().unwrap());
+    }
+
+    #[test]
+    fn test_simulate_user_activity_msg() {
+        let msg = simulate_user_activity_msg();
+        assert_eq!("/org/freedesktop/ScreenSaver", &*msg.path().unwrap());
+        assert_eq!("org.freedesktop.ScreenSaver", &*msg.interface().unwrap());
+        assert_eq!("SimulateUserActivity", &*msg.member().unwrap());
+        assert_eq!("org.freedesktop.ScreenSaver", &*msg.destination().unwrap());
+        assert!(msg.get_items().is_empty());
+    }
+
+    #[test]
+    fn test_uninhibit_freedesktop_screen_saver_api() {
+        let msg = uninhibit_msg(&DBusAPI::FreeDesktopScreenSaverAPI, 0);
+        assert_eq!("/org/freedesktop/ScreenSaver", &*msg.path().unwrap());
+        assert_eq!("org.freedesktop.ScreenSaver", &*msg.interface().unwrap());
+        assert_eq!("UnInhibit", &*msg.member().unwrap());
+        assert_eq!("org.freedesktop.ScreenSaver", &*msg.destination().unwrap());
+        assert_eq!(0, msg.get_items().last().unwrap().inner::<u32>().unwrap());
+    }
+
+    #[test]
+    fn test_freedesktop_power_api() {
+        let msg = inhibit_msg(
+            &DBusAPI::FreeDesktopPowerApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!(
+            "/org/freedesktop/PowerManagement/Inhibit",
+            &*msg.path().unwrap()
+        );
+        assert_eq!(
+            "org.freedesktop.PowerManagement.Inhibit",
+            &*msg.interface().unwrap()
+        );
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!(
+            "org.freedesktop.PowerManagement",
+            &*msg.destination().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_inhibit_portal_api_prevent_display_sleep() {
+        let msg = inhibit_msg(
+            &DBusAPI::PortalApi,
+            &NoSleepType::PreventUserIdleDisplaySleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!("/org/freedesktop/portal/desktop", &*msg.path().unwrap());
+        assert_eq!("org.freedesktop.portal.Inhibit", &*msg.interface().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!(
+            "org.freedesktop.portal.Desktop",
+            &*msg.destination().unwrap()
+        );
+        let (window, flags, options) = msg.read3::<&str, u32, PropMap>().unwrap();
+        assert_eq!("", window);
+        assert_eq!(12, flags);
+        assert_eq!(
+            Some("Power Save Blocker"),
+            options.get("reason").and_then(|reason| reason.0.as_str())
+        );
+    }
+
+    #[test]
+    fn test_inhibit_portal_api_prevent_system_sleep() {
+        let msg = inhibit_msg(
+            &DBusAPI::PortalApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &InhibitOptions::default(),
+        );
+        let (_, flags, _) = msg.read3::<&str, u32, PropMap>().unwrap();
+        assert_eq!(4, flags);
+    }
+
+    #[test]
+    fn test_inhibit_scoped_to_window() {
+        let nosleep_type = NoSleepType::PreventUserIdleDisplaySleep;
+        let msg = inhibit_msg(
+            &DBusAPI::GnomeApi,
+            &nosleep_type,
+            &InhibitOptions {
+                xid: Some(0x2a00007),
+                ..InhibitOptions::default()
+            },
+        );
+        let (_, xid, _, _) = msg.read4::<&str, u32, &str, u32>().unwrap();
+        assert_eq!(0x2a00007, xid);
+        let msg = inhibit_msg(
+            &DBusAPI::PortalApi,
+            &nosleep_type,
+            &InhibitOptions {
+                xid: Some(0x2a00007),
+                ..InhibitOptions::default()
+            },
+        );
+        let (window, _, _) = msg.read3::<&str, u32, PropMap>().unwrap();
+        assert_eq!("x11:2a00007", window);
+    }
+
+    #[test]
+    fn test_inhibit_reason() {
+        let nosleep_type = NoSleepType::PreventUserIdleSystemSleep;
+        let options = InhibitOptions {
+            reason: "Downloading",
+            ..InhibitOptions::default()
+        };
+        let msg = inhibit_msg(&DBusAPI::GnomeApi, &nosleep_type, &options);
+        let (_, _, reason, _) = msg.read4::<&str, u32, &str, u32>().unwrap();
+        assert_eq!("Downloading", reason);
+        let msg = inhibit_msg(&DBusAPI::FreeDesktopPowerApi, &nosleep_type, &options);
+        let (_, reason) = msg.read2::<&str, &str>().unwrap();
+        assert_eq!("Downloading", reason);
+        let msg = inhibit_msg(&DBusAPI::PortalApi, &nosleep_type, &options);
+        let (_, _, vardict) = msg.read3::<&str, u32, PropMap>().unwrap();
+        assert_eq!(
+            Some("Downloading"),
+            vardict.get("reason").and_then(|reason| reason.0.as_str())
+        );
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_set_reason_without_block() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_reason("Downloading").unwrap();
+        assert!(!nosleep.is_blocked());
+        assert_eq!("Downloading", nosleep.inhibit_options(None).reason);
+    }
+
+    // Can only run with an active Gnome Session
+    #[test]
+    #[ignore]
+    fn test_set_reason() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.handles();
+        nosleep.set_reason("Playing a video").unwrap();
+        assert!(nosleep.is_blocked());
+        assert_ne!(handles, nosleep.handles());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_inhibit_error_policy_denied() {
+        for name in POLICY_ERRORS {
+            assert!(matches!(
+                inhibit_error(dbus::Error::new_custom(name, "Denied")),
+                NoSleepError::PolicyDenied { .. }
+            ));
+        }
+        assert!(matches!(
+            inhibit_error(dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.ServiceUnknown",
+                "Not running"
+            )),
+            NoSleepError::PreventSleep { .. }
+        ));
+    }
+
+    #[test]
+    fn test_power_save_mode_is_on() {
+        assert_eq!(Some(true), power_save_mode_is_on(0));
+        assert_eq!(Some(false), power_save_mode_is_on(1));
+        assert_eq!(Some(false), power_save_mode_is_on(3));
+        assert_eq!(None, power_save_mode_is_on(-1));
+    }
+
+    // Can only run with an active Gnome Session
+    #[test]
+    #[ignore]
+    fn test_display_is_on() {
+        let nosleep = NoSleep::new().unwrap();
+        assert_eq!(Some(true), nosleep.display_is_on());
+    }
+
+    #[test]
+    fn test_close_portal_request() {
+        let path = dbus::Path::new("/org/freedesktop/portal/desktop/request/1_1/t").unwrap();
+        let msg = close_request_msg(&path);
+        assert_eq!(
+            "/org/freedesktop/portal/desktop/request/1_1/t",
+            &*msg.path().unwrap()
+        );
+        assert_eq!("org.freedesktop.portal.Request", &*msg.interface().unwrap());
+        assert_eq!("Close", &*msg.member().unwrap());
+        assert_eq!(
+            "org.freedesktop.portal.Desktop",
+            &*msg.destination().unwrap()
+        );
+    }
+
+    // Can only run with an active Gnome Session
+    #[test]
+    #[ignore]
+    fn test_prevent_system_sleep() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2000));
+        nosleep.stop().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2000));
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_new_with_connection() {
+        let d_bus = Connection::new_session().unwrap();
+        let unique_name = d_bus.unique_name().to_string();
+        let nosleep = NoSleep::new_with_connection(d_bus);
+        assert_eq!(unique_name, nosleep.connection().unique_name().to_string());
+        assert!(!nosleep.is_blocked());
+
+        let msg = inhibit_msg(
+            &DBusAPI::GnomeApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_handles() {
+        let nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.handles().is_empty());
+        nosleep.tracked.lock().handles = vec![
+            NoSleepHandle {
+                handle: InhibitHandle::Cookie(1),
+                api: DBusAPI::FreeDesktopScreenSaverAPI,
+                nosleep_type: NoSleepType::PreventUserIdleDisplaySleep,
+                who: None,
+            },
+            NoSleepHandle {
+                handle: InhibitHandle::Cookie(2),
+                api: DBusAPI::FreeDesktopPowerApi,
+                nosleep_type: NoSleepType::PreventUserIdleDisplaySleep,
+                who: None,
+            },
+        ];
+        let handles = nosleep.handles();
+        assert_eq!(2, handles.len());
+        assert_eq!("org.freedesktop.ScreenSaver", handles[0].backend);
+        assert_eq!(1, handles[0].raw);
+        assert_eq!("org.freedesktop.PowerManagement", handles[1].backend);
+        assert_eq!(NoSleepType::PreventUserIdleDisplaySleep, handles[1].kind);
+        // Nothing was actually acquired
+        nosleep.tracked.lock().handles.clear();
+    }
+
+    // A spec calling `Hold` and `Release` on the fake service `name`
+    pub(crate) fn spec(name: &str) -> CustomInhibitSpec {
+        CustomInhibitSpec {
+            destination: name.to_string(),
+            path: format!("/{}", name.replace('.', "/")),
+            interface: name.to_string(),
+            inhibit_method: "Hold".to_string(),
+            uninhibit_method: "Release".to_string(),
+            inhibit_args: vec![],
+        }
+    }
+
+    // The names owned by the live fake services, one service per name
+    static OWNED_NAMES: std::sync::Mutex<Vec<&str>> = std::sync::Mutex::new(vec![]);
+    static NAME_RELEASED: std::sync::Condvar = std::sync::Condvar::new();
+
+    // Answers every method call to `name` on the session bus with a cookie
+    // and records the members that were called. Never takes a name over
+    // from its owner: tests faking the services of the desktop are ignored,
+    // run them on a bus of their own with
+    // `dbus-run-session -- cargo test -- --ignored`.
+    pub(crate) struct FakeService {
+        name: &'static str,
+        calls: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+        done: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        thread: Option<std::thread::JoinHandle<()>>,
+    }
+
+    impl FakeService {
+        pub(crate) fn start(name: &'static str) -> FakeService {
+            FakeService::start_with_reply(name, |msg| msg.method_return().append1(7u32))
+        }
+
+        // Answers every call with `reply`
+        pub(crate) fn start_with_reply(
+            name: &'static str,
+            reply: fn(&dbus::Message) -> dbus::Message,
+        ) -> FakeService {
+            use dbus::channel::MatchingReceiver;
+            use dbus::message::MatchRule;
+            use std::sync::atomic::Ordering;
+
+            // Tests faking the same service take turns
+            let mut owned = OWNED_NAMES.lock().unwrap();
+            while owned.contains(&name) {
+                owned = NAME_RELEASED.wait(owned).unwrap();
+            }
+            owned.push(name);
+            drop(owned);
+
+            let calls = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+            let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+            let thread = {
+                let calls = calls.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let conn = Connection::new_session().unwrap();
+                    let owner = conn.request_name(name, false, false, true).unwrap();
+                    assert_eq!(
+                        dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply::PrimaryOwner,
+                        owner,
+                        "{name} is owned already"
+                    );
+                    conn.start_receive(
+                        MatchRule::new_method_call(),
+                        Box::new(move |msg, conn| {
+                            calls
+                                .lock()
+                                .unwrap()
+                                .push(msg.member().unwrap().to_string());
+                            let _ = conn.channel().send(reply(&msg));
+                            true
+                        }),
+                    );
+                    ready_tx.send(()).unwrap();
+                    while !done.load(Ordering::SeqCst) {
+                        conn.process(std::time::Duration::from_millis(20)).unwrap();
+                    }
+                })
+            };
+            ready_rx.recv().unwrap();
+            FakeService {
+                name,
+                calls,
+                done,
+                thread: Some(thread),
+            }
+        }
+
+        pub(crate) fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for FakeService {
+        fn drop(&mut self) {
+            self.done.store(true, std::sync::atomic::Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+            let mut owned = OWNED_NAMES.lock().unwrap_or_else(|e| e.into_inner());
+            owned.retain(|name| *name != self.name);
+            NAME_RELEASED.notify_all();
+        }
+    }
+
+    // A reply to an inhibit call of any service, see `inhibit_handle`
+    fn inhibit_reply(append: impl FnOnce(dbus::Message) -> dbus::Message) -> dbus::Message {
+        let mut call = simulate_user_activity_msg();
+        call.set_serial(1);
+        append(call.method_return())
+    }
+
+    #[test]
+    fn test_inhibit_handle() {
+        let apis = [
+            DBusAPI::GnomeApi,
+            DBusAPI::FreeDesktopPowerApi,
+            DBusAPI::FreeDesktopScreenSaverAPI,
+            DBusAPI::Custom,
+        ];
+        for api in &apis {
+            let reply = inhibit_reply(|reply| reply.append1(7u32));
+            assert_eq!(
+                InhibitHandle::Cookie(7),
+                inhibit_handle(api, &reply).unwrap()
+            );
+            let reply = inhibit_reply(|reply| reply.append1(-2i32));
+            assert_eq!(
+                InhibitHandle::Cookie(0xfffffffe),
+                inhibit_handle(api, &reply).unwrap()
+            );
+        }
+        let path = dbus::Path::from("/org/freedesktop/portal/desktop/request/1_1/t");
+        let reply = inhibit_reply(|reply| reply.append1(path.clone()));
+        assert_eq!(
+            InhibitHandle::Request(path.clone()),
+            inhibit_handle(&DBusAPI::PortalApi, &reply).unwrap()
+        );
+        for api in [DBusAPI::LoginApi, DBusAPI::GnomeApi] {
+            let fd = std::os::unix::io::IntoRawFd::into_raw_fd(
+                std::fs::File::open("/dev/null").unwrap(),
+            );
+            let reply = inhibit_reply(|reply| reply.append1(unsafe { OwnedFd::new(fd) }));
+            assert!(matches!(
+                inhibit_handle(&api, &reply),
+                Ok(InhibitHandle::Fd(_))
+            ));
+        }
+        // Only the portal hands out requests that can be closed
+        let reply = inhibit_reply(|reply| reply.append1(path));
+        let e = inhibit_handle(&DBusAPI::GnomeApi, &reply).unwrap_err();
+        assert!(e.message().unwrap().contains("signature `o`"));
+        let reply = inhibit_reply(|reply| reply);
+        for api in apis.iter().chain(&[DBusAPI::PortalApi, DBusAPI::LoginApi]) {
+            assert_eq!(
+                InhibitHandle::Connection,
+                inhibit_handle(api, &reply).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let reply = inhibit_reply(|reply| reply.append2("cookie", 7u32));
+        let e = reply.read1::<u32>().unwrap_err();
+        let message = decode_error(&reply, e).message().unwrap().to_string();
+        assert!(message.contains("signature `su`"), "{}", message);
+        assert!(message.contains("\"cookie\""), "{}", message);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_inhibit_without_handle() {
+        let service =
+            FakeService::start_with_reply("org.example.Handleless", |msg| msg.method_return());
+        let spec = spec("org.example.Handleless");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventUserIdleDisplaySleep,
+            ])
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(0, nosleep.handles()[0].raw);
+        // Would release the other lock as well
+        assert!(matches!(
+            nosleep.stop_type(NoSleepType::PreventUserIdleDisplaySleep),
+            Err(NoSleepError::StopLock { .. })
+        ));
+        assert_eq!(2, nosleep.handles().len());
+        let unique_name = nosleep.connection().unique_name().to_string();
+        nosleep.stop().unwrap();
+        // Released by closing the connection instead
+        assert_eq!(vec!["Hold", "Hold"], service.calls());
+        assert_ne!(unique_name, nosleep.connection().unique_name().to_string());
+        assert!(!nosleep.stray_locks.get());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_inhibit_unusable_handle() {
+        let service = FakeService::start_with_reply("org.example.Unusable", |msg| {
+            msg.method_return().append1("cookie")
+        });
+        let spec = spec("org.example.Unusable");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        let unique_name = nosleep.connection().unique_name().to_string();
+        assert!(nosleep.prevent_system_sleep().is_err());
+        assert!(!nosleep.is_blocked());
+        assert_eq!(vec!["Hold"], service.calls());
+        // Whatever the service holds is released by closing the connection
+        assert_ne!(unique_name, nosleep.connection().unique_name().to_string());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_stop_released_lock() {
+        // Forgot the lock on a restart
+        let service = FakeService::start_with_reply("org.example.Restarted", |msg| {
+            match msg.member().as_deref() {
+                Some("Hold") => msg.method_return().append1(7u32),
+                _ => msg.error(
+                    &"org.gnome.SessionManager.GeneralError".into(),
+                    c"Unable to uninhibit: Invalid cookie",
+                ),
+            }
+        });
+        let spec = spec("org.example.Restarted");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+        assert_eq!(vec!["Hold", "Release"], service.calls());
+    }
+
+    #[test]
+    fn test_is_released() {
+        let cookie = NoSleepHandle {
+            handle: InhibitHandle::Cookie(1),
+            api: DBusAPI::GnomeApi,
+            nosleep_type: NoSleepType::PreventUserIdleDisplaySleep,
+            who: None,
+        };
+        let request = NoSleepHandle {
+            handle: InhibitHandle::Request(dbus::Path::from("/org/freedesktop/portal/desktop/1")),
+            api: DBusAPI::PortalApi,
+            nosleep_type: NoSleepType::PreventUserIdleDisplaySleep,
+            who: None,
+        };
+        let error = |name: &str, message: &str| dbus::Error::new_custom(name, message);
+        for (handle, e, released) in [
+            (
+                &cookie,
+                error("org.freedesktop.DBus.Error.ServiceUnknown", "gone"),
+                true,
+            ),
+            (
+                &cookie,
+                error("org.gnome.SessionManager.GeneralError", "Invalid cookie"),
+                true,
+            ),
+            (
+                &cookie,
+                error("org.gnome.SessionManager.GeneralError", "Out of memory"),
+                false,
+            ),
+            (
+                &request,
+                error(
+                    "org.freedesktop.DBus.Error.UnknownMethod",
+                    "No such interface",
+                ),
+                true,
+            ),
+            (
+                &cookie,
+                error("org.freedesktop.DBus.Error.UnknownMethod", "No such method"),
+                false,
+            ),
+            (
+                &cookie,
+                error("org.freedesktop.DBus.Error.AccessDenied", "denied"),
+                false,
+            ),
+        ] {
+            assert_eq!(released, is_released(handle, &e), "{:?}", e);
+        }
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_with_custom_inhibit() {
+        use dbus::arg::messageitem::MessageItem;
+
+        let service = FakeService::start("org.example.Inhibitor");
+        let spec = CustomInhibitSpec {
+            inhibit_args: vec![MessageItem::Str("Power Save Blocker".to_string())],
+            ..spec("org.example.Inhibitor")
+        };
+        let mut nosleep = NoSleep::with_custom_inhibit(spec.clone()).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!("custom", handles[0].backend);
+        assert_eq!(7, handles[0].raw);
+        nosleep.stop().unwrap();
+        assert_eq!(vec!["Hold", "Release"], service.calls());
+
+        let invalid = CustomInhibitSpec {
+            inhibit_method: "not a method".to_string(),
+            ..spec
+        };
+        assert!(matches!(
+            NoSleep::with_custom_inhibit(invalid),
+            Err(NoSleepError::Init { .. })
+        ));
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_stop_all() {
+        use dbus::arg::messageitem::MessageItem;
+
+        let service = FakeService::start("org.example.StopAll");
+        let spec = CustomInhibitSpec {
+            inhibit_args: vec![MessageItem::Str("Power Save Blocker".to_string())],
+            ..spec("org.example.StopAll")
+        };
+        let mut first = NoSleep::with_custom_inhibit(spec.clone()).unwrap();
+        let mut second = NoSleep::with_custom_inhibit(spec).unwrap();
+        first.prevent_display_sleep().unwrap();
+        second.prevent_system_sleep().unwrap();
+        let registered = REGISTRY.live();
+        assert!(registered.contains(&first.tracked));
+        assert!(registered.contains(&second.tracked));
+
+        // Only these two, `stop_all` would also release the blocks of other tests
+        NoSleep::release_all(vec![first.tracked.clone(), second.tracked.clone()]).unwrap();
+        assert!(!first.is_blocked());
+        assert!(!second.is_blocked());
+        assert_eq!(vec!["Hold", "Hold", "Release", "Release"], service.calls());
+        // Nothing is released twice
+        first.stop().unwrap();
+        second.stop().unwrap();
+        assert_eq!(4, service.calls().len());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_on_revoked() {
+        let service = FakeService::start("org.example.Revoked");
+        let spec = spec("org.example.Revoked");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        let (revoked_tx, revoked_rx) = std::sync::mpsc::channel();
+        nosleep
+            .on_revoked(Box::new(move || revoked_tx.send(()).unwrap()))
+            .unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let events = nosleep.subscribe();
+
+        // The service exits, taking the lock with it
+        drop(service);
+        revoked_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+        // Nothing left to release
+        nosleep.stop().unwrap();
+        let received = events.try_iter().collect::<Vec<_>>();
+        assert!(matches!(received[..], [StateEvent::Revoked { .. }]));
+    }
+
+    // Whether the fake org.freedesktop.PowerManagement of
+    // `test_reacquire_on_resume` still holds the lock
+    static HAS_INHIBIT: AtomicBool = AtomicBool::new(true);
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_reacquire_on_resume() {
+        use crate::sleep_watch::tests::prepare_for_sleep;
+
+        let service = FakeService::start_with_reply("org.freedesktop.PowerManagement", |msg| {
+            match &*msg.member().unwrap() {
+                "HasInhibit" => msg
+                    .method_return()
+                    .append1(HAS_INHIBIT.load(Ordering::SeqCst)),
+                _ => msg.method_return().append1(7u32),
+            }
+        });
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::FreeDesktopPowerApi]).unwrap();
+        // Watches the session bus, where the test sends `PrepareForSleep`
+        nosleep.reacquire_watch = Some(
+            SleepWatch::on_resume_with(Connection::new_session, None, nosleep.reacquire_callback())
+                .unwrap(),
+        );
+        nosleep.prevent_system_sleep().unwrap();
+        let resume = |nosleep: &mut NoSleep| {
+            prepare_for_sleep(false);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !nosleep.resumed.load(Ordering::SeqCst) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            nosleep.dispatch().unwrap();
+        };
+
+        // Still held, not acquired again
+        prepare_for_sleep(true);
+        resume(&mut nosleep);
+        assert_eq!(vec!["Inhibit", "HasInhibit"], service.calls());
+
+        // Dropped across the suspend, acquired again on the same connection
+        HAS_INHIBIT.store(false, Ordering::SeqCst);
+        prepare_for_sleep(true);
+        resume(&mut nosleep);
+        assert_eq!(
+            vec!["Inhibit", "HasInhibit", "HasInhibit", "Inhibit"],
+            service.calls()
+        );
+        assert_eq!(1, nosleep.handles().len());
+
+        // Stopped while asleep, not acquired again on resume
+        prepare_for_sleep(true);
+        nosleep.stop().unwrap();
+        resume(&mut nosleep);
+        assert_eq!(
+            vec![
+                "Inhibit",
+                "HasInhibit",
+                "HasInhibit",
+                "Inhibit",
+                "UnInhibit"
+            ],
+            service.calls()
+        );
+        assert!(nosleep.reacquire_watch.is_some());
+    }
+
+    #[test]
+    fn test_lock_query_msg() {
+        let handle = |api, nosleep_type| NoSleepHandle {
+            handle: InhibitHandle::Cookie(7),
+            api,
+            nosleep_type,
+            who: None,
+        };
+        let msg = lock_query_msg(&handle(
+            DBusAPI::GnomeApi,
+            NoSleepType::PreventUserIdleSystemSleep,
+        ))
+        .unwrap();
+        assert_eq!("IsInhibited", &*msg.member().unwrap());
+        assert_eq!(4, msg.get_items()[0].inner::<u32>().unwrap());
+        let msg = lock_query_msg(&handle(
+            DBusAPI::FreeDesktopPowerApi,
+            NoSleepType::PreventUserIdleSystemSleep,
+        ))
+        .unwrap();
+        assert_eq!("HasInhibit", &*msg.member().unwrap());
+        let msg = lock_query_msg(&handle(
+            DBusAPI::LoginApi,
+            NoSleepType::PreventUserIdleSystemSleep,
+        ))
+        .unwrap();
+        assert_eq!("ListInhibitors", &*msg.member().unwrap());
+        // Cannot tell, assumed to be held
+        assert!(lock_query_msg(&handle(
+            DBusAPI::FreeDesktopScreenSaverAPI,
+            NoSleepType::PreventUserIdleDisplaySleep,
+        ))
+        .is_none());
+    }
+
+    #[test]
+    fn test_lock_held() {
+        let handle = NoSleepHandle {
+            handle: InhibitHandle::Connection,
+            api: DBusAPI::LoginApi,
+            nosleep_type: NoSleepType::PreventUserIdleSystemSleep,
+            who: None,
+        };
+        let inhibitor = |who: &str, pid: u32| {
+            (
+                "sleep".to_string(),
+                who.to_string(),
+                "Test".to_string(),
+                "block".to_string(),
+                1000u32,
+                pid,
+            )
+        };
+        let reply = inhibit_reply(|reply| {
+            reply.append1(vec![
+                inhibitor("other", std::process::id()),
+                inhibitor("nosleep", 1),
+            ])
+        });
+        assert!(!lock_held(&handle, &reply, "nosleep").unwrap());
+        let reply =
+            inhibit_reply(|reply| reply.append1(vec![inhibitor("nosleep", std::process::id())]));
+        assert!(lock_held(&handle, &reply, "nosleep").unwrap());
+
+        let handle = NoSleepHandle {
+            api: DBusAPI::FreeDesktopPowerApi,
+            ..handle
+        };
+        let reply = inhibit_reply(|reply| reply.append1(false));
+        assert!(!lock_held(&handle, &reply, "nosleep").unwrap());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_connection_fd() {
+        let nosleep = NoSleep::new().unwrap();
+        let fd = nosleep.connection_fd().unwrap();
+        assert!(fd >= 0);
+        // The connection of another instance, pooled ones are in use
+        let other = NoSleep::new().unwrap();
+        assert_ne!(Some(fd), other.connection_fd());
+        let nosleep = NoSleep::new_with_connection(Connection::new_session().unwrap());
+        assert_eq!(None, nosleep.connection_fd());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_on_revoked_event_loop() {
+        let service = FakeService::start("org.example.Loop");
+        let spec = spec("org.example.Loop");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep.set_event_loop(true);
+        let (revoked_tx, revoked_rx) = std::sync::mpsc::channel();
+        nosleep
+            .on_revoked(Box::new(move || revoked_tx.send(()).unwrap()))
+            .unwrap();
+        assert!(nosleep.revoke_watch.is_none());
+        nosleep.prevent_display_sleep().unwrap();
+
+        // The service exits, the loop picks up the signal
+        drop(service);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while revoked_rx.try_recv().is_err() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+            nosleep.dispatch().unwrap();
+        }
+        assert!(!nosleep.is_blocked());
+        nosleep.stop().unwrap();
+        assert!(nosleep.loop_revoke_watch.is_none());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_subscribe() {
+        let service = FakeService::start("org.example.Events");
+        let spec = spec("org.example.Events");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        let first = nosleep.subscribe();
+        let second = nosleep.subscribe();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        nosleep.stop().unwrap();
+        drop(service);
+
+        for events in [first, second] {
+            let received = events.try_iter().collect::<Vec<_>>();
+            assert!(matches!(
+                received[..],
+                [
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleDisplaySleep,
+                        ..
+                    },
+                    StateEvent::Switched {
+                        from: NoSleepType::PreventUserIdleDisplaySleep,
+                        to: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                ]
+            ));
+        }
+    }
+
+    // The only test that sets NOSLEEP_* variables, they are process wide
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_from_env() {
+        std::env::set_var("NOSLEEP_BACKEND", "portal,org.freedesktop.PowerManagement");
+        std::env::set_var("NOSLEEP_REASON", "Nightly backup");
+        std::env::set_var("NOSLEEP_APP_ID", "org.example.Backup");
+        std::env::set_var("NOSLEEP_DBUS_TIMEOUT_MS", "250");
+        let nosleep = NoSleep::from_env().unwrap();
+        assert_eq!(
+            vec![DBusAPI::PortalApi, DBusAPI::FreeDesktopPowerApi],
+            nosleep.backend_order
+        );
+        assert_eq!("Nightly backup", nosleep.reason);
+        assert_eq!("org.example.Backup", nosleep.app_id);
+        assert_eq!(Duration::from_millis(250), nosleep.call_timeout);
+        drop(nosleep);
+
+        std::env::set_var("NOSLEEP_BACKEND", "kde");
+        assert!(matches!(
+            NoSleep::from_env(),
+            Err(NoSleepError::Init { .. })
+        ));
+        std::env::remove_var("NOSLEEP_BACKEND");
+        std::env::set_var("NOSLEEP_DBUS_TIMEOUT_MS", "soon");
+        assert!(matches!(
+            NoSleep::from_env(),
+            Err(NoSleepError::Init { .. })
+        ));
+
+        for name in [
+            "NOSLEEP_REASON",
+            "NOSLEEP_APP_ID",
+            "NOSLEEP_DBUS_TIMEOUT_MS",
+        ] {
+            std::env::remove_var(name);
+        }
+        let nosleep = NoSleep::from_env().unwrap();
+        assert_eq!(DEFAULT_REASON, nosleep.reason);
+        assert_eq!(DEFAULT_APP_ID, nosleep.app_id);
+        assert_eq!(CALL_TIMEOUT, nosleep.call_timeout);
+    }
+
+    #[test]
+    fn test_inhibit_msg_app_id() {
+        let options = InhibitOptions {
+            app_id: "org.example.Backup",
+            ..InhibitOptions::default()
+        };
+        let msg = inhibit_msg(
+            &DBusAPI::FreeDesktopPowerApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &options,
+        );
+        let (app_id, reason) = msg.read2::<&str, &str>().unwrap();
+        assert_eq!("org.example.Backup", app_id);
+        assert_eq!(DEFAULT_REASON, reason);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_inhibit_msg_who() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        nosleep.set_app_id("org.example.Service");
+        // No backend to acquire it with, the attribution is set either way
+        assert!(nosleep
+            .prevent_sleep_as(
+                NoSleepType::PreventUserIdleSystemSleep,
+                "org.example.Client"
+            )
+            .is_err());
+        for api in [DBusAPI::GnomeApi, DBusAPI::FreeDesktopPowerApi] {
+            let msg = inhibit_msg(
+                &api,
+                &NoSleepType::PreventUserIdleSystemSleep,
+                &nosleep.inhibit_options(nosleep.who.as_deref()),
+            );
+            assert_eq!("org.example.Client", msg.read1::<&str>().unwrap());
+        }
+        let msg = logind_inhibit_msg(
+            LogindWhat::SLEEP,
+            LogindMode::Block,
+            nosleep.who.as_deref().unwrap(),
+            &nosleep.reason,
+        );
+        let (_, who) = msg.read2::<&str, &str>().unwrap();
+        assert_eq!("org.example.Client", who);
+
+        assert!(nosleep.prevent_system_sleep().is_err());
+        assert_eq!(None, nosleep.who);
+        assert_eq!("org.example.Service", nosleep.inhibit_options(None).app_id);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_prevent_sleep_as() {
+        let service = FakeService::start("org.example.Tenant");
+        let spec = spec("org.example.Tenant");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep
+            .prevent_sleep_as(NoSleepType::PreventUserIdleSystemSleep, "client-1")
+            .unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!(Some("client-1"), handles[0].who.as_deref());
+        // Re-acquired for the same client
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        assert_eq!(Some("client-1"), nosleep.handles()[0].who.as_deref());
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(None, nosleep.handles()[0].who);
+
+        // Covered by the block held, but acquired again for another client
+        nosleep.set_switch_policy(SwitchPolicy::KeepStrongest);
+        nosleep
+            .prevent_sleep_as(NoSleepType::PreventUserIdleSystemSleep, "client-2")
+            .unwrap();
+        assert_eq!(Some("client-2"), nosleep.handles()[0].who.as_deref());
+        nosleep
+            .prevent_sleep_as(NoSleepType::PreventUserIdleSystemSleep, "client-2")
+            .unwrap();
+        nosleep.stop().unwrap();
+        assert_eq!(
+            vec!["Hold", "Release", "Hold", "Release", "Hold", "Release", "Hold", "Release"],
+            service.calls()
+        );
+    }
+
+    #[test]
+    fn test_inhibit_msg_level() {
+        let high = InhibitOptions {
+            level: BlockLevel::High,
+            ..InhibitOptions::default()
+        };
+        for (options, gnome_flags, portal_flags) in [
+            (InhibitOptions::default(), 4, 4),
+            // Logging out is inhibited as well
+            (high, 1 | 4, 1 | 4),
+        ] {
+            let msg = inhibit_msg(
+                &DBusAPI::GnomeApi,
+                &NoSleepType::PreventUserIdleSystemSleep,
+                &options,
+            );
+            let (_, _, _, flags) = msg.read4::<&str, u32, &str, u32>().unwrap();
+            assert_eq!(gnome_flags, flags);
+            let msg = inhibit_msg(
+                &DBusAPI::PortalApi,
+                &NoSleepType::PreventUserIdleSystemSleep,
+                &options,
+            );
+            let (_, flags) = msg.read2::<&str, u32>().unwrap();
+            assert_eq!(portal_flags, flags);
+        }
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_level_logind_mode() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        nosleep.set_logind_mode(LogindMode::Delay);
+        assert_eq!(LogindMode::Delay, nosleep.effective_logind_mode());
+        // No backend to acquire it with, the level is set either way
+        assert!(nosleep
+            .prevent_sleep_with_level(NoSleepType::PreventUserIdleSystemSleep, BlockLevel::High)
+            .is_err());
+        assert_eq!(LogindMode::Block, nosleep.effective_logind_mode());
+        assert!(nosleep.prevent_system_sleep().is_err());
+        assert_eq!(BlockLevel::Normal, nosleep.level);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_health_check() {
+        let _service = FakeService::start("org.example.Health");
+        let spec = spec("org.example.Health");
+        let nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        let report = nosleep.health_check();
+        assert!(report.platform.starts_with("linux"));
+        assert_eq!(6, report.backends.len());
+        assert!(report
+            .backends
+            .iter()
+            .any(|probe| probe.name == "org.example.Health" && probe.available));
+        assert_eq!(Some("org.example.Health"), report.selected.as_deref());
+        assert_eq!(Some(CALL_TIMEOUT), report.timeout);
+        assert!(report.to_string().contains("org.example.Health: available"));
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_wait_for_backend() {
+        let spec = spec("org.example.Late");
+        let nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        assert!(matches!(
+            nosleep.wait_for_backend(Duration::from_millis(150)),
+            Err(NoSleepError::Init { .. })
+        ));
+
+        // Registers on the bus a while after the wait started
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let late = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            let _service = FakeService::start("org.example.Late");
+            let _ = done_rx.recv();
+        });
+        assert_eq!(
+            DBusAPI::Custom,
+            nosleep.wait_for_backend(Duration::from_secs(5)).unwrap()
+        );
+        assert_eq!(None, nosleep.active_backend());
+        drop(done_tx);
+        late.join().unwrap();
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_probe_and_select() {
+        let custom = FakeService::start("org.example.Probed");
+        let spec = spec("org.example.Probed");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep.set_backend_order(&[DBusAPI::PortalApi, DBusAPI::Custom]);
+        assert_eq!(None, nosleep.active_backend());
+        assert_eq!(DBusAPI::Custom, nosleep.probe_and_select().unwrap());
+        assert_eq!(Some(DBusAPI::Custom), nosleep.active_backend());
+
+        // Not probed again while the selected backend works
+        let portal = FakeService::start_with_reply("org.freedesktop.portal.Desktop", |msg| {
+            msg.method_return().append1(dbus::Path::from(
+                "/org/freedesktop/portal/desktop/request/1_1/t",
+            ))
+        });
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.stop().unwrap();
+        assert_eq!(vec!["Hold", "Release"], custom.calls());
+        assert!(portal.calls().is_empty());
+        assert_eq!(Some(DBusAPI::Custom), nosleep.active_backend());
+
+        // The selected backend is gone, the next one that works is selected
+        drop(custom);
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(Some(DBusAPI::PortalApi), nosleep.active_backend());
+        assert_eq!(
+            "org.freedesktop.portal.Desktop",
+            nosleep.handles()[0].backend
+        );
+        nosleep.stop().unwrap();
+
+        nosleep.set_backend_order(&[DBusAPI::Custom]);
+        assert_eq!(None, nosleep.active_backend());
+        assert!(matches!(
+            nosleep.probe_and_select(),
+            Err(NoSleepError::Init { .. })
+        ));
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_available() {
+        let _screensaver = FakeService::start("org.freedesktop.ScreenSaver");
+        assert!(available());
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_prevent_multiple_types() {
+        let power = FakeService::start("org.freedesktop.PowerManagement");
+        let screensaver = FakeService::start("org.freedesktop.ScreenSaver");
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::FreeDesktopPowerApi]).unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventScreenSaver,
+            ])
+            .unwrap();
+        assert_eq!(2, nosleep.handles().len());
+        assert_eq!(
+            vec![
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventScreenSaver
+            ],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+        assert!(nosleep.handles().is_empty());
+        assert_eq!(vec!["Inhibit", "UnInhibit"], power.calls());
+        assert_eq!(vec!["Inhibit", "UnInhibit"], screensaver.calls());
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_stop_type() {
+        let power = FakeService::start("org.freedesktop.PowerManagement");
+        let screensaver = FakeService::start("org.freedesktop.ScreenSaver");
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::FreeDesktopPowerApi]).unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventScreenSaver,
+            ])
+            .unwrap();
+        nosleep.stop_type(NoSleepType::PreventScreenSaver).unwrap();
+        assert_eq!(vec!["Inhibit", "UnInhibit"], screensaver.calls());
+        assert_eq!(vec!["Inhibit"], power.calls());
+        assert_eq!(1, nosleep.handles().len());
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types
+        );
+        assert!(nosleep.is_blocked());
+
+        // Not held, nothing to release
+        nosleep.stop_type(NoSleepType::PreventScreenSaver).unwrap();
+        assert_eq!(vec!["Inhibit", "UnInhibit"], screensaver.calls());
+
+        // The last type releases the block
+        nosleep
+            .stop_type(NoSleepType::PreventUserIdleSystemSleep)
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+        assert_eq!(vec!["Inhibit", "UnInhibit"], power.calls());
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_prevent_display_sleep_or_degrade() {
+        // The screensaver API that display blocks need is not available
+        let power = FakeService::start("org.freedesktop.PowerManagement");
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::FreeDesktopPowerApi]).unwrap();
+        nosleep.set_display_fallback_threshold(Some(2));
+        assert!(nosleep.prevent_display_sleep_or_degrade().is_err());
+        assert!(!nosleep.is_blocked());
+        assert_eq!(
+            BlockOutcome::SystemOnly,
+            nosleep.prevent_display_sleep_or_degrade().unwrap()
         );
         assert_eq!(
-            "org.freedesktop.PowerManagement.Inhibit",
-            &*msg.interface().unwrap()
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types
         );
-        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!(vec!["Inhibit"], power.calls());
+        // Still degraded on the next call
         assert_eq!(
-            "org.freedesktop.PowerManagement",
-            &*msg.destination().unwrap()
+            BlockOutcome::SystemOnly,
+            nosleep.prevent_display_sleep_or_degrade().unwrap()
+        );
+        nosleep.stop().unwrap();
+        assert_eq!(
+            2,
+            nosleep
+                .clone_config()
+                .unwrap()
+                .display_fallback
+                .threshold()
+                .unwrap()
+        );
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_prevent_display_sleep_or_degrade_denied() {
+        let _service = FakeService::start_with_reply("org.example.Denied", |msg| {
+            msg.error(&"org.freedesktop.DBus.Error.AccessDenied".into(), c"denied")
+        });
+        let mut nosleep = NoSleep::with_custom_inhibit(spec("org.example.Denied")).unwrap();
+        nosleep.set_display_fallback_threshold(Some(1));
+        assert!(matches!(
+            nosleep.prevent_display_sleep_or_degrade(),
+            Err(NoSleepError::PolicyDenied { .. })
+        ));
+        assert!(!nosleep.is_blocked());
+        assert_eq!(0, nosleep.display_fallback.failures());
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_prevent_multiple_types_rollback() {
+        // The screensaver API is not available
+        let power = FakeService::start("org.freedesktop.PowerManagement");
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::FreeDesktopPowerApi]).unwrap();
+        assert!(matches!(
+            nosleep.prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventScreenSaver,
+            ]),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.nosleep_types.is_empty());
+        assert_eq!(vec!["Inhibit", "UnInhibit"], power.calls());
+    }
+
+    // A display block held through Gnome, without a session manager
+    fn fake_display_block(nosleep: &mut NoSleep) {
+        nosleep.tracked.lock().handles = vec![NoSleepHandle {
+            handle: InhibitHandle::Cookie(1),
+            api: DBusAPI::GnomeApi,
+            nosleep_type: NoSleepType::PreventUserIdleDisplaySleep,
+            who: None,
+        }];
+        nosleep.nosleep_types = vec![NoSleepType::PreventUserIdleDisplaySleep];
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_switch_policy_keep_strongest() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::KeepStrongest);
+        fake_display_block(&mut nosleep);
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        assert_eq!(1, nosleep.handles().len());
+        nosleep.tracked.lock().handles.clear();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_switch_policy_reject() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::Reject);
+        fake_display_block(&mut nosleep);
+        assert!(matches!(
+            nosleep.prevent_system_sleep(),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
         );
+        assert_eq!(1, nosleep.handles().len());
+        nosleep.tracked.lock().handles.clear();
     }
 
     // Can only run with an active Gnome Session
     #[test]
     #[ignore]
-    fn test_prevent_system_sleep() {
+    fn test_switch_policy_replace() {
         let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::Replace);
+        nosleep.prevent_display_sleep().unwrap();
         nosleep.prevent_system_sleep().unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(2000));
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types
+        );
         nosleep.stop().unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(2000));
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_stop_clears_handles_on_failure() {
+        let _service = FakeService::start_with_reply("org.example.Failing", |msg| {
+            match msg.member().as_deref() {
+                Some("Hold") => msg.method_return().append1(7u32),
+                _ => msg.error(
+                    &"org.freedesktop.DBus.Error.Failed".into(),
+                    c"Release failed",
+                ),
+            }
+        });
+        let spec = spec("org.example.Failing");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(matches!(nosleep.stop(), Err(NoSleepError::StopLock { .. })));
+        assert!(nosleep.tracked.lock().handles.is_empty());
+        assert!(!nosleep.is_blocked());
+        // Reacquiring does not trip over the stale handle again
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(1, nosleep.handles().len());
+        nosleep.tracked.lock().handles.clear();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_handles_after_prevent_display_sleep() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.handles();
+        assert!(!handles.is_empty());
+        assert!(handles
+            .iter()
+            .all(|handle| handle.kind == NoSleepType::PreventUserIdleDisplaySleep));
+        nosleep.stop().unwrap();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_resume_without_suspend() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.resume().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_prevent_display_sleep_until_past_deadline() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() - std::time::Duration::from_millis(10))
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_prevent_display_sleep_until_config() {
+        let service = FakeService::start("org.example.Until");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec("org.example.Until")).unwrap();
+        let started = Instant::now();
+        nosleep
+            .prevent_display_sleep_until(started + Duration::from_millis(200))
+            .unwrap();
+        // Held by a copy with the same configuration
+        assert!(nosleep.is_blocked());
+        assert_eq!(vec!["Hold"], service.calls());
+        while nosleep.is_blocked() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(vec!["Hold", "Release"], service.calls());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_prevent_display_sleep_until() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() + std::time::Duration::from_millis(500))
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        assert!(!nosleep.is_blocked());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_suspend_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        assert!(!nosleep.is_blocked());
+        nosleep.resume().unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_stop_after_suspend() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        nosleep.stop().unwrap();
+        nosleep.resume().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_default_backend_order() {
+        let nosleep = NoSleep::new().unwrap();
+        assert_eq!(default_backend_order(), nosleep.backend_order);
+        let display = NoSleepType::PreventUserIdleDisplaySleep;
+        assert_eq!(
+            vec![
+                DBusAPI::FreeDesktopScreenSaverAPI,
+                DBusAPI::FreeDesktopPowerApi
+            ],
+            DBusAPI::FreeDesktopPowerApi.apis(display)
+        );
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_custom_backend_order() {
+        let nosleep =
+            NoSleep::with_backend_order(&[DBusAPI::PortalApi, DBusAPI::GnomeApi]).unwrap();
+        let nosleep_type = NoSleepType::PreventUserIdleSystemSleep;
+        let first = nosleep.backend_order[0].apis(nosleep_type)[0];
+        let msg = inhibit_msg(&first, &nosleep_type, &InhibitOptions::default());
+        assert_eq!("org.freedesktop.portal.Inhibit", &*msg.interface().unwrap());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_capabilities() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_backend_order(&[DBusAPI::GnomeApi, DBusAPI::FreeDesktopPowerApi]);
+        let capabilities = nosleep.capabilities();
+        assert!(capabilities.display_sleep);
+        assert!(capabilities.system_sleep);
+        assert!(capabilities.display_only);
+        assert!(capabilities.screensaver);
+        assert!(capabilities.timed_blocks);
+        assert!(!capabilities.battery_aware);
+
+        nosleep.set_backend_order(&[DBusAPI::FreeDesktopPowerApi, DBusAPI::PortalApi]);
+        let capabilities = nosleep.capabilities();
+        assert!(capabilities.system_sleep);
+        assert!(!capabilities.display_only);
+
+        nosleep.set_backend_order(&[DBusAPI::FreeDesktopScreenSaverAPI]);
+        let capabilities = nosleep.capabilities();
+        assert!(capabilities.display_sleep);
+        assert!(!capabilities.system_sleep);
+        assert!(capabilities.display_only);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_supported_types() {
+        assert!(!SUPPORTED_TYPES.contains(&NoSleepType::PreventSystemSleep));
+        // Aliased types are accepted too, but not listed
+        let nosleep = NoSleep::new().unwrap();
+        for nosleep_type in SUPPORTED_TYPES {
+            assert!(
+                nosleep.is_supported_type(*nosleep_type),
+                "{:?}",
+                nosleep_type
+            );
+        }
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_is_supported_type() {
+        let mut nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleDisplaySleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleSystemSleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventSystemSleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventScreenSaver));
+
+        // The screensaver API alone cannot keep the system awake
+        nosleep.set_backend_order(&[DBusAPI::FreeDesktopScreenSaverAPI]);
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleDisplaySleep));
+        assert!(!nosleep.is_supported_type(NoSleepType::PreventUserIdleSystemSleep));
+        assert!(!nosleep.is_supported_type(NoSleepType::PreventSystemSleep));
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_strict() {
+        let screensaver = FakeService::start("org.freedesktop.ScreenSaver");
+        let mut strict = NoSleep::strict().unwrap();
+        strict.set_backend_order(&[DBusAPI::FreeDesktopScreenSaverAPI]);
+        assert!(matches!(
+            strict.prevent_system_sleep(),
+            Err(NoSleepError::Unsupported { .. })
+        ));
+        assert!(!strict.is_blocked());
+        // Display blocks are held as usual
+        strict.prevent_display_sleep().unwrap();
+        strict.stop().unwrap();
+        assert_eq!(vec!["Inhibit", "UnInhibit"], screensaver.calls());
+
+        // Skipped as a fallback, the error of the other backend is reported
+        strict.set_backend_order(&[
+            DBusAPI::FreeDesktopPowerApi,
+            DBusAPI::FreeDesktopScreenSaverAPI,
+        ]);
+        assert!(matches!(
+            strict.prevent_system_sleep(),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert_eq!(2, screensaver.calls().len());
+
+        // Lenient mode holds it as a display block
+        let mut lenient =
+            NoSleep::with_backend_order(&[DBusAPI::FreeDesktopScreenSaverAPI]).unwrap();
+        lenient.prevent_system_sleep().unwrap();
+        assert!(lenient.is_blocked());
+        lenient.stop().unwrap();
+        assert_eq!(4, screensaver.calls().len());
+    }
+
+    // Fakes the services of the desktop, see `FakeService`
+    #[test]
+    #[ignore]
+    fn test_prevent_system_sleep_or_alias() {
+        let _power = FakeService::start("org.freedesktop.PowerManagement");
+        let _screensaver = FakeService::start("org.freedesktop.ScreenSaver");
+        let mut nosleep =
+            NoSleep::with_backend_order(&[DBusAPI::FreeDesktopScreenSaverAPI]).unwrap();
+        assert_eq!(
+            BlockOutcome::DisplayAlias,
+            nosleep.prevent_system_sleep_or_alias().unwrap()
+        );
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+
+        nosleep.set_backend_order(&[
+            DBusAPI::FreeDesktopPowerApi,
+            DBusAPI::FreeDesktopScreenSaverAPI,
+        ]);
+        assert_eq!(
+            BlockOutcome::Full,
+            nosleep.prevent_system_sleep_or_alias().unwrap()
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_lid_switch_msg() {
+        let msg = lid_switch_msg(DEFAULT_APP_ID, DEFAULT_REASON);
+        assert_eq!("org.freedesktop.login1", &*msg.destination().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        let (what, who, why, mode) = msg.read4::<&str, &str, &str, &str>().unwrap();
+        assert_eq!("handle-lid-switch", what);
+        assert_eq!(DEFAULT_APP_ID, who);
+        assert_eq!(DEFAULT_REASON, why);
+        assert_eq!("block", mode);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_lid_switch_apart_from_blocks() {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+        let _service = FakeService::start("org.example.LidSwitch");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec("org.example.LidSwitch")).unwrap();
+        // Stands in for the fd of logind
+        let null = std::fs::File::open("/dev/null").unwrap();
+        nosleep.lid_switch = Some(unsafe { OwnedFd::from_raw_fd(null.into_raw_fd()) });
+        nosleep.prevent_lid_close_suspend().unwrap();
+        assert!(!nosleep.is_blocked());
+
+        nosleep.set_switch_policy(SwitchPolicy::KeepStrongest);
+        nosleep.prevent_display_sleep().unwrap();
+        assert_eq!(1, nosleep.handles().len());
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.lid_switch.is_some());
+
+        nosleep.stop().unwrap();
+        assert!(nosleep.lid_switch.is_none());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_clone_config() {
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::LoginApi]).unwrap();
+        nosleep.set_logind_what(LogindWhat::SLEEP | LogindWhat::HANDLE_LID_SWITCH);
+        nosleep.set_logind_mode(LogindMode::Delay);
+        nosleep.set_window_xid(Some(42));
+        nosleep.set_allow_suspend(true);
+        nosleep.set_reason("Downloading").unwrap();
+        nosleep.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: std::time::Duration::from_millis(10),
+        });
+        nosleep.tracked.lock().handles = vec![NoSleepHandle {
+            handle: InhibitHandle::Cookie(1),
+            api: DBusAPI::GnomeApi,
+            nosleep_type: NoSleepType::PreventUserIdleSystemSleep,
+            who: None,
+        }];
+
+        let clone = nosleep.clone_config().unwrap();
+        assert_eq!(nosleep.backend_order, clone.backend_order);
+        assert_eq!(nosleep.logind_what, clone.logind_what);
+        assert_eq!(nosleep.logind_mode, clone.logind_mode);
+        assert_eq!(nosleep.window_xid, clone.window_xid);
+        assert_eq!(nosleep.allow_suspend, clone.allow_suspend);
+        assert_eq!(nosleep.reason, clone.reason);
+        assert_eq!(nosleep.retry_policy, clone.retry_policy);
+        assert!(clone.handles().is_empty());
+        assert!(!clone.is_blocked());
+        // Nothing was actually acquired
+        nosleep.tracked.lock().handles.clear();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_empty_backend_order() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        assert!(nosleep.prevent_system_sleep().is_err());
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_new_without_session_bus() {
+        // As if DBUS_SESSION_BUS_ADDRESS is not set, the test session bus
+        // stands in for the system bus
+        let missing = || {
+            Err(dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.NotSupported",
+                "Unable to autolaunch a dbus-daemon without a $DISPLAY for X11",
+            ))
+        };
+        let nosleep = NoSleep::connect(missing(), Connection::new_session).unwrap();
+        assert!(nosleep.headless);
+        assert_eq!(vec![DBusAPI::LoginApi], nosleep.backend_order);
+        assert!(std::ptr::eq(
+            nosleep.connection(),
+            nosleep.bus(&DBusAPI::LoginApi).unwrap()
+        ));
+
+        // Only logind of `NOSLEEP_BACKEND` can be used
+        let mut headless = NoSleep::connect(missing(), Connection::new_session).unwrap();
+        assert!(matches!(
+            headless.set_env_backend_order(&[DBusAPI::GnomeApi]),
+            Err(NoSleepError::Init { .. })
+        ));
+        headless
+            .set_env_backend_order(&[DBusAPI::GnomeApi, DBusAPI::LoginApi])
+            .unwrap();
+        assert_eq!(vec![DBusAPI::LoginApi], headless.backend_order);
+
+        let result = NoSleep::connect(missing(), missing);
+        assert!(
+            matches!(result, Err(NoSleepError::Init { reason, .. }) if reason.contains("autolaunch"))
+        );
+    }
+
+    #[test]
+    fn test_connect_stalled_bus() {
+        // Accepts connections but never answers the handshake
+        let path = std::env::temp_dir().join(format!("nosleep-stalled-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let address = format!("unix:path={}", path.display());
+        let stalled = || {
+            let address = address.clone();
+            pool::within(Duration::from_millis(100), move || {
+                let mut channel = dbus::channel::Channel::open_private(&address)?;
+                channel.register()?;
+                Ok(channel)
+            })
+            .map(Connection::from)
+        };
+        let started = Instant::now();
+        let result = NoSleep::connect(stalled(), stalled);
+        assert!(
+            matches!(result, Err(NoSleepError::Init { reason, .. }) if reason.contains("did not answer"))
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(listener);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_any_supported() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(!any_supported(&[]));
+        assert!(!any_supported(&names(&[
+            "org.freedesktop.DBus",
+            "org.freedesktop.Notifications"
+        ])));
+        assert!(any_supported(&names(&[
+            "org.freedesktop.DBus",
+            "org.gnome.SessionManager"
+        ])));
+        assert!(any_supported(&names(&["org.freedesktop.ScreenSaver"])));
+        assert!(any_supported(&names(&["org.freedesktop.portal.Desktop"])));
+    }
+
+    #[test]
+    fn test_inhibit_login_api() {
+        let msg = inhibit_msg(
+            &DBusAPI::LoginApi,
+            &NoSleepType::PreventUserIdleSystemSleep,
+            &InhibitOptions::default(),
+        );
+        assert_eq!("org.freedesktop.login1", &*msg.destination().unwrap());
+        assert_eq!("sleep", msg.read1::<&str>().unwrap());
+    }
+
+    // Can only run with systemd-logind on the system bus
+    #[test]
+    #[ignore]
+    fn test_prevent_system_sleep_login_api() {
+        let mut nosleep = NoSleep::with_backend_order(&[DBusAPI::LoginApi]).unwrap();
+        nosleep.set_logind_what(LogindWhat::SLEEP | LogindWhat::HANDLE_LID_SWITCH);
+        nosleep.prevent_system_sleep().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!("org.freedesktop.login1", handles[0].backend);
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a running screensaver service
+    #[test]
+    #[ignore]
+    fn test_prevent_blanking() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_blanking().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!("org.freedesktop.ScreenSaver", handles[0].backend);
+        nosleep.stop().unwrap();
+    }
+
+    // Only runs without an X server in `DISPLAY`
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[cfg(feature = "x11")]
+    #[test]
+    #[ignore]
+    fn test_prevent_dimming_without_x11() {
+        if std::env::var_os("DISPLAY").is_some() {
+            return;
+        }
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        fake_display_block(&mut nosleep);
+        assert!(matches!(
+            nosleep.prevent_dimming(),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        // The current block is kept
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.tracked.lock().handles.clear();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[cfg(feature = "x11")]
+    #[test]
+    #[ignore]
+    fn test_activity_poke() {
+        let _service = FakeService::start("org.example.Poked");
+        let spec = spec("org.example.Poked");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec).unwrap();
+        nosleep
+            .set_activity_poke(Some(Duration::from_secs(60)))
+            .unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.poke.is_none());
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.poke.is_some());
+        nosleep.suspend().unwrap();
+        assert!(nosleep.poke.is_none());
+        nosleep.resume().unwrap();
+        assert!(nosleep.poke.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.poke.is_none());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[cfg(not(feature = "x11"))]
+    #[test]
+    #[ignore]
+    fn test_activity_poke_unsupported() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        assert!(matches!(
+            nosleep.set_activity_poke(Some(Duration::from_secs(60))),
+            Err(NoSleepError::Unsupported { .. })
+        ));
+        nosleep.set_activity_poke(None).unwrap();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[cfg(not(feature = "x11"))]
+    #[test]
+    #[ignore]
+    fn test_prevent_dimming_unsupported() {
+        let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+        assert!(matches!(
+            nosleep.prevent_dimming(),
+            Err(NoSleepError::Unsupported { .. })
+        ));
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Only runs with an X server in `DISPLAY`
+    #[cfg(feature = "x11")]
+    #[test]
+    fn test_prevent_dimming() {
+        if std::env::var_os("DISPLAY").is_none() {
+            return;
+        }
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_dimming().unwrap();
+        assert!(nosleep
+            .handles()
+            .iter()
+            .any(|handle| handle.backend == "X11"));
+        assert_eq!(vec![NoSleepType::PreventScreenSaver], nosleep.nosleep_types);
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Can only run with a running screensaver service
+    #[test]
+    #[ignore]
+    fn test_prevent_screensaver() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_screensaver().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!("org.freedesktop.ScreenSaver", handles[0].backend);
+        assert_eq!(NoSleepType::PreventScreenSaver, handles[0].kind);
+        nosleep.stop().unwrap();
+    }
+
+    // Can only run with an active Gnome Session
+    #[test]
+    #[ignore]
+    fn test_stats() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        nosleep.stop().unwrap();
+        let stats = nosleep.stats();
+        assert!(stats.display_total >= std::time::Duration::from_millis(100));
+        assert_eq!(None, stats.current_started_at);
     }
 
     #[test]
@@ -303,4 +4498,41 @@ mod tests {
         nosleep.stop().unwrap();
         std::thread::sleep(std::time::Duration::from_millis(2000));
     }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_new_reuses_connection() {
+        let nosleep = NoSleep::new().unwrap();
+        let unique_name = nosleep.connection().unique_name().to_string();
+        drop(nosleep);
+        let nosleep = NoSleep::new().unwrap();
+        assert_eq!(unique_name, nosleep.connection().unique_name().to_string());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_new_with_connection_not_reused() {
+        let nosleep = NoSleep::new_with_connection(Connection::new_session().unwrap());
+        let unique_name = nosleep.connection().unique_name().to_string();
+        drop(nosleep);
+        let nosleep = NoSleep::new().unwrap();
+        assert_ne!(unique_name, nosleep.connection().unique_name().to_string());
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_drop_with_block_not_reused() {
+        let service = FakeService::start("org.example.Dropped");
+        let mut nosleep = NoSleep::with_custom_inhibit(spec("org.example.Dropped")).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let unique_name = nosleep.connection().unique_name().to_string();
+        drop(nosleep);
+        // Closing the connection released the lock, without a call
+        assert_eq!(vec!["Hold"], service.calls());
+        let nosleep = NoSleep::new().unwrap();
+        assert_ne!(unique_name, nosleep.connection().unique_name().to_string());
+    }
 }
@@ -0,0 +1,284 @@
+use std::fmt;
+use std::ops::BitOr;
+
+use nosleep_types::NoSleepType;
+
+use crate::decode_error;
+
+/// What a logind inhibitor blocks, flags can be combined with `|`.
+///
+/// By default `PreventUserIdleDisplaySleep` maps to [`LogindWhat::IDLE`]
+/// and `PreventUserIdleSystemSleep` to [`LogindWhat::SLEEP`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LogindWhat(u8);
+
+impl LogindWhat {
+    /// Blocks the system from going idle, and the idle action with it
+    pub const IDLE: LogindWhat = LogindWhat(1);
+    /// Blocks suspend and hibernation
+    pub const SLEEP: LogindWhat = LogindWhat(2);
+    /// Blocks the action taken when the laptop lid is closed
+    pub const HANDLE_LID_SWITCH: LogindWhat = LogindWhat(4);
+
+    /// Returns `true` if all flags of `other` are set.
+    pub fn contains(&self, other: LogindWhat) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for LogindWhat {
+    type Output = LogindWhat;
+
+    fn bitor(self, rhs: LogindWhat) -> LogindWhat {
+        LogindWhat(self.0 | rhs.0)
+    }
+}
+
+impl From<NoSleepType> for LogindWhat {
+    fn from(nosleep_type: NoSleepType) -> LogindWhat {
+        match nosleep_type {
+            NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver => {
+                LogindWhat::IDLE
+            }
+            NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep => {
+                LogindWhat::SLEEP
+            }
+        }
+    }
+}
+
+/// The colon separated list expected by logind, e.g. `sleep:idle`.
+impl fmt::Display for LogindWhat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (LogindWhat::SLEEP, "sleep"),
+            (LogindWhat::IDLE, "idle"),
+            (LogindWhat::HANDLE_LID_SWITCH, "handle-lid-switch"),
+        ];
+        let what = names
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect::<Vec<&str>>();
+        write!(f, "{}", what.join(":"))
+    }
+}
+
+/// How a logind inhibitor treats the operations it inhibits.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LogindMode {
+    /// Prevents the operations entirely
+    #[default]
+    Block,
+    /// Postpones the operations for a short time, to run cleanup first.
+    /// The delay ends as soon as the block is released, so release it
+    /// with `stop` once the cleanup is done.
+    Delay,
+}
+
+impl LogindMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LogindMode::Block => "block",
+            LogindMode::Delay => "delay",
+        }
+    }
+}
+
+/// An inhibitor held with logind, by any process, see
+/// [`NoSleep::find_own_inhibitors`](crate::NoSleep::find_own_inhibitors).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InhibitorInfo {
+    /// The colon separated list of what is inhibited, e.g. `sleep:idle`
+    pub what: String,
+    /// The application id the inhibitor was taken for
+    pub who: String,
+    /// The reason given for it
+    pub why: String,
+    /// `block` or `delay`
+    pub mode: String,
+    /// The user that holds it
+    pub uid: u32,
+    /// The process that holds it
+    pub pid: u32,
+}
+
+pub(crate) fn list_inhibitors_msg() -> dbus::Message {
+    dbus::Message::new_method_call(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+        "ListInhibitors",
+    )
+    .unwrap()
+}
+
+// The inhibitors in a `ListInhibitors` reply that were taken for one of `who`
+pub(crate) fn inhibitors_of(
+    reply: &dbus::Message,
+    who: &[&str],
+) -> Result<Vec<InhibitorInfo>, dbus::Error> {
+    let inhibitors: Vec<(String, String, String, String, u32, u32)> =
+        reply.read1().map_err(|e| decode_error(reply, e))?;
+    Ok(inhibitors
+        .into_iter()
+        .filter(|(_, inhibitor_who, ..)| who.contains(&inhibitor_who.as_str()))
+        .map(|(what, who, why, mode, uid, pid)| InhibitorInfo {
+            what,
+            who,
+            why,
+            mode,
+            uid,
+            pid,
+        })
+        .collect())
+}
+
+pub(crate) fn logind_inhibit_msg(
+    what: LogindWhat,
+    mode: LogindMode,
+    app_id: &str,
+    reason: &str,
+) -> dbus::Message {
+    // Arguments are
+    // what: colon separated list of what to inhibit
+    // who:  human readable name of the application
+    // why:  human readable reason
+    // mode: "block" or "delay"
+    dbus::Message::call_with_args(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+        "Inhibit",
+        (what.to_string(), app_id, reason, mode.as_str()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn what_arg(what: LogindWhat) -> String {
+        let msg = logind_inhibit_msg(
+            what,
+            LogindMode::Block,
+            "org.powersaveblocker.app",
+            "Power Save Blocker",
+        );
+        assert_eq!("/org/freedesktop/login1", &*msg.path().unwrap());
+        assert_eq!("org.freedesktop.login1.Manager", &*msg.interface().unwrap());
+        assert_eq!("Inhibit", &*msg.member().unwrap());
+        assert_eq!("org.freedesktop.login1", &*msg.destination().unwrap());
+        let (what, who, why, mode) = msg.read4::<&str, &str, &str, &str>().unwrap();
+        assert_eq!("org.powersaveblocker.app", who);
+        assert_eq!("Power Save Blocker", why);
+        assert_eq!("block", mode);
+        what.to_string()
+    }
+
+    #[test]
+    fn test_logind_inhibit_msg_mode() {
+        let msg = logind_inhibit_msg(
+            LogindWhat::SLEEP,
+            LogindMode::Block,
+            "org.powersaveblocker.app",
+            "Power Save Blocker",
+        );
+        let (_, _, _, mode) = msg.read4::<&str, &str, &str, &str>().unwrap();
+        assert_eq!("block", mode);
+        let msg = logind_inhibit_msg(
+            LogindWhat::SLEEP,
+            LogindMode::Delay,
+            "org.powersaveblocker.app",
+            "Power Save Blocker",
+        );
+        let (_, _, _, mode) = msg.read4::<&str, &str, &str, &str>().unwrap();
+        assert_eq!("delay", mode);
+    }
+
+    #[test]
+    fn test_inhibitors_of() {
+        let inhibitor = |what: &str, who: &str, pid: u32| {
+            (
+                what.to_string(),
+                who.to_string(),
+                "Power Save Blocker".to_string(),
+                "block".to_string(),
+                1000u32,
+                pid,
+            )
+        };
+        // Only a sent call has the serial a reply refers to
+        let mut call = list_inhibitors_msg();
+        call.set_serial(1);
+        let reply = call.method_return().append1(vec![
+            inhibitor("sleep", "org.powersaveblocker.app", 10),
+            inhibitor("idle", "NetworkManager", 20),
+            inhibitor("idle", "org.powersaveblocker.app", 30),
+        ]);
+        let inhibitors = inhibitors_of(&reply, &["org.powersaveblocker.app"]).unwrap();
+        assert_eq!(
+            vec![
+                InhibitorInfo {
+                    what: "sleep".to_string(),
+                    who: "org.powersaveblocker.app".to_string(),
+                    why: "Power Save Blocker".to_string(),
+                    mode: "block".to_string(),
+                    uid: 1000,
+                    pid: 10,
+                },
+                InhibitorInfo {
+                    what: "idle".to_string(),
+                    who: "org.powersaveblocker.app".to_string(),
+                    why: "Power Save Blocker".to_string(),
+                    mode: "block".to_string(),
+                    uid: 1000,
+                    pid: 30,
+                },
+            ],
+            inhibitors
+        );
+        assert!(inhibitors_of(&reply, &["org.example.Other"])
+            .unwrap()
+            .is_empty());
+        assert!(inhibitors_of(&call.method_return(), &[]).is_err());
+        let error = inhibitors_of(&call.method_return().append1(7u32), &[]).unwrap_err();
+        assert!(
+            error.message().unwrap().contains("signature `u`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_logind_what_from_nosleep_type() {
+        assert_eq!(
+            LogindWhat::IDLE,
+            LogindWhat::from(NoSleepType::PreventUserIdleDisplaySleep)
+        );
+        assert_eq!(
+            LogindWhat::SLEEP,
+            LogindWhat::from(NoSleepType::PreventUserIdleSystemSleep)
+        );
+    }
+
+    #[test]
+    fn test_logind_inhibit_msg() {
+        assert_eq!("idle", what_arg(LogindWhat::IDLE));
+        assert_eq!("sleep", what_arg(LogindWhat::SLEEP));
+        assert_eq!("handle-lid-switch", what_arg(LogindWhat::HANDLE_LID_SWITCH));
+        assert_eq!("sleep:idle", what_arg(LogindWhat::IDLE | LogindWhat::SLEEP));
+        assert_eq!(
+            "idle:handle-lid-switch",
+            what_arg(LogindWhat::IDLE | LogindWhat::HANDLE_LID_SWITCH)
+        );
+        assert_eq!(
+            "sleep:handle-lid-switch",
+            what_arg(LogindWhat::SLEEP | LogindWhat::HANDLE_LID_SWITCH)
+        );
+        assert_eq!(
+            "sleep:idle:handle-lid-switch",
+            what_arg(LogindWhat::SLEEP | LogindWhat::IDLE | LogindWhat::HANDLE_LID_SWITCH)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use dbus::blocking::Connection;
+use dbus::channel::{BusType, Channel};
+
+// Connections kept per thread, more are closed on recycle
+const MAX_IDLE: usize = 4;
+
+// Helper threads of `within` that may wait for a stalled bus at once
+const MAX_PENDING: usize = 4;
+
+// Helper threads of `within` that did not finish yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Session bus connections of dropped NoSleep instances
+    static IDLE: RefCell<Vec<Connection>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns an idle session bus connection of this thread, or opens a new one
+/// within `timeout`. Connections that died while idle are dropped on the way.
+pub(crate) fn session_bus(timeout: Duration) -> Result<Connection, dbus::Error> {
+    while let Some(d_bus) = IDLE.with_borrow_mut(Vec::pop) {
+        if is_alive(&d_bus) {
+            return Ok(d_bus);
+        }
+    }
+    open(BusType::Session, timeout)
+}
+
+/// Opens a new connection to `bus` that tracks its file descriptor,
+/// see `NoSleep::connection_fd`. Fails if the bus does not answer
+/// within `timeout`.
+pub(crate) fn open(bus: BusType, timeout: Duration) -> Result<Connection, dbus::Error> {
+    let mut channel = within(timeout, move || Channel::get_private(bus))?;
+    channel.set_watch_enabled(true);
+    Ok(Connection::from(channel))
+}
+
+/// Runs `open` on a helper thread, as libdbus has no timeout for the
+/// handshake with the bus. If it takes longer than `timeout`, the thread
+/// is left to finish by itself and the connection it opens is closed.
+/// A bus that never answers keeps the thread forever, so while
+/// `MAX_PENDING` threads wait, this fails right away instead.
+pub(crate) fn within(
+    timeout: Duration,
+    open: impl FnOnce() -> Result<Channel, dbus::Error> + Send + 'static,
+) -> Result<Channel, dbus::Error> {
+    if !reserve(&PENDING) {
+        return Err(dbus::Error::new_failed(
+            "Too many connections wait for a bus that does not answer",
+        ));
+    }
+    let (channel_tx, channel_rx) = mpsc::sync_channel(1);
+    std::thread::Builder::new()
+        .name("nosleep-connect".to_string())
+        .spawn(move || {
+            let _ = channel_tx.send(open());
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        })
+        .map_err(|e| {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+            dbus::Error::new_failed(&e.to_string())
+        })?;
+    channel_rx.recv_timeout(timeout).map_err(|_| {
+        dbus::Error::new_failed(&format!("The bus did not answer within {:?}", timeout))
+    })?
+}
+
+// Takes one of the `MAX_PENDING` slots counted by `pending`
+fn reserve(pending: &AtomicUsize) -> bool {
+    pending
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            (n < MAX_PENDING).then_some(n + 1)
+        })
+        .is_ok()
+}
+
+/// Keeps `d_bus` for the next [`session_bus`] call on this thread.
+/// It must not hold any lock anymore.
+pub(crate) fn recycle(d_bus: Connection) {
+    IDLE.with_borrow_mut(|idle| {
+        if idle.len() < MAX_IDLE {
+            idle.push(d_bus);
+        }
+    });
+}
+
+// The connection flag is only updated on I/O, so ping the bus itself
+fn is_alive(d_bus: &Connection) -> bool {
+    d_bus.channel().is_connected()
+        && d_bus
+            .with_proxy("org.freedesktop.DBus", "/", Duration::from_millis(100))
+            .method_call::<(), _, _, _>("org.freedesktop.DBus.Peer", "Ping", ())
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_session_bus_reuses_recycled() {
+        let d_bus = session_bus(Duration::from_secs(5)).unwrap();
+        let unique_name = d_bus.unique_name().to_string();
+        recycle(d_bus);
+        assert_eq!(
+            unique_name,
+            session_bus(Duration::from_secs(5))
+                .unwrap()
+                .unique_name()
+                .to_string()
+        );
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_recycle_limit() {
+        for _ in 0..MAX_IDLE + 2 {
+            recycle(Connection::new_session().unwrap());
+        }
+        assert_eq!(MAX_IDLE, IDLE.with_borrow(Vec::len));
+    }
+
+    #[test]
+    fn test_reserve_limit() {
+        let pending = AtomicUsize::new(0);
+        for _ in 0..MAX_PENDING {
+            assert!(reserve(&pending));
+        }
+        assert!(!reserve(&pending));
+        pending.fetch_sub(1, Ordering::SeqCst);
+        assert!(reserve(&pending));
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+/// How often a D-Bus call is retried when it fails with a transient error,
+/// for example when the session manager is still starting up.
+/// Permanent errors such as an unknown interface are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every next attempt
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// A single attempt, so failures are reported immediately.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+// Errors that may go away when the call is made again
+const RETRYABLE_ERRORS: [&str; 4] = [
+    "org.freedesktop.DBus.Error.NoReply",
+    "org.freedesktop.DBus.Error.Timeout",
+    "org.freedesktop.DBus.Error.TimedOut",
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+];
+
+fn is_retryable(e: &dbus::Error) -> bool {
+    e.name()
+        .is_some_and(|name| RETRYABLE_ERRORS.contains(&name))
+}
+
+/// Calls `f` until it succeeds, fails with a permanent error
+/// or the attempts of `policy` are exhausted.
+pub(crate) fn with_retry<T>(
+    policy: &RetryPolicy,
+    mut f: impl FnMut() -> Result<T, dbus::Error>,
+) -> Result<T, dbus::Error> {
+    let mut backoff = policy.backoff;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e) if attempt < policy.max_attempts && is_retryable(&e) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails with `error` for the first `failures` calls
+    fn failing(failures: u32, error: &'static str) -> impl FnMut() -> Result<u32, dbus::Error> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= failures {
+                Err(dbus::Error::new_custom(error, "mock failure"))
+            } else {
+                Ok(calls)
+            }
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_default_single_attempt() {
+        let result = with_retry(
+            &RetryPolicy::default(),
+            failing(1, "org.freedesktop.DBus.Error.NoReply"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_retry_until_success() {
+        let result = with_retry(&policy(3), failing(2, "org.freedesktop.DBus.Error.NoReply"));
+        assert_eq!(3, result.unwrap());
+    }
+
+    #[test]
+    fn test_retry_exhausted() {
+        let result = with_retry(
+            &policy(3),
+            failing(3, "org.freedesktop.DBus.Error.ServiceUnknown"),
+        );
+        assert_eq!(
+            Some("org.freedesktop.DBus.Error.ServiceUnknown"),
+            result.unwrap_err().name()
+        );
+    }
+
+    #[test]
+    fn test_permanent_error_not_retried() {
+        let mut f = failing(1, "org.freedesktop.DBus.Error.UnknownInterface");
+        assert!(with_retry(&policy(3), &mut f).is_err());
+        // The next call is the second one, so no retry happened
+        assert_eq!(2, f().unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use dbus::blocking::Connection;
+use dbus::channel::Token;
+use dbus::message::MatchRule;
+use nosleep_types::{NoSleepError, Registered};
+
+use crate::{DBusAPI, NoSleepHandle, Tracked};
+
+pub(crate) type RevokeCallback = Box<dyn Fn() + Send + Sync>;
+
+/// Watches the services holding the locks of an instance on a background
+/// thread until dropped. A service that loses its name, for example when
+/// the session manager exits on logout, drops all its locks. Their handles
+/// are then forgotten, so the block can be acquired again, and the
+/// callback is called.
+pub(crate) struct RevokeWatch {
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RevokeWatch {
+    /// Watches the locks on the bus `bus` connects to, `system_bus` tells
+    /// which one that is, as logind lives on the system bus.
+    pub(crate) fn start(
+        bus: impl FnOnce() -> Result<Connection, dbus::Error> + Send + 'static,
+        system_bus: bool,
+        tracked: Registered<Tracked>,
+        callback: RevokeCallback,
+    ) -> Result<RevokeWatch, NoSleepError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        let worker = {
+            let stop = stop.clone();
+            // A connection of its own keeps the match rule off the one
+            // that holds the lock
+            std::thread::spawn(move || {
+                let (d_bus, lost) = match watch(bus) {
+                    Ok(watch) => {
+                        let _ = ready_tx.send(Ok(()));
+                        watch
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                while !stop.load(Ordering::Relaxed) {
+                    if d_bus.process(Duration::from_millis(100)).is_err() {
+                        return;
+                    }
+                    for name in lost.try_iter() {
+                        if forget_held_by(&tracked, &name, system_bus) {
+                            callback();
+                        }
+                    }
+                }
+            })
+        };
+        ready_rx.recv().map_err(|e| NoSleepError::Init {
+            reason: e.to_string(),
+            code: None,
+        })??;
+        Ok(RevokeWatch {
+            stop,
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Drop for RevokeWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Same as `RevokeWatch`, but on the connection of the instance, without
+/// a thread. The signals are handled when the application's event loop
+/// calls `NoSleep::dispatch`.
+pub(crate) struct LoopRevokeWatch {
+    token: Token,
+    lost: mpsc::Receiver<String>,
+    system_bus: bool,
+    tracked: Registered<Tracked>,
+    callback: RevokeCallback,
+}
+
+impl LoopRevokeWatch {
+    /// Watches the locks on `d_bus`, `system_bus` tells which bus that is.
+    pub(crate) fn start(
+        d_bus: &Connection,
+        system_bus: bool,
+        tracked: Registered<Tracked>,
+        callback: RevokeCallback,
+    ) -> Result<LoopRevokeWatch, NoSleepError> {
+        let (token, lost) = subscribe(d_bus)?;
+        Ok(LoopRevokeWatch {
+            token,
+            lost,
+            system_bus,
+            tracked,
+            callback,
+        })
+    }
+
+    /// Forgets the locks of the services that lost their name since the
+    /// last call, after `d_bus` processed the signals.
+    pub(crate) fn handle_lost(&self) {
+        for name in self.lost.try_iter() {
+            if forget_held_by(&self.tracked, &name, self.system_bus) {
+                (self.callback)();
+            }
+        }
+    }
+
+    /// Moves the subscription to `d_bus`, which replaces the connection
+    /// the watch was started on.
+    pub(crate) fn resubscribe(&mut self, d_bus: &Connection) -> Result<(), NoSleepError> {
+        (self.token, self.lost) = subscribe(d_bus)?;
+        Ok(())
+    }
+
+    /// Unsubscribes from `d_bus`, which may be reused by another instance.
+    pub(crate) fn stop(self, d_bus: &Connection) -> Result<(), dbus::Error> {
+        d_bus.remove_match(self.token)
+    }
+}
+
+// Connects and subscribes to `NameOwnerChanged`, sending the names that lost their owner
+fn watch(
+    bus: impl FnOnce() -> Result<Connection, dbus::Error>,
+) -> Result<(Connection, mpsc::Receiver<String>), NoSleepError> {
+    let d_bus = bus().map_err(|e| NoSleepError::Init {
+        reason: e.to_string(),
+        code: None,
+    })?;
+    let (_, lost) = subscribe(&d_bus)?;
+    Ok((d_bus, lost))
+}
+
+// Subscribes `d_bus` to `NameOwnerChanged`, sending the names that lost their owner
+fn subscribe(d_bus: &Connection) -> Result<(Token, mpsc::Receiver<String>), NoSleepError> {
+    let (lost_tx, lost_rx) = mpsc::channel();
+    let mut rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged");
+    rule.sender = Some("org.freedesktop.DBus".into());
+    d_bus
+        .add_match(
+            rule,
+            move |(name, old_owner, _): (String, String, String), _, _| {
+                // A new name has no locks yet
+                if !old_owner.is_empty() {
+                    let _ = lost_tx.send(name);
+                }
+                true
+            },
+        )
+        .map_err(|e| NoSleepError::DBus {
+            reason: e.to_string(),
+        })
+        .map(|token| (token, lost_rx))
+}
+
+// Forgets the handles held by the service `name`, returns `true` if there were any
+fn forget_held_by(tracked: &Registered<Tracked>, name: &str, system_bus: bool) -> bool {
+    let mut tracked = tracked.lock();
+    let spec = tracked.custom_inhibit.clone();
+    let held_by = |handle: &NoSleepHandle| {
+        let service = match handle.api {
+            DBusAPI::Custom => spec.as_ref().map(|spec| spec.destination.as_str()),
+            api => Some(api.name()),
+        };
+        (handle.api == DBusAPI::LoginApi) == system_bus && service == Some(name)
+    };
+    let before = tracked.handles.len();
+    tracked.handles.retain(|handle| !held_by(handle));
+    tracked.handles.len() != before
+}
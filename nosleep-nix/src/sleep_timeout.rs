@@ -0,0 +1,142 @@
+use std::process::Command;
+use std::time::Duration;
+
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+
+const GNOME_POWER_SCHEMA: &str = "org.gnome.settings-daemon.plugins.power";
+
+/// The idle timeout of GNOME for the power source in use, read from the
+/// settings of gnome-settings-daemon with `gsettings`. The outer `None`
+/// if GNOME is not running or the settings cannot be read, the inner one
+/// if GNOME does not put the machine to sleep on idle.
+pub(crate) fn gnome_sleep_timeout() -> Option<Option<Duration>> {
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP").ok()?;
+    if !is_gnome(&desktop) {
+        return None;
+    }
+    let source = if on_battery() { "battery" } else { "ac" };
+    let action = gsettings(&format!("sleep-inactive-{}-type", source))?;
+    let timeout = gsettings(&format!("sleep-inactive-{}-timeout", source))?;
+    Some(gnome_timeout(&action, &timeout))
+}
+
+/// The `IdleAction` of logind, when it puts the machine to sleep, after
+/// `IdleActionUSec`. Read from the system bus.
+pub(crate) fn logind_sleep_timeout() -> Option<Duration> {
+    let system_bus = Connection::new_system().ok()?;
+    let proxy = system_bus.with_proxy(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        Duration::from_millis(500),
+    );
+    let action: String = proxy
+        .get("org.freedesktop.login1.Manager", "IdleAction")
+        .ok()?;
+    let usec: u64 = proxy
+        .get("org.freedesktop.login1.Manager", "IdleActionUSec")
+        .ok()?;
+    logind_timeout(&action, usec)
+}
+
+// Asks UPower, assumes AC power if it is not running
+fn on_battery() -> bool {
+    let Ok(system_bus) = Connection::new_system() else {
+        return false;
+    };
+    let proxy = system_bus.with_proxy(
+        "org.freedesktop.UPower",
+        "/org/freedesktop/UPower",
+        Duration::from_millis(500),
+    );
+    proxy
+        .get("org.freedesktop.UPower", "OnBattery")
+        .unwrap_or(false)
+}
+
+// Checks `XDG_CURRENT_DESKTOP`, a colon separated list such as `ubuntu:GNOME`
+fn is_gnome(desktop: &str) -> bool {
+    desktop.split(':').any(|name| name == "GNOME")
+}
+
+// The printed value of a key, e.g. `'suspend'` or `900`
+fn gsettings(key: &str) -> Option<String> {
+    let output = Command::new("gsettings")
+        .args(["get", GNOME_POWER_SCHEMA, key])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+// Parses the values printed by `gsettings` for sleep-inactive-*-type and
+// sleep-inactive-*-timeout (seconds), 0 disables the action
+fn gnome_timeout(action: &str, timeout: &str) -> Option<Duration> {
+    let action = action.trim().trim_matches('\'');
+    let timeout = timeout.trim();
+    let seconds: u64 = timeout
+        .strip_prefix("int32 ")
+        .unwrap_or(timeout)
+        .parse()
+        .ok()?;
+    let sleeps = matches!(action, "suspend" | "hibernate");
+    (sleeps && seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+// Only the actions that suspend or hibernate, not shutting down or locking
+fn logind_timeout(action: &str, usec: u64) -> Option<Duration> {
+    let sleeps = matches!(
+        action,
+        "sleep" | "suspend" | "hibernate" | "hybrid-sleep" | "suspend-then-hibernate"
+    );
+    (sleeps && usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_gnome() {
+        assert!(is_gnome("GNOME"));
+        assert!(is_gnome("ubuntu:GNOME"));
+        assert!(is_gnome("GNOME-Classic:GNOME"));
+        assert!(!is_gnome("GNOME-Flashback"));
+        assert!(!is_gnome("KDE"));
+        assert!(!is_gnome(""));
+    }
+
+    #[test]
+    fn test_gnome_timeout() {
+        assert_eq!(
+            Some(Duration::from_secs(900)),
+            gnome_timeout("'suspend'\n", "900\n")
+        );
+        assert_eq!(
+            Some(Duration::from_secs(1200)),
+            gnome_timeout("'hibernate'\n", "int32 1200\n")
+        );
+        assert_eq!(None, gnome_timeout("'nothing'\n", "900\n"));
+        assert_eq!(None, gnome_timeout("'blank'\n", "900\n"));
+        assert_eq!(None, gnome_timeout("'suspend'\n", "0\n"));
+        assert_eq!(None, gnome_timeout("'suspend'\n", "\n"));
+    }
+
+    #[test]
+    fn test_logind_timeout() {
+        assert_eq!(
+            Some(Duration::from_secs(1800)),
+            logind_timeout("suspend", 1_800_000_000)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            logind_timeout("suspend-then-hibernate", 60_000_000)
+        );
+        assert_eq!(None, logind_timeout("ignore", 1_800_000_000));
+        assert_eq!(None, logind_timeout("poweroff", 1_800_000_000));
+        assert_eq!(None, logind_timeout("lock", 1_800_000_000));
+        assert_eq!(None, logind_timeout("suspend", 0));
+    }
+}
@@ -0,0 +1,330 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use dbus::arg::OwnedFd;
+use dbus::blocking::{BlockingSender, Connection};
+use dbus::message::MatchRule;
+use nosleep_types::NoSleepError;
+
+use crate::decode_error;
+use crate::logind::{logind_inhibit_msg, LogindMode, LogindWhat};
+
+const LOGIND: &str = "org.freedesktop.login1";
+
+pub(crate) type SleepCallback = Arc<dyn Fn() + Send + Sync>;
+
+// What the watcher passes to its handler
+enum SleepEvent {
+    // The watcher is connected and subscribed, before any signal
+    Watching,
+    // `PrepareForSleep(true)`, the system is about to sleep
+    Sleeping,
+    // `PrepareForSleep(false)`, the system woke up
+    Resumed,
+}
+
+/// Watches the logind `PrepareForSleep` signal on a background thread
+/// until dropped.
+///
+/// With [`SleepWatch::delay_sleep`] a logind delay inhibitor for sleep is
+/// held, see [`crate::NoSleep::prevent_with_callback_on_sleep`]. When logind
+/// announces sleep the callback runs for at most `timeout`, then the
+/// inhibitor is released so sleep proceeds. It is taken again once the
+/// system resumes.
+pub(crate) struct SleepWatch {
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SleepWatch {
+    pub(crate) fn delay_sleep(
+        app_id: String,
+        reason: String,
+        timeout: Duration,
+        callback: SleepCallback,
+    ) -> Result<SleepWatch, NoSleepError> {
+        SleepWatch::delay_sleep_with(
+            Connection::new_system,
+            Some(LOGIND),
+            move |d_bus| delay_inhibitor(d_bus, &app_id, &reason),
+            timeout,
+            callback,
+        )
+    }
+
+    /// Calls `callback` on the watcher thread every time the system resumes.
+    pub(crate) fn on_resume(callback: SleepCallback) -> Result<SleepWatch, NoSleepError> {
+        SleepWatch::on_resume_with(Connection::new_system, Some(LOGIND), callback)
+    }
+
+    fn delay_sleep_with(
+        bus: impl FnOnce() -> Result<Connection, dbus::Error> + Send + 'static,
+        sender: Option<&'static str>,
+        mut acquire: impl FnMut(&Connection) -> Result<OwnedFd, dbus::Error> + Send + 'static,
+        timeout: Duration,
+        callback: SleepCallback,
+    ) -> Result<SleepWatch, NoSleepError> {
+        let mut lock = None;
+        let mut running = None;
+        SleepWatch::spawn(bus, sender, move |d_bus, event| {
+            match event {
+                SleepEvent::Watching => {
+                    lock = Some(acquire(d_bus).map_err(|e| NoSleepError::PreventSleep {
+                        reason: e.to_string(),
+                        code: None,
+                    })?);
+                }
+                SleepEvent::Sleeping => {
+                    if let Some(lock) = lock.take() {
+                        run_callback(&callback, timeout, &mut running);
+                        drop(lock);
+                    }
+                }
+                SleepEvent::Resumed if lock.is_none() => {
+                    // Best effort, the next sleep is not delayed otherwise
+                    lock = acquire(d_bus).ok();
+                }
+                SleepEvent::Resumed => {}
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn on_resume_with(
+        bus: impl FnOnce() -> Result<Connection, dbus::Error> + Send + 'static,
+        sender: Option<&'static str>,
+        callback: SleepCallback,
+    ) -> Result<SleepWatch, NoSleepError> {
+        SleepWatch::spawn(bus, sender, move |_, event| {
+            if let SleepEvent::Resumed = event {
+                callback();
+            }
+            Ok(())
+        })
+    }
+
+    // The worker opens its own connection, which keeps the match rule and
+    // the signals it receives off the connection of the caller.
+    // Errors of `handler` are only reported for `SleepEvent::Watching`.
+    fn spawn(
+        bus: impl FnOnce() -> Result<Connection, dbus::Error> + Send + 'static,
+        sender: Option<&'static str>,
+        mut handler: impl FnMut(&Connection, SleepEvent) -> Result<(), NoSleepError> + Send + 'static,
+    ) -> Result<SleepWatch, NoSleepError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        let worker = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let (d_bus, signals) = match watch(bus, sender).and_then(|(d_bus, signals)| {
+                    handler(&d_bus, SleepEvent::Watching)?;
+                    Ok((d_bus, signals))
+                }) {
+                    Ok(watch) => {
+                        let _ = ready_tx.send(Ok(()));
+                        watch
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                while !stop.load(Ordering::Relaxed) {
+                    if d_bus.process(Duration::from_millis(100)).is_err() {
+                        return;
+                    }
+                    for sleeping in signals.try_iter() {
+                        let event = if sleeping {
+                            SleepEvent::Sleeping
+                        } else {
+                            SleepEvent::Resumed
+                        };
+                        let _ = handler(&d_bus, event);
+                    }
+                }
+            })
+        };
+        ready_rx.recv().map_err(|e| NoSleepError::Init {
+            reason: e.to_string(),
+            code: None,
+        })??;
+        Ok(SleepWatch {
+            stop,
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Drop for SleepWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Connects and subscribes to `PrepareForSleep`
+fn watch(
+    bus: impl FnOnce() -> Result<Connection, dbus::Error>,
+    sender: Option<&'static str>,
+) -> Result<(Connection, mpsc::Receiver<bool>), NoSleepError> {
+    let d_bus = bus().map_err(|e| NoSleepError::Init {
+        reason: e.to_string(),
+        code: None,
+    })?;
+    let (signals_tx, signals_rx) = mpsc::channel();
+    let mut rule = MatchRule::new_signal("org.freedesktop.login1.Manager", "PrepareForSleep");
+    rule.sender = sender.map(Into::into);
+    d_bus
+        .add_match(rule, move |(sleeping,): (bool,), _, _| {
+            let _ = signals_tx.send(sleeping);
+            true
+        })
+        .map_err(|e| NoSleepError::DBus {
+            reason: e.to_string(),
+        })?;
+    Ok((d_bus, signals_rx))
+}
+
+fn delay_inhibitor(d_bus: &Connection, app_id: &str, reason: &str) -> Result<OwnedFd, dbus::Error> {
+    let msg = logind_inhibit_msg(LogindWhat::SLEEP, LogindMode::Delay, app_id, reason);
+    let reply = d_bus.send_with_reply_and_block(msg, Duration::from_millis(5000))?;
+    reply
+        .read1::<OwnedFd>()
+        .map_err(|e| decode_error(&reply, e))
+}
+
+// Sleep proceeds once `timeout` passes, even if the callback still runs.
+// Its thread is then kept in `running` and left to finish by itself, the
+// callback is not run again until it did, so a callback that never returns
+// leaves one thread per watch behind.
+fn run_callback(callback: &SleepCallback, timeout: Duration, running: &mut Option<JoinHandle<()>>) {
+    if running.as_ref().is_some_and(|thread| !thread.is_finished()) {
+        return;
+    }
+    let (done_tx, done_rx) = mpsc::channel();
+    let callback = callback.clone();
+    let thread = std::thread::spawn(move || {
+        callback();
+        let _ = done_tx.send(());
+    });
+    if done_rx.recv_timeout(timeout).is_ok() {
+        // Returns right away, the callback is done
+        let _ = thread.join();
+        *running = None;
+    } else {
+        *running = Some(thread);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::sync::atomic::AtomicUsize;
+
+    use dbus::channel::Sender;
+    use dbus::Message;
+
+    use super::*;
+
+    // Sends `PrepareForSleep` on the session bus, as logind would on the system bus
+    pub(crate) fn prepare_for_sleep(start: bool) {
+        let d_bus = Connection::new_session().unwrap();
+        let msg = Message::new_signal(
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+            "PrepareForSleep",
+        )
+        .unwrap()
+        .append1(start);
+        d_bus.send(msg).unwrap();
+        d_bus.channel().flush();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_callback_on_prepare_for_sleep() {
+        let acquired = Arc::new(AtomicUsize::new(0));
+        let (called_tx, called_rx) = mpsc::channel();
+        let delay = {
+            let acquired = acquired.clone();
+            SleepWatch::delay_sleep_with(
+                Connection::new_session,
+                None,
+                move |_| {
+                    acquired.fetch_add(1, Ordering::SeqCst);
+                    let null = std::fs::File::open("/dev/null").unwrap();
+                    Ok(unsafe { OwnedFd::from_raw_fd(null.into_raw_fd()) })
+                },
+                Duration::from_secs(1),
+                Arc::new(move || called_tx.send(()).unwrap()),
+            )
+            .unwrap()
+        };
+        assert_eq!(1, acquired.load(Ordering::SeqCst));
+
+        prepare_for_sleep(true);
+        called_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Taken again on resume
+        prepare_for_sleep(false);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while acquired.load(Ordering::SeqCst) < 2 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(2, acquired.load(Ordering::SeqCst));
+        drop(delay);
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_on_resume() {
+        let (called_tx, called_rx) = mpsc::channel();
+        let watch = SleepWatch::on_resume_with(
+            Connection::new_session,
+            None,
+            Arc::new(move || called_tx.send(()).unwrap()),
+        )
+        .unwrap();
+        prepare_for_sleep(true);
+        assert!(called_rx.recv_timeout(Duration::from_millis(300)).is_err());
+        prepare_for_sleep(false);
+        called_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Unsubscribed once dropped
+        drop(watch);
+        prepare_for_sleep(false);
+        assert!(called_rx.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+
+    #[test]
+    fn test_run_callback_timeout() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let callback: SleepCallback = {
+            let calls = calls.clone();
+            Arc::new(move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(300));
+            })
+        };
+        let mut running = None;
+        let start = std::time::Instant::now();
+        run_callback(&callback, Duration::from_millis(50), &mut running);
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert!(running.is_some());
+
+        // Not run again while the first call still runs
+        run_callback(&callback, Duration::from_millis(50), &mut running);
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        running.take().unwrap().join().unwrap();
+        run_callback(&callback, Duration::from_secs(1), &mut running);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        assert!(running.is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use nosleep_types::{NoSleepError, NoSleepTrait, NoSleepType};
+
+use crate::NoSleep;
+
+enum Command {
+    Prevent(NoSleepType, mpsc::SyncSender<Result<(), NoSleepError>>),
+    Stop(mpsc::SyncSender<Result<(), NoSleepError>>),
+    Query(mpsc::SyncSender<bool>),
+}
+
+/// A [`NoSleep`] that lives on a dedicated worker thread.
+///
+/// The D-Bus connection is not `Sync`, so the worker thread owns it and
+/// executes the commands sent by this handle one at a time. The handle
+/// itself is `Send + Sync` and can be shared between threads or used from
+/// async runtimes. Dropping it releases the block and stops the worker.
+///
+/// Calls from several threads never interleave: each one completes
+/// before the next starts, so concurrent `prevent_*` and `stop` calls
+/// leave either one block of the type of the last `prevent_*` call or
+/// none, without leaking handles.
+pub struct ThreadedNoSleep {
+    // Dropping the sender tells the worker to release the block and exit
+    commands: Option<mpsc::Sender<Command>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl ThreadedNoSleep {
+    /// Spawns the worker thread and connects to the D-Bus from there.
+    pub fn new() -> Result<ThreadedNoSleep, NoSleepError> {
+        ThreadedNoSleep::spawn(NoSleep::new)
+    }
+
+    // `connect` runs on the worker thread, the only one that ever uses the
+    // connection, and its error is passed back before this returns
+    pub(crate) fn spawn(
+        connect: impl FnOnce() -> Result<NoSleep, NoSleepError> + Send + 'static,
+    ) -> Result<ThreadedNoSleep, NoSleepError> {
+        let (commands_tx, commands_rx) = mpsc::channel::<Command>();
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        let worker = std::thread::spawn(move || {
+            let mut nosleep = match connect() {
+                Ok(nosleep) => {
+                    let _ = ready_tx.send(Ok(()));
+                    nosleep
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            for command in commands_rx {
+                match command {
+                    Command::Prevent(nosleep_type, reply) => {
+                        let _ = reply.send(nosleep.prevent_sleep(nosleep_type));
+                    }
+                    Command::Stop(reply) => {
+                        let _ = reply.send(nosleep.stop());
+                    }
+                    Command::Query(reply) => {
+                        let _ = reply.send(nosleep.is_blocked());
+                    }
+                }
+            }
+            // Best effort, the handle is gone
+            let _ = nosleep.stop();
+        });
+        ready_rx.recv().map_err(|e| NoSleepError::Init {
+            reason: e.to_string(),
+            code: None,
+        })??;
+        Ok(ThreadedNoSleep {
+            commands: Some(commands_tx),
+            worker: Some(worker),
+        })
+    }
+
+    fn call<T>(&self, command: impl FnOnce(mpsc::SyncSender<T>) -> Command) -> Option<T> {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        self.commands.as_ref()?.send(command(reply_tx)).ok()?;
+        reply_rx.recv().ok()
+    }
+
+    fn worker_gone() -> NoSleepError {
+        NoSleepError::DBus {
+            reason: "Worker thread exited".to_string(),
+        }
+    }
+
+    /// See [`NoSleepTrait::prevent_sleep`].
+    pub fn prevent_sleep(&self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.call(|reply| Command::Prevent(nosleep_type, reply))
+            .unwrap_or_else(|| Err(Self::worker_gone()))
+    }
+
+    /// See [`NoSleepTrait::prevent_display_sleep`].
+    pub fn prevent_display_sleep(&self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventUserIdleDisplaySleep)
+    }
+
+    /// See [`NoSleepTrait::prevent_system_sleep`].
+    pub fn prevent_system_sleep(&self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)
+    }
+
+    /// See [`NoSleepTrait::stop`].
+    pub fn stop(&self) -> Result<(), NoSleepError> {
+        self.call(Command::Stop)
+            .unwrap_or_else(|| Err(Self::worker_gone()))
+    }
+
+    /// See [`NoSleepTrait::is_blocked`].
+    pub fn is_blocked(&self) -> bool {
+        self.call(Command::Query).unwrap_or(false)
+    }
+}
+
+impl Drop for ThreadedNoSleep {
+    fn drop(&mut self) {
+        self.commands.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tests::FakeService;
+    use crate::CustomInhibitSpec;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_threaded_is_send_sync() {
+        assert_send_sync::<ThreadedNoSleep>();
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_threaded_from_multiple_threads() {
+        let nosleep = Arc::new(ThreadedNoSleep::new().unwrap());
+        let threads = (0..4)
+            .map(|_| {
+                let nosleep = nosleep.clone();
+                std::thread::spawn(move || {
+                    nosleep.stop().unwrap();
+                    assert!(!nosleep.is_blocked());
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_threaded_concurrent_prevent_stop() {
+        let service = FakeService::start("org.example.ThreadedInhibitor");
+        let spec = CustomInhibitSpec {
+            destination: "org.example.ThreadedInhibitor".to_string(),
+            path: "/org/example/Inhibitor".to_string(),
+            interface: "org.example.Inhibitor".to_string(),
+            inhibit_method: "Inhibit".to_string(),
+            uninhibit_method: "UnInhibit".to_string(),
+            inhibit_args: vec![],
+        };
+        let nosleep =
+            Arc::new(ThreadedNoSleep::spawn(move || NoSleep::with_custom_inhibit(spec)).unwrap());
+        let threads = (0..8)
+            .map(|i| {
+                let nosleep = nosleep.clone();
+                std::thread::spawn(move || {
+                    for j in 0..25 {
+                        match (i + j) % 3 {
+                            0 => nosleep.prevent_display_sleep().unwrap(),
+                            1 => nosleep.prevent_system_sleep().unwrap(),
+                            _ => nosleep.stop().unwrap(),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+
+        // Every handle acquired was released again
+        let calls = service.calls();
+        let acquired = calls.iter().filter(|call| *call == "Inhibit").count();
+        let released = calls.iter().filter(|call| *call == "UnInhibit").count();
+        assert!(acquired > 0);
+        assert_eq!(acquired, released);
+    }
+
+    // Can only run with an active Gnome Session
+    #[test]
+    #[ignore]
+    fn test_threaded_prevent_display_sleep() {
+        let nosleep = Arc::new(ThreadedNoSleep::new().unwrap());
+        let other = nosleep.clone();
+        std::thread::spawn(move || other.prevent_display_sleep().unwrap())
+            .join()
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+}
@@ -0,0 +1,366 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use nosleep_types::{NoSleepError, NoSleepTrait};
+
+use crate::NoSleep;
+
+// What the timer thread is asked to do
+enum Command {
+    // Keeps the block held by `nosleep` until `timer` expires
+    Start {
+        nosleep: Box<NoSleep>,
+        timer: OwnedFd,
+        released: Arc<AtomicBool>,
+        reply: mpsc::SyncSender<u64>,
+    },
+    // Hands a block back before its deadline, to be released by the caller
+    Stop {
+        id: u64,
+        reply: mpsc::SyncSender<Option<Timed>>,
+    },
+}
+
+// The commands of the timer thread, and the eventfd that wakes it up for them
+struct TimerThread {
+    commands: mpsc::Sender<Command>,
+    wake: Arc<OwnedFd>,
+}
+
+// Started by the first timed block, shared by all of them
+static TIMER_THREAD: Mutex<Option<TimerThread>> = Mutex::new(None);
+
+/// A block held until a deadline, timed by the timer thread that all
+/// timed blocks of the process share. Each block waits on a timerfd of its
+/// own, polled by that thread, so there is no sleeping thread per block.
+///
+/// The block is held by its own instance, with its own connection, which
+/// the caller acquires. The timer thread only keeps the time and never
+/// talks to the bus: once the deadline passes, it hands the instance to a
+/// worker thread that releases it, so a slow release does not delay the
+/// other deadlines. [`DeadlineBlock::stop`] and drop release it on the
+/// calling thread instead.
+pub(crate) struct DeadlineBlock {
+    id: Option<u64>,
+    released: Arc<AtomicBool>,
+}
+
+impl DeadlineBlock {
+    /// Keeps the block held by `nosleep` until `deadline`. The block is
+    /// released right away if it cannot be timed.
+    pub(crate) fn start(
+        mut nosleep: NoSleep,
+        deadline: Instant,
+    ) -> Result<DeadlineBlock, NoSleepError> {
+        let timer = match timerfd(deadline) {
+            Ok(timer) => timer,
+            Err(e) => {
+                // Best effort, the original error is more useful
+                let _ = nosleep.stop();
+                return Err(NoSleepError::PreventSleep {
+                    reason: e.to_string(),
+                    code: e.raw_os_error().map(i64::from),
+                });
+            }
+        };
+        let released = Arc::new(AtomicBool::new(false));
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        send(Command::Start {
+            nosleep: Box::new(nosleep),
+            timer,
+            released: released.clone(),
+            reply: reply_tx,
+        })?;
+        let id = reply_rx.recv().map_err(|e| NoSleepError::PreventSleep {
+            reason: e.to_string(),
+            code: None,
+        })?;
+        Ok(DeadlineBlock {
+            id: Some(id),
+            released,
+        })
+    }
+
+    /// Returns `true` until the block has been released.
+    pub(crate) fn is_active(&self) -> bool {
+        !self.released.load(Ordering::SeqCst)
+    }
+
+    /// Releases the block before the deadline and waits until it is.
+    /// A block past its deadline may still be released by the worker.
+    pub(crate) fn stop(&mut self) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        if send(Command::Stop {
+            id,
+            reply: reply_tx,
+        })
+        .is_ok()
+        {
+            if let Ok(Some(timed)) = reply_rx.recv() {
+                timed.release();
+            }
+        }
+    }
+}
+
+impl Drop for DeadlineBlock {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Starts the timer thread if needed and wakes it up for `command`
+fn send(command: Command) -> Result<(), NoSleepError> {
+    let mut timer_thread = TIMER_THREAD.lock().unwrap_or_else(PoisonError::into_inner);
+    if timer_thread.is_none() {
+        *timer_thread = Some(spawn().map_err(|e| NoSleepError::Init {
+            reason: e.to_string(),
+            code: e.raw_os_error().map(i64::from),
+        })?);
+    }
+    let timer_thread = timer_thread.as_ref().unwrap();
+    timer_thread
+        .commands
+        .send(command)
+        .map_err(|_| NoSleepError::PreventSleep {
+            reason: "Timer thread exited".to_string(),
+            code: None,
+        })?;
+    let one = 1u64;
+    // Cannot fail short of an overflow of the counter, which wakes it up anyway
+    unsafe {
+        libc::write(
+            timer_thread.wake.as_raw_fd(),
+            &one as *const u64 as *const libc::c_void,
+            std::mem::size_of::<u64>(),
+        )
+    };
+    Ok(())
+}
+
+fn spawn() -> io::Result<TimerThread> {
+    let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if wake < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let wake = Arc::new(unsafe { OwnedFd::from_raw_fd(wake) });
+    let (commands_tx, commands_rx) = mpsc::channel();
+    {
+        let wake = wake.clone();
+        std::thread::Builder::new()
+            .name("nosleep-timer".to_string())
+            .spawn(move || run(&wake, commands_rx))?;
+    }
+    Ok(TimerThread {
+        commands: commands_tx,
+        wake,
+    })
+}
+
+// A block of the timer thread
+struct Timed {
+    id: u64,
+    timer: OwnedFd,
+    nosleep: Box<NoSleep>,
+    released: Arc<AtomicBool>,
+}
+
+impl Timed {
+    fn release(mut self) {
+        // Best effort, there is no one left to report the error to
+        let _ = self.nosleep.stop();
+        self.released.store(true, Ordering::SeqCst);
+    }
+
+    // Releases the block on a thread of its own, the timer thread never
+    // waits for the bus
+    fn release_on_worker(self) {
+        let released = self.released.clone();
+        let worker = std::thread::Builder::new()
+            .name("nosleep-release".to_string())
+            .spawn(move || self.release());
+        if worker.is_err() {
+            // Dropping the instance closes its connection, which releases
+            // the locks of most services
+            released.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+// Waits for the wake up eventfd and the timerfds of the blocks
+fn run(wake: &OwnedFd, commands: mpsc::Receiver<Command>) {
+    let mut blocks: Vec<Timed> = vec![];
+    let mut next_id = 0;
+    loop {
+        let mut fds = std::iter::once(wake.as_raw_fd())
+            .chain(blocks.iter().map(|block| block.timer.as_raw_fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+
+        // Before the commands change `blocks`, which `fds` follows
+        let expired = fds[1..]
+            .iter()
+            .zip(&blocks)
+            .filter(|(fd, _)| fd.revents != 0)
+            .map(|(_, block)| block.id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            if let Some(index) = blocks.iter().position(|block| block.id == id) {
+                blocks.swap_remove(index).release_on_worker();
+            }
+        }
+
+        if fds[0].revents == 0 {
+            continue;
+        }
+        let mut count = 0u64;
+        unsafe {
+            libc::read(
+                wake.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        for command in commands.try_iter() {
+            match command {
+                Command::Start {
+                    nosleep,
+                    timer,
+                    released,
+                    reply,
+                } => {
+                    let id = next_id;
+                    next_id += 1;
+                    blocks.push(Timed {
+                        id,
+                        timer,
+                        nosleep,
+                        released,
+                    });
+                    let _ = reply.send(id);
+                }
+                Command::Stop { id, reply } => {
+                    let block = blocks
+                        .iter()
+                        .position(|block| block.id == id)
+                        .map(|index| blocks.swap_remove(index));
+                    let _ = reply.send(block);
+                }
+            }
+        }
+    }
+}
+
+// A timerfd that becomes readable at `deadline`. The clock of `Instant`
+// is CLOCK_MONOTONIC, so is the one of the timer.
+fn timerfd(deadline: Instant) -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::timerfd_create(
+            libc::CLOCK_MONOTONIC,
+            libc::TFD_CLOEXEC | libc::TFD_NONBLOCK,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let timer = unsafe { OwnedFd::from_raw_fd(fd) };
+    // A zero value disarms the timer, a passed deadline expires right away
+    let remaining = deadline
+        .saturating_duration_since(Instant::now())
+        .max(Duration::from_nanos(1));
+    let value = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        },
+        it_value: libc::timespec {
+            tv_sec: remaining.as_secs() as libc::time_t,
+            tv_nsec: remaining.subsec_nanos() as libc::c_long,
+        },
+    };
+    if unsafe { libc::timerfd_settime(fd, 0, &value, std::ptr::null_mut()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(timer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{spec, FakeService};
+
+    // A display block held with the service `name`
+    fn held(name: &str) -> NoSleep {
+        let mut nosleep = NoSleep::with_custom_inhibit(spec(name)).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_deadline_block() {
+        let service = FakeService::start("org.example.Deadline");
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(200);
+        let block = DeadlineBlock::start(held("org.example.Deadline"), deadline).unwrap();
+        assert!(block.is_active());
+        assert_eq!(vec!["Hold"], service.calls());
+        while block.is_active() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(vec!["Hold", "Release"], service.calls());
+        // Released once the deadline passed
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_deadline_block_stop() {
+        let service = FakeService::start("org.example.DeadlineStop");
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut blocks = (0..3)
+            .map(|_| DeadlineBlock::start(held("org.example.DeadlineStop"), deadline).unwrap())
+            .collect::<Vec<_>>();
+        // The other blocks keep waiting for their deadline
+        blocks[1].stop();
+        assert!(!blocks[1].is_active());
+        assert!(blocks[0].is_active() && blocks[2].is_active());
+        drop(blocks);
+        assert_eq!(
+            vec!["Hold", "Hold", "Hold", "Release", "Release", "Release"],
+            service.calls()
+        );
+    }
+
+    // Can only run with a session bus, e.g. under dbus-run-session
+    #[test]
+    #[ignore]
+    fn test_deadline_block_passed() {
+        let service = FakeService::start("org.example.DeadlinePassed");
+        let started = Instant::now();
+        let block = DeadlineBlock::start(held("org.example.DeadlinePassed"), started).unwrap();
+        while block.is_active() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!block.is_active());
+        assert_eq!(vec!["Hold", "Release"], service.calls());
+    }
+}
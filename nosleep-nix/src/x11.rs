@@ -0,0 +1,136 @@
+use nosleep_types::NoSleepError;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::dpms::{self, ConnectionExt as _};
+use x11rb::protocol::screensaver::ConnectionExt as _;
+use x11rb::protocol::xproto::{ConnectionExt as _, ScreenSaver};
+use x11rb::rust_connection::RustConnection;
+
+/// Keeps the display on through the X server itself, for bare X11
+/// sessions without a session manager on the D-Bus.
+///
+/// Suspends the screensaver with the MIT-SCREEN-SAVER extension and
+/// disables DPMS while held. The X server resumes the screensaver when
+/// the connection closes, but DPMS has to be re-enabled explicitly, which
+/// [`X11Block::release`] and drop take care of.
+pub(crate) struct X11Block {
+    conn: RustConnection,
+    // DPMS was enabled before and has to be re-enabled on release
+    dpms_enabled: bool,
+    released: bool,
+}
+
+fn error(e: impl std::fmt::Display) -> NoSleepError {
+    NoSleepError::PreventSleep {
+        reason: format!("X11: {}", e),
+        code: None,
+    }
+}
+
+impl X11Block {
+    /// Connects to the display in `DISPLAY` and suspends the screensaver and DPMS.
+    pub(crate) fn acquire() -> Result<X11Block, NoSleepError> {
+        let (conn, _) = x11rb::connect(None).map_err(error)?;
+        conn.screensaver_suspend(1)
+            .map_err(error)?
+            .check()
+            .map_err(error)?;
+        let dpms_enabled = conn
+            .extension_information(dpms::X11_EXTENSION_NAME)
+            .map_err(error)?
+            .is_some()
+            && conn
+                .dpms_info()
+                .map_err(error)?
+                .reply()
+                .map_err(error)?
+                .state;
+        let mut block = X11Block {
+            conn,
+            dpms_enabled: false,
+            released: false,
+        };
+        if dpms_enabled {
+            block
+                .conn
+                .dpms_disable()
+                .map_err(error)?
+                .check()
+                .map_err(error)?;
+            block.dpms_enabled = true;
+        }
+        Ok(block)
+    }
+
+    /// Resumes the screensaver and re-enables DPMS if it was enabled before.
+    pub(crate) fn release(mut self) -> Result<(), NoSleepError> {
+        self.released = true;
+        self.resume().map_err(|e| NoSleepError::StopLock {
+            reason: format!("X11: {}", e),
+            code: None,
+        })
+    }
+
+    fn resume(&self) -> Result<(), x11rb::errors::ReplyError> {
+        self.conn.screensaver_suspend(0)?.check()?;
+        if self.dpms_enabled {
+            self.conn.dpms_enable()?.check()?;
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for X11Block {
+    fn drop(&mut self) {
+        if !self.released {
+            // Best effort, DPMS stays disabled for the whole X server otherwise
+            let _ = self.resume();
+        }
+    }
+}
+
+/// Resets the screensaver timer of the X server in `DISPLAY` on every
+/// call, like `XResetScreenSaver`, as if the user was active. Connects on
+/// the first call and again after a failed one, failures are ignored.
+pub(crate) fn reset_screensaver() -> impl FnMut() + Send {
+    let mut conn: Option<RustConnection> = None;
+    move || {
+        if conn.is_none() {
+            conn = x11rb::connect(None).ok().map(|(conn, _)| conn);
+        }
+        let reset = conn.as_ref().map(|conn| {
+            conn.force_screen_saver(ScreenSaver::RESET)
+                .map_err(error)
+                .and_then(|cookie| cookie.check().map_err(error))
+                .is_ok()
+        });
+        if reset == Some(false) {
+            conn = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only runs with an X server in `DISPLAY`
+    #[test]
+    fn test_x11_block() {
+        if std::env::var_os("DISPLAY").is_none() {
+            return;
+        }
+        let block = X11Block::acquire().unwrap();
+        let dpms_enabled = block.dpms_enabled;
+        if dpms_enabled {
+            let info = block.conn.dpms_info().unwrap().reply().unwrap();
+            assert!(!info.state);
+        }
+        block.release().unwrap();
+
+        if dpms_enabled {
+            let (conn, _) = x11rb::connect(None).unwrap();
+            assert!(conn.dpms_info().unwrap().reply().unwrap().state);
+        }
+    }
+}
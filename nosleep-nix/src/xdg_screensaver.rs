@@ -0,0 +1,84 @@
+use std::process::Command;
+
+use nosleep_types::NoSleepError;
+
+/// Keeps the screensaver away by running the `xdg-screensaver` script of
+/// xdg-utils, for sessions where no D-Bus backend works.
+///
+/// `xdg-screensaver suspend` starts a background process that keeps the
+/// screensaver suspended until `xdg-screensaver resume` is run for the same
+/// window or the window is destroyed, so a window id is required.
+/// The script has to be installed and in `PATH`.
+pub(crate) struct XdgScreensaverBlock {
+    xid: u32,
+    released: bool,
+}
+
+// The command line of `action` on the window `xid`
+fn command(action: &str, xid: u32) -> Command {
+    let mut command = Command::new("xdg-screensaver");
+    command.arg(action).arg(format!("{:#x}", xid));
+    command
+}
+
+// Runs `action` and waits for the script to exit
+fn run(action: &str, xid: u32) -> Result<(), String> {
+    let status = command(action, xid)
+        .status()
+        .map_err(|e| format!("xdg-screensaver: {}", e))?;
+    if !status.success() {
+        return Err(format!("xdg-screensaver {}: {}", action, status));
+    }
+    Ok(())
+}
+
+impl XdgScreensaverBlock {
+    /// Suspends the screensaver for the window `xid`.
+    pub(crate) fn acquire(xid: u32) -> Result<XdgScreensaverBlock, NoSleepError> {
+        run("suspend", xid).map_err(|reason| NoSleepError::PreventSleep { reason, code: None })?;
+        Ok(XdgScreensaverBlock {
+            xid,
+            released: false,
+        })
+    }
+
+    /// The window the screensaver is suspended for.
+    pub(crate) fn xid(&self) -> u32 {
+        self.xid
+    }
+
+    /// Resumes the screensaver.
+    pub(crate) fn release(mut self) -> Result<(), NoSleepError> {
+        self.released = true;
+        run("resume", self.xid).map_err(|reason| NoSleepError::StopLock { reason, code: None })
+    }
+}
+
+impl Drop for XdgScreensaverBlock {
+    fn drop(&mut self) {
+        if !self.released {
+            // Best effort, the background process ends with the window otherwise
+            let _ = run("resume", self.xid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let suspend = command("suspend", 0x2a00007);
+        assert_eq!("xdg-screensaver", suspend.get_program());
+        assert_eq!(
+            vec!["suspend", "0x2a00007"],
+            suspend.get_args().collect::<Vec<_>>()
+        );
+        let resume = command("resume", 42);
+        assert_eq!(
+            vec!["resume", "0x2a"],
+            resume.get_args().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! `set_reason_cow` counted with an allocator of its own, which would count
+//! the allocations of every other test if it lived in the unit tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use nosleep_nix::NoSleep;
+use nosleep_types::NoSleepTrait;
+
+thread_local! {
+    // The allocations made by the current thread, tests run in parallel
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Not available while the thread shuts down
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Can only run with a session bus, e.g. under dbus-run-session
+#[test]
+#[ignore]
+fn test_set_reason_cow_static() {
+    let mut nosleep = NoSleep::with_backend_order(&[]).unwrap();
+    let before = ALLOCATIONS.with(Cell::get);
+    nosleep.set_reason_cow("Playing a video").unwrap();
+    assert_eq!(before, ALLOCATIONS.with(Cell::get));
+    // Owned reasons are moved, not copied
+    let reason = String::from("Downloading");
+    let before = ALLOCATIONS.with(Cell::get);
+    nosleep.set_reason_cow(reason).unwrap();
+    assert_eq!(before, ALLOCATIONS.with(Cell::get));
+}
@@ -0,0 +1,86 @@
+//! Python bindings to block the power save functionality in the OS.
+//!
+//! ```python
+//! from nosleep import NoSleep
+//!
+//! with NoSleep() as nosleep:
+//!     nosleep.prevent_display_sleep()
+//!     # The block is released when leaving the context
+//! ```
+
+use nosleep::{NoSleep, NoSleepTrait};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+create_exception!(
+    nosleep,
+    NoSleepError,
+    PyException,
+    "Raised when the power save block could not be changed."
+);
+
+fn to_py_err(e: nosleep::NoSleepError) -> PyErr {
+    NoSleepError::new_err(e.to_string())
+}
+
+/// Blocks the power save functionality in the OS.
+/// Can be used as a context manager that releases the block on exit.
+#[pyclass(name = "NoSleep", unsendable)]
+struct PyNoSleep {
+    inner: NoSleep,
+}
+
+#[pymethods]
+impl PyNoSleep {
+    #[new]
+    fn new() -> PyResult<Self> {
+        Ok(PyNoSleep {
+            inner: NoSleep::new().map_err(to_py_err)?,
+        })
+    }
+
+    /// Prevents the display from dimming automatically.
+    fn prevent_display_sleep(&mut self) -> PyResult<()> {
+        self.inner.prevent_display_sleep().map_err(to_py_err)
+    }
+
+    /// Prevents the system from sleeping automatically.
+    fn prevent_system_sleep(&mut self) -> PyResult<()> {
+        self.inner.prevent_system_sleep().map_err(to_py_err)
+    }
+
+    /// Releases any block held.
+    fn stop(&mut self) -> PyResult<()> {
+        self.inner.stop().map_err(to_py_err)
+    }
+
+    /// Returns `True` if a block is currently held.
+    fn is_blocked(&self) -> bool {
+        self.inner.is_blocked()
+    }
+
+    fn __enter__(slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (_exc_type, _exc_value, _traceback))]
+    fn __exit__(
+        &mut self,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        self.stop()?;
+        // Do not suppress exceptions raised inside the context
+        Ok(false)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "nosleep")]
+fn nosleep_python(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyNoSleep>()?;
+    m.add("NoSleepError", m.py().get_type::<NoSleepError>())?;
+    Ok(())
+}
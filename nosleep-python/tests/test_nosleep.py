@@ -0,0 +1,34 @@
+import pytest
+
+from nosleep import NoSleep, NoSleepError
+
+
+def test_round_trip():
+    nosleep = NoSleep()
+    assert not nosleep.is_blocked()
+    nosleep.stop()
+    assert not nosleep.is_blocked()
+
+
+def test_context_manager():
+    with NoSleep() as nosleep:
+        assert not nosleep.is_blocked()
+    assert not nosleep.is_blocked()
+
+
+def test_context_manager_does_not_suppress_exceptions():
+    with pytest.raises(ValueError):
+        with NoSleep():
+            raise ValueError()
+
+
+def test_error_type():
+    assert issubclass(NoSleepError, Exception)
+
+
+@pytest.mark.skip(reason="Requires a platform where blocking is possible")
+def test_prevent_display_sleep():
+    with NoSleep() as nosleep:
+        nosleep.prevent_display_sleep()
+        assert nosleep.is_blocked()
+    assert not nosleep.is_blocked()
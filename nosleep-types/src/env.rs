@@ -0,0 +1,122 @@
+use std::ffi::OsString;
+use std::time::Duration;
+
+use crate::NoSleepError;
+
+/// The configuration `NoSleep::from_env` reads from the environment.
+/// Unset variables are `None`, so the backend keeps its default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvConfig {
+    /// `NOSLEEP_BACKEND`, the backends to try, comma separated
+    pub backends: Option<Vec<String>>,
+    /// `NOSLEEP_REASON`, the reason attached to blocks
+    pub reason: Option<String>,
+    /// `NOSLEEP_APP_ID`, the application id sent along with blocks
+    pub app_id: Option<String>,
+    /// `NOSLEEP_DBUS_TIMEOUT_MS`, how long a D-Bus call may take
+    pub dbus_timeout: Option<Duration>,
+}
+
+impl EnvConfig {
+    /// Reads the `NOSLEEP_*` variables of this process.
+    pub fn read() -> Result<EnvConfig, NoSleepError> {
+        EnvConfig::from_lookup(|name| std::env::var_os(name))
+    }
+
+    /// Reads the `NOSLEEP_*` variables through `lookup`, for example
+    /// from a configuration file. Invalid values are an `Init` error.
+    pub fn from_lookup(
+        lookup: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<EnvConfig, NoSleepError> {
+        let var = |name: &str| {
+            lookup(name)
+                .map(|value| {
+                    value.into_string().map_err(|value| NoSleepError::Init {
+                        reason: format!("{} is not valid unicode: {:?}", name, value),
+                        code: None,
+                    })
+                })
+                .transpose()
+        };
+        let backends = var("NOSLEEP_BACKEND")?.map(|backends| {
+            backends
+                .split(',')
+                .map(|backend| backend.trim().to_string())
+                .filter(|backend| !backend.is_empty())
+                .collect::<Vec<_>>()
+        });
+        if backends.as_ref().is_some_and(Vec::is_empty) {
+            return Err(NoSleepError::Init {
+                reason: "NOSLEEP_BACKEND lists no backend".to_string(),
+                code: None,
+            });
+        }
+        let dbus_timeout = var("NOSLEEP_DBUS_TIMEOUT_MS")?
+            .map(|timeout| match timeout.trim().parse::<u64>() {
+                Ok(timeout) if timeout > 0 => Ok(Duration::from_millis(timeout)),
+                _ => Err(NoSleepError::Init {
+                    reason: format!(
+                        "NOSLEEP_DBUS_TIMEOUT_MS is not a positive number of milliseconds: {:?}",
+                        timeout
+                    ),
+                    code: None,
+                }),
+            })
+            .transpose()?;
+        Ok(EnvConfig {
+            backends,
+            reason: var("NOSLEEP_REASON")?,
+            app_id: var("NOSLEEP_APP_ID")?,
+            dbus_timeout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<EnvConfig, NoSleepError> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect::<HashMap<_, _>>();
+        EnvConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_env_config() {
+        assert_eq!(EnvConfig::default(), from_vars(&[]).unwrap());
+        assert_eq!(
+            EnvConfig {
+                backends: Some(vec!["portal".to_string(), "gnome".to_string()]),
+                reason: Some("Nightly backup".to_string()),
+                app_id: Some("org.example.Backup".to_string()),
+                dbus_timeout: Some(Duration::from_millis(250)),
+            },
+            from_vars(&[
+                ("NOSLEEP_BACKEND", "portal, gnome"),
+                ("NOSLEEP_REASON", "Nightly backup"),
+                ("NOSLEEP_APP_ID", "org.example.Backup"),
+                ("NOSLEEP_DBUS_TIMEOUT_MS", "250"),
+            ])
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_env_config_invalid() {
+        for (name, value) in [
+            ("NOSLEEP_DBUS_TIMEOUT_MS", "soon"),
+            ("NOSLEEP_DBUS_TIMEOUT_MS", "0"),
+            ("NOSLEEP_BACKEND", " , "),
+        ] {
+            assert!(matches!(
+                from_vars(&[(name, value)]),
+                Err(NoSleepError::Init { .. })
+            ));
+        }
+    }
+}
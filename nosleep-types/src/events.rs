@@ -0,0 +1,207 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::Mutex;
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::Mutex;
+
+use crate::registry::lock;
+use crate::NoSleepType;
+
+/// A change of the block held by a backend, see `NoSleepTrait::subscribe`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StateEvent {
+    /// A block of `kind` was acquired while none was held
+    Blocked { kind: NoSleepType, at: Instant },
+    /// The block held was replaced by one of another kind
+    Switched {
+        from: NoSleepType,
+        to: NoSleepType,
+        at: Instant,
+    },
+    /// The block was released, for example by `stop` or `suspend`
+    Stopped { at: Instant },
+    /// The OS took the block away, see `NoSleepTrait::on_revoked`
+    Revoked { at: Instant },
+}
+
+impl StateEvent {
+    /// When the change happened.
+    pub fn at(&self) -> Instant {
+        match *self {
+            StateEvent::Blocked { at, .. }
+            | StateEvent::Switched { at, .. }
+            | StateEvent::Stopped { at }
+            | StateEvent::Revoked { at } => at,
+        }
+    }
+}
+
+/// Sends a [`StateEvent`] to every subscriber when the block held by a
+/// backend changes. Shared by the backends, which call
+/// [`StateEvents::update`] after every call that may change the block.
+/// Clones send to the same subscribers, for example from a watcher thread.
+#[derive(Debug, Clone, Default)]
+pub struct StateEvents(Arc<Mutex<Subscribers>>);
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    // The type of the block held as last reported
+    current: Option<NoSleepType>,
+    senders: Vec<Sender<StateEvent>>,
+}
+
+impl Subscribers {
+    // Forgets the subscribers that dropped their receiver
+    fn send(&mut self, event: StateEvent) {
+        self.senders.retain(|sender| sender.send(event).is_ok());
+    }
+}
+
+impl StateEvents {
+    /// Returns a receiver of every change from now on.
+    pub fn subscribe(&self) -> Receiver<StateEvent> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.0).senders.push(sender);
+        receiver
+    }
+
+    /// Reports the type of the block now held, `None` if none is held.
+    /// Sends nothing if it did not change.
+    pub fn update(&self, held: Option<NoSleepType>) {
+        let mut subscribers = lock(&self.0);
+        let at = Instant::now();
+        let event = match (subscribers.current, held) {
+            (None, Some(kind)) => StateEvent::Blocked { kind, at },
+            (Some(from), Some(to)) if from != to => StateEvent::Switched { from, to, at },
+            (Some(_), None) => StateEvent::Stopped { at },
+            _ => return,
+        };
+        subscribers.current = held;
+        subscribers.send(event);
+    }
+
+    /// Returns a read-only view of the block reported to these events.
+    pub fn observer(&self) -> NoSleepObserver {
+        NoSleepObserver(self.clone())
+    }
+
+    /// Reports that the OS took the block away. Sends nothing if no
+    /// block was held.
+    pub fn revoked(&self) {
+        let mut subscribers = lock(&self.0);
+        if subscribers.current.take().is_some() {
+            subscribers.send(StateEvent::Revoked { at: Instant::now() });
+        }
+    }
+}
+
+/// A read-only view of the block held by a backend, for example for
+/// UI code that must not change or release the block. Dropping it does
+/// not affect the block. Created by `NoSleepTrait::observer`.
+///
+/// It reflects the block as of the last call on the backend, like the
+/// events of `subscribe`. Once the backend is dropped nothing is blocked.
+#[derive(Debug, Clone, Default)]
+pub struct NoSleepObserver(StateEvents);
+
+impl NoSleepObserver {
+    /// Returns `true` if the backend holds a block.
+    pub fn is_blocked(&self) -> bool {
+        self.current_block().is_some()
+    }
+
+    /// The type of the block held, the strongest if several are held.
+    pub fn current_block(&self) -> Option<NoSleepType> {
+        lock(&self.0 .0).current
+    }
+
+    /// Returns a receiver of every change from now on,
+    /// see `NoSleepTrait::subscribe`.
+    pub fn subscribe(&self) -> Receiver<StateEvent> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_events() {
+        let events = StateEvents::default();
+        let first = events.subscribe();
+        let second = events.subscribe();
+        events.update(Some(NoSleepType::PreventUserIdleDisplaySleep));
+        // Unchanged
+        events.update(Some(NoSleepType::PreventUserIdleDisplaySleep));
+        events.update(Some(NoSleepType::PreventUserIdleSystemSleep));
+        events.update(None);
+        events.update(None);
+        events.revoked();
+        events.update(Some(NoSleepType::PreventScreenSaver));
+        events.clone().revoked();
+
+        for receiver in [first, second] {
+            let received = receiver.try_iter().collect::<Vec<_>>();
+            assert!(matches!(
+                received[..],
+                [
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleDisplaySleep,
+                        ..
+                    },
+                    StateEvent::Switched {
+                        from: NoSleepType::PreventUserIdleDisplaySleep,
+                        to: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventScreenSaver,
+                        ..
+                    },
+                    StateEvent::Revoked { .. },
+                ]
+            ));
+            assert!(received.windows(2).all(|pair| pair[0].at() <= pair[1].at()));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let events = StateEvents::default();
+        let observer = events.observer();
+        let receiver = observer.subscribe();
+        assert!(!observer.is_blocked());
+        events.update(Some(NoSleepType::PreventUserIdleDisplaySleep));
+        assert!(observer.is_blocked());
+        assert_eq!(
+            Some(NoSleepType::PreventUserIdleDisplaySleep),
+            observer.clone().current_block()
+        );
+        events.revoked();
+        assert_eq!(None, observer.current_block());
+        assert_eq!(2, receiver.try_iter().count());
+        // Nothing to observe
+        assert!(!NoSleepObserver::default().is_blocked());
+    }
+
+    #[test]
+    fn test_state_events_dropped_subscriber() {
+        let events = StateEvents::default();
+        drop(events.subscribe());
+        let receiver = events.subscribe();
+        events.update(Some(NoSleepType::PreventSystemSleep));
+        assert_eq!(1, lock(&events.0).senders.len());
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(StateEvent::Blocked {
+                kind: NoSleepType::PreventSystemSleep,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::NoSleepError;
+
+/// What `prevent_display_sleep_or_degrade` or
+/// `prevent_system_sleep_or_alias` managed to hold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The block requested is held
+    Full,
+    /// Only a block of `PreventUserIdleSystemSleep` is held, as display
+    /// blocks failed too many times in a row
+    SystemOnly,
+    /// Only a display block is held for the system block requested, as
+    /// no backend available can keep the system awake
+    DisplayAlias,
+}
+
+/// Counts the display blocks that failed in a row, to degrade to a system
+/// block once a threshold is reached instead of failing.
+/// Shared by the backends behind `prevent_display_sleep_or_degrade`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DisplayFallback {
+    threshold: Option<u32>,
+    failures: u32,
+}
+
+impl DisplayFallback {
+    /// Degrades once `threshold` display blocks failed in a row,
+    /// `None` never degrades.
+    pub fn new(threshold: Option<u32>) -> Self {
+        DisplayFallback {
+            threshold,
+            failures: 0,
+        }
+    }
+
+    /// Changes the threshold, the failures counted so far are kept.
+    pub fn set_threshold(&mut self, threshold: Option<u32>) {
+        self.threshold = threshold;
+    }
+
+    /// The number of failures in a row that degrades to a system block.
+    pub fn threshold(&self) -> Option<u32> {
+        self.threshold
+    }
+
+    /// The display blocks that failed since the last one that succeeded.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Counts the `result` of a display block. Returns `SystemOnly` if the
+    /// caller should hold a system block instead, or the error of `result`
+    /// if the threshold is not reached yet.
+    pub fn outcome(
+        &mut self,
+        result: Result<(), NoSleepError>,
+    ) -> Result<BlockOutcome, NoSleepError> {
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                Ok(BlockOutcome::Full)
+            }
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                match self.threshold {
+                    Some(threshold) if self.failures >= threshold => Ok(BlockOutcome::SystemOnly),
+                    _ => Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    fn failed() -> Result<(), NoSleepError> {
+        Err(NoSleepError::PreventSleep {
+            reason: "No screensaver".to_string(),
+            code: None,
+        })
+    }
+
+    #[test]
+    fn test_outcome() {
+        let mut fallback = DisplayFallback::new(Some(3));
+        assert!(fallback.outcome(failed()).is_err());
+        assert!(fallback.outcome(failed()).is_err());
+        assert!(matches!(
+            fallback.outcome(failed()),
+            Ok(BlockOutcome::SystemOnly)
+        ));
+        assert!(matches!(
+            fallback.outcome(failed()),
+            Ok(BlockOutcome::SystemOnly)
+        ));
+        assert_eq!(4, fallback.failures());
+
+        // A display block that succeeds starts the count over
+        assert!(matches!(fallback.outcome(Ok(())), Ok(BlockOutcome::Full)));
+        assert_eq!(0, fallback.failures());
+        assert!(fallback.outcome(failed()).is_err());
+    }
+
+    #[test]
+    fn test_outcome_without_threshold() {
+        let mut fallback = DisplayFallback::default();
+        for _ in 0..10 {
+            assert!(matches!(
+                fallback.outcome(failed()),
+                Err(NoSleepError::PreventSleep { .. })
+            ));
+        }
+        fallback.set_threshold(Some(5));
+        assert!(matches!(
+            fallback.outcome(failed()),
+            Ok(BlockOutcome::SystemOnly)
+        ));
+    }
+}
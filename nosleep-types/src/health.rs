@@ -0,0 +1,108 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+/// What the `health_check` of a backend found out about the system.
+/// The `Display` output is meant to be pasted into a bug report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// The OS and the backend crate, for example `linux (nosleep-nix 0.3.0)`
+    pub platform: String,
+    /// Every OS API that was probed, in the order they are tried
+    pub backends: Vec<BackendProbe>,
+    /// The API a block would be held with, if any is available
+    pub selected: Option<String>,
+    /// The reason attached to blocks
+    pub reason: String,
+    /// The application id sent along with blocks, if the OS takes one
+    pub app_id: Option<String>,
+    /// How long a single call to the OS may take, if it is limited
+    pub timeout: Option<Duration>,
+}
+
+/// The result of probing an OS API for a [`HealthReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendProbe {
+    /// The API, for example a D-Bus service name
+    pub name: String,
+    /// Whether the API can be used
+    pub available: bool,
+    /// Why probing the API failed, if it did
+    pub error: Option<String>,
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "nosleep health check")?;
+        writeln!(f, "platform: {}", self.platform)?;
+        writeln!(f, "reason: {:?}", self.reason)?;
+        if let Some(app_id) = &self.app_id {
+            writeln!(f, "app id: {}", app_id)?;
+        }
+        if let Some(timeout) = self.timeout {
+            writeln!(f, "timeout: {:?}", timeout)?;
+        }
+        writeln!(f, "backends:")?;
+        for probe in &self.backends {
+            let status = match (&probe.error, probe.available) {
+                (Some(error), _) => format!("error: {}", error),
+                (None, true) => "available".to_string(),
+                (None, false) => "missing".to_string(),
+            };
+            writeln!(f, "  {}: {}", probe.name, status)?;
+        }
+        write!(
+            f,
+            "selected: {}",
+            self.selected.as_deref().unwrap_or("none available")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_report_display() {
+        let report = HealthReport {
+            platform: "linux (nosleep-nix 0.3.0)".to_string(),
+            backends: vec![
+                BackendProbe {
+                    name: "org.gnome.SessionManager".to_string(),
+                    available: false,
+                    error: None,
+                },
+                BackendProbe {
+                    name: "org.freedesktop.PowerManagement".to_string(),
+                    available: true,
+                    error: None,
+                },
+                BackendProbe {
+                    name: "org.freedesktop.login1".to_string(),
+                    available: false,
+                    error: Some("no system bus".to_string()),
+                },
+            ],
+            selected: Some("org.freedesktop.PowerManagement".to_string()),
+            reason: "Power Save Blocker".to_string(),
+            app_id: Some("org.powersaveblocker.app".to_string()),
+            timeout: Some(Duration::from_secs(5)),
+        };
+        assert_eq!(
+            "nosleep health check\n\
+             platform: linux (nosleep-nix 0.3.0)\n\
+             reason: \"Power Save Blocker\"\n\
+             app id: org.powersaveblocker.app\n\
+             timeout: 5s\n\
+             backends:\n  \
+               org.gnome.SessionManager: missing\n  \
+               org.freedesktop.PowerManagement: available\n  \
+               org.freedesktop.login1: error: no system bus\n\
+             selected: org.freedesktop.PowerManagement",
+            report.to_string()
+        );
+    }
+}
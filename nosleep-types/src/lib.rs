@@ -1,30 +1,633 @@
+//! The trait, error and types shared by the platform backends.
+//!
+//! Without the default `std` feature the crate is `no_std` with `alloc`:
+//! [`NoSleepTrait`], [`NoSleepError`] and the plain types remain, minus
+//! the parts that need a clock, threads or locks.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::mpsc::{self, Receiver};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
 use snafu::Snafu;
 
-#[derive(Debug, Snafu)]
+#[cfg(feature = "std")]
+mod env;
+#[cfg(feature = "std")]
+mod events;
+mod fallback;
+mod health;
+#[cfg(all(feature = "std", any(test, feature = "mock")))]
+mod mock;
+#[cfg(feature = "std")]
+mod poke;
+#[cfg(feature = "std")]
+mod predicate;
+#[cfg(feature = "std")]
+mod registry;
+#[cfg(feature = "std")]
+mod stats;
+mod switch;
+#[cfg(feature = "std")]
+mod timer;
+
+#[cfg(feature = "std")]
+pub use env::EnvConfig;
+#[cfg(feature = "std")]
+pub use events::{NoSleepObserver, StateEvent, StateEvents};
+pub use fallback::{BlockOutcome, DisplayFallback};
+pub use health::{BackendProbe, HealthReport};
+#[cfg(all(feature = "std", any(test, feature = "mock")))]
+pub use mock::{MockCall, MockNoSleep};
+#[cfg(feature = "std")]
+pub use poke::{ActivityPoke, MIN_POKE_INTERVAL};
+#[cfg(feature = "std")]
+pub use predicate::PredicateBlock;
+#[cfg(feature = "std")]
+pub use registry::{HandleRegistry, Registered};
+#[cfg(feature = "std")]
+pub use stats::{BlockStats, BlockTracker};
+pub use switch::SwitchPolicy;
+#[cfg(feature = "std")]
+pub use timer::TimedBlock;
+
+/// `code` holds the raw platform error code if there is one, the `IOReturn`
+/// on macOS and the `HRESULT` on Windows, both as unsigned, for example
+/// `0xe00002c1` for `kIOReturnNotPrivileged`.
+///
+/// New variants may be added in minor releases, so a `match` on it needs
+/// a wildcard arm, for example one that reports the error as is.
+///
+/// Variants that only one platform can return exist only when building for
+/// that platform: `DBus` on Linux. The other variants are common to all.
+#[derive(Debug, Clone, Snafu)]
+#[non_exhaustive]
 pub enum NoSleepError {
     #[snafu(display("Could not initialize: {:?}", reason))]
-    Init { reason: String },
+    Init { reason: String, code: Option<i64> },
     #[snafu(display("Could not prevent sleep: {:?}", reason))]
-    PreventSleep { reason: String },
+    PreventSleep { reason: String, code: Option<i64> },
+    #[cfg(target_os = "linux")]
     #[snafu(display("DBus error: {:?}", reason))]
     DBus { reason: String },
     #[snafu(display("Could not stop lock: {:?}", reason))]
-    StopLock { reason: String },
+    StopLock { reason: String, code: Option<i64> },
+    #[snafu(display("Unsupported on this platform: {:?}", operation))]
+    Unsupported { operation: String },
+    /// The OS refused the block on purpose, for example because a group
+    /// policy or polkit rule set by an administrator forbids it
+    #[snafu(display("Denied by a system policy: {:?}", reason))]
+    PolicyDenied { reason: String, code: Option<i64> },
+}
+
+impl NoSleepError {
+    /// The raw platform error code, if any.
+    pub fn code(&self) -> Option<i64> {
+        match self {
+            NoSleepError::Init { code, .. }
+            | NoSleepError::PreventSleep { code, .. }
+            | NoSleepError::StopLock { code, .. }
+            | NoSleepError::PolicyDenied { code, .. } => *code,
+            #[cfg(target_os = "linux")]
+            NoSleepError::DBus { .. } => None,
+            NoSleepError::Unsupported { .. } => None,
+        }
+    }
+}
+
+/// The kind of power save block to hold.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NoSleepType {
+    PreventUserIdleDisplaySleep,
+    PreventUserIdleSystemSleep,
+    /// Prevents the system from sleeping at all, also when sleep is
+    /// requested by the user. Stronger and more power-hungry than
+    /// `PreventUserIdleSystemSleep`. Maps to `kIOPMAssertionTypePreventSystemSleep`
+    /// on macOS, which is only honoured on AC power. Other platforms
+    /// cannot prevent explicit sleep and treat it as `PreventUserIdleSystemSleep`.
+    PreventSystemSleep,
+    /// Keeps the screensaver and lock screen from starting, for example
+    /// during a presentation. Uses only `org.freedesktop.ScreenSaver` on
+    /// Linux and a display-only request on Windows. macOS has no separate
+    /// screensaver assertion and treats it as `PreventUserIdleDisplaySleep`.
+    PreventScreenSaver,
+}
+
+impl NoSleepType {
+    const ALL: [NoSleepType; 4] = [
+        NoSleepType::PreventUserIdleDisplaySleep,
+        NoSleepType::PreventUserIdleSystemSleep,
+        NoSleepType::PreventSystemSleep,
+        NoSleepType::PreventScreenSaver,
+    ];
+
+    /// The canonical name, for example in a CLI or a configuration file.
+    /// `FromStr` accepts it back.
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoSleepType::PreventUserIdleDisplaySleep => "display",
+            NoSleepType::PreventUserIdleSystemSleep => "system",
+            NoSleepType::PreventSystemSleep => "system-forced",
+            NoSleepType::PreventScreenSaver => "screensaver",
+        }
+    }
+}
+
+impl fmt::Display for NoSleepType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A string that is not the name of a [`NoSleepType`].
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(display(
+    "Unknown block type {:?}, expected one of display, system, system-forced, screensaver",
+    value
+))]
+pub struct ParseNoSleepTypeError {
+    /// The string that was parsed
+    pub value: String,
 }
 
+impl FromStr for NoSleepType {
+    type Err = ParseNoSleepTypeError;
+
+    /// Parses the canonical name in any case, or the variant name.
+    fn from_str(value: &str) -> Result<NoSleepType, ParseNoSleepTypeError> {
+        let trimmed = value.trim();
+        NoSleepType::ALL
+            .into_iter()
+            .find(|nosleep_type| {
+                nosleep_type.name().eq_ignore_ascii_case(trimmed)
+                    || alloc::format!("{:?}", nosleep_type) == trimmed
+            })
+            .ok_or_else(|| ParseNoSleepTypeError {
+                value: value.to_string(),
+            })
+    }
+}
+
+impl TryFrom<&str> for NoSleepType {
+    type Error = ParseNoSleepTypeError;
+
+    fn try_from(value: &str) -> Result<NoSleepType, ParseNoSleepTypeError> {
+        value.parse()
+    }
+}
+
+/// How important a block is, see [`NoSleepTrait::prevent_sleep_with_level`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum BlockLevel {
+    /// A regular block, what `prevent_sleep` holds
+    #[default]
+    Normal,
+    /// A block that also holds off what the user or the OS would otherwise
+    /// do despite a regular block, where the OS can tell the difference.
+    /// For example: burning a disc or flashing firmware.
+    High,
+}
+
+/// Describes a single handle held by a backend, for debugging purposes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleInfo {
+    /// The type of block the handle was acquired for
+    pub kind: NoSleepType,
+    /// The OS API that issued the handle
+    pub backend: &'static str,
+    /// The raw handle as returned by the OS
+    pub raw: u64,
+    /// Who the handle was acquired for with `prevent_sleep_as`,
+    /// `None` for the instance itself
+    pub who: Option<String>,
+}
+
+/// What a backend supports, for example to disable unavailable options in a UI.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `PreventUserIdleDisplaySleep` can be held
+    pub display_sleep: bool,
+    /// `PreventUserIdleSystemSleep` can be held
+    pub system_sleep: bool,
+    /// The display can be kept on without also keeping the system awake
+    pub display_only: bool,
+    /// `PreventScreenSaver` can be held
+    pub screensaver: bool,
+    /// `prevent_display_sleep_until` is supported
+    pub timed_blocks: bool,
+    /// Blocks can be released depending on the battery state
+    pub battery_aware: bool,
+}
+
+/// Common interface implemented by every platform backend.
+///
+/// The trait is object-safe: `new` is only available on sized
+/// implementors, so a backend can be held as `Box<dyn NoSleepTrait>`.
+///
+/// `prevent_display_sleep_until`, `stats`, `reset_stats`, `subscribe`
+/// and `observer` need the `std` feature.
 pub trait NoSleepTrait {
     fn new() -> Result<Self, NoSleepError>
     where
         Self: Sized;
 
+    /// Prevents the OS from sleeping as described by `nosleep_type`,
+    /// replacing any block currently held.
+    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError>;
+
+    /// Holds a block of every type in `nosleep_types` at once, replacing
+    /// any block currently held. If one of them cannot be acquired, the
+    /// others are released again. `stop` releases them all together.
+    fn prevent(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        let _ = nosleep_types;
+        Err(NoSleepError::Unsupported {
+            operation: "prevent".to_string(),
+        })
+    }
+
+    /// Same as `prevent_sleep`, with a hint of how important the block is.
+    /// Backends apply the level where the OS can express it and ignore it
+    /// elsewhere:
+    /// - Linux: `High` also inhibits logging out through the Gnome session
+    ///   manager and the portal, and holds logind inhibitors in `block`
+    ///   mode even if `delay` is set.
+    /// - macOS: `High` makes a `PreventSystemSleep` block apply on battery
+    ///   as well (`AppliesToLimitedPower`), other types are not affected.
+    /// - Windows: ignored, power requests have no priority.
+    ///
+    /// The level applies until the next `prevent_*` call, `resume` keeps it.
+    fn prevent_sleep_with_level(
+        &mut self,
+        nosleep_type: NoSleepType,
+        level: BlockLevel,
+    ) -> Result<(), NoSleepError> {
+        let _ = level;
+        self.prevent_sleep(nosleep_type)
+    }
+
+    /// Same as `prevent_sleep`, attributed to `who` instead of this
+    /// instance, for example a service blocking sleep on behalf of one of
+    /// its clients. Backends pass `who` where the OS lists the blocks:
+    /// - Linux: as the application id (`app_id`, the logind `who`).
+    /// - Windows and macOS: as a `who: ` prefix of the reason.
+    ///
+    /// [`HandleInfo::who`] reports it. It applies until the next `prevent_*`
+    /// call, `resume` keeps it. Backends that cannot attribute blocks
+    /// ignore it.
+    fn prevent_sleep_as(
+        &mut self,
+        nosleep_type: NoSleepType,
+        who: &str,
+    ) -> Result<(), NoSleepError> {
+        let _ = who;
+        self.prevent_sleep(nosleep_type)
+    }
+
     /// Prevents the display from dimming automatically.
     /// For example: playing a video.
-    fn prevent_display_sleep(&mut self) -> Result<(), NoSleepError>;
+    fn prevent_display_sleep(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventUserIdleDisplaySleep)
+    }
+
+    /// Prevents the display from dimming until `deadline`,
+    /// after which the block is released automatically.
+    /// A deadline in the past only releases any block currently held.
+    #[cfg(feature = "std")]
+    fn prevent_display_sleep_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        let _ = deadline;
+        Err(NoSleepError::Unsupported {
+            operation: "prevent_display_sleep_until".to_string(),
+        })
+    }
+
+    /// Prevents the screensaver from starting, without holding off system sleep.
+    /// For example: showing a presentation.
+    fn prevent_screensaver(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventScreenSaver)
+    }
 
     /// Prevents the system from sleeping automatically due to a lack of user activity.
     /// For example: downloading a file in the background.
-    fn prevent_system_sleep(&mut self) -> Result<(), NoSleepError>;
+    fn prevent_system_sleep(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)
+    }
 
-    /// Cancels any previous call to `prevent_display_sleep` or `prevent_system_sleep`.
+    /// Cancels any previous call to `prevent_display_sleep` or `prevent_system_sleep`,
+    /// and the callbacks registered with `on_resume` and `on_revoked`.
     fn stop(&mut self) -> Result<(), NoSleepError>;
+
+    /// Releases only the block of `nosleep_type` out of those held by
+    /// `prevent`, leaving the others held. Does nothing if no block of that
+    /// type is held. Unlike `stop`, the callbacks are kept.
+    fn stop_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        let _ = nosleep_type;
+        Err(NoSleepError::Unsupported {
+            operation: "stop_type".to_string(),
+        })
+    }
+
+    /// Sets the human readable reason shown by the OS for the block.
+    /// Applies to the block currently held, without releasing it in between,
+    /// and to every block acquired afterwards.
+    fn set_reason(&mut self, reason: &str) -> Result<(), NoSleepError> {
+        let _ = reason;
+        Err(NoSleepError::Unsupported {
+            operation: "set_reason".to_string(),
+        })
+    }
+
+    /// Same as `set_reason`, but stores the reason as given, so a
+    /// `&'static str` is not copied. For reasons that change often, for
+    /// example on every track of a playlist. Not available on
+    /// `dyn NoSleepTrait`.
+    fn set_reason_cow(&mut self, reason: impl Into<Cow<'static, str>>) -> Result<(), NoSleepError>
+    where
+        Self: Sized,
+    {
+        self.set_reason(&reason.into())
+    }
+
+    /// Temporarily releases the current block, remembering its type.
+    /// Does nothing if no block is held.
+    fn suspend(&mut self) -> Result<(), NoSleepError> {
+        Err(NoSleepError::Unsupported {
+            operation: "suspend".to_string(),
+        })
+    }
+
+    /// Reacquires a block released by `suspend`.
+    /// Does nothing if not suspended or if `stop` was called in between.
+    fn resume(&mut self) -> Result<(), NoSleepError> {
+        Err(NoSleepError::Unsupported {
+            operation: "resume".to_string(),
+        })
+    }
+
+    /// Returns `true` if a block is currently held by this instance.
+    fn is_blocked(&self) -> bool;
+
+    /// Lists the OS handles currently held by this instance.
+    /// Backends that do not track them return an empty list.
+    fn handles(&self) -> Vec<HandleInfo> {
+        Vec::new()
+    }
+
+    /// Returns how long blocks were held by this instance,
+    /// counted from creation or the last `reset_stats`.
+    /// Backends that do not keep count return empty totals.
+    #[cfg(feature = "std")]
+    fn stats(&self) -> BlockStats {
+        BlockStats::default()
+    }
+
+    /// Clears the totals returned by `stats`.
+    #[cfg(feature = "std")]
+    fn reset_stats(&mut self) {}
+
+    /// Describes what this backend supports.
+    /// Backends that do not describe themselves report nothing.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Returns `true` if the display is currently on, `false` if it is off,
+    /// or `None` if the platform cannot tell. For example, to skip keeping
+    /// a display awake that the user turned off.
+    fn display_is_on(&self) -> Option<bool> {
+        None
+    }
+
+    /// Returns how long the machine may stay idle before the OS puts it to
+    /// sleep, as configured by the user for the current power source, or
+    /// `None` if it never sleeps on idle or the platform cannot tell.
+    /// For example, to only hold a block for tasks that outlast it.
+    fn system_sleep_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Calls `callback` every time the machine wakes up from sleep, for
+    /// example to re-sync with a server, until `stop` is called or the
+    /// backend is dropped. Calling it again replaces the callback.
+    /// The callback runs on a background thread, see the backend for details.
+    fn on_resume(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        let _ = callback;
+        Err(NoSleepError::Unsupported {
+            operation: "on_resume".to_string(),
+        })
+    }
+
+    /// Calls `callback` when the OS revokes a block held by this instance,
+    /// for example when the service holding it goes away on logout, so it
+    /// can be re-acquired or the user notified. Applies to every block until
+    /// `stop` is called or the backend is dropped. Calling it again replaces
+    /// the callback. What counts as revoked depends on the backend.
+    fn on_revoked(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        let _ = callback;
+        Err(NoSleepError::Unsupported {
+            operation: "on_revoked".to_string(),
+        })
+    }
+
+    /// Resets the idle timer of the OS once, as if the user was active,
+    /// for example on remote input. This defers the screensaver and the
+    /// lock screen without holding a block.
+    fn simulate_user_activity(&mut self) -> Result<(), NoSleepError> {
+        Err(NoSleepError::Unsupported {
+            operation: "simulate_user_activity".to_string(),
+        })
+    }
+
+    /// Returns a receiver of the changes of the block held by this instance,
+    /// from now on. Every subscriber receives every event. Changes that
+    /// happen in between calls, like a timed block running out, are
+    /// reported on the next call. Backends without events return a
+    /// receiver that is disconnected right away.
+    #[cfg(feature = "std")]
+    fn subscribe(&self) -> Receiver<StateEvent> {
+        mpsc::channel().1
+    }
+
+    /// Returns a read-only view of the block held by this instance, for
+    /// example to hand to UI code. Backends without events return an
+    /// observer that never sees a block.
+    #[cfg(feature = "std")]
+    fn observer(&self) -> NoSleepObserver {
+        NoSleepObserver::default()
+    }
+
+    /// Returns `true` if `prevent_sleep` can hold `nosleep_type`,
+    /// possibly as the closest type the platform supports.
+    fn is_supported_type(&self, nosleep_type: NoSleepType) -> bool {
+        let capabilities = self.capabilities();
+        match nosleep_type {
+            NoSleepType::PreventUserIdleDisplaySleep => capabilities.display_sleep,
+            NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep => {
+                capabilities.system_sleep
+            }
+            NoSleepType::PreventScreenSaver => capabilities.screensaver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_clone() {
+        let errors = vec![
+            NoSleepError::Init {
+                reason: "init".to_string(),
+                code: Some(1),
+            },
+            NoSleepError::PreventSleep {
+                reason: "prevent".to_string(),
+                code: Some(2),
+            },
+            NoSleepError::StopLock {
+                reason: "stop".to_string(),
+                code: None,
+            },
+            NoSleepError::Unsupported {
+                operation: "unsupported".to_string(),
+            },
+            NoSleepError::PolicyDenied {
+                reason: "denied".to_string(),
+                code: Some(3),
+            },
+        ];
+        #[cfg(target_os = "linux")]
+        let errors = [
+            errors,
+            vec![NoSleepError::DBus {
+                reason: "dbus".to_string(),
+            }],
+        ]
+        .concat();
+        for error in errors {
+            let clone = error.clone();
+            assert_eq!(error.to_string(), clone.to_string());
+            assert_eq!(error.code(), clone.code());
+        }
+    }
+
+    // Exhaustive, so a variant added to or gated out of a platform fails here
+    fn variant_name(error: &NoSleepError) -> &'static str {
+        match error {
+            NoSleepError::Init { .. } => "Init",
+            NoSleepError::PreventSleep { .. } => "PreventSleep",
+            #[cfg(target_os = "linux")]
+            NoSleepError::DBus { .. } => "DBus",
+            NoSleepError::StopLock { .. } => "StopLock",
+            NoSleepError::Unsupported { .. } => "Unsupported",
+            NoSleepError::PolicyDenied { .. } => "PolicyDenied",
+        }
+    }
+
+    #[test]
+    fn test_error_common_variants() {
+        let errors = [
+            NoSleepError::Init {
+                reason: "init".to_string(),
+                code: None,
+            },
+            NoSleepError::PreventSleep {
+                reason: "prevent".to_string(),
+                code: None,
+            },
+            NoSleepError::StopLock {
+                reason: "stop".to_string(),
+                code: None,
+            },
+            NoSleepError::Unsupported {
+                operation: "unsupported".to_string(),
+            },
+            NoSleepError::PolicyDenied {
+                reason: "denied".to_string(),
+                code: None,
+            },
+        ];
+        assert_eq!(
+            vec![
+                "Init",
+                "PreventSleep",
+                "StopLock",
+                "Unsupported",
+                "PolicyDenied"
+            ],
+            errors.iter().map(variant_name).collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_error_linux_variants() {
+        let error = NoSleepError::DBus {
+            reason: "dbus".to_string(),
+        };
+        assert_eq!("DBus", variant_name(&error));
+        assert_eq!(None, error.code());
+    }
+
+    #[test]
+    fn test_nosleep_type_names() {
+        for nosleep_type in NoSleepType::ALL {
+            assert_eq!(Ok(nosleep_type), nosleep_type.to_string().parse());
+            assert_eq!(
+                Ok(nosleep_type),
+                NoSleepType::try_from(format!("{:?}", nosleep_type).as_str())
+            );
+        }
+        assert_eq!(
+            Ok(NoSleepType::PreventScreenSaver),
+            " ScreenSaver ".parse::<NoSleepType>()
+        );
+    }
+
+    // Implements only the required methods
+    struct MinimalNoSleep;
+
+    impl NoSleepTrait for MinimalNoSleep {
+        fn new() -> Result<Self, NoSleepError> {
+            Ok(MinimalNoSleep)
+        }
+
+        fn prevent_sleep(&mut self, _nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+            Ok(())
+        }
+
+        fn stop(&mut self) -> Result<(), NoSleepError> {
+            Ok(())
+        }
+
+        fn is_blocked(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_trait_defaults() {
+        let nosleep = MinimalNoSleep::new().unwrap();
+        assert!(nosleep.handles().is_empty());
+        assert_eq!(Capabilities::default(), nosleep.capabilities());
+    }
+
+    #[test]
+    fn test_nosleep_type_parse_error() {
+        let error = "hibernate".parse::<NoSleepType>().unwrap_err();
+        assert_eq!("hibernate", error.value);
+        assert!(error.to_string().contains("\"hibernate\""));
+        assert!(error.to_string().contains("screensaver"));
+    }
 }
@@ -0,0 +1,507 @@
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
+
+use crate::{
+    BlockStats, BlockTracker, Capabilities, HandleInfo, NoSleepError, NoSleepObserver,
+    NoSleepTrait, NoSleepType, StateEvent, StateEvents,
+};
+
+/// A call made on [`MockNoSleep`], see [`MockNoSleep::calls`].
+/// The default methods of [`NoSleepTrait`] are recorded as the calls
+/// they make, for example `prevent_display_sleep` as `PreventSleep`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockCall {
+    PreventSleep(NoSleepType),
+    Prevent(Vec<NoSleepType>),
+    PreventDisplaySleepUntil(Instant),
+    Stop,
+    StopType(NoSleepType),
+    SetReason(String),
+    Suspend,
+    Resume,
+    OnResume,
+    OnRevoked,
+    SimulateUserActivity,
+}
+
+/// A backend that never touches the OS, for testing keep-awake logic
+/// in downstream crates. Enabled with the `mock` feature, which needs `std`.
+///
+/// Records every call and behaves like a backend that always succeeds,
+/// unless an error is injected with [`MockNoSleep::fail_next`].
+///
+/// ```rust
+/// # use nosleep_types::{MockCall, MockNoSleep, NoSleepTrait, NoSleepType};
+/// let mut nosleep = MockNoSleep::new().unwrap();
+/// nosleep.prevent_display_sleep().unwrap();
+/// assert!(nosleep.is_blocked());
+/// assert_eq!(
+///     &[MockCall::PreventSleep(NoSleepType::PreventUserIdleDisplaySleep)],
+///     nosleep.calls()
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct MockNoSleep {
+    calls: Vec<MockCall>,
+    // Returned by the next fallible call instead of succeeding
+    error: Option<NoSleepError>,
+    nosleep_types: Vec<NoSleepType>,
+    suspended: Vec<NoSleepType>,
+    deadline: Option<Instant>,
+    stats: BlockTracker,
+    events: StateEvents,
+    capabilities: Option<Capabilities>,
+    resume_callback: Option<Callback>,
+    revoked_callback: Option<Callback>,
+}
+
+// A callback of `on_resume` or `on_revoked`, called by
+// `MockNoSleep::wake` and `MockNoSleep::revoke`
+struct Callback(Box<dyn Fn() + Send + Sync>);
+
+impl std::fmt::Debug for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+impl MockNoSleep {
+    /// The calls made so far, oldest first.
+    pub fn calls(&self) -> &[MockCall] {
+        &self.calls
+    }
+
+    /// Forgets the calls made so far.
+    pub fn clear_calls(&mut self) {
+        self.calls.clear();
+    }
+
+    /// Makes the next fallible call return `error` without changing the block.
+    pub fn fail_next(&mut self, error: NoSleepError) {
+        self.error = Some(error);
+    }
+
+    /// Sets what [`NoSleepTrait::capabilities`] returns.
+    /// Everything is supported by default.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = Some(capabilities);
+    }
+
+    /// Simulates the machine waking up, calling the callback registered
+    /// with [`NoSleepTrait::on_resume`] if any.
+    pub fn wake(&self) {
+        if let Some(callback) = &self.resume_callback {
+            (callback.0)();
+        }
+    }
+
+    /// Simulates the OS revoking the block: releases it and calls the
+    /// callback registered with [`NoSleepTrait::on_revoked`] if any.
+    /// Does nothing if no block is held.
+    pub fn revoke(&mut self) {
+        if !self.is_blocked() {
+            return;
+        }
+        self.events.revoked();
+        self.release();
+        if let Some(callback) = &self.revoked_callback {
+            (callback.0)();
+        }
+    }
+
+    // Records `call` and returns the injected error, if any
+    fn record(&mut self, call: MockCall) -> Result<(), NoSleepError> {
+        self.calls.push(call);
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn hold(&mut self, nosleep_types: &[NoSleepType], deadline: Option<Instant>) {
+        self.nosleep_types = nosleep_types.to_vec();
+        self.suspended.clear();
+        self.deadline = deadline;
+        match NoSleepType::strongest(nosleep_types) {
+            Some(strongest) => self.stats.start(strongest, deadline),
+            None => self.stats.finish(),
+        }
+        self.events.update(NoSleepType::strongest(nosleep_types));
+    }
+
+    fn release(&mut self) {
+        self.nosleep_types.clear();
+        self.suspended.clear();
+        self.deadline = None;
+        self.stats.finish();
+        self.events.update(None);
+    }
+}
+
+// Observers must not see the block of a dropped mock
+impl Drop for MockNoSleep {
+    fn drop(&mut self) {
+        self.events.update(None);
+    }
+}
+
+impl NoSleepTrait for MockNoSleep {
+    fn new() -> Result<Self, NoSleepError> {
+        Ok(MockNoSleep::default())
+    }
+
+    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.record(MockCall::PreventSleep(nosleep_type))?;
+        self.hold(&[nosleep_type], None);
+        Ok(())
+    }
+
+    fn prevent(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        self.record(MockCall::Prevent(nosleep_types.to_vec()))?;
+        self.hold(nosleep_types, None);
+        Ok(())
+    }
+
+    fn prevent_display_sleep_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.record(MockCall::PreventDisplaySleepUntil(deadline))?;
+        if deadline <= Instant::now() {
+            self.release();
+        } else {
+            self.hold(&[NoSleepType::PreventUserIdleDisplaySleep], Some(deadline));
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), NoSleepError> {
+        self.record(MockCall::Stop)?;
+        self.release();
+        self.resume_callback = None;
+        self.revoked_callback = None;
+        Ok(())
+    }
+
+    fn stop_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.record(MockCall::StopType(nosleep_type))?;
+        if !self.nosleep_types.contains(&nosleep_type) {
+            return Ok(());
+        }
+        let others = self
+            .nosleep_types
+            .iter()
+            .copied()
+            .filter(|held| *held != nosleep_type)
+            .collect::<Vec<_>>();
+        if others.is_empty() {
+            self.release();
+        } else {
+            self.hold(&others, self.deadline);
+        }
+        Ok(())
+    }
+
+    fn set_reason(&mut self, reason: &str) -> Result<(), NoSleepError> {
+        self.record(MockCall::SetReason(reason.to_string()))
+    }
+
+    fn suspend(&mut self) -> Result<(), NoSleepError> {
+        self.record(MockCall::Suspend)?;
+        if self.is_blocked() {
+            let nosleep_types = std::mem::take(&mut self.nosleep_types);
+            self.release();
+            self.suspended = nosleep_types;
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), NoSleepError> {
+        self.record(MockCall::Resume)?;
+        let nosleep_types = std::mem::take(&mut self.suspended);
+        if !nosleep_types.is_empty() {
+            self.hold(&nosleep_types, None);
+        }
+        Ok(())
+    }
+
+    fn on_resume(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.record(MockCall::OnResume)?;
+        self.resume_callback = Some(Callback(callback));
+        Ok(())
+    }
+
+    fn on_revoked(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.record(MockCall::OnRevoked)?;
+        self.revoked_callback = Some(Callback(callback));
+        Ok(())
+    }
+
+    fn simulate_user_activity(&mut self) -> Result<(), NoSleepError> {
+        self.record(MockCall::SimulateUserActivity)
+    }
+
+    fn is_blocked(&self) -> bool {
+        !self.nosleep_types.is_empty()
+            && self
+                .deadline
+                .is_none_or(|deadline| Instant::now() < deadline)
+    }
+
+    fn handles(&self) -> Vec<HandleInfo> {
+        if !self.is_blocked() {
+            return vec![];
+        }
+        self.nosleep_types
+            .iter()
+            .enumerate()
+            .map(|(raw, kind)| HandleInfo {
+                kind: *kind,
+                backend: "Mock",
+                raw: raw as u64,
+                who: None,
+            })
+            .collect()
+    }
+
+    fn stats(&self) -> BlockStats {
+        self.stats.stats()
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// `Revoked` is reported by [`MockNoSleep::revoke`].
+    fn subscribe(&self) -> Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    fn observer(&self) -> NoSleepObserver {
+        self.events.observer()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.unwrap_or(Capabilities {
+            display_sleep: true,
+            system_sleep: true,
+            display_only: true,
+            screensaver: true,
+            timed_blocks: true,
+            battery_aware: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_mock_records_calls() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.set_reason("Playing a video").unwrap();
+        nosleep.suspend().unwrap();
+        assert!(!nosleep.is_blocked());
+        nosleep.resume().unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.simulate_user_activity().unwrap();
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+        assert_eq!(
+            &[
+                MockCall::PreventSleep(NoSleepType::PreventUserIdleDisplaySleep),
+                MockCall::SetReason("Playing a video".to_string()),
+                MockCall::Suspend,
+                MockCall::Resume,
+                MockCall::SimulateUserActivity,
+                MockCall::Stop,
+            ],
+            nosleep.calls()
+        );
+        nosleep.clear_calls();
+        assert!(nosleep.calls().is_empty());
+    }
+
+    #[test]
+    fn test_mock_stop_type() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleDisplaySleep,
+                NoSleepType::PreventSystemSleep,
+            ])
+            .unwrap();
+        nosleep
+            .stop_type(NoSleepType::PreventUserIdleDisplaySleep)
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(vec![NoSleepType::PreventSystemSleep], nosleep.nosleep_types);
+        // Not held, nothing changes
+        nosleep.stop_type(NoSleepType::PreventScreenSaver).unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.stop_type(NoSleepType::PreventSystemSleep).unwrap();
+        assert!(!nosleep.is_blocked());
+        assert_eq!(
+            &MockCall::StopType(NoSleepType::PreventSystemSleep),
+            nosleep.calls().last().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mock_fail_next() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        nosleep.fail_next(NoSleepError::PreventSleep {
+            reason: "Injected".to_string(),
+            code: None,
+        });
+        assert!(matches!(
+            nosleep.prevent_system_sleep(),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert!(!nosleep.is_blocked());
+        // Only the next call fails
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.is_blocked());
+
+        nosleep.fail_next(NoSleepError::StopLock {
+            reason: "Injected".to_string(),
+            code: None,
+        });
+        assert!(matches!(nosleep.stop(), Err(NoSleepError::StopLock { .. })));
+        assert!(nosleep.is_blocked());
+        assert_eq!(3, nosleep.calls().len());
+    }
+
+    #[test]
+    fn test_mock_prevent_display_sleep_until() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() + Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(1, nosleep.handles().len());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.handles().is_empty());
+    }
+
+    #[test]
+    fn test_mock_on_resume() {
+        let woken = Arc::new(AtomicUsize::new(0));
+        let mut nosleep = MockNoSleep::new().unwrap();
+        let counter = woken.clone();
+        nosleep
+            .on_resume(Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .unwrap();
+        nosleep.wake();
+        assert_eq!(1, woken.load(Ordering::SeqCst));
+        nosleep.stop().unwrap();
+        nosleep.wake();
+        assert_eq!(1, woken.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_mock_on_revoked() {
+        let revoked = Arc::new(AtomicUsize::new(0));
+        let mut nosleep = MockNoSleep::new().unwrap();
+        let counter = revoked.clone();
+        nosleep
+            .on_revoked(Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .unwrap();
+        // Nothing to revoke yet
+        nosleep.revoke();
+        assert_eq!(0, revoked.load(Ordering::SeqCst));
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.revoke();
+        assert!(!nosleep.is_blocked());
+        assert_eq!(1, revoked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_mock_observer() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        let observer = nosleep.observer();
+        assert!(!observer.is_blocked());
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(observer.is_blocked());
+        assert_eq!(
+            Some(NoSleepType::PreventUserIdleDisplaySleep),
+            observer.current_block()
+        );
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(
+            Some(NoSleepType::PreventUserIdleSystemSleep),
+            observer.current_block()
+        );
+        nosleep.stop().unwrap();
+        assert!(!observer.is_blocked());
+
+        nosleep.prevent_display_sleep().unwrap();
+        let events = observer.subscribe();
+        drop(nosleep);
+        assert!(!observer.is_blocked());
+        assert!(matches!(
+            events.try_iter().collect::<Vec<_>>()[..],
+            [StateEvent::Stopped { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_mock_subscribe() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        let first = nosleep.subscribe();
+        let second = nosleep.subscribe();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        nosleep.revoke();
+        nosleep.prevent_screensaver().unwrap();
+        nosleep.stop().unwrap();
+
+        for events in [first, second] {
+            let received = events.try_iter().collect::<Vec<_>>();
+            assert!(matches!(
+                received[..],
+                [
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleDisplaySleep,
+                        ..
+                    },
+                    StateEvent::Switched {
+                        from: NoSleepType::PreventUserIdleDisplaySleep,
+                        to: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Revoked { .. },
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventScreenSaver,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                ]
+            ));
+            assert!(received.windows(2).all(|pair| pair[0].at() <= pair[1].at()));
+        }
+    }
+
+    #[test]
+    fn test_mock_capabilities() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        assert!(nosleep.is_supported_type(NoSleepType::PreventScreenSaver));
+        nosleep.set_capabilities(Capabilities::default());
+        assert!(!nosleep.is_supported_type(NoSleepType::PreventScreenSaver));
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The shortest interval of an [`ActivityPoke`], shorter ones are raised to it.
+pub const MIN_POKE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Calls a function on a helper thread every interval, for example to
+/// reset the idle timer of the OS as if the user was active. For the
+/// environments that ignore inhibitors but not simulated user activity.
+///
+/// The first poke is made right away. The pokes keep to the schedule of
+/// the first one: a slow poke does not delay the next, and pokes missed
+/// while one ran are skipped. The pokes end on [`ActivityPoke::stop`] or
+/// on drop, after which the function is dropped on the helper thread.
+pub struct ActivityPoke {
+    // Dropping the sender wakes up the helper thread before the next poke
+    cancel: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ActivityPoke {
+    /// Starts poking every `interval`, at least [`MIN_POKE_INTERVAL`].
+    pub fn start(interval: Duration, mut poke: impl FnMut() + Send + 'static) -> ActivityPoke {
+        let interval = interval.max(MIN_POKE_INTERVAL);
+        let (cancel_tx, cancel_rx) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let started = Instant::now();
+            loop {
+                poke();
+                let now = Instant::now();
+                let timeout = next_poke(started, interval, now).saturating_duration_since(now);
+                if !matches!(
+                    cancel_rx.recv_timeout(timeout),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                ) {
+                    break;
+                }
+            }
+        });
+        ActivityPoke {
+            cancel: Some(cancel_tx),
+            thread: Some(thread),
+        }
+    }
+
+    /// Ends the pokes and waits for the helper thread.
+    pub fn stop(&mut self) {
+        self.cancel.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ActivityPoke {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// The first poke of the schedule that started at `started` after `now`
+fn next_poke(started: Instant, interval: Duration, now: Instant) -> Instant {
+    let interval = interval.as_nanos();
+    let elapsed = now.saturating_duration_since(started).as_nanos();
+    started + Duration::from_nanos(((elapsed / interval + 1) * interval) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_next_poke() {
+        let started = Instant::now();
+        let interval = Duration::from_secs(30);
+        let at = |secs: u64| started + Duration::from_secs(secs);
+        assert_eq!(at(30), next_poke(started, interval, started));
+        assert_eq!(at(30), next_poke(started, interval, at(1)));
+        // A poke due right now is the one that just ran
+        assert_eq!(at(60), next_poke(started, interval, at(30)));
+        // Pokes missed while a slow one ran are skipped
+        assert_eq!(at(120), next_poke(started, interval, at(95)));
+    }
+
+    #[test]
+    fn test_activity_poke() {
+        let pokes = Arc::new(AtomicUsize::new(0));
+        let mut poke = {
+            let pokes = pokes.clone();
+            ActivityPoke::start(Duration::ZERO, move || {
+                pokes.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(MIN_POKE_INTERVAL * 3 + MIN_POKE_INTERVAL / 2);
+        poke.stop();
+        let poked = pokes.load(Ordering::SeqCst);
+        // Right away and three times after, give or take a slow thread
+        assert!((2..=4).contains(&poked), "{} pokes", poked);
+        std::thread::sleep(MIN_POKE_INTERVAL * 2);
+        assert_eq!(poked, pokes.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,148 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{NoSleepError, NoSleepTrait, NoSleepType};
+
+/// A block held by a helper thread while a predicate returns `true`,
+/// for example while the CPU is busy or a file is being written.
+///
+/// The helper thread owns its own backend instance and calls the predicate
+/// every interval. The block is released once the predicate returns `false`
+/// or panics, on [`PredicateBlock::stop`] or on drop.
+pub struct PredicateBlock {
+    // Dropping the sender wakes up the helper thread before the next check
+    cancel: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PredicateBlock {
+    /// Acquires a block of `nosleep_type` on a helper thread and
+    /// returns once the block is held (or failed to be acquired).
+    /// The predicate is first called `interval` after that.
+    pub fn start<T: NoSleepTrait + 'static>(
+        nosleep_type: NoSleepType,
+        interval: Duration,
+        mut predicate: impl FnMut() -> bool + Send + 'static,
+    ) -> Result<PredicateBlock, NoSleepError> {
+        let (cancel_tx, cancel_rx) = mpsc::channel::<()>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let mut nosleep = match T::new() {
+                Ok(nosleep) => nosleep,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let result = nosleep.prevent_sleep(nosleep_type);
+            let acquired = result.is_ok();
+            let _ = ready_tx.send(result);
+            if !acquired {
+                return;
+            }
+            while let Err(mpsc::RecvTimeoutError::Timeout) = cancel_rx.recv_timeout(interval) {
+                // A panicking predicate cannot tell anymore, so it counts as `false`
+                if !panic::catch_unwind(AssertUnwindSafe(&mut predicate)).unwrap_or(false) {
+                    break;
+                }
+            }
+            // Best effort, there is no one left to report the error to
+            let _ = nosleep.stop();
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(PredicateBlock {
+                cancel: Some(cancel_tx),
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(NoSleepError::PreventSleep {
+                reason: e.to_string(),
+                code: None,
+            }),
+        }
+    }
+
+    /// Returns `true` until the block has been released.
+    pub fn is_active(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Releases the block without waiting for the predicate and waits
+    /// for the helper thread.
+    pub fn stop(&mut self) {
+        self.cancel.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for PredicateBlock {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::MockNoSleep;
+
+    fn wait_until_inactive(block: &PredicateBlock) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while block.is_active() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_predicate_block() {
+        let nosleep_type = NoSleepType::PreventUserIdleSystemSleep;
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let block = {
+            let ticks = ticks.clone();
+            // Busy for three ticks
+            PredicateBlock::start::<MockNoSleep>(
+                nosleep_type,
+                Duration::from_millis(20),
+                move || ticks.fetch_add(1, Ordering::SeqCst) < 3,
+            )
+            .unwrap()
+        };
+        assert!(block.is_active());
+        wait_until_inactive(&block);
+        assert!(!block.is_active());
+        assert_eq!(4, ticks.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_predicate_block_panic() {
+        let nosleep_type = NoSleepType::PreventUserIdleDisplaySleep;
+        let block =
+            PredicateBlock::start::<MockNoSleep>(nosleep_type, Duration::from_millis(20), || {
+                panic!("predicate failed")
+            })
+            .unwrap();
+        wait_until_inactive(&block);
+        assert!(!block.is_active());
+    }
+
+    #[test]
+    fn test_predicate_block_stop() {
+        let nosleep_type = NoSleepType::PreventSystemSleep;
+        let mut block =
+            PredicateBlock::start::<MockNoSleep>(nosleep_type, Duration::from_secs(60), || true)
+                .unwrap();
+        assert!(block.is_active());
+        block.stop();
+        assert!(!block.is_active());
+    }
+}
@@ -0,0 +1,128 @@
+use std::ops::DerefMut;
+use std::sync::{Arc, Weak};
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::{Mutex, MutexGuard};
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// The handles of every live instance of a backend, so `stop_all` can
+/// release them from any thread. A backend keeps one in a `static`
+/// and stores the handles of each instance in a [`Registered`].
+///
+/// Locks with `std::sync::Mutex`, or `parking_lot::Mutex` with the
+/// `parking_lot` feature, which is cheaper for the frequent checks
+/// like `is_blocked`.
+#[derive(Debug)]
+pub struct HandleRegistry<T> {
+    live: Mutex<Vec<Weak<Mutex<T>>>>,
+}
+
+/// The handles of one instance, shared with its [`HandleRegistry`].
+/// Unregistered once it and its clones are dropped.
+#[derive(Debug)]
+pub struct Registered<T>(Arc<Mutex<T>>);
+
+impl<T> HandleRegistry<T> {
+    pub const fn new() -> Self {
+        HandleRegistry {
+            live: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Tracks `handles` until the returned [`Registered`] is dropped.
+    pub fn register(&self, handles: T) -> Registered<T> {
+        let registered = Arc::new(Mutex::new(handles));
+        let mut live = lock(&self.live);
+        live.retain(|handles| handles.strong_count() > 0);
+        live.push(Arc::downgrade(&registered));
+        Registered(registered)
+    }
+
+    /// The handles of the instances that are still alive.
+    pub fn live(&self) -> Vec<Registered<T>> {
+        lock(&self.live)
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(Registered)
+            .collect()
+    }
+}
+
+impl<T> Default for HandleRegistry<T> {
+    fn default() -> Self {
+        HandleRegistry::new()
+    }
+}
+
+impl<T> Clone for Registered<T> {
+    fn clone(&self) -> Self {
+        Registered(self.0.clone())
+    }
+}
+
+// The same instance, not the same handles
+impl<T> PartialEq for Registered<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T> Eq for Registered<T> {}
+
+impl<T> Registered<T> {
+    /// Locks the handles. A panic while they were locked leaves them as they were.
+    pub fn lock(&self) -> impl DerefMut<Target = T> + '_ {
+        lock(&self.0)
+    }
+}
+
+#[cfg(feature = "parking_lot")]
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock()
+}
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_registry() {
+        let registry = HandleRegistry::new();
+        let first = registry.register(vec![1]);
+        let second = registry.register(vec![2, 3]);
+        assert_eq!(2, registry.live().len());
+        assert!(registry.live().contains(&first));
+        assert!(first != second);
+
+        for handles in registry.live() {
+            handles.lock().clear();
+        }
+        assert!(first.lock().is_empty());
+        assert!(second.lock().is_empty());
+
+        drop(first);
+        assert_eq!(1, registry.live().len());
+    }
+
+    #[test]
+    fn test_registered_lock_after_panic() {
+        let registry = HandleRegistry::new();
+        let registered = registry.register(vec![1]);
+        let handles = registered.clone();
+        let panicked = std::thread::spawn(move || {
+            let mut handles = handles.lock();
+            handles.push(2);
+            panic!("while locked");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert_eq!(vec![1, 2], *registered.lock());
+        assert_eq!(1, registry.live().len());
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use crate::NoSleepType;
+
+/// Cumulative time blocks were held, for example to report energy impact.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Total time display sleep was prevented
+    pub display_total: Duration,
+    /// Total time system sleep was prevented, excluding display blocks
+    pub system_total: Duration,
+    /// When the block currently held was acquired
+    pub current_started_at: Option<Instant>,
+}
+
+/// Records how long blocks are held. Shared by the backends,
+/// which call [`BlockTracker::start`] when a block is acquired
+/// and [`BlockTracker::finish`] when it is released.
+#[derive(Debug, Default, Clone)]
+pub struct BlockTracker {
+    totals: BlockStats,
+    // Type and optional deadline of the block currently held
+    current: Option<(NoSleepType, Option<Instant>)>,
+}
+
+impl BlockTracker {
+    /// Starts timing a block of `nosleep_type`, finishing any block timed before.
+    /// A block with a `deadline` is counted until the deadline at most.
+    pub fn start(&mut self, nosleep_type: NoSleepType, deadline: Option<Instant>) {
+        self.finish();
+        self.totals.current_started_at = Some(Instant::now());
+        self.current = Some((nosleep_type, deadline));
+    }
+
+    /// Adds the time the current block was held to the totals.
+    pub fn finish(&mut self) {
+        self.add_held(Instant::now());
+        self.current = None;
+        self.totals.current_started_at = None;
+    }
+
+    /// The totals of the released blocks and when the current block was acquired.
+    pub fn stats(&self) -> BlockStats {
+        let now = Instant::now();
+        match self.current {
+            // Released by the deadline, but not finished yet
+            Some((_, Some(deadline))) if deadline <= now => {
+                let mut tracker = self.clone();
+                tracker.finish();
+                tracker.totals
+            }
+            _ => self.totals,
+        }
+    }
+
+    /// Clears the totals, the block currently held is counted from now on.
+    pub fn reset(&mut self) {
+        self.totals = BlockStats {
+            current_started_at: self.current.map(|_| Instant::now()),
+            ..BlockStats::default()
+        };
+    }
+
+    fn add_held(&mut self, now: Instant) {
+        if let (Some((nosleep_type, deadline)), Some(started_at)) =
+            (self.current, self.totals.current_started_at)
+        {
+            let end = deadline.map_or(now, |deadline| deadline.min(now));
+            let held = end.saturating_duration_since(started_at);
+            match nosleep_type {
+                NoSleepType::PreventUserIdleDisplaySleep | NoSleepType::PreventScreenSaver => {
+                    self.totals.display_total += held
+                }
+                NoSleepType::PreventUserIdleSystemSleep | NoSleepType::PreventSystemSleep => {
+                    self.totals.system_total += held
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulates_held_time() {
+        let mut tracker = BlockTracker::default();
+        tracker.start(NoSleepType::PreventUserIdleDisplaySleep, None);
+        assert!(tracker.stats().current_started_at.is_some());
+        std::thread::sleep(Duration::from_millis(100));
+        tracker.finish();
+        let stats = tracker.stats();
+        assert!(stats.display_total >= Duration::from_millis(100));
+        assert!(stats.display_total < Duration::from_millis(500));
+        assert_eq!(Duration::ZERO, stats.system_total);
+        assert_eq!(None, stats.current_started_at);
+    }
+
+    #[test]
+    fn test_deadline_caps_held_time() {
+        let mut tracker = BlockTracker::default();
+        tracker.start(
+            NoSleepType::PreventUserIdleSystemSleep,
+            Some(Instant::now() + Duration::from_millis(50)),
+        );
+        std::thread::sleep(Duration::from_millis(150));
+        let stats = tracker.stats();
+        assert_eq!(None, stats.current_started_at);
+        // Counted from the start call, which is just after the deadline was set
+        assert!(stats.system_total > Duration::from_millis(40));
+        assert!(stats.system_total <= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut tracker = BlockTracker::default();
+        tracker.start(NoSleepType::PreventUserIdleSystemSleep, None);
+        std::thread::sleep(Duration::from_millis(10));
+        tracker.finish();
+        tracker.reset();
+        assert_eq!(BlockStats::default(), tracker.stats());
+    }
+}
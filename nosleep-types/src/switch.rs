@@ -0,0 +1,135 @@
+use alloc::format;
+
+use crate::{NoSleepError, NoSleepType};
+
+/// What `prevent_sleep` does when a block of a different type is held.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SwitchPolicy {
+    /// Releases the block held and acquires the requested one
+    #[default]
+    Replace,
+    /// Keeps the block held if it already covers the requested type,
+    /// for example a display block when a system block is requested
+    KeepStrongest,
+    /// Fails with [`NoSleepError::PreventSleep`] while a block of another type is held
+    Reject,
+}
+
+impl SwitchPolicy {
+    /// Returns `true` if the blocks of the types in `current` should be kept
+    /// instead of acquiring the types in `requested`.
+    /// Shared by the backends, which call it before replacing a block.
+    pub fn keep_current(
+        &self,
+        current: &[NoSleepType],
+        requested: &[NoSleepType],
+    ) -> Result<bool, NoSleepError> {
+        if current.is_empty() || requested.is_empty() {
+            return Ok(false);
+        }
+        match self {
+            SwitchPolicy::Replace => Ok(false),
+            SwitchPolicy::KeepStrongest => Ok(requested
+                .iter()
+                .all(|other| current.iter().any(|held| covers(*held, *other)))),
+            SwitchPolicy::Reject if !same_types(current, requested) => {
+                Err(NoSleepError::PreventSleep {
+                    reason: format!("A block of type {:?} is already held", current),
+                    code: None,
+                })
+            }
+            SwitchPolicy::Reject => Ok(false),
+        }
+    }
+}
+
+impl NoSleepType {
+    /// Returns the type in `nosleep_types` that covers most of the others,
+    /// for example the display type of a display and a system block.
+    /// The first one wins a tie.
+    pub fn strongest(nosleep_types: &[NoSleepType]) -> Option<NoSleepType> {
+        let covered = |held: &NoSleepType| {
+            nosleep_types
+                .iter()
+                .filter(|other| covers(*held, **other))
+                .count()
+        };
+        nosleep_types
+            .iter()
+            .rev()
+            .max_by_key(|held| covered(held))
+            .copied()
+    }
+}
+
+// Whether both slices hold the same types, ignoring order and duplicates
+fn same_types(a: &[NoSleepType], b: &[NoSleepType]) -> bool {
+    a.iter().all(|t| b.contains(t)) && b.iter().all(|t| a.contains(t))
+}
+
+// Whether a block of type `held` also prevents what `other` prevents
+fn covers(held: NoSleepType, other: NoSleepType) -> bool {
+    match held {
+        NoSleepType::PreventUserIdleDisplaySleep => matches!(
+            other,
+            NoSleepType::PreventUserIdleDisplaySleep
+                | NoSleepType::PreventUserIdleSystemSleep
+                | NoSleepType::PreventScreenSaver
+        ),
+        NoSleepType::PreventSystemSleep => matches!(
+            other,
+            NoSleepType::PreventSystemSleep | NoSleepType::PreventUserIdleSystemSleep
+        ),
+        _ => held == other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISPLAY: NoSleepType = NoSleepType::PreventUserIdleDisplaySleep;
+    const SYSTEM: NoSleepType = NoSleepType::PreventUserIdleSystemSleep;
+    const FORCED: NoSleepType = NoSleepType::PreventSystemSleep;
+
+    #[test]
+    fn test_replace() {
+        let policy = SwitchPolicy::Replace;
+        assert!(!policy.keep_current(&[DISPLAY], &[SYSTEM]).unwrap());
+        assert!(!policy.keep_current(&[], &[SYSTEM]).unwrap());
+    }
+
+    #[test]
+    fn test_keep_strongest() {
+        let policy = SwitchPolicy::KeepStrongest;
+        assert!(policy.keep_current(&[DISPLAY], &[SYSTEM]).unwrap());
+        assert!(policy.keep_current(&[DISPLAY], &[DISPLAY]).unwrap());
+        assert!(!policy.keep_current(&[SYSTEM], &[DISPLAY]).unwrap());
+        assert!(!policy.keep_current(&[SYSTEM], &[FORCED]).unwrap());
+        assert!(!policy.keep_current(&[], &[SYSTEM]).unwrap());
+        assert!(policy.keep_current(&[DISPLAY, FORCED], &[SYSTEM]).unwrap());
+        assert!(!policy.keep_current(&[DISPLAY], &[SYSTEM, FORCED]).unwrap());
+    }
+
+    #[test]
+    fn test_reject() {
+        let policy = SwitchPolicy::Reject;
+        assert!(matches!(
+            policy.keep_current(&[DISPLAY], &[SYSTEM]),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert!(!policy.keep_current(&[DISPLAY], &[DISPLAY]).unwrap());
+        assert!(!policy
+            .keep_current(&[DISPLAY, SYSTEM], &[SYSTEM, DISPLAY])
+            .unwrap());
+        assert!(!policy.keep_current(&[], &[SYSTEM]).unwrap());
+    }
+
+    #[test]
+    fn test_strongest() {
+        assert_eq!(Some(DISPLAY), NoSleepType::strongest(&[SYSTEM, DISPLAY]));
+        assert_eq!(Some(FORCED), NoSleepType::strongest(&[SYSTEM, FORCED]));
+        assert_eq!(Some(DISPLAY), NoSleepType::strongest(&[DISPLAY, FORCED]));
+        assert_eq!(None, NoSleepType::strongest(&[]));
+    }
+}
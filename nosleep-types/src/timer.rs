@@ -0,0 +1,99 @@
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::{NoSleepError, NoSleepTrait, NoSleepType};
+
+/// A block held by a helper thread until a deadline, for backends
+/// without native support for timed blocks. It takes a sleeping thread
+/// per block, the Linux and Windows backends wait on OS timers instead.
+///
+/// The helper thread owns its own backend instance, so the block is
+/// released when the deadline passes, on [`TimedBlock::stop`] or on drop.
+pub struct TimedBlock {
+    // Dropping the sender wakes up the helper thread before the deadline
+    cancel: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TimedBlock {
+    /// Acquires a block of `nosleep_type` on a helper thread and
+    /// returns once the block is held (or failed to be acquired).
+    pub fn start<T: NoSleepTrait + 'static>(
+        nosleep_type: NoSleepType,
+        deadline: Instant,
+    ) -> Result<TimedBlock, NoSleepError> {
+        let (cancel_tx, cancel_rx) = mpsc::channel::<()>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let mut nosleep = match T::new() {
+                Ok(nosleep) => nosleep,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let result = nosleep.prevent_sleep(nosleep_type);
+            let acquired = result.is_ok();
+            let _ = ready_tx.send(result);
+            if acquired {
+                let _ = cancel_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+                // Best effort, there is no one left to report the error to
+                let _ = nosleep.stop();
+            }
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(TimedBlock {
+                cancel: Some(cancel_tx),
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(NoSleepError::PreventSleep {
+                reason: e.to_string(),
+                code: None,
+            }),
+        }
+    }
+
+    /// Returns `true` until the block has been released.
+    pub fn is_active(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Releases the block before the deadline and waits for the helper thread.
+    pub fn stop(&mut self) {
+        self.cancel.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for TimedBlock {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::MockNoSleep;
+
+    #[test]
+    fn test_timed_block() {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut block =
+            TimedBlock::start::<MockNoSleep>(NoSleepType::PreventUserIdleDisplaySleep, deadline)
+                .unwrap();
+        assert!(block.is_active());
+        std::thread::sleep(Duration::from_millis(300));
+        // The helper thread exits once it released the block
+        assert!(!block.is_active());
+        block.stop();
+    }
+}
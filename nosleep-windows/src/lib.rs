@@ -4,157 +4,2149 @@
 //! Inspired on the Chromium source code
 //! https://chromium.googlesource.com/chromium/src/+/87cd0848a0d1453e7553a72b0686d42fabf8ff3a/device/power_save_blocker/power_save_blocker_win.cc
 
-use nosleep_types::{NoSleepError, NoSleepTrait};
+use std::borrow::Cow;
+use std::ffi::{c_void, OsStr, OsString};
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use nosleep_types::{
+    ActivityPoke, BackendProbe, BlockOutcome, BlockStats, BlockTracker, Capabilities,
+    DisplayFallback, EnvConfig, HandleInfo, HandleRegistry, HealthReport, NoSleepError,
+    NoSleepObserver, NoSleepTrait, Registered, StateEvent, StateEvents,
+};
+pub use nosleep_types::{BlockLevel, NoSleepType, SwitchPolicy};
+use timer::DeadlineTimer;
+use windows::core::GUID;
 use windows::core::PWSTR;
-use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Foundation::{CloseHandle, LocalFree, ERROR_SUCCESS, HANDLE, HLOCAL};
 use windows::Win32::System::Power::{
-    PowerClearRequest, PowerCreateRequest, PowerRequestDisplayRequired, PowerRequestSystemRequired,
-    PowerSetRequest, POWER_REQUEST_TYPE,
+    GetSystemPowerStatus, PowerClearRequest, PowerCreateRequest, PowerGetActiveScheme,
+    PowerReadACValueIndex, PowerReadDCValueIndex, PowerRegisterSuspendResumeNotification,
+    PowerRequestDisplayRequired, PowerRequestSystemRequired, PowerSetRequest,
+    PowerSettingRegisterNotification, PowerSettingUnregisterNotification,
+    PowerUnregisterSuspendResumeNotification, SetThreadExecutionState,
+    DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS, ES_DISPLAY_REQUIRED, HPOWERNOTIFY, POWERBROADCAST_SETTING,
+    POWER_REQUEST_TYPE, SYSTEM_POWER_STATUS,
+};
+use windows::Win32::System::Registry::HKEY;
+use windows::Win32::System::SystemServices::{
+    GUID_CONSOLE_DISPLAY_STATE, GUID_SLEEP_SUBGROUP, GUID_STANDBY_TIMEOUT,
 };
 use windows::Win32::System::Threading::{
     POWER_REQUEST_CONTEXT_SIMPLE_STRING, REASON_CONTEXT, REASON_CONTEXT_0,
 };
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    SendInput, INPUT, INPUT_0, INPUT_MOUSE, MOUSEEVENTF_MOVE, MOUSEINPUT,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND, PBT_POWERSETTINGCHANGE,
+};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum NoSleepType {
-    PreventUserIdleDisplaySleep,
-    PreventUserIdleSystemSleep,
-}
+mod timer;
 
+/// The types held as asked on Windows, for static checks where
+/// [`NoSleepTrait::is_supported_type`] needs an instance. Power requests
+/// cannot stop a sleep requested by the user, so `PreventSystemSleep` is
+/// left out: it is held as `PreventUserIdleSystemSleep`, or fails in
+/// strict mode.
+pub const SUPPORTED_TYPES: &[NoSleepType] = &[
+    NoSleepType::PreventUserIdleDisplaySleep,
+    NoSleepType::PreventUserIdleSystemSleep,
+    NoSleepType::PreventScreenSaver,
+];
+
+// Encodes as NUL terminated UTF-16. An interior NUL would silently
+// truncate the string on the Windows side, so it is rejected instead.
 trait IntoPWSTR {
-    fn into_pwstr(self) -> (PWSTR, Vec<u16>);
+    fn into_pwstr(self) -> Result<(PWSTR, Vec<u16>), NoSleepError>;
 }
 
-impl IntoPWSTR for &str {
-    fn into_pwstr(self) -> (PWSTR, Vec<u16>) {
-        let mut encoded = self.encode_utf16().chain([0u16]).collect::<Vec<u16>>();
+// Rejects what `into_pwstr` rejects, without encoding
+fn check_reason(reason: &OsStr) -> Result<(), NoSleepError> {
+    if reason.encode_wide().any(|unit| unit == 0) {
+        return Err(NoSleepError::PreventSleep {
+            reason: format!("Reason contains a NUL character: {:?}", reason),
+            code: None,
+        });
+    }
+    Ok(())
+}
 
-        (PWSTR(encoded.as_mut_ptr()), encoded)
+impl IntoPWSTR for &str {
+    fn into_pwstr(self) -> Result<(PWSTR, Vec<u16>), NoSleepError> {
+        OsStr::new(self).into_pwstr()
     }
 }
 impl IntoPWSTR for String {
-    fn into_pwstr(self) -> (PWSTR, Vec<u16>) {
-        let mut encoded = self.encode_utf16().chain([0u16]).collect::<Vec<u16>>();
+    fn into_pwstr(self) -> Result<(PWSTR, Vec<u16>), NoSleepError> {
+        self.as_str().into_pwstr()
+    }
+}
+
+// The wide string as is, paths are not always valid UTF-16
+impl IntoPWSTR for &OsStr {
+    fn into_pwstr(self) -> Result<(PWSTR, Vec<u16>), NoSleepError> {
+        check_reason(self)?;
+        let mut encoded = self.encode_wide().chain([0u16]).collect::<Vec<u16>>();
+
+        Ok((PWSTR(encoded.as_mut_ptr()), encoded))
+    }
+}
+impl IntoPWSTR for &Path {
+    fn into_pwstr(self) -> Result<(PWSTR, Vec<u16>), NoSleepError> {
+        self.as_os_str().into_pwstr()
+    }
+}
 
-        (PWSTR(encoded.as_mut_ptr()), encoded)
+// Keeps a `'static` reason borrowed
+fn os_reason(reason: Cow<'static, str>) -> Cow<'static, OsStr> {
+    match reason {
+        Cow::Borrowed(reason) => Cow::Borrowed(OsStr::new(reason)),
+        Cow::Owned(reason) => Cow::Owned(reason.into()),
     }
 }
 
-/// Returned by [`NoSleep::start`] to handle
-/// the power save block
-pub struct NoSleepHandle {
+// The power requests of a block
+#[derive(Clone, Copy)]
+struct PowerRequests {
     // Handle to the PowerRequestSystemRequired block
-    system_handle: HANDLE,
+    system_handle: Option<HANDLE>,
     // Handle to the PowerRequestDisplayRequired block
     display_handle: Option<HANDLE>,
 }
 
+// The requests of every live instance
+static REGISTRY: HandleRegistry<Option<PowerRequests>> = HandleRegistry::new();
+
 pub struct NoSleep {
-    // Handle to unlock the power save block
-    no_sleep_handle: Option<NoSleepHandle>,
+    // Handle to unlock the power save block, shared with `NoSleep::stop_all`
+    no_sleep_handle: Registered<Option<PowerRequests>>,
+
+    // The types of the blocks currently held
+    nosleep_types: Vec<NoSleepType>,
+
+    // The types of the blocks released by `suspend`
+    suspended: Vec<NoSleepType>,
+
+    // How long blocks were held
+    stats: BlockTracker,
+
+    // The subscribers of `subscribe`
+    events: StateEvents,
+
+    // Block held until a deadline
+    timed_block: Option<DeadlineTimer>,
+
+    // Human readable reason attached to the power requests, not always
+    // valid UTF-16 when set from a path, see `set_reason_os`
+    reason: Cow<'static, OsStr>,
+
+    // Who the block held is attributed to, see `prevent_sleep_as`
+    who: Option<String>,
+
+    // What `prevent_sleep` does when a block is already held
+    switch_policy: SwitchPolicy,
+
+    // The display blocks that failed in a row, see `prevent_display_sleep_or_degrade`
+    display_fallback: DisplayFallback,
+
+    // Why the block held went without its display request, if it did
+    display_error: Option<NoSleepError>,
+
+    // Calls back when the system resumes, until `stop`
+    resume_notification: Option<PowerNotification>,
+
+    // Calls back when the system suspends despite the block, until `stop`
+    revoke_notification: Option<PowerNotification>,
+
+    // Sets the requests again on resume, see `set_reacquire_on_resume`
+    reacquire_notification: Option<PowerNotification>,
+
+    // Keeps the workstation from locking while a display block is held
+    idle_input: Option<ActivityPoke>,
+
+    // How often display blocks simulate user activity, see `set_activity_poke`
+    activity_poke: Option<Duration>,
+
+    // How display blocks are held
+    display_mode: DisplayMode,
+
+    // Presentation mode entered for the display block, see `DisplayMode`
+    presentation: Option<PresentationMode>,
+
+    // Fails `PreventSystemSleep` instead of aliasing it, see `set_strict`
+    strict: bool,
+}
+
+/// How display blocks are held, see [`NoSleep::set_display_mode`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayMode {
+    /// Only power requests. Windows presentation mode is left alone and
+    /// does not show the block.
+    #[default]
+    PowerRequest,
+    /// The power requests, and Windows presentation mode is turned on while
+    /// the block is held, for IT tooling that looks at presentation mode.
+    /// Presentation mode also turns off the screensaver and, depending on
+    /// its settings, mutes the sound and changes the wallpaper.
+    ///
+    /// Toggled with `PresentationSettings.exe`, which only exists on mobile
+    /// PCs of the Pro and Enterprise editions. Without it acquiring the
+    /// block fails.
+    PresentationMode,
+}
+
+// Windows presentation mode, on until stopped or dropped
+struct PresentationMode {
+    stopped: bool,
+}
+
+// The command line that turns presentation mode on (`/start`) or off (`/stop`)
+fn presentation_command(action: &str) -> Command {
+    let mut command = Command::new("PresentationSettings.exe");
+    command.arg(action);
+    command
+}
+
+// Runs `action` and waits for `PresentationSettings.exe` to exit
+fn run_presentation_settings(action: &str) -> Result<(), String> {
+    let status = presentation_command(action)
+        .status()
+        .map_err(|e| format!("PresentationSettings.exe: {}", e))?;
+    if !status.success() {
+        return Err(format!("PresentationSettings.exe {}: {}", action, status));
+    }
+    Ok(())
 }
 
-fn create_power_request(power_request_type: POWER_REQUEST_TYPE) -> Result<HANDLE, NoSleepError> {
+impl PresentationMode {
+    fn start() -> Result<PresentationMode, NoSleepError> {
+        run_presentation_settings("/start")
+            .map_err(|reason| NoSleepError::PreventSleep { reason, code: None })?;
+        Ok(PresentationMode { stopped: false })
+    }
+
+    fn stop(mut self) -> Result<(), NoSleepError> {
+        self.stopped = true;
+        run_presentation_settings("/stop")
+            .map_err(|reason| NoSleepError::StopLock { reason, code: None })
+    }
+}
+
+impl Drop for PresentationMode {
+    fn drop(&mut self) {
+        if !self.stopped {
+            // Best effort, there is no way to report the error
+            let _ = run_presentation_settings("/stop");
+        }
+    }
+}
+
+// The reason shown by `powercfg /requests` when none is set
+const DEFAULT_REASON: &str = "Power Save Blocker";
+
+fn create_power_request(
+    power_request_type: POWER_REQUEST_TYPE,
+    reason: &OsStr,
+) -> Result<HANDLE, NoSleepError> {
+    set_power_request(new_power_request(reason)?, power_request_type)
+}
+
+// Creates a request that is not set yet
+fn new_power_request(reason: &OsStr) -> Result<HANDLE, NoSleepError> {
+    // The buffer must outlive the call to `PowerCreateRequest`
+    let (reason, _buffer) = reason.into_pwstr()?;
     let reason = REASON_CONTEXT {
         Version: 0,
         Flags: POWER_REQUEST_CONTEXT_SIMPLE_STRING,
         Reason: REASON_CONTEXT_0 {
-            SimpleReasonString: "Power Save Blocker".into_pwstr().0,
+            SimpleReasonString: reason,
         },
     };
+    unsafe { PowerCreateRequest(&reason).map_err(prevent_error) }
+}
+
+// Sets the new request `handle`, which is closed if that fails
+fn set_power_request(
+    handle: HANDLE,
+    power_request_type: POWER_REQUEST_TYPE,
+) -> Result<HANDLE, NoSleepError> {
     unsafe {
-        let handle = PowerCreateRequest(&reason).map_err(|e| NoSleepError::PreventSleep {
+        if let Err(e) = PowerSetRequest(handle, power_request_type) {
+            // The request was created but never set, do not leak it
+            let _ = CloseHandle(handle);
+            return Err(prevent_error(e));
+        }
+        Ok(handle)
+    }
+}
+
+// The HRESULT of `e` as unsigned, as Windows documents it
+fn hresult_code(e: &windows::core::Error) -> Option<i64> {
+    Some(e.code().0 as u32 as i64)
+}
+
+// HRESULTs of requests refused on purpose, e.g. by a group policy:
+// E_ACCESSDENIED and HRESULT_FROM_WIN32(ERROR_PRIVILEGE_NOT_HELD)
+const POLICY_DENIED: [u32; 2] = [0x80070005, 0x80070522];
+
+// Classifies a failed request, policy denials get their own variant
+fn prevent_error(e: windows::core::Error) -> NoSleepError {
+    let code = hresult_code(&e);
+    if code.is_some_and(|code| POLICY_DENIED.contains(&(code as u32))) {
+        NoSleepError::PolicyDenied {
             reason: e.to_string(),
-        })?;
-        PowerSetRequest(handle, power_request_type).map_err(|e| NoSleepError::PreventSleep {
+            code,
+        }
+    } else {
+        NoSleepError::PreventSleep {
             reason: e.to_string(),
-        })?;
-        Ok(handle)
+            code,
+        }
     }
 }
 
-impl NoSleep {
-    /// Blocks the system from entering low-power (sleep) mode by
-    /// making a call to the Windows `PowerCreateRequest`/`PowerSetRequest` system call.
-    /// If [`self::stop`] is not called, then he lock will be cleaned up
-    /// when NoSleep is dropped.
-    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
-        // Clear any previous lock held
-        self.stop()?;
-
-        // TODO:
-        // PowerRequestSystemRequired implies PowerRequestExsecutionRequired
-        // So we don't have to check the Windows version?
-        let system_handle = create_power_request(PowerRequestSystemRequired)?;
-        let display_handle = if nosleep_type == NoSleepType::PreventUserIdleDisplaySleep {
-            create_power_request(PowerRequestDisplayRequired).ok()
-        } else {
-            None
-        };
-        self.no_sleep_handle = Some(NoSleepHandle {
+// Creates the requests needed by `nosleep_types`. A block of
+// `PreventUserIdleDisplaySleep` goes without the display request if it
+// cannot be created, the error is returned alongside the requests.
+fn create_power_requests(
+    nosleep_types: &[NoSleepType],
+    reason: &OsStr,
+) -> Result<(PowerRequests, Option<NoSleepError>), NoSleepError> {
+    // TODO:
+    // PowerRequestSystemRequired implies PowerRequestExsecutionRequired
+    // So we don't have to check the Windows version?
+    let system_handle = if nosleep_types
+        .iter()
+        // Equivalent to ES_DISPLAY_REQUIRED, the system may still sleep
+        .any(|nosleep_type| *nosleep_type != NoSleepType::PreventScreenSaver)
+    {
+        Some(create_power_request(PowerRequestSystemRequired, reason)?)
+    } else {
+        None
+    };
+    let mut display_error = None;
+    let display_handle = if nosleep_types.contains(&NoSleepType::PreventScreenSaver) {
+        match create_power_request(PowerRequestDisplayRequired, reason) {
+            Ok(display_handle) => Some(display_handle),
+            Err(e) => {
+                if let Some(system_handle) = system_handle {
+                    // Best effort, the original error is more useful
+                    let _ = release_power_request(system_handle, PowerRequestSystemRequired);
+                }
+                return Err(e);
+            }
+        }
+    } else if nosleep_types.contains(&NoSleepType::PreventUserIdleDisplaySleep) {
+        match create_power_request(PowerRequestDisplayRequired, reason) {
+            Ok(display_handle) => Some(display_handle),
+            Err(e) => {
+                display_error = Some(e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    Ok((
+        PowerRequests {
             system_handle,
             display_handle,
-        });
-        Ok(())
+        },
+        display_error,
+    ))
+}
+
+// Clears and closes the requests of a block, if any. They are taken before
+// clearing, as the handles are closed even if clearing fails, so they must
+// never be cleared again.
+fn release_power_requests(
+    no_sleep_handle: &Registered<Option<PowerRequests>>,
+) -> Result<(), NoSleepError> {
+    let mut result = Ok(());
+    if let Some(handle) = no_sleep_handle.lock().take() {
+        let requests = [
+            (handle.system_handle, PowerRequestSystemRequired),
+            (handle.display_handle, PowerRequestDisplayRequired),
+        ];
+        for (handle, power_request_type) in requests {
+            if let Some(handle) = handle {
+                result = result.and(release_power_request(handle, power_request_type));
+            }
+        }
     }
+    result
 }
 
-impl NoSleepTrait for NoSleep {
-    fn new() -> Result<NoSleep, NoSleepError> {
-        Ok(NoSleep {
-            no_sleep_handle: None,
+// Clears and closes a request created by `create_power_request`
+fn release_power_request(
+    handle: HANDLE,
+    power_request_type: POWER_REQUEST_TYPE,
+) -> Result<(), NoSleepError> {
+    unsafe {
+        let result = PowerClearRequest(handle, power_request_type);
+        let _ = CloseHandle(handle);
+        result.map_err(|e| NoSleepError::StopLock {
+            reason: e.to_string(),
+            code: hresult_code(&e),
         })
     }
+}
 
-    fn prevent_display_sleep(&mut self) -> Result<(), NoSleepError> {
-        self.prevent_sleep(NoSleepType::PreventUserIdleDisplaySleep)
+// Clears and sets again the requests of a block. Holds the lock
+// throughout, so `stop` releases them either before or after.
+fn reapply_power_requests(
+    no_sleep_handle: &Registered<Option<PowerRequests>>,
+) -> Result<(), NoSleepError> {
+    let handle = no_sleep_handle.lock();
+    let Some(handle) = handle.as_ref() else {
+        return Ok(());
+    };
+    let requests = [
+        (handle.system_handle, PowerRequestSystemRequired),
+        (handle.display_handle, PowerRequestDisplayRequired),
+    ];
+    for (handle, power_request_type) in requests {
+        if let Some(handle) = handle {
+            unsafe {
+                // Requests are counted, a second set would need a second clear
+                let _ = PowerClearRequest(handle, power_request_type);
+                PowerSetRequest(handle, power_request_type).map_err(prevent_error)?;
+            }
+        }
     }
+    Ok(())
+}
 
-    fn prevent_system_sleep(&mut self) -> Result<(), NoSleepError> {
-        self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)
+// Receives the console display state, `context` is a `SyncSender<u8>`
+unsafe extern "system" fn display_state_callback(
+    context: *const c_void,
+    r#type: u32,
+    setting: *const c_void,
+) -> u32 {
+    if r#type == PBT_POWERSETTINGCHANGE && !setting.is_null() {
+        let setting = &*(setting as *const POWERBROADCAST_SETTING);
+        if setting.PowerSetting == GUID_CONSOLE_DISPLAY_STATE && setting.DataLength >= 1 {
+            let sender = &*(context as *const mpsc::SyncSender<u8>);
+            let _ = sender.try_send(setting.Data[0]);
+        }
     }
+    0
+}
 
-    fn stop(&mut self) -> Result<(), NoSleepError> {
-        if let Some(handle) = &self.no_sleep_handle {
-            unsafe {
-                PowerClearRequest(handle.system_handle, PowerRequestSystemRequired).map_err(
-                    |e| NoSleepError::StopLock {
-                        reason: e.to_string(),
-                    },
-                )?;
-                if let Some(display_handle) = handle.display_handle {
-                    PowerClearRequest(display_handle, PowerRequestDisplayRequired).map_err(
-                        |e| NoSleepError::StopLock {
-                            reason: e.to_string(),
-                        },
-                    )?;
+// Windows reports the current display state right after registering
+// for GUID_CONSOLE_DISPLAY_STATE, 0 is off, 1 is on and 2 is dimmed
+fn display_state() -> Option<u8> {
+    let (sender, receiver) = mpsc::sync_channel::<u8>(1);
+    let mut params = DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(display_state_callback),
+        Context: &sender as *const _ as *mut c_void,
+    };
+    let mut registration = std::ptr::null_mut();
+    unsafe {
+        PowerSettingRegisterNotification(
+            &GUID_CONSOLE_DISPLAY_STATE,
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(&mut params as *mut _ as isize),
+            &mut registration,
+        )
+        .ok()
+        .ok()?;
+    }
+    let state = receiver.recv_timeout(Duration::from_millis(500)).ok();
+    unsafe {
+        // Stops the callbacks before `sender` goes out of scope
+        let _ = PowerSettingUnregisterNotification(HPOWERNOTIFY(registration as isize));
+    }
+    state
+}
+
+// The standby timeout of the active power scheme in seconds, the DC
+// value when running on battery. 0 means the system never sleeps.
+fn standby_timeout() -> Option<u32> {
+    unsafe {
+        let mut scheme: *mut GUID = std::ptr::null_mut();
+        if PowerGetActiveScheme(HKEY::default(), &mut scheme) != ERROR_SUCCESS || scheme.is_null() {
+            return None;
+        }
+        let mut status = SYSTEM_POWER_STATUS::default();
+        // 0 is offline, 1 online and 255 unknown, which counts as AC
+        let on_battery = GetSystemPowerStatus(&mut status).is_ok() && status.ACLineStatus == 0;
+        let mut seconds = 0;
+        let read = if on_battery {
+            PowerReadDCValueIndex(
+                HKEY::default(),
+                Some(scheme),
+                Some(&GUID_SLEEP_SUBGROUP),
+                Some(&GUID_STANDBY_TIMEOUT),
+                &mut seconds,
+            )
+        } else {
+            PowerReadACValueIndex(
+                HKEY::default(),
+                Some(scheme),
+                Some(&GUID_SLEEP_SUBGROUP),
+                Some(&GUID_STANDBY_TIMEOUT),
+                &mut seconds,
+            )
+        };
+        let _ = LocalFree(HLOCAL(scheme as *mut c_void));
+        (read == ERROR_SUCCESS.0).then_some(seconds)
+    }
+}
+
+// A "Sleep after" of 0 seconds is "Never"
+fn sleep_after(seconds: u32) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(u64::from(seconds)))
+}
+
+// Injects a zero-distance mouse movement, which resets the input idle
+// timer without moving the cursor
+fn send_idle_input() {
+    let input = INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx: 0,
+                dy: 0,
+                mouseData: 0,
+                dwFlags: MOUSEEVENTF_MOVE,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    };
+    // Best effort, fails on the secure desktop for example
+    unsafe { SendInput(&[input], std::mem::size_of::<INPUT>() as i32) };
+}
+
+// Resets the display idle timer once, and the input idle timer with it
+fn poke_activity() {
+    unsafe { SetThreadExecutionState(ES_DISPLAY_REQUIRED) };
+    send_idle_input();
+}
+
+type PowerCallback = Box<dyn Fn() + Send + Sync>;
+
+// The context of a `PowerNotification`
+struct PowerEventCallback {
+    // PBT_APMRESUMEAUTOMATIC or PBT_APMSUSPEND
+    event: u32,
+    callback: PowerCallback,
+}
+
+// Calls back on every suspend or resume event until dropped
+struct PowerNotification {
+    registration: HPOWERNOTIFY,
+    // The context of the registration, freed after unregistering
+    _callback: Box<PowerEventCallback>,
+}
+
+// Runs on a thread of the power manager, `context` is a `PowerEventCallback`
+unsafe extern "system" fn power_event_callback(
+    context: *const c_void,
+    r#type: u32,
+    _setting: *const c_void,
+) -> u32 {
+    let callback = &*(context as *const PowerEventCallback);
+    if r#type == callback.event {
+        (callback.callback)();
+    }
+    0
+}
+
+impl PowerNotification {
+    fn register(event: u32, callback: PowerCallback) -> Result<PowerNotification, NoSleepError> {
+        let callback = Box::new(PowerEventCallback { event, callback });
+        let mut params = DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+            Callback: Some(power_event_callback),
+            Context: &*callback as *const PowerEventCallback as *mut c_void,
+        };
+        let mut registration = std::ptr::null_mut();
+        unsafe {
+            PowerRegisterSuspendResumeNotification(
+                DEVICE_NOTIFY_CALLBACK,
+                HANDLE(&mut params as *mut _ as isize),
+                &mut registration,
+            )
+            .ok()
+            .map_err(|e| NoSleepError::Init {
+                reason: e.to_string(),
+                code: hresult_code(&e),
+            })?;
+        }
+        Ok(PowerNotification {
+            registration: HPOWERNOTIFY(registration as isize),
+            _callback: callback,
+        })
+    }
+}
+
+impl Drop for PowerNotification {
+    fn drop(&mut self) {
+        unsafe {
+            // Stops the callbacks before the callback is freed
+            let _ = PowerUnregisterSuspendResumeNotification(self.registration);
+        }
+    }
+}
+
+impl NoSleep {
+    // The reason of new power requests, prefixed with who they are attributed to
+    fn attributed_reason(&self) -> Cow<'_, OsStr> {
+        match &self.who {
+            Some(who) => {
+                let mut reason = OsString::from(format!("{}: ", who));
+                reason.push(&self.reason);
+                Cow::Owned(reason)
+            }
+            None => Cow::Borrowed(&self.reason),
+        }
+    }
+
+    // Sets the reason and creates the power requests held again with it,
+    // as the reason of a power request cannot be changed
+    fn replace_reason(&mut self, reason: Cow<'static, OsStr>) -> Result<(), NoSleepError> {
+        check_reason(&reason)?;
+        self.reason = reason;
+        let Some(handle) = *self.no_sleep_handle.lock() else {
+            return Ok(());
+        };
+        let system_handle = match handle.system_handle {
+            Some(_) => Some(create_power_request(
+                PowerRequestSystemRequired,
+                &self.attributed_reason(),
+            )?),
+            None => None,
+        };
+        let display_handle = match handle.display_handle {
+            Some(_) => {
+                match create_power_request(PowerRequestDisplayRequired, &self.attributed_reason()) {
+                    Ok(display_handle) => Some(display_handle),
+                    Err(e) => {
+                        if let Some(system_handle) = system_handle {
+                            // Best effort, the original error is more useful
+                            let _ =
+                                release_power_request(system_handle, PowerRequestSystemRequired);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+        let new = PowerRequests {
+            system_handle,
+            display_handle,
+        };
+        let old = match self.no_sleep_handle.lock().as_mut() {
+            Some(current) => std::mem::replace(current, new),
+            // The deadline of a timed block passed in the meantime
+            None => new,
+        };
+        let mut result = Ok(());
+        let requests = [
+            (old.system_handle, PowerRequestSystemRequired),
+            (old.display_handle, PowerRequestDisplayRequired),
+        ];
+        for (handle, power_request_type) in requests {
+            if let Some(handle) = handle {
+                result = result.and(release_power_request(handle, power_request_type));
+            }
+        }
+        result
+    }
+
+    /// Sets the reason from an OS string, for example a file path, which
+    /// is passed to Windows as is, even if it is not valid Unicode.
+    /// Otherwise like [`NoSleepTrait::set_reason`].
+    pub fn set_reason_os(&mut self, reason: impl AsRef<OsStr>) -> Result<(), NoSleepError> {
+        self.replace_reason(Cow::Owned(reason.as_ref().to_os_string()))
+    }
+
+    /// Creates a new, unblocked NoSleep type carrying the same
+    /// configuration as `self`.
+    pub fn clone_config(&self) -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.reason = self.reason.clone();
+        nosleep.switch_policy = self.switch_policy;
+        nosleep.display_mode = self.display_mode;
+        nosleep.activity_poke = self.activity_poke;
+        nosleep.display_fallback = DisplayFallback::new(self.display_fallback.threshold());
+        nosleep.strict = self.strict;
+        Ok(nosleep)
+    }
+
+    /// Creates a new NoSleep type in strict mode, see [`NoSleep::set_strict`].
+    pub fn strict() -> Result<NoSleep, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.set_strict(true);
+        Ok(nosleep)
+    }
+
+    /// Fails `PreventSystemSleep` with [`NoSleepError::Unsupported`] instead
+    /// of holding it as `PreventUserIdleSystemSleep`, as power requests
+    /// cannot stop a sleep requested by the user. Defaults to `false`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Creates a new NoSleep type configured by environment variables,
+    /// for deployments that cannot change code. Unset variables keep the
+    /// defaults, invalid values are an `Init` error.
+    ///
+    /// - `NOSLEEP_BACKEND`: only `PowerCreateRequest` exists on Windows
+    /// - `NOSLEEP_REASON`: see [`NoSleepTrait::set_reason`]
+    ///
+    /// `NOSLEEP_APP_ID` and `NOSLEEP_DBUS_TIMEOUT_MS` only apply to Linux
+    /// and are ignored.
+    pub fn from_env() -> Result<NoSleep, NoSleepError> {
+        let config = EnvConfig::read()?;
+        if let Some(backend) = config
+            .backends
+            .iter()
+            .flatten()
+            .find(|backend| !backend.eq_ignore_ascii_case("PowerCreateRequest"))
+        {
+            return Err(NoSleepError::Init {
+                reason: format!("Unknown NOSLEEP_BACKEND: {}", backend),
+                code: None,
+            });
+        }
+        let mut nosleep = NoSleep::new()?;
+        if let Some(reason) = config.reason {
+            if reason.contains('\0') {
+                return Err(NoSleepError::Init {
+                    reason: format!(
+                        "Invalid NOSLEEP_REASON, contains a NUL character: {:?}",
+                        reason
+                    ),
+                    code: None,
+                });
+            }
+            nosleep.reason = os_reason(reason.into());
+        }
+        Ok(nosleep)
+    }
+
+    /// Creates and closes a power request with the configured reason,
+    /// without setting it. Meant for bug reports, see [`HealthReport`].
+    pub fn health_check(&self) -> HealthReport {
+        let (reason, _buffer) = match (&*self.reason).into_pwstr() {
+            Ok(reason) => reason,
+            Err(e) => return self.health_report(Some(e.to_string())),
+        };
+        let context = REASON_CONTEXT {
+            Version: 0,
+            Flags: POWER_REQUEST_CONTEXT_SIMPLE_STRING,
+            Reason: REASON_CONTEXT_0 {
+                SimpleReasonString: reason,
+            },
+        };
+        let error = match unsafe { PowerCreateRequest(&context) } {
+            Ok(handle) => {
+                unsafe {
+                    let _ = CloseHandle(handle);
+                }
+                None
+            }
+            Err(e) => Some(e.to_string()),
+        };
+        self.health_report(error)
+    }
+
+    // The report of a probe of `PowerCreateRequest` that failed with `error`, if any
+    fn health_report(&self, error: Option<String>) -> HealthReport {
+        let available = error.is_none();
+        HealthReport {
+            platform: format!("windows (nosleep-windows {})", env!("CARGO_PKG_VERSION")),
+            backends: vec![BackendProbe {
+                name: "PowerCreateRequest".to_string(),
+                available,
+                error,
+            }],
+            selected: available.then(|| "PowerCreateRequest".to_string()),
+            reason: self.reason.to_string_lossy().into_owned(),
+            app_id: None,
+            timeout: None,
+        }
+    }
+
+    /// Clears the power requests of every live NoSleep in this process,
+    /// best effort. Timed blocks are not released. Continues past failures
+    /// and returns the first error.
+    pub fn stop_all() -> Result<(), NoSleepError> {
+        NoSleep::release_all(REGISTRY.live())
+    }
+
+    fn release_all(live: Vec<Registered<Option<PowerRequests>>>) -> Result<(), NoSleepError> {
+        let mut result = Ok(());
+        for no_sleep_handle in live {
+            let Some(handle) = no_sleep_handle.lock().take() else {
+                continue;
+            };
+            let requests = [
+                (handle.system_handle, PowerRequestSystemRequired),
+                (handle.display_handle, PowerRequestDisplayRequired),
+            ];
+            for (handle, power_request_type) in requests {
+                if let Some(handle) = handle {
+                    result = result.and(release_power_request(handle, power_request_type));
                 }
             }
         }
+        result
+    }
+
+    /// Sets what `prevent_sleep` does when a block of a different type
+    /// is already held. Defaults to [`SwitchPolicy::Replace`].
+    pub fn set_switch_policy(&mut self, policy: SwitchPolicy) {
+        self.switch_policy = policy;
+    }
+
+    /// Sets how many calls in a row of `prevent_display_sleep_or_degrade`
+    /// may fail to hold the display block before they hold a system block
+    /// instead. `None`, the default, never degrades.
+    pub fn set_display_fallback_threshold(&mut self, threshold: Option<u32>) {
+        self.display_fallback.set_threshold(threshold);
+    }
+
+    /// Sets how display blocks are held, see [`DisplayMode`].
+    /// Applies to blocks acquired afterwards, except for
+    /// `prevent_display_sleep_until`, which only uses power requests.
+    pub fn set_display_mode(&mut self, mode: DisplayMode) {
+        self.display_mode = mode;
+    }
+
+    /// Simulates user activity every `interval` while a display block is
+    /// held, for environments that only respect user activity and not
+    /// power requests. Each poke resets the display idle timer with
+    /// `SetThreadExecutionState` and injects a mouse movement of zero
+    /// pixels with `SendInput`. `None`, the default, turns it off.
+    ///
+    /// Applies to blocks acquired afterwards, except for
+    /// `prevent_display_sleep_until`. `stop` and `suspend` end the pokes.
+    pub fn set_activity_poke(&mut self, interval: Option<Duration>) {
+        self.activity_poke = interval;
+    }
+
+    /// Sets the power requests of the block again every time the system
+    /// resumes, for drivers that drop them across a suspend. The callback
+    /// runs on a thread of the power manager on `PBT_APMRESUMEAUTOMATIC`.
+    /// A block released while the system sleeps is not acquired again.
+    /// Defaults to `false`.
+    pub fn set_reacquire_on_resume(&mut self, enabled: bool) -> Result<(), NoSleepError> {
+        self.reacquire_notification = None;
+        if enabled {
+            let no_sleep_handle = self.no_sleep_handle.clone();
+            self.reacquire_notification = Some(PowerNotification::register(
+                PBT_APMRESUMEAUTOMATIC,
+                Box::new(move || {
+                    // Best effort, there is no one to report the error to
+                    let _ = reapply_power_requests(&no_sleep_handle);
+                }),
+            )?);
+        }
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Acquires the block of `prevent`
+    fn hold(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        if let Some(nosleep_type) = nosleep_types
+            .iter()
+            .find(|nosleep_type| !self.is_supported_type(**nosleep_type))
+        {
+            return Err(NoSleepError::Unsupported {
+                operation: format!("{nosleep_type} in strict mode"),
+            });
+        }
+        let current: &[NoSleepType] = if self.is_blocked() {
+            &self.nosleep_types
+        } else {
+            &[]
+        };
+        if self.switch_policy.keep_current(current, nosleep_types)? {
+            return Ok(());
+        }
 
-    #[test]
-    fn test_display_sleep() {
-        let mut nosleep = NoSleep::new().unwrap();
-        nosleep.prevent_display_sleep().unwrap();
+        // Clear any previous lock held
+        self.release()?;
+
+        let Some(strongest) = NoSleepType::strongest(nosleep_types) else {
+            return Ok(());
+        };
+        let (requests, display_error) =
+            create_power_requests(nosleep_types, &self.attributed_reason())?;
+        *self.no_sleep_handle.lock() = Some(requests);
+        self.display_error = display_error;
+        self.nosleep_types = nosleep_types.to_vec();
+        self.stats.start(strongest, None);
+        if self.display_mode == DisplayMode::PresentationMode && requests.display_handle.is_some() {
+            match PresentationMode::start() {
+                Ok(presentation) => self.presentation = Some(presentation),
+                Err(e) => {
+                    // Best effort, the original error is more useful
+                    let _ = self.release();
+                    return Err(e);
+                }
+            }
+        }
+        self.start_activity_poke();
+        Ok(())
     }
 
-    #[test]
-    fn test_system_sleep() {
-        let mut nosleep = NoSleep::new().unwrap();
-        nosleep.prevent_system_sleep().unwrap();
+    // Starts the pokes of `set_activity_poke` if a display request is held
+    fn start_activity_poke(&mut self) {
+        let display_held = self
+            .no_sleep_handle
+            .lock()
+            .as_ref()
+            .is_some_and(|handle| handle.display_handle.is_some());
+        if let (Some(interval), true) = (self.activity_poke, display_held) {
+            self.idle_input = Some(ActivityPoke::start(interval, poke_activity));
+        }
     }
 
-    #[test]
-    fn test_stop() {
-        let mut nosleep = NoSleep::new().unwrap();
-        nosleep.prevent_display_sleep().unwrap();
-        nosleep.stop().unwrap();
+    // Acquires the block of `prevent_display_sleep_until`
+    fn hold_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.release()?;
+        if deadline <= Instant::now() {
+            return Ok(());
+        }
+        let (requests, _) = create_power_requests(
+            &[NoSleepType::PreventUserIdleDisplaySleep],
+            &self.attributed_reason(),
+        )?;
+        *self.no_sleep_handle.lock() = Some(requests);
+        let no_sleep_handle = self.no_sleep_handle.clone();
+        match DeadlineTimer::start(deadline, move || {
+            // Best effort, there is no one left to report the error to
+            let _ = release_power_requests(&no_sleep_handle);
+        }) {
+            Ok(timer) => self.timed_block = Some(timer),
+            Err(e) => {
+                // Best effort, the original error is more useful
+                let _ = release_power_requests(&self.no_sleep_handle);
+                return Err(e);
+            }
+        }
+        self.stats
+            .start(NoSleepType::PreventUserIdleDisplaySleep, Some(deadline));
+        Ok(())
+    }
+
+    // Runs `change` and reports the block held afterwards to the subscribers
+    fn reported<R>(&mut self, change: impl FnOnce(&mut NoSleep) -> R) -> R {
+        let result = change(self);
+        self.events.update(self.held_type());
+        result
+    }
+
+    // The type of the block held as reported to `subscribe`
+    fn held_type(&self) -> Option<NoSleepType> {
+        // Timed blocks are display blocks without `nosleep_types`
+        if self
+            .timed_block
+            .as_ref()
+            .is_some_and(DeadlineTimer::is_active)
+        {
+            return Some(NoSleepType::PreventUserIdleDisplaySleep);
+        }
+        NoSleepType::strongest(&self.nosleep_types)
+    }
+
+    // Releases the block, `stop` also ends the `on_resume` notifications
+    fn release(&mut self) -> Result<(), NoSleepError> {
+        self.idle_input = None;
+        // Reported after the power requests are cleared
+        let presentation = self
+            .presentation
+            .take()
+            .map_or(Ok(()), PresentationMode::stop);
+        // Cancelled first, so it does not release the requests of the next block
+        self.timed_block = None;
+        let result = release_power_requests(&self.no_sleep_handle);
+        self.stats.finish();
+        self.nosleep_types.clear();
+        self.suspended.clear();
+        self.display_error = None;
+        result.and(presentation)
+    }
+
+    /// Like `prevent_display_sleep`, but once display blocks failed as
+    /// many times in a row as set with
+    /// [`NoSleep::set_display_fallback_threshold`], a failure holds only a
+    /// `PowerRequestSystemRequired` request instead and returns
+    /// [`BlockOutcome::SystemOnly`]. For example when a policy refuses
+    /// display requests, or presentation mode cannot be entered.
+    /// A display block that succeeds starts the count over. Unlike
+    /// `prevent_display_sleep`, a block without its display request counts
+    /// as failed and is not held.
+    pub fn prevent_display_sleep_or_degrade(&mut self) -> Result<BlockOutcome, NoSleepError> {
+        let result = self.prevent_display_sleep().and_then(|()| {
+            match self.display_error.take() {
+                Some(e) => {
+                    // Best effort, the error of the display request is more useful
+                    let _ = self.reported(NoSleep::release);
+                    Err(e)
+                }
+                None => Ok(()),
+            }
+        });
+        let outcome = self.display_fallback.outcome(result)?;
+        if outcome == BlockOutcome::SystemOnly {
+            self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Keeps the system awake while explicitly allowing the display to dim
+    /// and turn off, for example in a kiosk that wants to save the panel.
+    ///
+    /// Only a `PowerRequestSystemRequired` request is held. In contrast,
+    /// [`NoSleepTrait::prevent_display_sleep`] additionally holds a
+    /// `PowerRequestDisplayRequired` request which keeps the display on.
+    pub fn prevent_system_sleep_allow_display_dim(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)
+    }
+
+    /// Keeps the display on and the workstation from locking itself as far
+    /// as Windows allows, for example on a dashboard.
+    ///
+    /// The display request of [`NoSleepTrait::prevent_display_sleep`]
+    /// keeps the screensaver away, and with it the lock of a password
+    /// protected screensaver. The "Machine inactivity limit" security policy
+    /// however locks after a lack of user input, which no power request
+    /// can prevent, so that lock cannot be suppressed. Locking with Win+L
+    /// or Dynamic Lock still works.
+    pub fn prevent_display_sleep_and_lock(&mut self) -> Result<(), NoSleepError> {
+        self.prevent_display_sleep()
+    }
+
+    /// Keeps the display on without holding a `PowerRequestSystemRequired`
+    /// request, so the system idle-sleep timer keeps running.
+    /// For example, to keep the screensaver away while allowing the
+    /// system to sleep once the display times out by other means.
+    /// [`NoSleepTrait::stop`] clears only the display request.
+    pub fn prevent_display_sleep_only(&mut self) -> Result<(), NoSleepError> {
+        self.who = None;
+        self.reported(NoSleep::hold_display_only)
+    }
+
+    // Acquires the block of `prevent_display_sleep_only`
+    fn hold_display_only(&mut self) -> Result<(), NoSleepError> {
+        self.release()?;
+        let display_handle =
+            create_power_request(PowerRequestDisplayRequired, &self.attributed_reason())?;
+        *self.no_sleep_handle.lock() = Some(PowerRequests {
+            system_handle: None,
+            display_handle: Some(display_handle),
+        });
+        self.nosleep_types = vec![NoSleepType::PreventUserIdleDisplaySleep];
+        self.stats
+            .start(NoSleepType::PreventUserIdleDisplaySleep, None);
+        self.start_activity_poke();
+        Ok(())
+    }
+
+    /// Releases only the display request while keeping the system request,
+    /// for example when a video finishes but a download continues.
+    /// Does nothing if no display request is held.
+    pub fn unblock_display_keep_system(&mut self) -> Result<(), NoSleepError> {
+        self.reported(NoSleep::release_display)
+    }
+
+    // Releases the display request of `unblock_display_keep_system`
+    fn release_display(&mut self) -> Result<(), NoSleepError> {
+        let mut no_sleep_handle = self.no_sleep_handle.lock();
+        if let Some(handle) = no_sleep_handle.as_mut() {
+            if let Some(display_handle) = handle.display_handle.take() {
+                release_power_request(display_handle, PowerRequestDisplayRequired)?;
+                self.idle_input = None;
+                if handle.system_handle.is_some() {
+                    self.nosleep_types = vec![NoSleepType::PreventUserIdleSystemSleep];
+                    self.stats
+                        .start(NoSleepType::PreventUserIdleSystemSleep, None);
+                } else {
+                    // Nothing left from `prevent_display_sleep_only`
+                    *no_sleep_handle = None;
+                    self.nosleep_types.clear();
+                    self.stats.finish();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Releases the requests of `stop_type` that no other type held needs
+    fn release_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        // Timed blocks are display blocks without `nosleep_types`
+        if self.timed_block.is_some() && nosleep_type == NoSleepType::PreventUserIdleDisplaySleep {
+            return self.release();
+        }
+        if !self.nosleep_types.contains(&nosleep_type) {
+            return Ok(());
+        }
+        let others = self
+            .nosleep_types
+            .iter()
+            .copied()
+            .filter(|held| *held != nosleep_type)
+            .collect::<Vec<_>>();
+        let Some(strongest) = NoSleepType::strongest(&others) else {
+            return self.release();
+        };
+        // The same union as `create_power_requests`
+        let system_needed = others
+            .iter()
+            .any(|other| *other != NoSleepType::PreventScreenSaver);
+        let display_needed = others.iter().any(|other| {
+            matches!(
+                other,
+                NoSleepType::PreventScreenSaver | NoSleepType::PreventUserIdleDisplaySleep
+            )
+        });
+        let mut result = Ok(());
+        if let Some(handle) = self.no_sleep_handle.lock().as_mut() {
+            if !system_needed {
+                if let Some(system_handle) = handle.system_handle.take() {
+                    result = result.and(release_power_request(
+                        system_handle,
+                        PowerRequestSystemRequired,
+                    ));
+                }
+            }
+            if !display_needed {
+                if let Some(display_handle) = handle.display_handle.take() {
+                    result = result.and(release_power_request(
+                        display_handle,
+                        PowerRequestDisplayRequired,
+                    ));
+                }
+            }
+        }
+        if !display_needed {
+            self.idle_input = None;
+            if let Some(presentation) = self.presentation.take() {
+                result = result.and(presentation.stop());
+            }
+        }
+        self.nosleep_types = others;
+        self.stats.start(strongest, None);
+        result
+    }
+}
+
+impl NoSleepTrait for NoSleep {
+    fn new() -> Result<NoSleep, NoSleepError> {
+        Ok(NoSleep {
+            no_sleep_handle: REGISTRY.register(None),
+            nosleep_types: vec![],
+            suspended: vec![],
+            timed_block: None,
+            stats: BlockTracker::default(),
+            events: StateEvents::default(),
+            reason: Cow::Borrowed(OsStr::new(DEFAULT_REASON)),
+            who: None,
+            switch_policy: SwitchPolicy::default(),
+            resume_notification: None,
+            revoke_notification: None,
+            reacquire_notification: None,
+            idle_input: None,
+            activity_poke: None,
+            display_mode: DisplayMode::default(),
+            presentation: None,
+            strict: false,
+            display_fallback: DisplayFallback::default(),
+            display_error: None,
+        })
+    }
+
+    /// Blocks the system from entering low-power (sleep) mode by
+    /// making a call to the Windows `PowerCreateRequest`/`PowerSetRequest` system call.
+    /// If [`self::stop`] is not called, then he lock will be cleaned up
+    /// when NoSleep is dropped.
+    fn prevent_sleep(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.prevent(&[nosleep_type])
+    }
+
+    /// Holds the union of the requests needed by `nosleep_types`.
+    fn prevent(&mut self, nosleep_types: &[NoSleepType]) -> Result<(), NoSleepError> {
+        self.who = None;
+        self.reported(|nosleep| nosleep.hold(nosleep_types))
+    }
+
+    /// Creates the power requests with the reason `who: reason`.
+    /// Rejected if `who` contains a NUL character.
+    fn prevent_sleep_as(
+        &mut self,
+        nosleep_type: NoSleepType,
+        who: &str,
+    ) -> Result<(), NoSleepError> {
+        self.who = Some(who.to_string());
+        self.reported(|nosleep| nosleep.hold(&[nosleep_type]))
+    }
+
+    fn prevent_display_sleep_until(&mut self, deadline: Instant) -> Result<(), NoSleepError> {
+        self.who = None;
+        self.reported(|nosleep| nosleep.hold_until(deadline))
+    }
+
+    /// Releases the block and stops calling the callbacks of `on_resume`
+    /// and `on_revoked`.
+    fn stop(&mut self) -> Result<(), NoSleepError> {
+        self.resume_notification = None;
+        self.revoke_notification = None;
+        self.reported(NoSleep::release)
+    }
+
+    /// Clears the requests that only `nosleep_type` needed. A display
+    /// block also holds the system request, which stays set as long as any
+    /// type other than `PreventScreenSaver` is held.
+    fn stop_type(&mut self, nosleep_type: NoSleepType) -> Result<(), NoSleepError> {
+        self.reported(|nosleep| nosleep.release_type(nosleep_type))
+    }
+
+    /// The reason of a power request cannot be changed, so new requests
+    /// are created before the old ones are cleared.
+    /// Reasons with an interior NUL character are rejected.
+    fn set_reason(&mut self, reason: &str) -> Result<(), NoSleepError> {
+        self.set_reason_cow(reason.to_string())
+    }
+
+    /// Only encodes the reason as UTF-16 if requests are held.
+    fn set_reason_cow(&mut self, reason: impl Into<Cow<'static, str>>) -> Result<(), NoSleepError> {
+        self.replace_reason(os_reason(reason.into()))
+    }
+
+    fn suspend(&mut self) -> Result<(), NoSleepError> {
+        if self.nosleep_types.is_empty() {
+            return Ok(());
+        }
+        let nosleep_types = self.nosleep_types.clone();
+        self.reported(NoSleep::release)?;
+        self.suspended = nosleep_types;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), NoSleepError> {
+        let nosleep_types = std::mem::take(&mut self.suspended);
+        if nosleep_types.is_empty() {
+            return Ok(());
+        }
+        // Keeps the attribution of the suspended block
+        self.reported(|nosleep| nosleep.hold(&nosleep_types))
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.no_sleep_handle.lock().is_some()
+            || self
+                .timed_block
+                .as_ref()
+                .is_some_and(DeadlineTimer::is_active)
+    }
+
+    fn handles(&self) -> Vec<HandleInfo> {
+        let mut handles = vec![];
+        if let Some(handle) = *self.no_sleep_handle.lock() {
+            if let Some(system_handle) = handle.system_handle {
+                handles.push(HandleInfo {
+                    kind: NoSleepType::PreventUserIdleSystemSleep,
+                    backend: "PowerRequestSystemRequired",
+                    raw: system_handle.0 as u64,
+                    who: self.who.clone(),
+                });
+            }
+            if let Some(display_handle) = handle.display_handle {
+                // The display request is all a screensaver block holds
+                let kind = if self
+                    .nosleep_types
+                    .contains(&NoSleepType::PreventScreenSaver)
+                {
+                    NoSleepType::PreventScreenSaver
+                } else {
+                    NoSleepType::PreventUserIdleDisplaySleep
+                };
+                handles.push(HandleInfo {
+                    kind,
+                    backend: "PowerRequestDisplayRequired",
+                    raw: display_handle.0 as u64,
+                    who: self.who.clone(),
+                });
+            }
+        }
+        handles
+    }
+
+    fn stats(&self) -> BlockStats {
+        self.stats.stats()
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// The callback runs on a thread of the power manager on
+    /// `PBT_APMRESUMEAUTOMATIC`, no message loop is needed.
+    fn on_resume(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.resume_notification = None;
+        self.resume_notification = Some(PowerNotification::register(
+            PBT_APMRESUMEAUTOMATIC,
+            callback,
+        )?);
+        Ok(())
+    }
+
+    /// Power requests are never taken away, but they do not stop a suspend
+    /// the user or the system asks for, for example on a lid close or
+    /// a critical battery. A block counts as revoked when the system
+    /// suspends while it is held. The callback runs on a thread of the
+    /// power manager on `PBT_APMSUSPEND`. The requests stay held and
+    /// apply again after resume.
+    fn on_revoked(&mut self, callback: Box<dyn Fn() + Send + Sync>) -> Result<(), NoSleepError> {
+        self.revoke_notification = None;
+        let no_sleep_handle = self.no_sleep_handle.clone();
+        self.revoke_notification = Some(PowerNotification::register(
+            PBT_APMSUSPEND,
+            Box::new(move || {
+                if no_sleep_handle.lock().is_some() {
+                    callback();
+                }
+            }),
+        )?);
+        Ok(())
+    }
+
+    /// `Revoked` is never reported, the requests stay held across a suspend.
+    fn subscribe(&self) -> Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    fn observer(&self) -> NoSleepObserver {
+        self.events.observer()
+    }
+
+    /// Resets the display idle timer once with `SetThreadExecutionState`,
+    /// which turns a display that is off back on.
+    fn simulate_user_activity(&mut self) -> Result<(), NoSleepError> {
+        if unsafe { SetThreadExecutionState(ES_DISPLAY_REQUIRED) }.0 == 0 {
+            return Err(NoSleepError::PreventSleep {
+                reason: "SetThreadExecutionState failed".to_string(),
+                code: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads the console display state, a dimmed display counts as on.
+    fn display_is_on(&self) -> Option<bool> {
+        display_state().map(|state| state != 0)
+    }
+
+    /// Reads the "Sleep after" setting of the active power plan,
+    /// for AC or battery power depending on the power source in use.
+    fn system_sleep_timeout(&self) -> Option<Duration> {
+        standby_timeout().and_then(sleep_after)
+    }
+
+    /// Every type is supported, except `PreventSystemSleep` in strict
+    /// mode, see [`NoSleep::set_strict`].
+    fn is_supported_type(&self, nosleep_type: NoSleepType) -> bool {
+        !self.strict || nosleep_type != NoSleepType::PreventSystemSleep
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            display_sleep: true,
+            system_sleep: true,
+            display_only: true,
+            screensaver: true,
+            timed_blocks: true,
+            battery_aware: false,
+        }
+    }
+}
+
+// `new` cannot fail on this platform, nothing is acquired until a block
+impl Default for NoSleep {
+    fn default() -> Self {
+        NoSleep::new().expect("NoSleep::new is infallible")
+    }
+}
+
+// Clears the power requests, then tells the observers
+impl Drop for NoSleep {
+    fn drop(&mut self) {
+        // Best effort, there is no one to report an error to
+        let _ = self.release();
+        self.events.update(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_sleep() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+    }
+
+    #[test]
+    fn test_system_sleep() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_is_blocked() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_stop_twice() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.stop().is_ok());
+        assert!(nosleep.stop().is_ok());
+        assert!(nosleep.handles().is_empty());
+    }
+
+    #[test]
+    fn test_stop_twice_display() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.stop().is_ok());
+        assert!(nosleep.stop().is_ok());
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.no_sleep_handle.lock().is_none());
+    }
+
+    #[test]
+    fn test_unblock_display_keep_system() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep
+            .no_sleep_handle
+            .lock()
+            .unwrap()
+            .display_handle
+            .is_some());
+        nosleep.unblock_display_keep_system().unwrap();
+        assert!(nosleep.is_blocked());
+        assert!(nosleep
+            .no_sleep_handle
+            .lock()
+            .unwrap()
+            .display_handle
+            .is_none());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_drop() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let handle = nosleep.no_sleep_handle.clone();
+        let observer = nosleep.observer();
+        drop(nosleep);
+        assert!(handle.lock().is_none());
+        assert!(!observer.is_blocked());
+    }
+
+    #[test]
+    fn test_stop_all() {
+        let mut first = NoSleep::new().unwrap();
+        let mut second = NoSleep::new().unwrap();
+        first.prevent_display_sleep().unwrap();
+        second.prevent_system_sleep().unwrap();
+        let registered = REGISTRY.live();
+        assert!(registered.contains(&first.no_sleep_handle));
+        assert!(registered.contains(&second.no_sleep_handle));
+
+        // Only these two, `stop_all` would also release the blocks of other tests
+        NoSleep::release_all(vec![
+            first.no_sleep_handle.clone(),
+            second.no_sleep_handle.clone(),
+        ])
+        .unwrap();
+        assert!(!first.is_blocked());
+        assert!(!second.is_blocked());
+        assert!(first.handles().is_empty());
+        // Nothing is cleared twice
+        first.stop().unwrap();
+        second.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_until() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() + std::time::Duration::from_millis(200))
+            .unwrap();
+        assert!(nosleep.is_blocked());
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(!nosleep.is_blocked());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_until_past_deadline() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_display_sleep_until(Instant::now() - std::time::Duration::from_millis(10))
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.handles().is_empty());
+        nosleep.prevent_display_sleep().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(2, handles.len());
+        assert_eq!(NoSleepType::PreventUserIdleSystemSleep, handles[0].kind);
+        assert_eq!(NoSleepType::PreventUserIdleDisplaySleep, handles[1].kind);
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_suspend_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.suspend().unwrap();
+        assert!(!nosleep.is_blocked());
+        nosleep.resume().unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut nosleep = NoSleep::new().unwrap();
+        let first = nosleep.subscribe();
+        let second = nosleep.subscribe();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.unblock_display_keep_system().unwrap();
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        nosleep.stop().unwrap();
+
+        for events in [first, second] {
+            let received = events.try_iter().collect::<Vec<_>>();
+            assert!(matches!(
+                received[..],
+                [
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleDisplaySleep,
+                        ..
+                    },
+                    StateEvent::Switched {
+                        from: NoSleepType::PreventUserIdleDisplaySleep,
+                        to: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                    StateEvent::Blocked {
+                        kind: NoSleepType::PreventUserIdleSystemSleep,
+                        ..
+                    },
+                    StateEvent::Stopped { .. },
+                ]
+            ));
+        }
+    }
+
+    #[test]
+    fn test_create_power_request_set_fails() {
+        let handle = new_power_request(OsStr::new(DEFAULT_REASON)).unwrap();
+        // Not a valid request type, so PowerSetRequest fails
+        let error = set_power_request(handle, POWER_REQUEST_TYPE(42)).unwrap_err();
+        // HRESULT_FROM_WIN32(ERROR_INVALID_PARAMETER)
+        assert_eq!(Some(0x80070057), error.code());
+        // The request was closed
+        let mut flags = 0u32;
+        assert!(
+            unsafe { windows::Win32::Foundation::GetHandleInformation(handle, &mut flags) }
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_prevent_error_policy_denied() {
+        use windows::core::HRESULT;
+
+        for code in POLICY_DENIED {
+            let e = windows::core::Error::from(HRESULT(code as i32));
+            assert!(matches!(
+                prevent_error(e),
+                NoSleepError::PolicyDenied { code: Some(c), .. } if c == code as i64
+            ));
+        }
+        // E_INVALIDARG
+        let e = windows::core::Error::from(HRESULT(0x80070057u32 as i32));
+        assert!(matches!(
+            prevent_error(e),
+            NoSleepError::PreventSleep { .. }
+        ));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        nosleep.stop().unwrap();
+        let stats = nosleep.stats();
+        assert!(stats.system_total >= std::time::Duration::from_millis(100));
+        assert_eq!(std::time::Duration::ZERO, stats.display_total);
+        nosleep.reset_stats();
+        assert_eq!(std::time::Duration::ZERO, nosleep.stats().system_total);
+    }
+
+    #[test]
+    fn test_health_check() {
+        let nosleep = NoSleep::new().unwrap();
+        let report = nosleep.health_check();
+        assert!(report.platform.starts_with("windows"));
+        assert_eq!(1, report.backends.len());
+        assert_eq!(Some("PowerCreateRequest"), report.selected.as_deref());
+        assert_eq!(DEFAULT_REASON, report.reason);
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let nosleep = NoSleep::new().unwrap();
+        let capabilities = nosleep.capabilities();
+        assert!(capabilities.display_sleep);
+        assert!(capabilities.system_sleep);
+        assert!(capabilities.display_only);
+        assert!(capabilities.screensaver);
+        assert!(capabilities.timed_blocks);
+        assert!(!capabilities.battery_aware);
+    }
+
+    // The only test that sets NOSLEEP_* variables, they are process wide
+    #[test]
+    fn test_from_env() {
+        std::env::set_var("NOSLEEP_BACKEND", "PowerCreateRequest");
+        std::env::set_var("NOSLEEP_REASON", "Nightly backup");
+        let nosleep = NoSleep::from_env().unwrap();
+        assert_eq!(OsStr::new("Nightly backup"), nosleep.reason);
+
+        std::env::set_var("NOSLEEP_BACKEND", "gnome");
+        assert!(matches!(
+            NoSleep::from_env(),
+            Err(NoSleepError::Init { .. })
+        ));
+
+        std::env::remove_var("NOSLEEP_BACKEND");
+        std::env::remove_var("NOSLEEP_REASON");
+        let nosleep = NoSleep::from_env().unwrap();
+        assert_eq!(OsStr::new(DEFAULT_REASON), nosleep.reason);
+    }
+
+    #[test]
+    fn test_presentation_command() {
+        let start = presentation_command("/start");
+        assert_eq!("PresentationSettings.exe", start.get_program());
+        assert_eq!(vec!["/start"], start.get_args().collect::<Vec<_>>());
+    }
+
+    // Needs PresentationSettings.exe, only on mobile PCs of the Pro and
+    // Enterprise editions. Verify in Windows Mobility Center.
+    #[test]
+    #[ignore]
+    fn test_display_mode_presentation() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_display_mode(DisplayMode::PresentationMode);
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.presentation.is_some());
+        // System blocks leave presentation mode alone
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.presentation.is_none());
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.stop().unwrap();
+        assert!(nosleep.presentation.is_none());
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[test]
+    fn test_clone_config() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_display_fallback_threshold(Some(3));
+        nosleep.prevent_system_sleep().unwrap();
+        let clone = nosleep.clone_config().unwrap();
+        assert!(clone.handles().is_empty());
+        assert_eq!(Some(3), clone.display_fallback.threshold());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_or_degrade() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_display_fallback_threshold(Some(1));
+        assert_eq!(
+            BlockOutcome::Full,
+            nosleep.prevent_display_sleep_or_degrade().unwrap()
+        );
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        assert_eq!(0, nosleep.display_fallback.failures());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_supported_types() {
+        assert!(!SUPPORTED_TYPES.contains(&NoSleepType::PreventSystemSleep));
+        // Strict mode only accepts the types held as asked
+        let nosleep = NoSleep::strict().unwrap();
+        for nosleep_type in [
+            NoSleepType::PreventUserIdleDisplaySleep,
+            NoSleepType::PreventUserIdleSystemSleep,
+            NoSleepType::PreventSystemSleep,
+            NoSleepType::PreventScreenSaver,
+        ] {
+            assert_eq!(
+                SUPPORTED_TYPES.contains(&nosleep_type),
+                nosleep.is_supported_type(nosleep_type),
+                "{:?}",
+                nosleep_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut strict = NoSleep::strict().unwrap();
+        assert!(!strict.is_supported_type(NoSleepType::PreventSystemSleep));
+        assert!(matches!(
+            strict.prevent_sleep(NoSleepType::PreventSystemSleep),
+            Err(NoSleepError::Unsupported { .. })
+        ));
+        assert!(!strict.is_blocked());
+        // The other types are held as usual
+        strict
+            .prevent_sleep(NoSleepType::PreventUserIdleSystemSleep)
+            .unwrap();
+        strict.stop().unwrap();
+        let clone = strict.clone_config().unwrap();
+        assert!(!clone.is_supported_type(NoSleepType::PreventSystemSleep));
+    }
+
+    #[test]
+    fn test_is_supported_type() {
+        let nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleDisplaySleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventUserIdleSystemSleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventSystemSleep));
+        assert!(nosleep.is_supported_type(NoSleepType::PreventScreenSaver));
+    }
+
+    #[test]
+    fn test_resume_without_suspend() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.resume().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Returns the entries listed under `section` (e.g. "DISPLAY") by `powercfg /requests`
+    fn powercfg_requests(section: &str) -> String {
+        let output = std::process::Command::new("powercfg")
+            .arg("/requests")
+            .output()
+            .unwrap();
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+        output
+            .lines()
+            .skip_while(|line| line.trim() != format!("{}:", section))
+            .skip(1)
+            .take_while(|line| !line.trim().is_empty())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    // `powercfg /requests` requires administrator privileges
+    #[test]
+    #[ignore]
+    fn test_prevent_system_sleep_allow_display_dim() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep_allow_display_dim().unwrap();
+        assert!(powercfg_requests("SYSTEM").contains("Power Save Blocker"));
+        assert!(!powercfg_requests("DISPLAY").contains("Power Save Blocker"));
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_screensaver() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_screensaver().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!(NoSleepType::PreventScreenSaver, handles[0].kind);
+        assert_eq!("PowerRequestDisplayRequired", handles[0].backend);
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_only() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep_only().unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!(NoSleepType::PreventUserIdleDisplaySleep, handles[0].kind);
+        nosleep.stop().unwrap();
+    }
+
+    // `powercfg /requests` requires administrator privileges
+    #[test]
+    #[ignore]
+    fn test_prevent_display_sleep_only_powercfg() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep_only().unwrap();
+        assert!(powercfg_requests("DISPLAY").contains("Power Save Blocker"));
+        assert!(!powercfg_requests("SYSTEM").contains("Power Save Blocker"));
+        nosleep.stop().unwrap();
+        assert!(!powercfg_requests("DISPLAY").contains("Power Save Blocker"));
+    }
+
+    #[test]
+    fn test_prevent_display_sleep_and_lock() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep_and_lock().unwrap();
+        assert!(nosleep.is_blocked());
+        // No input is injected, only the power requests are held
+        assert!(nosleep.idle_input.is_none());
+        assert_eq!(2, nosleep.handles().len());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_activity_poke() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_activity_poke(Some(Duration::from_secs(60)));
+        nosleep.prevent_system_sleep().unwrap();
+        assert!(nosleep.idle_input.is_none());
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.idle_input.is_some());
+        nosleep.unblock_display_keep_system().unwrap();
+        assert!(nosleep.idle_input.is_none());
+        nosleep.prevent_display_sleep_only().unwrap();
+        assert!(nosleep.idle_input.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.idle_input.is_none());
+    }
+
+    // Needs an interactive desktop, injected input is dropped otherwise
+    #[test]
+    #[ignore]
+    fn test_idle_input_resets_idle_timer() {
+        use windows::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
+
+        let last_input = || {
+            let mut info = LASTINPUTINFO {
+                cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+                dwTime: 0,
+            };
+            unsafe { GetLastInputInfo(&mut info).unwrap() };
+            info.dwTime
+        };
+        let before = last_input();
+        let idle_input = ActivityPoke::start(Duration::from_millis(100), send_idle_input);
+        std::thread::sleep(Duration::from_millis(500));
+        drop(idle_input);
+        assert!(last_input() > before);
+    }
+
+    #[test]
+    fn test_set_reason() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.set_reason("Playing a video").unwrap();
+        assert!(nosleep.is_blocked());
+        assert_eq!(2, nosleep.handles().len());
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_attributed_reason() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.reason = OsStr::new("Backup").into();
+        assert_eq!(OsStr::new("Backup"), nosleep.attributed_reason());
+        nosleep.who = Some("client-1".to_string());
+        assert_eq!(OsStr::new("client-1: Backup"), nosleep.attributed_reason());
+    }
+
+    #[test]
+    fn test_prevent_sleep_as() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent_sleep_as(NoSleepType::PreventUserIdleSystemSleep, "client-1")
+            .unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!(Some("client-1"), handles[0].who.as_deref());
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        assert_eq!(Some("client-1"), nosleep.handles()[0].who.as_deref());
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(None, nosleep.handles()[0].who);
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_into_pwstr_multibyte() {
+        let reason = "動画を再生中 🎬";
+        let (pwstr, buffer) = reason.into_pwstr().unwrap();
+        assert_eq!(buffer.as_ptr(), pwstr.0 as *const u16);
+        assert_eq!(Some(&0), buffer.last());
+        // The emoji is a surrogate pair
+        assert_eq!(reason.chars().count() + 2, buffer.len());
+        assert_eq!(reason, unsafe { pwstr.to_string() }.unwrap());
+    }
+
+    #[test]
+    fn test_into_pwstr_interior_nul() {
+        assert!(matches!(
+            "Playing\0a video".into_pwstr(),
+            Err(NoSleepError::PreventSleep { code: None, .. })
+        ));
+        let mut nosleep = NoSleep::new().unwrap();
+        assert!(nosleep.set_reason("Playing\0a video").is_err());
+        assert_eq!(OsStr::new(DEFAULT_REASON), nosleep.reason);
+    }
+
+    #[test]
+    fn test_into_pwstr_path() {
+        use std::os::windows::ffi::OsStringExt;
+        use std::path::PathBuf;
+
+        // A lone surrogate, valid in NTFS names but not in UTF-16
+        let wide = [
+            0x0043, 0x003A, 0x005C, 0xD800, 0x002E, 0x006D, 0x0070, 0x0034,
+        ];
+        let path = PathBuf::from(OsString::from_wide(&wide));
+        assert!(path.to_str().is_none());
+        let (pwstr, buffer) = path.as_path().into_pwstr().unwrap();
+        assert_eq!(&wide[..], &buffer[..wide.len()]);
+        assert_eq!(Some(&0), buffer.last());
+        assert_eq!(buffer.as_ptr(), pwstr.0 as *const u16);
+
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.set_reason_os(&path).unwrap();
+        assert_eq!(path.as_os_str(), nosleep.reason);
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+    }
+
+    // `powercfg /requests` requires administrator privileges
+    #[test]
+    #[ignore]
+    fn test_set_reason_powercfg() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.set_reason("Downloading").unwrap();
+        let requests = powercfg_requests("SYSTEM");
+        assert!(requests.contains("Downloading"));
+        assert!(!requests.contains("Power Save Blocker"));
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_switch_policy_replace() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::Replace);
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_switch_policy_keep_strongest() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::KeepStrongest);
+        nosleep.prevent_display_sleep().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+        nosleep.prevent_system_sleep().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_switch_policy_reject() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_switch_policy(SwitchPolicy::Reject);
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(matches!(
+            nosleep.prevent_system_sleep(),
+            Err(NoSleepError::PreventSleep { .. })
+        ));
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleDisplaySleep],
+            nosleep.nosleep_types
+        );
+        nosleep.stop().unwrap();
+    }
+
+    #[test]
+    fn test_default() {
+        let nosleep = NoSleep::default();
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.handles().is_empty());
+    }
+
+    // Needs an interactive session with a console display
+    #[test]
+    #[ignore]
+    fn test_display_is_on() {
+        let nosleep = NoSleep::new().unwrap();
+        assert_eq!(Some(true), nosleep.display_is_on());
+    }
+
+    #[test]
+    fn test_sleep_after() {
+        assert_eq!(None, sleep_after(0));
+        assert_eq!(Some(Duration::from_secs(1800)), sleep_after(1800));
+    }
+
+    #[test]
+    fn test_system_sleep_timeout() {
+        let nosleep = NoSleep::new().unwrap();
+        // Never sleeping on idle is a valid setting, zero is not
+        if let Some(timeout) = nosleep.system_sleep_timeout() {
+            assert!(timeout > Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_on_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.on_resume(Box::new(|| {})).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.resume_notification.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.resume_notification.is_none());
+    }
+
+    #[test]
+    fn test_reacquire_on_resume() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.set_reacquire_on_resume(true).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        // What the notification runs on resume
+        reapply_power_requests(&nosleep.no_sleep_handle).unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+
+        // Stopped while asleep, not acquired again on resume
+        reapply_power_requests(&nosleep.no_sleep_handle).unwrap();
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.reacquire_notification.is_some());
+        nosleep.set_reacquire_on_resume(false).unwrap();
+        assert!(nosleep.reacquire_notification.is_none());
+    }
+
+    #[test]
+    fn test_on_revoked() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep.on_revoked(Box::new(|| {})).unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.revoke_notification.is_some());
+        nosleep.stop().unwrap();
+        assert!(nosleep.revoke_notification.is_none());
+    }
+
+    #[test]
+    fn test_prevent_multiple_types() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventScreenSaver,
+            ])
+            .unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(2, handles.len());
+        assert_eq!("PowerRequestSystemRequired", handles[0].backend);
+        assert_eq!("PowerRequestDisplayRequired", handles[1].backend);
+        assert_eq!(NoSleepType::PreventScreenSaver, handles[1].kind);
+        nosleep.suspend().unwrap();
+        nosleep.resume().unwrap();
+        assert_eq!(2, nosleep.handles().len());
+        nosleep.stop().unwrap();
+        assert!(nosleep.nosleep_types.is_empty());
+    }
+
+    #[test]
+    fn test_stop_type() {
+        let mut nosleep = NoSleep::new().unwrap();
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventScreenSaver,
+            ])
+            .unwrap();
+        nosleep.stop_type(NoSleepType::PreventScreenSaver).unwrap();
+        let handles = nosleep.handles();
+        assert_eq!(1, handles.len());
+        assert_eq!("PowerRequestSystemRequired", handles[0].backend);
+        assert_eq!(
+            vec![NoSleepType::PreventUserIdleSystemSleep],
+            nosleep.nosleep_types
+        );
+
+        // The display block keeps the system request of the system block
+        nosleep
+            .prevent(&[
+                NoSleepType::PreventUserIdleSystemSleep,
+                NoSleepType::PreventUserIdleDisplaySleep,
+            ])
+            .unwrap();
+        nosleep
+            .stop_type(NoSleepType::PreventUserIdleSystemSleep)
+            .unwrap();
+        assert_eq!(2, nosleep.handles().len());
+        nosleep
+            .stop_type(NoSleepType::PreventUserIdleDisplaySleep)
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+        assert!(nosleep.handles().is_empty());
     }
 }
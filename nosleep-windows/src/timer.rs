@@ -0,0 +1,130 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use nosleep_types::NoSleepError;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{CloseHandle, BOOLEAN, HANDLE, INVALID_HANDLE_VALUE};
+use windows::Win32::System::Threading::{
+    CreateWaitableTimerW, RegisterWaitForSingleObject, SetWaitableTimer, UnregisterWaitEx,
+    INFINITE, WT_EXECUTEONLYONCE,
+};
+
+use crate::prevent_error;
+
+type TimerCallback = Box<dyn Fn() + Send + Sync>;
+
+// Runs on a thread of the thread pool, `context` is a `TimerCallback`
+unsafe extern "system" fn timer_callback(context: *mut c_void, _timed_out: BOOLEAN) {
+    let callback = &*(context as *const TimerCallback);
+    callback();
+}
+
+/// Runs a callback once at a deadline, on a thread of the Windows thread
+/// pool. A waitable timer expires at the deadline and the thread pool
+/// waits for it, so no thread of its own sleeps until then.
+/// Dropping it before the deadline cancels the callback.
+pub(crate) struct DeadlineTimer {
+    timer: HANDLE,
+    wait: HANDLE,
+    // The context of the wait, freed after unregistering
+    _callback: Box<TimerCallback>,
+    fired: Arc<AtomicBool>,
+}
+
+impl DeadlineTimer {
+    pub(crate) fn start(
+        deadline: Instant,
+        callback: impl Fn() + Send + Sync + 'static,
+    ) -> Result<DeadlineTimer, NoSleepError> {
+        let fired = Arc::new(AtomicBool::new(false));
+        let callback: Box<TimerCallback> = {
+            let fired = fired.clone();
+            Box::new(Box::new(move || {
+                callback();
+                fired.store(true, Ordering::SeqCst);
+            }))
+        };
+        // Negative due times are relative, in 100 nanosecond intervals.
+        // Zero would be absolute, a passed deadline expires right away.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let due_time = -((remaining.as_nanos() / 100).max(1) as i64);
+        unsafe {
+            let timer = CreateWaitableTimerW(None, true, PCWSTR::null()).map_err(prevent_error)?;
+            let mut wait = HANDLE::default();
+            let registered =
+                SetWaitableTimer(timer, &due_time, 0, None, None, false).and_then(|_| {
+                    RegisterWaitForSingleObject(
+                        &mut wait,
+                        timer,
+                        Some(timer_callback),
+                        Some(&*callback as *const TimerCallback as *const c_void),
+                        INFINITE,
+                        WT_EXECUTEONLYONCE,
+                    )
+                });
+            if let Err(e) = registered {
+                let _ = CloseHandle(timer);
+                return Err(prevent_error(e));
+            }
+            Ok(DeadlineTimer {
+                timer,
+                wait,
+                _callback: callback,
+                fired,
+            })
+        }
+    }
+
+    /// Returns `true` until the callback has run.
+    pub(crate) fn is_active(&self) -> bool {
+        !self.fired.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for DeadlineTimer {
+    fn drop(&mut self) {
+        unsafe {
+            // Waits for a running callback before the callback is freed
+            let _ = UnregisterWaitEx(self.wait, INVALID_HANDLE_VALUE);
+            let _ = CloseHandle(self.timer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_deadline_timer() {
+        let (fired_tx, fired_rx) = mpsc::channel();
+        let started = Instant::now();
+        let timer = DeadlineTimer::start(started + Duration::from_millis(200), move || {
+            let _ = fired_tx.send(Instant::now());
+        })
+        .unwrap();
+        assert!(timer.is_active());
+        let elapsed = fired_rx.recv_timeout(Duration::from_secs(5)).unwrap() - started;
+        // Timers tick every 15.6 milliseconds by default
+        assert!(elapsed >= Duration::from_millis(180), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!timer.is_active());
+    }
+
+    #[test]
+    fn test_deadline_timer_cancel() {
+        let (fired_tx, fired_rx) = mpsc::channel();
+        let timer = DeadlineTimer::start(Instant::now() + Duration::from_millis(100), move || {
+            let _ = fired_tx.send(());
+        })
+        .unwrap();
+        drop(timer);
+        assert!(fired_rx.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+}
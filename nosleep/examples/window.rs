@@ -0,0 +1,44 @@
+//! Keeps the display awake while the window is focused.
+//!
+//! Run with `cargo run --example window --features winit`
+use nosleep::{NoSleepType, WindowNoSleep};
+use winit::application::ApplicationHandler;
+use winit::event::WindowEvent;
+use winit::event_loop::{ActiveEventLoop, EventLoop};
+use winit::window::{Window, WindowId};
+
+#[derive(Default)]
+struct App {
+    window: Option<Window>,
+    nosleep: Option<WindowNoSleep>,
+}
+
+impl ApplicationHandler for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        let window = event_loop
+            .create_window(Window::default_attributes().with_title("nosleep"))
+            .unwrap();
+        self.nosleep =
+            Some(WindowNoSleep::new(&window, NoSleepType::PreventUserIdleDisplaySleep).unwrap());
+        self.window = Some(window);
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
+        let Some(nosleep) = self.nosleep.as_mut() else {
+            return;
+        };
+        match event {
+            WindowEvent::Focused(true) => nosleep.on_focus().unwrap(),
+            // Minimized or hidden behind other windows
+            WindowEvent::Focused(false) | WindowEvent::Occluded(true) => nosleep.on_blur().unwrap(),
+            WindowEvent::CloseRequested => event_loop.exit(),
+            _ => {}
+        }
+        println!("Blocked: {}", nosleep.is_blocked());
+    }
+}
+
+fn main() {
+    let event_loop = EventLoop::new().unwrap();
+    event_loop.run_app(&mut App::default()).unwrap();
+}
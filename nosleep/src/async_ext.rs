@@ -0,0 +1,148 @@
+use std::future::Future;
+
+use nosleep_types::{NoSleepError, NoSleepTrait};
+use tokio_util::sync::CancellationToken;
+
+/// Async helpers for every backend, enabled with the `async` feature.
+pub trait NoSleepAsyncExt: NoSleepTrait {
+    /// Prevents the display from dimming until `token` is cancelled,
+    /// after which the block is released. Composes with `tokio::select!`.
+    ///
+    /// The block is also released when the future is dropped before
+    /// the token is cancelled.
+    ///
+    /// ```rust,no_run
+    /// # use nosleep::{NoSleep, NoSleepAsyncExt, NoSleepTrait};
+    /// # use tokio_util::sync::CancellationToken;
+    /// # async fn play(token: CancellationToken) -> Result<(), nosleep::NoSleepError> {
+    /// let mut nosleep = NoSleep::new()?;
+    /// nosleep.prevent_display_sleep_until_cancelled(token).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn prevent_display_sleep_until_cancelled(
+        &mut self,
+        token: CancellationToken,
+    ) -> impl Future<Output = Result<(), NoSleepError>>;
+
+    /// Resolves once the OS revokes a block held by this instance, see
+    /// [`NoSleepTrait::on_revoked`], whose callback it replaces.
+    /// The callback is registered right away and the future does not
+    /// borrow `self`, so the block can be re-acquired in a `tokio::select!`
+    /// branch. It never resolves once `stop` is called.
+    ///
+    /// ```rust,no_run
+    /// # use nosleep::{NoSleep, NoSleepAsyncExt, NoSleepTrait};
+    /// # async fn keep_awake() -> Result<(), nosleep::NoSleepError> {
+    /// let mut nosleep = NoSleep::new()?;
+    /// nosleep.prevent_display_sleep()?;
+    /// loop {
+    ///     nosleep.revoked().await?;
+    ///     nosleep.prevent_display_sleep()?;
+    /// }
+    /// # }
+    /// ```
+    fn revoked(&mut self) -> impl Future<Output = Result<(), NoSleepError>> + Send + 'static;
+}
+
+impl<T: NoSleepTrait + ?Sized> NoSleepAsyncExt for T {
+    async fn prevent_display_sleep_until_cancelled(
+        &mut self,
+        token: CancellationToken,
+    ) -> Result<(), NoSleepError> {
+        self.prevent_display_sleep()?;
+        let guard = ReleaseGuard {
+            nosleep: Some(self),
+        };
+        token.cancelled().await;
+        guard.release()
+    }
+
+    fn revoked(&mut self) -> impl Future<Output = Result<(), NoSleepError>> + Send + 'static {
+        let revoked = CancellationToken::new();
+        let cancel = revoked.clone();
+        let registered = self.on_revoked(Box::new(move || cancel.cancel()));
+        async move {
+            registered?;
+            revoked.cancelled().await;
+            Ok(())
+        }
+    }
+}
+
+// Releases the block when dropped, for futures dropped mid-flight
+struct ReleaseGuard<'a, T: NoSleepTrait + ?Sized> {
+    nosleep: Option<&'a mut T>,
+}
+
+impl<T: NoSleepTrait + ?Sized> ReleaseGuard<'_, T> {
+    fn release(mut self) -> Result<(), NoSleepError> {
+        match self.nosleep.take() {
+            Some(nosleep) => nosleep.stop(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: NoSleepTrait + ?Sized> Drop for ReleaseGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(nosleep) = self.nosleep.take() {
+            // Best effort, there is no one left to report the error to
+            let _ = nosleep.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nosleep_types::MockNoSleep;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prevent_display_sleep_until_cancelled() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        nosleep
+            .prevent_display_sleep_until_cancelled(token)
+            .await
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+
+    #[tokio::test]
+    async fn test_prevent_display_sleep_until_cancelled_dropped() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        let token = CancellationToken::new();
+        tokio::select! {
+            _ = nosleep.prevent_display_sleep_until_cancelled(token.clone()) => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+        }
+        assert!(!nosleep.is_blocked());
+        assert!(!token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_revoked() {
+        let mut nosleep = MockNoSleep::new().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        let revoked = tokio::spawn(nosleep.revoked());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!revoked.is_finished());
+
+        nosleep.revoke();
+        tokio::time::timeout(Duration::from_secs(1), revoked)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(!nosleep.is_blocked());
+    }
+}
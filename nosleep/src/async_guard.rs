@@ -0,0 +1,213 @@
+use std::sync::mpsc;
+
+use nosleep_types::{NoSleepError, NoSleepTrait, NoSleepType};
+use tokio::sync::oneshot;
+
+use crate::NoSleep;
+
+// Sent to the worker by `release`, for the result of `stop`
+type Release = oneshot::Sender<Result<(), NoSleepError>>;
+
+/// A block for async code, released with [`release`] without blocking
+/// the executor. Enabled with the `async` feature.
+///
+/// `stop` talks to the OS synchronously, and can take a while on Linux
+/// where it is a D-Bus call, so the block is held by a backend that lives
+/// on a worker thread of its own and `release` awaits the result of its
+/// `stop`. The backend is created on the worker too, so it does not have
+/// to be `Send`.
+///
+/// `Drop` cannot await, so a guard dropped without [`release`] lets the
+/// worker release the block in the background: the block may be held a
+/// little longer than the guard and an error of `stop` is lost. Call
+/// [`release`] where the release must be confirmed, and
+/// [`set_on_unreleased`] to be warned of a guard that is dropped instead.
+///
+/// ```rust,no_run
+/// # use nosleep::*;
+/// # async fn render() -> Result<(), NoSleepError> {
+/// let guard = AsyncNoSleepGuard::start(NoSleepType::PreventUserIdleSystemSleep).await?;
+/// // ...
+/// guard.release().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`release`]: AsyncNoSleepGuard::release
+/// [`set_on_unreleased`]: AsyncNoSleepGuard::set_on_unreleased
+pub struct AsyncNoSleepGuard {
+    // Dropping it without a `Release` tells the worker to release the block
+    release: Option<mpsc::Sender<Release>>,
+    // Called by `drop` if the guard was not released
+    on_unreleased: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl AsyncNoSleepGuard {
+    /// Holds a block of `nosleep_type` until [`AsyncNoSleepGuard::release`]
+    /// or drop. Resolves once the block is held.
+    pub async fn start(nosleep_type: NoSleepType) -> Result<Self, NoSleepError> {
+        AsyncNoSleepGuard::with_backend(NoSleep::new, nosleep_type).await
+    }
+
+    /// Like [`AsyncNoSleepGuard::start`], with the backend returned by
+    /// `connect`, which runs on the worker thread.
+    pub async fn with_backend<T: NoSleepTrait>(
+        connect: impl FnOnce() -> Result<T, NoSleepError> + Send + 'static,
+        nosleep_type: NoSleepType,
+    ) -> Result<Self, NoSleepError> {
+        let (release_tx, release_rx) = mpsc::channel::<Release>();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("nosleep-async-guard".to_string())
+            .spawn(move || {
+                let acquired = connect().and_then(|mut nosleep| {
+                    nosleep.prevent_sleep(nosleep_type)?;
+                    Ok(nosleep)
+                });
+                let mut nosleep = match acquired {
+                    Ok(nosleep) => {
+                        let _ = ready_tx.send(Ok(()));
+                        nosleep
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                // Disconnected when the guard is dropped without `release`
+                let release = release_rx.recv();
+                let result = nosleep.stop();
+                if let Ok(release) = release {
+                    let _ = release.send(result);
+                }
+            })
+            .map_err(|e| NoSleepError::Init {
+                reason: e.to_string(),
+                code: e.raw_os_error().map(i64::from),
+            })?;
+        ready_rx.await.map_err(|e| NoSleepError::PreventSleep {
+            reason: e.to_string(),
+            code: None,
+        })??;
+        Ok(AsyncNoSleepGuard {
+            release: Some(release_tx),
+            on_unreleased: None,
+        })
+    }
+
+    /// Calls `warn` when the guard is dropped without
+    /// [`AsyncNoSleepGuard::release`], for example to log a warning.
+    /// Nothing is reported by default.
+    pub fn set_on_unreleased(&mut self, warn: impl FnOnce() + Send + 'static) {
+        self.on_unreleased = Some(Box::new(warn));
+    }
+
+    /// Releases the block and resolves with the result of `stop` once it
+    /// is released.
+    pub async fn release(mut self) -> Result<(), NoSleepError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let sent = self
+            .release
+            .take()
+            .is_some_and(|release| release.send(reply_tx).is_ok());
+        match (sent, reply_rx.await) {
+            (true, Ok(result)) => result,
+            _ => Err(NoSleepError::StopLock {
+                reason: "Worker thread exited".to_string(),
+                code: None,
+            }),
+        }
+    }
+}
+
+impl Drop for AsyncNoSleepGuard {
+    fn drop(&mut self) {
+        if let (Some(_), Some(warn)) = (self.release.take(), self.on_unreleased.take()) {
+            warn();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use std::time::Duration;
+
+    use nosleep_types::StateEvent;
+
+    use super::*;
+    use crate::MockNoSleep;
+
+    // A guard of a `MockNoSleep`, with the events of the mock
+    async fn mock_guard() -> (AsyncNoSleepGuard, mpsc::Receiver<StateEvent>) {
+        let (events_tx, events_rx) = mpsc::channel();
+        let guard = AsyncNoSleepGuard::with_backend(
+            move || {
+                let nosleep = MockNoSleep::new()?;
+                let _ = events_tx.send(nosleep.subscribe());
+                Ok(nosleep)
+            },
+            NoSleepType::PreventUserIdleDisplaySleep,
+        )
+        .await
+        .unwrap();
+        (guard, events_rx.recv().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let (guard, events) = mock_guard().await;
+        assert!(matches!(
+            events.recv().unwrap(),
+            StateEvent::Blocked {
+                kind: NoSleepType::PreventUserIdleDisplaySleep,
+                ..
+            }
+        ));
+        guard.release().await.unwrap();
+        // Released by the time `release` resolves
+        assert!(matches!(events.try_recv(), Ok(StateEvent::Stopped { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_dropped() {
+        let (guard, events) = mock_guard().await;
+        events.recv().unwrap();
+        drop(guard);
+        assert!(matches!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(StateEvent::Stopped { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_on_unreleased() {
+        let (warned_tx, warned_rx) = mpsc::channel();
+        let (mut guard, _) = mock_guard().await;
+        let warned = warned_tx.clone();
+        guard.set_on_unreleased(move || warned.send("dropped").unwrap());
+        drop(guard);
+        assert_eq!(Ok("dropped"), warned_rx.try_recv());
+
+        let (mut guard, _) = mock_guard().await;
+        guard.set_on_unreleased(move || warned_tx.send("released").unwrap());
+        guard.release().await.unwrap();
+        assert!(warned_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_start_failed() {
+        let result = AsyncNoSleepGuard::with_backend(
+            || {
+                let mut nosleep = MockNoSleep::new()?;
+                nosleep.fail_next(NoSleepError::PreventSleep {
+                    reason: "refused".to_string(),
+                    code: None,
+                });
+                Ok(nosleep)
+            },
+            NoSleepType::PreventUserIdleDisplaySleep,
+        )
+        .await;
+        assert!(matches!(result, Err(NoSleepError::PreventSleep { .. })));
+    }
+}
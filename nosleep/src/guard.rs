@@ -0,0 +1,163 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use nosleep_types::{NoSleepError, NoSleepTrait, NoSleepType};
+
+use crate::NoSleep;
+
+// The backend and the number of live guards
+struct Shared<T> {
+    nosleep: T,
+    guards: usize,
+}
+
+/// One block shared by nested code that each wants it held, for example
+/// two functions that both keep the display awake. Every [`guard`] call
+/// takes a [`BlockGuard`]: the block is acquired by the first live guard
+/// and released when the last one drops, so an inner guard dropping
+/// leaves it held for the outer one.
+///
+/// ```rust,no_run
+/// # use nosleep::*;
+/// # fn main() -> Result<(), NoSleepError> {
+/// let display = SharedBlock::new(NoSleepType::PreventUserIdleDisplaySleep)?;
+/// let outer = display.guard()?;
+/// {
+///     let _inner = display.guard()?;
+/// }
+/// // Still held, until `outer` drops
+/// assert!(display.is_blocked());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`guard`]: SharedBlock::guard
+pub struct SharedBlock<T: NoSleepTrait = NoSleep> {
+    shared: Arc<Mutex<Shared<T>>>,
+    nosleep_type: NoSleepType,
+}
+
+impl SharedBlock {
+    /// Shares a block of `nosleep_type`, nothing is acquired until the
+    /// first guard.
+    pub fn new(nosleep_type: NoSleepType) -> Result<Self, NoSleepError> {
+        Ok(SharedBlock::with_backend(NoSleep::new()?, nosleep_type))
+    }
+}
+
+impl<T: NoSleepTrait> SharedBlock<T> {
+    /// Shares a block of `nosleep_type` held with an existing backend,
+    /// for example one configured with `clone_config`.
+    pub fn with_backend(nosleep: T, nosleep_type: NoSleepType) -> Self {
+        SharedBlock {
+            shared: Arc::new(Mutex::new(Shared { nosleep, guards: 0 })),
+            nosleep_type,
+        }
+    }
+
+    /// Takes a guard, acquiring the block if no other guard is live.
+    /// If acquiring fails, no guard is taken.
+    pub fn guard(&self) -> Result<BlockGuard<T>, NoSleepError> {
+        let mut shared = lock(&self.shared);
+        if shared.guards == 0 {
+            shared.nosleep.prevent_sleep(self.nosleep_type)?;
+        }
+        shared.guards += 1;
+        Ok(BlockGuard {
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// The number of live guards.
+    pub fn guards(&self) -> usize {
+        lock(&self.shared).guards
+    }
+
+    /// Returns `true` while the block is held.
+    pub fn is_blocked(&self) -> bool {
+        lock(&self.shared).nosleep.is_blocked()
+    }
+}
+
+/// A guard of a [`SharedBlock`], the block is released when the last
+/// guard drops.
+pub struct BlockGuard<T: NoSleepTrait = NoSleep> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T: NoSleepTrait> Drop for BlockGuard<T> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.guards -= 1;
+        if shared.guards == 0 {
+            // Best effort, there is no way to report the error
+            let _ = shared.nosleep.stop();
+        }
+    }
+}
+
+// A backend call that panicked leaves the count usable
+fn lock<T>(shared: &Mutex<Shared<T>>) -> MutexGuard<'_, Shared<T>> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::{MockCall, MockNoSleep};
+
+    fn shared_block() -> SharedBlock<MockNoSleep> {
+        SharedBlock::with_backend(
+            MockNoSleep::new().unwrap(),
+            NoSleepType::PreventUserIdleDisplaySleep,
+        )
+    }
+
+    fn calls(block: &SharedBlock<MockNoSleep>) -> Vec<MockCall> {
+        lock(&block.shared).nosleep.calls().to_vec()
+    }
+
+    #[test]
+    fn test_nested_guards() {
+        let block = shared_block();
+        let outer = block.guard().unwrap();
+        let inner = block.guard().unwrap();
+        assert_eq!(2, block.guards());
+        drop(inner);
+        assert!(block.is_blocked());
+        assert_eq!(
+            vec![MockCall::PreventSleep(
+                NoSleepType::PreventUserIdleDisplaySleep
+            )],
+            calls(&block)
+        );
+        drop(outer);
+        assert!(!block.is_blocked());
+        assert_eq!(0, block.guards());
+        assert_eq!(
+            vec![
+                MockCall::PreventSleep(NoSleepType::PreventUserIdleDisplaySleep),
+                MockCall::Stop
+            ],
+            calls(&block)
+        );
+
+        // Acquired again by the next guard
+        let _guard = block.guard().unwrap();
+        assert!(block.is_blocked());
+    }
+
+    #[test]
+    fn test_guard_failed() {
+        let block = shared_block();
+        lock(&block.shared)
+            .nosleep
+            .fail_next(NoSleepError::PreventSleep {
+                reason: "refused".to_string(),
+                code: None,
+            });
+        assert!(block.guard().is_err());
+        assert_eq!(0, block.guards());
+        let _guard = block.guard().unwrap();
+        assert!(block.is_blocked());
+    }
+}
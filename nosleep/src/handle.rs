@@ -0,0 +1,103 @@
+use nosleep_types::{HandleInfo, NoSleepError, NoSleepTrait, NoSleepType};
+
+use crate::NoSleep;
+
+/// A block that is not tied to a `NoSleep` kept by the caller, returned
+/// by [`start`]. It can be stored, or moved to another thread, and is
+/// released by [`stop`] or when dropped.
+///
+/// ```rust,no_run
+/// # use nosleep::*;
+/// # fn main() -> Result<(), NoSleepError> {
+/// let handle = nosleep::start(NoSleepType::PreventUserIdleSystemSleep)?;
+/// // ...
+/// if nosleep::is_active(&handle) {
+///     nosleep::stop(handle)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct NoSleepHandle<T: NoSleepTrait = NoSleep> {
+    nosleep: T,
+}
+
+impl<T: NoSleepTrait> NoSleepHandle<T> {
+    /// Holds a block of `nosleep_type` with an existing backend, for
+    /// example one configured with `clone_config`.
+    pub fn with_backend(mut nosleep: T, nosleep_type: NoSleepType) -> Result<Self, NoSleepError> {
+        nosleep.prevent_sleep(nosleep_type)?;
+        Ok(NoSleepHandle { nosleep })
+    }
+
+    /// The OS handles behind the block, with the API that issued each,
+    /// for example the D-Bus service on Linux. See [`NoSleepTrait::handles`].
+    pub fn handles(&self) -> Vec<HandleInfo> {
+        self.nosleep.handles()
+    }
+}
+
+impl<T: NoSleepTrait> Drop for NoSleepHandle<T> {
+    fn drop(&mut self) {
+        // Best effort, `stop` reports the error
+        let _ = self.nosleep.stop();
+    }
+}
+
+/// Holds a block of `nosleep_type` until the returned handle is passed
+/// to [`stop`] or dropped.
+pub fn start(nosleep_type: NoSleepType) -> Result<NoSleepHandle, NoSleepError> {
+    NoSleepHandle::with_backend(NoSleep::new()?, nosleep_type)
+}
+
+/// Releases the block of a handle returned by [`start`].
+pub fn stop<T: NoSleepTrait>(mut handle: NoSleepHandle<T>) -> Result<(), NoSleepError> {
+    handle.nosleep.stop()
+}
+
+/// Returns `true` while the block of `handle` is held.
+pub fn is_active<T: NoSleepTrait>(handle: &NoSleepHandle<T>) -> bool {
+    handle.nosleep.is_blocked()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Requires a platform where blocking is possible (e.g. an active Gnome session)
+    #[test]
+    #[ignore]
+    fn test_start_stop() {
+        let handle = start(NoSleepType::PreventUserIdleSystemSleep).unwrap();
+        assert!(is_active(&handle));
+        assert!(!handle.handles().is_empty());
+        stop(handle).unwrap();
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_start_store_stop() {
+        use crate::MockNoSleep;
+
+        let mut handles = vec![];
+        for nosleep_type in [
+            NoSleepType::PreventUserIdleDisplaySleep,
+            NoSleepType::PreventUserIdleSystemSleep,
+        ] {
+            let nosleep = MockNoSleep::new().unwrap();
+            handles.push(NoSleepHandle::with_backend(nosleep, nosleep_type).unwrap());
+        }
+        assert!(handles.iter().all(is_active));
+        assert_eq!(
+            NoSleepType::PreventUserIdleSystemSleep,
+            handles[1].handles()[0].kind
+        );
+
+        // Released on another thread than the one that acquired it
+        let handle = handles.remove(0);
+        std::thread::spawn(move || stop(handle).unwrap())
+            .join()
+            .unwrap();
+        assert!(is_active(&handles[0]));
+        stop(handles.remove(0)).unwrap();
+    }
+}
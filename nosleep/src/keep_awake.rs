@@ -0,0 +1,118 @@
+use nosleep_types::{HandleInfo, NoSleepError, NoSleepTrait, NoSleepType};
+
+use crate::NoSleep;
+
+/// Keeps the machine awake for a given intent, without having to
+/// choose between display and system blocks.
+/// The block is released when the guard is dropped.
+///
+/// ```rust,no_run
+/// # use nosleep::KeepAwake;
+/// # fn main() -> Result<(), nosleep::NoSleepError> {
+/// let _guard = KeepAwake::for_download()?;
+/// // The system stays awake until `_guard` goes out of scope
+/// # Ok(())
+/// # }
+/// ```
+pub struct KeepAwake {
+    nosleep: NoSleep,
+}
+
+impl KeepAwake {
+    fn acquire(nosleep_type: NoSleepType) -> Result<KeepAwake, NoSleepError> {
+        let mut nosleep = NoSleep::new()?;
+        nosleep.prevent_sleep(nosleep_type)?;
+        Ok(KeepAwake { nosleep })
+    }
+
+    /// Keeps both the display and the system awake while a video plays.
+    ///
+    /// - Linux: `Inhibit` with the suspend and idle flags (GNOME),
+    ///   otherwise `org.freedesktop.ScreenSaver` and
+    ///   `org.freedesktop.PowerManagement`
+    /// - macOS: `PreventUserIdleDisplaySleep`
+    /// - Windows: `PowerRequestDisplayRequired` and `PowerRequestSystemRequired`
+    pub fn for_video() -> Result<KeepAwake, NoSleepError> {
+        KeepAwake::acquire(NoSleepType::PreventUserIdleDisplaySleep)
+    }
+
+    /// Keeps the system awake while the display may still dim and turn off.
+    ///
+    /// - Linux: `Inhibit` with the suspend flag (GNOME),
+    ///   otherwise `org.freedesktop.PowerManagement`
+    /// - macOS: `PreventUserIdleSystemSleep`
+    /// - Windows: `PowerRequestSystemRequired`
+    pub fn for_download() -> Result<KeepAwake, NoSleepError> {
+        KeepAwake::acquire(NoSleepType::PreventUserIdleSystemSleep)
+    }
+
+    /// Keeps the display on and the screensaver from starting during a presentation.
+    ///
+    /// - Linux: `Inhibit` with the suspend and idle flags (GNOME),
+    ///   otherwise `org.freedesktop.ScreenSaver` and
+    ///   `org.freedesktop.PowerManagement`
+    /// - macOS: `PreventUserIdleDisplaySleep`, which also holds off the screensaver
+    /// - Windows: `PowerRequestDisplayRequired` and `PowerRequestSystemRequired`
+    pub fn for_presentation() -> Result<KeepAwake, NoSleepError> {
+        KeepAwake::acquire(NoSleepType::PreventUserIdleDisplaySleep)
+    }
+
+    /// Returns `true` if the block is still held.
+    pub fn is_blocked(&self) -> bool {
+        self.nosleep.is_blocked()
+    }
+
+    /// See [`NoSleepTrait::handles`].
+    pub fn handles(&self) -> Vec<HandleInfo> {
+        self.nosleep.handles()
+    }
+}
+
+impl Drop for KeepAwake {
+    fn drop(&mut self) {
+        // Best effort, there is no way to report the error
+        let _ = self.nosleep.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(keep_awake: &KeepAwake) -> Vec<NoSleepType> {
+        keep_awake
+            .handles()
+            .iter()
+            .map(|handle| handle.kind)
+            .collect()
+    }
+
+    // Requires a platform where blocking is possible (e.g. an active Gnome session)
+    #[test]
+    #[ignore]
+    fn test_for_video() {
+        let keep_awake = KeepAwake::for_video().unwrap();
+        assert!(keep_awake.is_blocked());
+        assert!(kinds(&keep_awake).contains(&NoSleepType::PreventUserIdleDisplaySleep));
+    }
+
+    // Requires a platform where blocking is possible (e.g. an active Gnome session)
+    #[test]
+    #[ignore]
+    fn test_for_download() {
+        let keep_awake = KeepAwake::for_download().unwrap();
+        assert!(keep_awake.is_blocked());
+        let kinds = kinds(&keep_awake);
+        assert!(kinds.contains(&NoSleepType::PreventUserIdleSystemSleep));
+        assert!(!kinds.contains(&NoSleepType::PreventUserIdleDisplaySleep));
+    }
+
+    // Requires a platform where blocking is possible (e.g. an active Gnome session)
+    #[test]
+    #[ignore]
+    fn test_for_presentation() {
+        let keep_awake = KeepAwake::for_presentation().unwrap();
+        assert!(keep_awake.is_blocked());
+        assert!(kinds(&keep_awake).contains(&NoSleepType::PreventUserIdleDisplaySleep));
+    }
+}
@@ -1,22 +1,70 @@
 //! Cross-platform library to block the
 //! power save functionality in the OS.
 //!
-//! ```rust
+//! ```rust,no_run
 //! # use std::error::Error;
 //! # use std::{time::Duration, thread};
 //! # use nosleep::*;
 //! # fn main() -> Result<(), Box<dyn Error>> {
-//!    let nosleep = NoSleep::new()?;
+//!    let mut nosleep = NoSleep::new()?;
 //!    nosleep.prevent_display_sleep()?;
-//!    // Depending on the platform, the block will hold
-//!    // until either nosleep will be dropped (Linux)
-//!    // or the process exits (macOS) or you manually
-//!    // call `nosleep.stop()`
+//!    // The block will hold until either nosleep
+//!    // will be dropped or you manually call
+//!    // `nosleep.stop()`
 //! #  Ok(())
 //! # }
 //! ```
 
-pub use nosleep_types::{NoSleepError, NoSleepTrait};
+pub use nosleep_types::{
+    BackendProbe, BlockLevel, BlockOutcome, Capabilities, HandleInfo, HealthReport, NoSleepError,
+    NoSleepObserver, NoSleepTrait, NoSleepType, ParseNoSleepTypeError, PredicateBlock, StateEvent,
+    SwitchPolicy,
+};
+
+#[cfg(feature = "async")]
+mod async_ext;
+#[cfg(feature = "async")]
+pub use async_ext::NoSleepAsyncExt;
+
+#[cfg(all(
+    feature = "async",
+    any(target_os = "linux", target_os = "macos", target_os = "windows")
+))]
+mod async_guard;
+#[cfg(all(
+    feature = "async",
+    any(target_os = "linux", target_os = "macos", target_os = "windows")
+))]
+pub use async_guard::AsyncNoSleepGuard;
+
+#[cfg(feature = "mock")]
+pub use nosleep_types::{MockCall, MockNoSleep};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod guard;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub use guard::{BlockGuard, SharedBlock};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod handle;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub use handle::{is_active, start, stop, NoSleepHandle};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod keep_awake;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub use keep_awake::KeepAwake;
+
+#[cfg(all(
+    feature = "raw-window-handle",
+    any(target_os = "linux", target_os = "macos", target_os = "windows")
+))]
+mod window;
+#[cfg(all(
+    feature = "raw-window-handle",
+    any(target_os = "linux", target_os = "macos", target_os = "windows")
+))]
+pub use window::WindowNoSleep;
 
 #[cfg(target_os = "macos")]
 pub use nosleep_mac_sys::*;
@@ -27,7 +75,62 @@ pub use nosleep_nix::*;
 #[cfg(target_os = "windows")]
 pub use nosleep_windows::*;
 
-#[cfg(test)]
+/// Returns `true` if blocks can be held on this system, without acquiring one.
+/// On Linux this checks the session bus for a supported service.
+#[cfg(target_os = "linux")]
+pub fn available() -> bool {
+    nosleep_nix::available()
+}
+
+/// Returns `true` if blocks can be held on this system, without acquiring one.
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+pub fn available() -> bool {
+    true
+}
+
+/// Returns `true` if blocks can be held on this system, without acquiring one.
+/// There is no backend for this target.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub fn available() -> bool {
+    false
+}
+
+/// Holds a block of `nosleep_type` while `predicate` returns `true`,
+/// checking it every `interval` on a helper thread, see [`PredicateBlock`].
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use nosleep::*;
+/// # fn main() -> Result<(), NoSleepError> {
+/// let output = std::path::PathBuf::from("export.mp4");
+/// let _block = prevent_while_predicate(
+///     NoSleepType::PreventUserIdleSystemSleep,
+///     Duration::from_secs(30),
+///     move || output.with_extension("part").exists(),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub fn prevent_while_predicate(
+    nosleep_type: NoSleepType,
+    interval: std::time::Duration,
+    predicate: impl FnMut() -> bool + Send + 'static,
+) -> Result<PredicateBlock, NoSleepError> {
+    PredicateBlock::start::<NoSleep>(nosleep_type, interval, predicate)
+}
+
+/// Creates the platform backend behind a trait object.
+/// Useful to abstract over platforms, for example in a plugin system.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub fn new_boxed() -> Result<Box<dyn NoSleepTrait>, NoSleepError> {
+    Ok(Box::new(NoSleep::new()?))
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "linux", target_os = "macos", target_os = "windows")
+))]
 mod tests {
     use nosleep_types::NoSleepTrait;
 
@@ -40,4 +143,60 @@ mod tests {
         std::thread::sleep(std::time::Duration::from_millis(2000));
         nosleep.stop().unwrap();
     }
+
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    #[test]
+    fn test_available() {
+        assert!(available());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    #[test]
+    fn test_default() {
+        let nosleep = NoSleep::default();
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Needs a session bus on Linux
+    #[cfg_attr(target_os = "linux", ignore)]
+    #[test]
+    fn test_supported_types() {
+        let nosleep = NoSleep::new().unwrap();
+        for nosleep_type in SUPPORTED_TYPES {
+            assert!(
+                nosleep.is_supported_type(*nosleep_type),
+                "{:?}",
+                nosleep_type
+            );
+        }
+    }
+
+    // Needs a session bus on Linux
+    #[cfg_attr(target_os = "linux", ignore)]
+    #[test]
+    fn test_trait_object() {
+        let nosleep: Box<dyn NoSleepTrait> = Box::new(NoSleep::new().unwrap());
+        assert!(!nosleep.is_blocked());
+    }
+
+    // Needs a session bus on Linux
+    #[cfg_attr(target_os = "linux", ignore)]
+    #[test]
+    fn test_health_check() {
+        let nosleep = NoSleep::new().unwrap();
+        let report = nosleep.health_check();
+        assert!(!report.backends.is_empty());
+        assert!(report.to_string().contains(&report.platform));
+    }
+
+    // Requires a platform where blocking is possible (e.g. an active Gnome session)
+    #[test]
+    #[ignore]
+    fn test_new_boxed() {
+        let mut nosleep = new_boxed().unwrap();
+        nosleep.prevent_display_sleep().unwrap();
+        assert!(nosleep.is_blocked());
+        nosleep.stop().unwrap();
+        assert!(!nosleep.is_blocked());
+    }
 }
@@ -0,0 +1,120 @@
+use nosleep_types::{NoSleepError, NoSleepTrait, NoSleepType};
+use raw_window_handle::HasWindowHandle;
+
+use crate::NoSleep;
+
+/// Ties a block to a window: held while the window is focused and
+/// released when it loses focus or is minimized. Forward the window
+/// events of the GUI event loop to [`WindowNoSleep::on_focus`] and
+/// [`WindowNoSleep::on_blur`], see `examples/window.rs` for `winit`.
+///
+/// On Linux the block is scoped to the X11 window when Gnome or the portal
+/// is used. Wayland surfaces cannot be passed on without exporting them
+/// first, so those blocks are not scoped, just like on macOS and Windows.
+pub struct WindowNoSleep<T: NoSleepTrait = NoSleep> {
+    nosleep: T,
+    nosleep_type: NoSleepType,
+}
+
+impl WindowNoSleep<NoSleep> {
+    /// Creates a block of `nosleep_type` for `window`, which is not held
+    /// until the first call to [`WindowNoSleep::on_focus`].
+    pub fn new(
+        window: &impl HasWindowHandle,
+        nosleep_type: NoSleepType,
+    ) -> Result<WindowNoSleep, NoSleepError> {
+        #[allow(unused_mut)]
+        let mut nosleep = NoSleep::new()?;
+        #[cfg(target_os = "linux")]
+        nosleep.set_window_xid(x11_window(window));
+        #[cfg(not(target_os = "linux"))]
+        let _ = window;
+        Ok(WindowNoSleep::with_backend(nosleep, nosleep_type))
+    }
+}
+
+impl<T: NoSleepTrait> WindowNoSleep<T> {
+    /// Uses an existing backend instead of creating a new one.
+    pub fn with_backend(nosleep: T, nosleep_type: NoSleepType) -> WindowNoSleep<T> {
+        WindowNoSleep {
+            nosleep,
+            nosleep_type,
+        }
+    }
+
+    /// Acquires the block, if not held already.
+    pub fn on_focus(&mut self) -> Result<(), NoSleepError> {
+        if !self.nosleep.is_blocked() {
+            self.nosleep.prevent_sleep(self.nosleep_type)?;
+        }
+        Ok(())
+    }
+
+    /// Releases the block, if held.
+    pub fn on_blur(&mut self) -> Result<(), NoSleepError> {
+        if self.nosleep.is_blocked() {
+            self.nosleep.stop()?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the block is currently held.
+    pub fn is_blocked(&self) -> bool {
+        self.nosleep.is_blocked()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn x11_window(window: &impl HasWindowHandle) -> Option<u32> {
+    use raw_window_handle::RawWindowHandle;
+
+    match window.window_handle().ok()?.as_raw() {
+        RawWindowHandle::Xlib(handle) => u32::try_from(handle.window).ok(),
+        RawWindowHandle::Xcb(handle) => Some(handle.window.get()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nosleep_types::{MockCall, MockNoSleep};
+
+    use super::*;
+
+    // The blocks acquired by `window` so far
+    fn acquired(window: &WindowNoSleep<MockNoSleep>) -> usize {
+        window
+            .nosleep
+            .calls()
+            .iter()
+            .filter(|call| matches!(call, MockCall::PreventSleep(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_focus_blur() {
+        let mut window = WindowNoSleep::with_backend(
+            MockNoSleep::new().unwrap(),
+            NoSleepType::PreventUserIdleDisplaySleep,
+        );
+        assert!(!window.is_blocked());
+
+        window.on_focus().unwrap();
+        assert_eq!(
+            NoSleepType::PreventUserIdleDisplaySleep,
+            window.nosleep.handles()[0].kind
+        );
+        // Focusing again keeps the block that is held
+        window.on_focus().unwrap();
+        assert_eq!(1, acquired(&window));
+
+        window.on_blur().unwrap();
+        assert!(!window.is_blocked());
+        window.on_blur().unwrap();
+        assert!(!window.is_blocked());
+
+        window.on_focus().unwrap();
+        assert!(window.is_blocked());
+        assert_eq!(2, acquired(&window));
+    }
+}